@@ -0,0 +1,52 @@
+//! Just enough of the calendar to find "the 1st of the month" from a ledger
+//! timestamp, without `std` or a date crate. The conversions are Howard
+//! Hinnant's well-known `days_from_civil` and `civil_from_days`, trimmed down
+//! to dates from 1970 on, since that's all a ledger timestamp can be.
+
+const SECONDS_IN_DAY: u64 = 24 * 60 * 60;
+
+/// Which month `timestamp` falls in, counting January 1970 as month `0`.
+pub fn month_of(timestamp: u64) -> u64 {
+    let (year, month, _) = civil_from_days(timestamp / SECONDS_IN_DAY);
+    (year - 1970) * 12 + (month - 1)
+}
+
+/// Midnight (UTC) at the start of `day` in the given `month` (counted the same
+/// way as `month_of`).
+pub fn day_in_month(month: u64, day: u32) -> Option<u64> {
+    let year = month.checked_div(12)?.checked_add(1970)?;
+    days_from_civil(year, month % 12 + 1, day as u64)?.checked_mul(SECONDS_IN_DAY)
+}
+
+/// The number of days from 1970-01-01 to `year`-`month`-`day`.
+fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+    // The calendar is easier to work with if the year starts in March, so the
+    // leap day is the very last day of it.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era.checked_mul(146097)?
+        .checked_add(day_of_era)?
+        .checked_sub(719468)
+}
+
+/// The (year, month, day) that falls `days` days after 1970-01-01.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let march_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * march_month + 2) / 5 + 1;
+    let month = if march_month < 10 {
+        march_month + 3
+    } else {
+        march_month - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
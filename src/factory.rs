@@ -0,0 +1,279 @@
+//! A factory for people who'd rather have one AllowanceContract per
+//! agreement than lots of subscriptions in one. Deploying a contract and then
+//! calling `init` on it takes two transactions, and anybody who spots the new
+//! contract in between can call `init` first. The factory does both in one
+//! invocation, and keeps a list of everything it has deployed.
+//!
+//! It's only built with the `factory` feature. The factory needs the hash of
+//! the AllowanceContract WASM it deploys, so build (and install) that without
+//! the feature first. Every contract the factory deploys runs that WASM, so
+//! only whoever deployed the factory gets to say which it is, the same way
+//! the factory proves it deployed an AllowanceContract: deploy the factory
+//! from a contract, and have that call `set_wasm` in the same invocation.
+
+use soroban_auth::Identifier;
+use soroban_sdk::{
+    contractimpl, contracttype, serde::Serialize, symbol, Address, BytesN, Env, Vec,
+};
+
+use crate::{check_deployer, AllowanceContractClient, Error, Options, MAX_PAGE};
+
+#[contracttype]
+#[derive(Clone)]
+pub enum FactoryKey {
+    WasmHash, // BytesN<32>
+    Deployed, // Vec<BytesN<32>>
+}
+
+pub struct FactoryContract;
+
+pub trait FactoryTrait {
+    // Tells the factory which installed WASM to deploy. Only the contract
+    // that deployed the factory can, with the `salt` it deployed it with,
+    // and only once.
+    fn set_wasm(e: Env, salt: BytesN<32>, wasm_hash: BytesN<32>) -> Result<(), Error>;
+
+    // Deploys a new AllowanceContract and initializes it, with the invoker as
+    // the `Parent`, returning its contract id. The `Parent` still has to
+    // approve the new contract on the token, just like after `init`.
+    fn deploy(
+        e: Env,
+        child: Identifier,
+        token_id: BytesN<32>,
+        amount: i128,
+        step: u64,
+        options: Options,
+    ) -> Result<BytesN<32>, Error>;
+
+    // The ids of the contracts this factory has deployed, oldest first, a
+    // page at a time: up to `limit` of them (and never more than 50),
+    // skipping the first `offset`.
+    fn deployed(e: Env, offset: u32, limit: u32) -> Result<Vec<BytesN<32>>, Error>;
+}
+
+fn read_deployed(e: &Env) -> Result<Vec<BytesN<32>>, Error> {
+    match e.storage().get(FactoryKey::Deployed) {
+        Some(Ok(ids)) => Ok(ids),
+        Some(Err(_)) => Err(Error::CorruptData),
+        None => Ok(Vec::new(e)),
+    }
+}
+
+#[contractimpl]
+impl FactoryTrait for FactoryContract {
+    fn set_wasm(e: Env, salt: BytesN<32>, wasm_hash: BytesN<32>) -> Result<(), Error> {
+        check_deployer(&e, &salt)?;
+        if e.storage().has(FactoryKey::WasmHash) {
+            return Err(Error::ContractAlreadyInitialized);
+        }
+        e.storage().set(FactoryKey::WasmHash, wasm_hash);
+        Ok(())
+    }
+
+    fn deploy(
+        e: Env,
+        child: Identifier,
+        token_id: BytesN<32>,
+        amount: i128,
+        step: u64,
+        options: Options,
+    ) -> Result<BytesN<32>, Error> {
+        let wasm_hash: BytesN<32> = match e.storage().get(FactoryKey::WasmHash) {
+            Some(Ok(hash)) => hash,
+            Some(Err(_)) => return Err(Error::CorruptData),
+            None => return Err(Error::ContractNotInitialized),
+        };
+        let parent = match e.invoker() {
+            Address::Account(id) => Identifier::Account(id),
+            Address::Contract(id) => Identifier::Contract(id),
+        };
+
+        // The same `Parent` and `Child` can have more than one agreement, so
+        // the salt also counts how many contracts the factory has deployed.
+        let mut ids = read_deployed(&e)?;
+        let salt_data = (parent.clone(), child.clone(), ids.len()).serialize(&e);
+        let salt = e.compute_hash_sha256(salt_data);
+        let id = e.deployer().with_current_contract(&salt).deploy(&wasm_hash);
+
+        // If this fails, so does the whole invocation, and the new contract
+        // is never deployed at all.
+        AllowanceContractClient::new(&e, &id)
+            .init_on_behalf(&salt, &parent, &child, &token_id, &amount, &step, &options);
+
+        ids.push_back(id.clone());
+        e.storage().set(FactoryKey::Deployed, ids);
+        e.events()
+            .publish((symbol!("deployed"), parent, child), id.clone());
+        Ok(id)
+    }
+
+    fn deployed(e: Env, offset: u32, limit: u32) -> Result<Vec<BytesN<32>>, Error> {
+        let ids = read_deployed(&e)?;
+        let mut page = Vec::new(&e);
+        let end = offset.saturating_add(limit.min(MAX_PAGE)).min(ids.len());
+        for i in offset..end {
+            page.push_back(
+                ids.get(i)
+                    .ok_or(Error::CorruptData)?
+                    .map_err(|_| Error::CorruptData)?,
+            );
+        }
+        Ok(page)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::deployed_id;
+    use crate::testlib::{set_timestamp, setup_token};
+    use soroban_auth::Signature;
+    use soroban_sdk::{testutils::Accounts, vec};
+
+    /// The AllowanceContract as it's built without the `factory` feature, by
+    /// `cargo build --target wasm32-unknown-unknown --release`. That has to
+    /// have been run before these tests will build.
+    mod allowance {
+        soroban_sdk::contractimport!(
+            file = "./target/wasm32-unknown-unknown/release/soroban_asset_interop_contract.wasm"
+        );
+    }
+
+    /// Stands in for whatever deployed the factory, and tells it which WASM
+    /// to deploy.
+    pub struct Deployer;
+
+    #[contractimpl]
+    impl Deployer {
+        pub fn set_wasm(
+            e: Env,
+            factory: BytesN<32>,
+            salt: BytesN<32>,
+            wasm_hash: BytesN<32>,
+        ) -> Result<(), Error> {
+            match FactoryContractClient::new(&e, &factory).try_set_wasm(&salt, &wasm_hash) {
+                Ok(_) => Ok(()),
+                Err(Ok(err)) => Err(err),
+                Err(Err(_)) => panic!("set_wasm failed without a contract error"),
+            }
+        }
+    }
+
+    /// A factory as `Deployer` deployed it with `salt`, and the `Deployer`.
+    fn setup_factory(env: &Env, salt: &BytesN<32>) -> (FactoryContractClient, DeployerClient) {
+        let deployer_id = env.register_contract(None, Deployer);
+        let factory_id =
+            env.register_contract(Some(&deployed_id(env, &deployer_id, salt)), FactoryContract);
+        (
+            FactoryContractClient::new(env, &factory_id),
+            DeployerClient::new(env, &deployer_id),
+        )
+    }
+
+    /// In `test_set_wasm()`, only the contract that deployed the factory gets
+    /// to say what it deploys, and only the once.
+    #[test]
+    fn test_set_wasm() {
+        let env = Env::default();
+        let salt = BytesN::from_array(&env, &[7; 32]);
+        let (factory, deployer) = setup_factory(&env, &salt);
+        let wasm_hash = env.install_contract_wasm(allowance::WASM);
+        let other = BytesN::from_array(&env, &[9; 32]);
+
+        let u1 = env.accounts().generate();
+        assert_eq!(
+            factory.with_source_account(&u1).try_set_wasm(&salt, &other),
+            Err(Ok(Error::InvalidInvoker))
+        );
+        let (_, outsider) = setup_factory(&env, &salt);
+        assert_eq!(
+            outsider.try_set_wasm(&factory.contract_id, &salt, &other),
+            Err(Ok(Error::InvalidInvoker))
+        );
+        assert_eq!(
+            deployer.try_set_wasm(&factory.contract_id, &other, &other),
+            Err(Ok(Error::InvalidInvoker))
+        );
+
+        deployer.set_wasm(&factory.contract_id, &salt, &wasm_hash);
+        assert_eq!(
+            deployer.try_set_wasm(&factory.contract_id, &salt, &other),
+            Err(Ok(Error::ContractAlreadyInitialized))
+        );
+    }
+
+    #[test]
+    fn test_deploy() {
+        let env = Env::default();
+        set_timestamp(&env, 1669726145);
+
+        let salt = BytesN::from_array(&env, &[7; 32]);
+        let (factory, deployer) = setup_factory(&env, &salt);
+        deployer.set_wasm(
+            &factory.contract_id,
+            &salt,
+            &env.install_contract_wasm(allowance::WASM),
+        );
+
+        let u1 = env.accounts().generate();
+        let u2 = env.accounts().generate();
+        let u3 = env.accounts().generate();
+        let (id, token) = setup_token(&env, &u1, &BytesN::from_array(&env, &[1; 32]));
+
+        // Two agreements, with different children and schedules.
+        let weekly = factory.with_source_account(&u1).deploy(
+            &Identifier::Account(u2.clone()),
+            &id,
+            &52000,
+            &(7 * 24 * 60 * 60),
+            &Options::default(),
+        );
+        let daily = factory.with_source_account(&u1).deploy(
+            &Identifier::Account(u3.clone()),
+            &id,
+            &365000,
+            &(24 * 60 * 60),
+            &Options::default(),
+        );
+        assert_ne!(weekly, daily);
+
+        assert_eq!(
+            factory.deployed(&0, &10),
+            vec![&env, weekly.clone(), daily.clone()]
+        );
+
+        // Nobody else gets to `init` them.
+        let client = AllowanceContractClient::new(&env, &weekly);
+        assert_eq!(
+            client.with_source_account(&u3).try_init(
+                &Identifier::Account(u3.clone()),
+                &id,
+                &1000,
+                &1,
+                &Options::default(),
+            ),
+            Err(Ok(Error::ContractAlreadyInitialized))
+        );
+
+        for contract in [&weekly, &daily] {
+            token.with_source_account(&u1).approve(
+                &Signature::Invoker,
+                &0,
+                &Identifier::Contract(contract.clone()),
+                &1000000,
+            );
+        }
+
+        // Both pay out straight away, and then each keeps its own schedule.
+        AllowanceContractClient::new(&env, &weekly).withdraw();
+        AllowanceContractClient::new(&env, &daily).withdraw();
+        assert_eq!(token.balance(&Identifier::Account(u2.clone())), 1000);
+        assert_eq!(token.balance(&Identifier::Account(u3.clone())), 1000);
+
+        set_timestamp(&env, 1669726145 + 24 * 60 * 60);
+        assert!(!AllowanceContractClient::new(&env, &weekly).can_withdraw());
+        AllowanceContractClient::new(&env, &daily).withdraw();
+        assert_eq!(token.balance(&Identifier::Account(u2)), 1000);
+        assert_eq!(token.balance(&Identifier::Account(u3)), 2000);
+    }
+}
@@ -0,0 +1,664 @@
+//! For the services that run alongside the contract rather than in it, like a
+//! billing backend that wants to build this contract's arguments and decode
+//! its state and events without keeping a copy of every struct. It's only
+//! built with the `std` feature, and never goes into the WASM.
+//!
+//! Most of the contract's types hold an `Identifier`, a `Vec` or something
+//! else that only lives inside an `Env`, so they can't be serialized on their
+//! own. Instead, any of them (and any event payload, which is just a tuple of
+//! them) converts to and from the XDR `ScVal` it's stored and published as,
+//! and the `ScVal` serializes however you like: as XDR, or with serde. The
+//! types that are plain data all the way down (`Status`, `Role`, `Outcome`,
+//! `Checkpoint`, `ReconcileReport`, `RateChange`, `PriceData` and
+//! `PauseRequest`) derive serde's traits themselves as well.
+//!
+//! For the ones a backend deals with most, there's a plain-data mirror, too:
+//! `ConfigDto`, `OptionsDto` and `ReceiptDto`, with an `IdentifierDto`, a
+//! `MetadataDto`, a `SignersDto` and a `SwapRouteDto` for what they hold.
+//! They derive serde's traits, and convert to and from the contract's types
+//! with `from_contract` and `to_contract`. An event payload is a tuple of
+//! these and plain numbers (a share, say, is an `(IdentifierDto, u32)`), so
+//! it converts piece by piece.
+//!
+//! Every conversion takes the `Env` to convert with from the caller. Any
+//! `Env` will do, even one with nothing in it, so this module doesn't make
+//! one itself (in the SDK the workspace pins, `Env::default()` is behind
+//! `soroban-sdk/testutils`, which a service can turn on for itself if that's
+//! where it gets one).
+
+use serde::{Deserialize, Serialize};
+use soroban_auth::Identifier;
+use soroban_sdk::{
+    xdr::{AccountId as XdrAccountId, ScObject, ScVal},
+    AccountId, Bytes, BytesN, Env, IntoVal, Map, RawVal, Symbol, TryFromVal, Vec as SorobanVec,
+};
+
+use crate::{Config, Error, Metadata, Options, Receipt, Signers, SwapRoute};
+
+/// Converts `value` to the `ScVal` the contract would store or publish it as.
+pub fn to_scval<T: IntoVal<Env, RawVal>>(env: &Env, value: T) -> Result<ScVal, Error> {
+    ScVal::try_from_val(env, value.into_val(env)).map_err(|_| Error::CorruptData)
+}
+
+/// Reads a `T` back out of an `ScVal`, say from an event or a ledger entry.
+/// Anything that isn't a `T` is `Error::CorruptData`, just like it is to the
+/// contract.
+pub fn from_scval<T: TryFromVal<Env, RawVal>>(env: &Env, scval: &ScVal) -> Result<T, Error> {
+    let raw = RawVal::try_from_val(env, scval.clone()).map_err(|_| Error::CorruptData)?;
+    T::try_from_val(env, raw).map_err(|_| Error::CorruptData)
+}
+
+/// A contract or token id (or a key) as its 32 bytes.
+fn to_array(bytes: &BytesN<32>) -> [u8; 32] {
+    let mut array = [0; 32];
+    for (i, byte) in Bytes::from(bytes.clone()).iter().enumerate() {
+        array[i] = byte;
+    }
+    array
+}
+
+fn to_account(env: &Env, account: &AccountId) -> Result<XdrAccountId, Error> {
+    match to_scval(env, account.clone())? {
+        ScVal::Object(Some(ScObject::AccountId(account))) => Ok(account),
+        _ => Err(Error::CorruptData),
+    }
+}
+
+fn from_account(env: &Env, account: &XdrAccountId) -> Result<AccountId, Error> {
+    from_scval(
+        env,
+        &ScVal::Object(Some(ScObject::AccountId(account.clone()))),
+    )
+}
+
+fn from_symbol(symbol: &Symbol) -> String {
+    symbol.to_string()
+}
+
+fn to_symbol(symbol: &str) -> Result<Symbol, Error> {
+    Symbol::try_from_str(symbol).map_err(|_| Error::CorruptData)
+}
+
+fn from_shares(
+    env: &Env,
+    shares: &SorobanVec<(Identifier, u32)>,
+) -> Result<Vec<(IdentifierDto, u32)>, Error> {
+    let mut dtos = Vec::new();
+    for share in shares.iter() {
+        let (id, bps) = share.map_err(|_| Error::CorruptData)?;
+        dtos.push((IdentifierDto::from_contract(env, &id)?, bps));
+    }
+    Ok(dtos)
+}
+
+fn to_shares(
+    env: &Env,
+    dtos: &[(IdentifierDto, u32)],
+) -> Result<SorobanVec<(Identifier, u32)>, Error> {
+    let mut shares = SorobanVec::new(env);
+    for (id, bps) in dtos {
+        shares.push_back((id.to_contract(env)?, *bps));
+    }
+    Ok(shares)
+}
+
+fn from_map(tokens: &Map<BytesN<32>, i128>) -> Result<Vec<([u8; 32], i128)>, Error> {
+    let mut dtos = Vec::new();
+    for entry in tokens.iter() {
+        let (token_id, amount) = entry.map_err(|_| Error::CorruptData)?;
+        dtos.push((to_array(&token_id), amount));
+    }
+    Ok(dtos)
+}
+
+fn to_map(env: &Env, dtos: &[([u8; 32], i128)]) -> Map<BytesN<32>, i128> {
+    let mut tokens = Map::new(env);
+    for (token_id, amount) in dtos {
+        tokens.set(BytesN::from_array(env, token_id), *amount);
+    }
+    tokens
+}
+
+/// `Identifier`, with an account as the XDR `AccountId` it is on the ledger.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum IdentifierDto {
+    Contract([u8; 32]),
+    Ed25519([u8; 32]),
+    Account(XdrAccountId),
+}
+
+impl IdentifierDto {
+    pub fn from_contract(env: &Env, id: &Identifier) -> Result<Self, Error> {
+        Ok(match id {
+            Identifier::Contract(id) => IdentifierDto::Contract(to_array(id)),
+            Identifier::Ed25519(key) => IdentifierDto::Ed25519(to_array(key)),
+            Identifier::Account(account) => IdentifierDto::Account(to_account(env, account)?),
+        })
+    }
+
+    pub fn to_contract(&self, env: &Env) -> Result<Identifier, Error> {
+        Ok(match self {
+            IdentifierDto::Contract(id) => Identifier::Contract(BytesN::from_array(env, id)),
+            IdentifierDto::Ed25519(key) => Identifier::Ed25519(BytesN::from_array(env, key)),
+            IdentifierDto::Account(account) => Identifier::Account(from_account(env, account)?),
+        })
+    }
+}
+
+/// `Metadata`, with the `reference` as its bytes.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MetadataDto {
+    pub name: String,
+    pub reference: Vec<u8>,
+}
+
+impl MetadataDto {
+    pub fn from_contract(metadata: &Metadata) -> Self {
+        MetadataDto {
+            name: from_symbol(&metadata.name),
+            reference: metadata.reference.iter().collect(),
+        }
+    }
+
+    pub fn to_contract(&self, env: &Env) -> Result<Metadata, Error> {
+        Ok(Metadata {
+            name: to_symbol(&self.name)?,
+            reference: Bytes::from_slice(env, &self.reference),
+        })
+    }
+}
+
+/// `Signers`, with each account as its XDR `AccountId`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SignersDto {
+    pub accounts: Vec<XdrAccountId>,
+    pub threshold: u32,
+    pub approval_window: u64,
+}
+
+impl SignersDto {
+    pub fn from_contract(env: &Env, signers: &Signers) -> Result<Self, Error> {
+        let mut accounts = Vec::new();
+        for account in signers.accounts.iter() {
+            let account = account.map_err(|_| Error::CorruptData)?;
+            accounts.push(to_account(env, &account)?);
+        }
+        Ok(SignersDto {
+            accounts,
+            threshold: signers.threshold,
+            approval_window: signers.approval_window,
+        })
+    }
+
+    pub fn to_contract(&self, env: &Env) -> Result<Signers, Error> {
+        let mut accounts = SorobanVec::new(env);
+        for account in &self.accounts {
+            accounts.push_back(from_account(env, account)?);
+        }
+        Ok(Signers {
+            accounts,
+            threshold: self.threshold,
+            approval_window: self.approval_window,
+        })
+    }
+}
+
+/// `SwapRoute`, with the ids as their bytes.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SwapRouteDto {
+    pub pool: [u8; 32],
+    pub token_out: [u8; 32],
+    pub min_price: i128,
+}
+
+impl SwapRouteDto {
+    pub fn from_contract(route: &SwapRoute) -> Self {
+        SwapRouteDto {
+            pool: to_array(&route.pool),
+            token_out: to_array(&route.token_out),
+            min_price: route.min_price,
+        }
+    }
+
+    pub fn to_contract(&self, env: &Env) -> SwapRoute {
+        SwapRoute {
+            pool: BytesN::from_array(env, &self.pool),
+            token_out: BytesN::from_array(env, &self.token_out),
+            min_price: self.min_price,
+        }
+    }
+}
+
+/// `Options`, field for field.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OptionsDto {
+    pub auto_approve_child_pause: bool,
+    pub lenient: bool,
+    pub decreases_need_acceptance: bool,
+    pub amount_notice_period: u64,
+    pub escalator_bps: u32,
+    pub escalator_every: u32,
+    pub unit_price: i128,
+    pub included_units: u64,
+    pub max_overage: u64,
+    pub trial_periods: u32,
+    pub discount_bps: u32,
+    pub discount_periods: u32,
+    pub streaming: bool,
+    pub monthly_anchor: u32,
+    pub prorate_first: bool,
+    pub bill_in_arrears: bool,
+    pub allow_contract_invokers: bool,
+    pub cancel_notice_period: u64,
+    pub min_periods: u32,
+    pub early_termination_fee: i128,
+    pub late_fee_bps: u32,
+    pub max_failures: u32,
+    pub fee_collector: Option<IdentifierDto>,
+    pub fee_bps: u32,
+    pub recipients: Option<Vec<(IdentifierDto, u32)>>,
+    pub shares_window: u64,
+    pub guarantor: Option<IdentifierDto>,
+    pub payers: Option<Vec<(IdentifierDto, u32)>>,
+    pub suspend_on_payer_exit: bool,
+    pub metadata: Option<MetadataDto>,
+    pub signers: Option<SignersDto>,
+    pub reconcile_tolerance: i128,
+    pub measure_received: bool,
+    pub swap: Option<SwapRouteDto>,
+    pub accepted_tokens: Option<Vec<([u8; 32], i128)>>,
+    pub grace_periods: u32,
+    pub registry: Option<[u8; 32]>,
+    pub lifetime_cap: i128,
+    pub amount_change_cooldown: u64,
+    pub max_increase_bps: u32,
+    pub tolerance: u64,
+    pub lapse_missed: bool,
+    pub anchor_on_first_withdraw: bool,
+    pub deployer_salt: Option<[u8; 32]>,
+}
+
+impl OptionsDto {
+    pub fn from_contract(env: &Env, o: &Options) -> Result<Self, Error> {
+        Ok(OptionsDto {
+            auto_approve_child_pause: o.auto_approve_child_pause,
+            lenient: o.lenient,
+            decreases_need_acceptance: o.decreases_need_acceptance,
+            amount_notice_period: o.amount_notice_period,
+            escalator_bps: o.escalator_bps,
+            escalator_every: o.escalator_every,
+            unit_price: o.unit_price,
+            included_units: o.included_units,
+            max_overage: o.max_overage,
+            trial_periods: o.trial_periods,
+            discount_bps: o.discount_bps,
+            discount_periods: o.discount_periods,
+            streaming: o.streaming,
+            monthly_anchor: o.monthly_anchor,
+            prorate_first: o.prorate_first,
+            bill_in_arrears: o.bill_in_arrears,
+            allow_contract_invokers: o.allow_contract_invokers,
+            cancel_notice_period: o.cancel_notice_period,
+            min_periods: o.min_periods,
+            early_termination_fee: o.early_termination_fee,
+            late_fee_bps: o.late_fee_bps,
+            max_failures: o.max_failures,
+            fee_collector: o
+                .fee_collector
+                .as_ref()
+                .map(|id| IdentifierDto::from_contract(env, id))
+                .transpose()?,
+            fee_bps: o.fee_bps,
+            recipients: o
+                .recipients
+                .as_ref()
+                .map(|shares| from_shares(env, shares))
+                .transpose()?,
+            shares_window: o.shares_window,
+            guarantor: o
+                .guarantor
+                .as_ref()
+                .map(|id| IdentifierDto::from_contract(env, id))
+                .transpose()?,
+            payers: o
+                .payers
+                .as_ref()
+                .map(|shares| from_shares(env, shares))
+                .transpose()?,
+            suspend_on_payer_exit: o.suspend_on_payer_exit,
+            metadata: o.metadata.as_ref().map(MetadataDto::from_contract),
+            signers: o
+                .signers
+                .as_ref()
+                .map(|s| SignersDto::from_contract(env, s))
+                .transpose()?,
+            reconcile_tolerance: o.reconcile_tolerance,
+            measure_received: o.measure_received,
+            swap: o.swap.as_ref().map(SwapRouteDto::from_contract),
+            accepted_tokens: o.accepted_tokens.as_ref().map(from_map).transpose()?,
+            grace_periods: o.grace_periods,
+            registry: o.registry.as_ref().map(to_array),
+            lifetime_cap: o.lifetime_cap,
+            amount_change_cooldown: o.amount_change_cooldown,
+            max_increase_bps: o.max_increase_bps,
+            tolerance: o.tolerance,
+            lapse_missed: o.lapse_missed,
+            anchor_on_first_withdraw: o.anchor_on_first_withdraw,
+            deployer_salt: o.deployer_salt.as_ref().map(to_array),
+        })
+    }
+
+    pub fn to_contract(&self, env: &Env) -> Result<Options, Error> {
+        Ok(Options {
+            auto_approve_child_pause: self.auto_approve_child_pause,
+            lenient: self.lenient,
+            decreases_need_acceptance: self.decreases_need_acceptance,
+            amount_notice_period: self.amount_notice_period,
+            escalator_bps: self.escalator_bps,
+            escalator_every: self.escalator_every,
+            unit_price: self.unit_price,
+            included_units: self.included_units,
+            max_overage: self.max_overage,
+            trial_periods: self.trial_periods,
+            discount_bps: self.discount_bps,
+            discount_periods: self.discount_periods,
+            streaming: self.streaming,
+            monthly_anchor: self.monthly_anchor,
+            prorate_first: self.prorate_first,
+            bill_in_arrears: self.bill_in_arrears,
+            allow_contract_invokers: self.allow_contract_invokers,
+            cancel_notice_period: self.cancel_notice_period,
+            min_periods: self.min_periods,
+            early_termination_fee: self.early_termination_fee,
+            late_fee_bps: self.late_fee_bps,
+            max_failures: self.max_failures,
+            fee_collector: self
+                .fee_collector
+                .as_ref()
+                .map(|id| id.to_contract(env))
+                .transpose()?,
+            fee_bps: self.fee_bps,
+            recipients: self
+                .recipients
+                .as_ref()
+                .map(|shares| to_shares(env, shares))
+                .transpose()?,
+            shares_window: self.shares_window,
+            guarantor: self
+                .guarantor
+                .as_ref()
+                .map(|id| id.to_contract(env))
+                .transpose()?,
+            payers: self
+                .payers
+                .as_ref()
+                .map(|shares| to_shares(env, shares))
+                .transpose()?,
+            suspend_on_payer_exit: self.suspend_on_payer_exit,
+            metadata: self
+                .metadata
+                .as_ref()
+                .map(|m| m.to_contract(env))
+                .transpose()?,
+            signers: self
+                .signers
+                .as_ref()
+                .map(|s| s.to_contract(env))
+                .transpose()?,
+            reconcile_tolerance: self.reconcile_tolerance,
+            measure_received: self.measure_received,
+            swap: self.swap.as_ref().map(|route| route.to_contract(env)),
+            accepted_tokens: self
+                .accepted_tokens
+                .as_ref()
+                .map(|tokens| to_map(env, tokens)),
+            grace_periods: self.grace_periods,
+            registry: self.registry.as_ref().map(|id| BytesN::from_array(env, id)),
+            lifetime_cap: self.lifetime_cap,
+            amount_change_cooldown: self.amount_change_cooldown,
+            max_increase_bps: self.max_increase_bps,
+            tolerance: self.tolerance,
+            lapse_missed: self.lapse_missed,
+            anchor_on_first_withdraw: self.anchor_on_first_withdraw,
+            deployer_salt: self
+                .deployer_salt
+                .as_ref()
+                .map(|id| BytesN::from_array(env, id)),
+        })
+    }
+}
+
+/// `Config`, as `verify_config` takes it and `get_state` gives it.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ConfigDto {
+    pub parent: IdentifierDto,
+    pub child: IdentifierDto,
+    pub token_id: [u8; 32],
+    pub amount: i128,
+    pub step: u64,
+    pub options: OptionsDto,
+}
+
+impl ConfigDto {
+    pub fn from_contract(env: &Env, config: &Config) -> Result<Self, Error> {
+        Ok(ConfigDto {
+            parent: IdentifierDto::from_contract(env, &config.parent)?,
+            child: IdentifierDto::from_contract(env, &config.child)?,
+            token_id: to_array(&config.token_id),
+            amount: config.amount,
+            step: config.step,
+            options: OptionsDto::from_contract(env, &config.options)?,
+        })
+    }
+
+    pub fn to_contract(&self, env: &Env) -> Result<Config, Error> {
+        Ok(Config {
+            parent: self.parent.to_contract(env)?,
+            child: self.child.to_contract(env)?,
+            token_id: BytesN::from_array(env, &self.token_id),
+            amount: self.amount,
+            step: self.step,
+            options: self.options.to_contract(env)?,
+        })
+    }
+}
+
+/// `Receipt`, with the `label` as a string.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ReceiptDto {
+    pub period: u64,
+    pub amount: i128,
+    pub delivered: i128,
+    pub paid_at: u64,
+    pub label: String,
+}
+
+impl ReceiptDto {
+    pub fn from_contract(receipt: &Receipt) -> Self {
+        ReceiptDto {
+            period: receipt.period,
+            amount: receipt.amount,
+            delivered: receipt.delivered,
+            paid_at: receipt.paid_at,
+            label: from_symbol(&receipt.label),
+        }
+    }
+
+    pub fn to_contract(&self) -> Result<Receipt, Error> {
+        Ok(Receipt {
+            period: self.period,
+            amount: self.amount,
+            delivered: self.delivered,
+            paid_at: self.paid_at,
+            label: to_symbol(&self.label)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::testlib::{Config as Setup, Fixture, START, WEEK};
+    use crate::{
+        Checkpoint, Config, FullState, Options, Outcome, PauseRequest, PriceData, RateChange,
+        Receipt, ReconcileReport, Role, Status,
+    };
+    use core::fmt::Debug;
+    use soroban_auth::Identifier;
+    use soroban_sdk::{symbol, vec, BytesN};
+
+    fn round_trip<T>(env: &Env, value: T)
+    where
+        T: Clone + Debug + PartialEq + IntoVal<Env, RawVal> + TryFromVal<Env, RawVal>,
+    {
+        let scval = to_scval(env, value.clone()).unwrap();
+        assert_eq!(from_scval::<T>(env, &scval), Ok(value));
+
+        // The `ScVal` itself survives serde, too.
+        let json = serde_json::to_string(&scval).unwrap();
+        assert_eq!(serde_json::from_str::<ScVal>(&json).unwrap(), scval);
+    }
+
+    fn serde_round_trip<T>(value: T)
+    where
+        T: Debug + PartialEq + serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<T>(&json).unwrap(), value);
+    }
+
+    #[test]
+    fn test_scval_round_trip() {
+        let f = Fixture::new(Setup {
+            options: Options {
+                trial_periods: 1,
+                ..Options::default()
+            },
+            ..Setup::default()
+        });
+        f.client.withdraw();
+        let env = &f.env;
+
+        let state: FullState = f.client.get_state();
+        round_trip(env, state.clone());
+        round_trip::<Config>(env, state.config);
+        round_trip(env, state.progress);
+        round_trip(env, state.pending);
+        round_trip(env, state.extras);
+        round_trip(env, Options::default());
+        for receipt in f.client.history(&0, &10).iter() {
+            round_trip::<Receipt>(env, receipt.unwrap());
+        }
+        round_trip(env, Status::Active);
+        round_trip(env, Role::Child);
+        round_trip(env, Outcome::Failed(4));
+
+        // Event payloads are tuples, like the `amount` event's
+        // `(old, amount, effective_from)`.
+        round_trip(env, (500000000_i128, 600000000_i128, START + WEEK));
+        round_trip(env, (symbol!("amount"), 0_u64));
+        round_trip(
+            env,
+            vec![env, (f.child_id(), 5000_u32), (f.parent_id(), 5000_u32)],
+        );
+    }
+
+    #[test]
+    fn test_scval_wrong_type() {
+        let f = Fixture::new(Setup::default());
+        let scval = to_scval(&f.env, START).unwrap();
+        assert_eq!(
+            from_scval::<Identifier>(&f.env, &scval),
+            Err(Error::CorruptData)
+        );
+        assert_eq!(
+            from_scval::<BytesN<32>>(&f.env, &scval),
+            Err(Error::CorruptData)
+        );
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        serde_round_trip(Status::InArrears);
+        serde_round_trip(Role::Parent);
+        serde_round_trip(Outcome::Paid(3));
+        serde_round_trip(Checkpoint {
+            at: START,
+            balance: 9615384,
+            delivered: 9615384,
+        });
+        serde_round_trip(ReconcileReport {
+            since: START,
+            paid: 9615384,
+            expected: 9615384 * 2,
+            actual: 9615384 * 2 - 1,
+        });
+        serde_round_trip(RateChange {
+            effective_from: START + WEEK,
+            amount: i128::MAX,
+        });
+        serde_round_trip(PriceData {
+            price: 10_500_000,
+            decimals: 7,
+            timestamp: START,
+        });
+        serde_round_trip(PauseRequest {
+            from: START,
+            until: START + 3 * WEEK,
+            approved: false,
+        });
+    }
+
+    #[test]
+    fn test_dto_round_trip() {
+        let f = Fixture::new(Setup::default());
+        let env = &f.env;
+        f.client.withdraw();
+
+        let config = f.client.get_state().config;
+        let dto = ConfigDto::from_contract(env, &config).unwrap();
+        serde_round_trip(dto.clone());
+        assert_eq!(dto.to_contract(env), Ok(config));
+
+        let receipt: Receipt = f.client.history(&0, &1).iter().next().unwrap().unwrap();
+        let dto = ReceiptDto::from_contract(&receipt);
+        assert_eq!(dto.label, "withdraw");
+        serde_round_trip(dto.clone());
+        assert_eq!(dto.to_contract(), Ok(receipt));
+
+        // Everything that only lives in an `Env` comes back out of one.
+        let token = BytesN::from_array(env, &[3; 32]);
+        let mut accepted = Map::new(env);
+        accepted.set(token.clone(), 250000000_i128);
+        let options = Options {
+            fee_collector: Some(Identifier::Contract(token.clone())),
+            recipients: Some(vec![
+                env,
+                (f.child_id(), 7500_u32),
+                (Identifier::Ed25519(token.clone()), 2500_u32),
+            ]),
+            metadata: Some(Metadata {
+                name: symbol!("gold"),
+                reference: Bytes::from_slice(env, b"plan/gold"),
+            }),
+            signers: Some(Signers {
+                accounts: vec![env, f.parent.clone(), f.child.clone()],
+                threshold: 2,
+                approval_window: WEEK,
+            }),
+            swap: Some(SwapRoute {
+                pool: token.clone(),
+                token_out: f.token_id.clone(),
+                min_price: 19_900_000,
+            }),
+            accepted_tokens: Some(accepted),
+            deployer_salt: Some(token),
+            ..Options::default()
+        };
+        let dto = OptionsDto::from_contract(env, &options).unwrap();
+        serde_round_trip(dto.clone());
+        assert_eq!(dto.to_contract(env), Ok(options));
+    }
+}
@@ -0,0 +1,199 @@
+#![cfg(test)]
+
+//! The timing math is exactly the kind of thing that hides an off-by-one, so
+//! as well as the hand-picked cases in `test.rs`, this module throws randomly
+//! generated ones at the contract: a start time, a `step` and an `amount`,
+//! then a sequence of time advances and `withdraw` attempts. After every
+//! action, it checks that
+//!
+//! - `withdraw` pays exactly the withdrawals that have come due since the last
+//!   one, and refuses with `ChildAlreadyWithdrawn` when none have,
+//! - what the `Child` holds is what that many withdrawals come to, priced by
+//!   the contract's own `per_withdrawal`,
+//! - the `delivered` total and the `withdraw_count` agree with it,
+//! - `Latest` is the due time of the last withdrawal paid, so it's never after
+//!   the current time, and is never more than one `step` behind it once
+//!   `withdraw` has succeeded.
+//!
+//! The generator is a fixed-seed splitmix64, so every run tries the same
+//! cases. When one fails, the message gives its seed and the whole action
+//! sequence, and `run(&generate(seed))` replays it.
+
+extern crate std;
+
+use std::{format, panic, string::String, vec::Vec};
+
+use crate::testlib::{Config, Fixture};
+use crate::{per_withdrawal, Error};
+
+/// How many cases each run tries, and how many actions each case has.
+const CASES: u64 = 100;
+const ACTIONS: usize = 30;
+
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Somewhere from `low` to `high`, inclusive.
+    fn between(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next() % (high - low + 1)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Action {
+    Advance(u64),
+    Withdraw,
+}
+
+#[derive(Debug)]
+struct Case {
+    seed: u64,
+    start: u64,
+    step: u64,
+    amount: i128,
+    actions: Vec<Action>,
+}
+
+/// The bounds keep every case well inside the 500,000,000 stroops the
+/// `Parent` approves: at most `ACTIONS * 4 + 1` withdrawals of a yearly
+/// allowance of up to 30,000,000 split at least eleven ways.
+fn generate(seed: u64) -> Case {
+    let mut rng = Rng(seed);
+    let start = rng.between(10_000_000, 2_000_000_000);
+    let step = rng.between(60, 31 * 24 * 60 * 60);
+    let amount = rng.between(1_000_000, 30_000_000) as i128;
+
+    let mut actions = Vec::new();
+    for _ in 0..ACTIONS {
+        let action = match rng.between(0, 9) {
+            // Mostly short hops, landing anywhere within the period...
+            0..=2 => Action::Advance(rng.between(0, step - 1)),
+            // ...or right around the next due time, where the off-by-ones
+            // live...
+            3 => Action::Advance(rng.between(step - 1, step + 1)),
+            // ...and now and then a long gap to catch up on.
+            4 => Action::Advance(rng.between(step, 4 * step)),
+            _ => Action::Withdraw,
+        };
+        actions.push(action);
+    }
+    Case {
+        seed,
+        start,
+        step,
+        amount,
+        actions,
+    }
+}
+
+/// Runs `case` against a fresh contract, and says what went wrong (if
+/// anything) after which action.
+fn run(case: &Case) -> Result<(), String> {
+    let f = Fixture::new(Config {
+        start: case.start,
+        amount: case.amount,
+        step: case.step,
+        ..Config::default()
+    });
+    let per = per_withdrawal(case.amount, case.step).unwrap();
+    let child = f.child_id();
+
+    // The model: everything due at `start + k * step` for `k` from `0` up,
+    // and how many of those have been paid.
+    let mut paid: u64 = 0;
+    let mut succeeded = false;
+    for (i, action) in case.actions.iter().enumerate() {
+        let now = f.now();
+        match action {
+            Action::Advance(seconds) => {
+                f.advance_time(*seconds);
+                succeeded = false;
+            }
+            Action::Withdraw => {
+                let due = (now - case.start) / case.step + 1;
+                let result = f.client.try_withdraw();
+                if due > paid {
+                    if result != Ok(Ok((due - paid) as u32)) {
+                        return Err(format!(
+                            "after action #{}: {} withdrawals were due, but withdraw gave {:?}",
+                            i,
+                            due - paid,
+                            result
+                        ));
+                    }
+                    paid = due;
+                    succeeded = true;
+                } else if result != Err(Ok(Error::ChildAlreadyWithdrawn)) {
+                    return Err(format!(
+                        "after action #{}: nothing was due, but withdraw gave {:?}",
+                        i, result
+                    ));
+                }
+            }
+        }
+
+        let now = f.now();
+        let progress = f.client.get_state().progress;
+        let balance = f.token.balance(&child);
+        let expected = per * paid as i128;
+        let latest = case.start + paid * case.step - case.step;
+        let problem = if balance != expected {
+            Some(format!(
+                "the Child holds {}, not {} for {} withdrawals",
+                balance, expected, paid
+            ))
+        } else if progress.delivered != balance {
+            Some(format!(
+                "delivered says {}, but the Child holds {}",
+                progress.delivered, balance
+            ))
+        } else if progress.withdraw_count != paid {
+            Some(format!(
+                "withdraw_count says {}, not {}",
+                progress.withdraw_count, paid
+            ))
+        } else if progress.latest != latest {
+            Some(format!("Latest is {}, not {}", progress.latest, latest))
+        } else if progress.latest > now {
+            Some(format!(
+                "Latest is {}, after the time {}",
+                progress.latest, now
+            ))
+        } else if succeeded && now - progress.latest >= case.step {
+            Some(format!(
+                "Latest is {}, a whole step behind the time {} right after a withdraw",
+                progress.latest, now
+            ))
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            return Err(format!("after action #{}: {}", i, problem));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_schedule_invariants() {
+    for seed in 0..CASES {
+        let case = generate(seed);
+        // A trap in the contract shouldn't lose the case that caused it.
+        let outcome = panic::catch_unwind(panic::AssertUnwindSafe(|| run(&case)))
+            .unwrap_or_else(|_| Err(String::from("the contract panicked")));
+        if let Err(problem) = outcome {
+            panic!(
+                "seed {}: {}\nstart {}, step {}, amount {}\nactions: {:?}",
+                case.seed, problem, case.start, case.step, case.amount, case.actions
+            );
+        }
+    }
+}
@@ -0,0 +1,56 @@
+#![cfg(test)]
+
+//! A snapshot of the storage layout written by the very first release of the
+//! AllowanceContract (we call it "v0"). Contracts initialized back then are
+//! still out there, so this module writes their state *exactly* the way the
+//! original `init` and `withdraw` did. The logic is duplicated on purpose:
+//! it must not change when the contract's own storage code does. If you find
+//! yourself editing this file to make a test pass, you've probably broken
+//! existing deployments instead.
+
+use soroban_sdk::{symbol, AccountId, BytesN, Env, IntoVal, RawVal, Vec};
+
+/// The six pieces of data a v0 contract stores.
+pub struct V0Params {
+    pub parent: AccountId,
+    pub child: AccountId,
+    pub token_id: BytesN<32>,
+    pub amount: i128,
+    pub step: u64,
+    pub latest: u64,
+}
+
+/// v0 keys were a unit enum, and each one is encoded on the ledger as a vector
+/// holding just the variant's name.
+pub fn key(env: &Env, name: RawVal) -> Vec<RawVal> {
+    let mut key = Vec::new(env);
+    key.push_back(name);
+    key
+}
+
+/// Every key a v0 contract writes, in the order the original `init` wrote them.
+pub fn keys(env: &Env) -> [Vec<RawVal>; 6] {
+    [
+        key(env, symbol!("TokenId").into_val(env)),
+        key(env, symbol!("Parent").into_val(env)),
+        key(env, symbol!("Child").into_val(env)),
+        key(env, symbol!("Amount").into_val(env)),
+        key(env, symbol!("Step").into_val(env)),
+        key(env, symbol!("Latest").into_val(env)),
+    ]
+}
+
+/// Writes v0 state for the contract at `contract_id`, as if the original
+/// `init` had been invoked (and, for a `latest` other than `timestamp - step`,
+/// as if some withdrawals had happened since).
+pub fn write_v0_state(env: &Env, contract_id: &BytesN<32>, params: &V0Params) {
+    env.as_contract(contract_id, || {
+        let [token_id, parent, child, amount, step, latest] = keys(env);
+        env.storage().set(token_id, params.token_id.clone());
+        env.storage().set(parent, params.parent.clone());
+        env.storage().set(child, params.child.clone());
+        env.storage().set(amount, params.amount);
+        env.storage().set(step, params.step);
+        env.storage().set(latest, params.latest);
+    });
+}
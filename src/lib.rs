@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 /// DON'T PANIC!! You don't actually have to change anything here. Yes, you
 /// should read this file to understand what's happening, but there's nothing
 /// that needs to be changed in the code for today.
@@ -7,7 +7,12 @@
 /// and some invocations in our contract. It's a really powerful SDK to get
 /// familiar with. https://soroban.stellar.org/docs/sdks/rust-auth
 use soroban_auth::{Identifier, Signature};
-use soroban_sdk::{contracterror, contractimpl, contracttype, AccountId, Address, BytesN, Env};
+use soroban_sdk::{
+    contracterror, contractimpl, contracttype, serde::Serialize, symbol, vec, AccountId, Address,
+    Bytes, BytesN, Env, IntoVal, Map, RawVal, Symbol, TryFromVal, Vec,
+};
+
+use core::ops::Deref;
 
 /// The `contractimport` macro will bring in the contents of the built-in
 /// soroban token contract and generate a module we can use with it.
@@ -16,17 +21,75 @@ mod token {
 }
 
 /// An `Error` enum is used to meaningfully and concisely share error
-/// information with a contract user.
+/// information with a contract user. All a client ever sees is the number, so
+/// each one keeps its number for good, and new ones only go on the end.
 #[contracterror]
 #[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
 #[repr(u32)]
 pub enum Error {
     ContractAlreadyInitialized = 1,
     ContractNotInitialized = 2,
+    /// No longer returned: a role check that fails is `Unauthorized`.
     InvalidAuth = 3,
     ChildAlreadyWithdrawn = 4,
     InvalidInvoker = 5,
     InvalidArguments = 6,
+    Overflow = 7,
+    NoPauseRequest = 8,
+    Paused = 9,
+    InsufficientAllowance = 10,
+    NoExtraInvoice = 11,
+    MissingData = 12,
+    CorruptData = 13,
+    NothingDue = 14,
+    AlreadyStarted = 15,
+    NoAmountProposal = 16,
+    TooManyRateChanges = 17,
+    StalePrice = 18,
+    UsageCapExceeded = 19,
+    SubscriptionNotFound = 20,
+    ConfigMismatch = 21,
+    MigrationRequired = 22,
+    NoParentProposal = 23,
+    /// A cancellation is already under way, so it can't be started again or
+    /// changed.
+    Cancelled = 24,
+    PenaltyPaymentFailed = 25,
+    NoSkipProposal = 26,
+    Suspended = 27,
+    NoShareProposal = 28,
+    AlreadySponsored = 29,
+    InsufficientCollateral = 30,
+    AlreadyAssigned = 31,
+    InvalidNonce = 32,
+    ApprovalRequired = 33,
+    NoTokenProposal = 34,
+    ArrearsOutstanding = 35,
+    ReceiverCannotReceive = 36,
+    SlippageExceeded = 37,
+    PolicyRejected = 38,
+    NotEnded = 39,
+    /// No longer returned: a spent `lifetime_cap` is `SubscriptionExpired`.
+    CapReached = 40,
+    TooSoon = 41,
+    RaiseTooLarge = 42,
+    CollateralHeld = 43,
+    Unbounded = 44,
+    ZeroStep = 45,
+    NonPositiveAmount = 46,
+    AmountUnchanged = 47,
+    TooManyEscalations = 48,
+    ReceiptsNotPruned = 49,
+    InsufficientBalance = 50,
+    /// The invoker isn't the `Parent`, `Child`, admin or officer the call
+    /// needs.
+    Unauthorized = 51,
+    /// The allowance has been cancelled and its notice has run out, so nothing
+    /// more will come due: `get_status` says `Cancelled`.
+    SubscriptionCancelled = 52,
+    /// The allowance has paid out its whole `lifetime_cap`: `get_status` says
+    /// `Expired`.
+    SubscriptionExpired = 53,
 }
 
 /// We are using a `StorageKey` enum to store different types of data, but keying
@@ -35,20 +98,657 @@ pub enum Error {
 #[contracttype]
 #[derive(Clone)]
 pub enum StorageKey {
-    Parent,  // AccountId
-    Child,   // AccountId
-    TokenId, // BytesN<32>
-    Amount,  // i128
-    Step,    // u64
-    Latest,  // u64
+    Parent,               // Identifier
+    Child,                // Identifier
+    TokenId,              // BytesN<32>
+    Amount,               // i128
+    Step,                 // u64
+    Latest,               // u64
+    Options,              // Options
+    PauseReq,             // PauseRequest
+    Extra,                // i128
+    StartEpoch,           // u64
+    PendingAmt,           // AmountProposal
+    RateHistory,          // Vec<RateChange>
+    Oracle,               // Oracle
+    Usage(u64),           // u64
+    JoinOffset,           // u64
+    WithdrawCount,        // u64
+    Invokers,             // Vec<AccountId>
+    NextId,               // u64
+    ByParent(Identifier), // Vec<u64>
+    ByChild(Identifier),  // Vec<u64>
+    WasmHash,             // BytesN<32>
+    SchemaVersion,        // u32
+    Admin,                // Identifier
+    PendingParent,        // Identifier
+    EndsAt,               // u64
+    CloseApproval(Role),  // bool
+    SkipReq(u64),         // Identifier
+    SkippedPeriods,       // Vec<u64>
+    FailedAt(u64),        // u64
+    FailureCount,         // u32
+    Suspended,            // bool
+    Recipients,           // Vec<(Identifier, u32)>
+    SharesProposal,       // SharesProposal
+    GuarantorDraws,       // u32
+    Payers,               // Vec<(Identifier, u32)>
+    Sponsorship,          // Sponsorship
+    Collateral,           // i128
+    Assignment,           // Assignment
+    Metadata,             // Metadata
+    State,                // State
+    Nonce(Identifier),    // i128
+    PendingAction,        // Approvals
+    PendingToken,         // TokenProposal
+    Delivered,            // i128
+    Checkpoint,           // Checkpoint
+    PreferredToken,       // BytesN<32>
+    PaymentHook,          // BytesN<32>
+    SpendPolicy,          // BytesN<32>
+    EndReported,          // bool
+    Receipt(u64),         // Receipt
+    Receipts,             // (u64, u64)
+    Spent,                // i128
+    LastAmtChange,        // u64
+    UsedPeriods,          // Vec<u64>
+    SkipReqs,             // Vec<u64>
+    FailedPeriods,        // Vec<u64>
+    MigratedTo,           // u64
+}
+
+/// Some behaviours of the contract are optional, and are switched on when the
+/// contract is `init`ialized. Every field has a sensible "off" default, so
+/// `Options::default()` gives you exactly the allowance contract described in
+/// this file.
+#[contracttype]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Options {
+    // When `true`, a pause requested by the `Child` goes into effect at its
+    // `from` time unless the `Parent` has rejected it by then.
+    pub auto_approve_child_pause: bool,
+    // When `true`, a `withdraw` with nothing to pay out returns `Ok` (having
+    // paid zero withdrawals) instead of an error. Handy for bots that sweep
+    // lots of contracts in one transaction.
+    pub lenient: bool,
+    // When `true`, the `Parent` can't lower the allowance without the `Child`
+    // accepting the change. (Raising it always needs the `Parent`'s say-so.)
+    pub decreases_need_acceptance: bool,
+    // How much notice (in seconds) is given before a change to the `Amount`
+    // takes effect. Withdrawals that came due before then are still paid at
+    // the old amount.
+    pub amount_notice_period: u64,
+    // When `escalator_bps` isn't zero, the allowance goes up by that many
+    // basis points (hundredths of a percent) every `escalator_every`
+    // withdrawals, compounding, like the yearly raise in a lease. It goes up
+    // at most 1000 times, and after that withdrawals are refused with
+    // `TooManyEscalations`.
+    pub escalator_bps: u32,
+    pub escalator_every: u32,
+    // When `unit_price` isn't zero, the `Child` (think of them as a vendor
+    // here) can `report_usage`, and every unit over `included_units` in a
+    // period is billed on top of the regular allowance. No more than
+    // `max_overage` units over can be reported for any one period.
+    pub unit_price: i128,
+    pub included_units: u64,
+    pub max_overage: u64,
+    // The first `trial_periods` withdrawals are free: they come due and are
+    // "collected" as usual, so the schedule stays in step, but no money moves.
+    pub trial_periods: u32,
+    // The first `discount_periods` withdrawals that are actually paid (so,
+    // after any trial) come `discount_bps` basis points off.
+    pub discount_bps: u32,
+    pub discount_periods: u32,
+    // When `true`, the allowance trickles in every second instead of a whole
+    // `step` at a time, and the `Child` can collect whatever has built up
+    // whenever they like (think of a salary).
+    pub streaming: bool,
+    // When set (to a day from 1 to 28), withdrawals come due on that day of
+    // every calendar month instead of every `step` seconds, and the `step`
+    // given to `init` is ignored.
+    pub monthly_anchor: u32,
+    // When `true` and the contract is set up partway through a period (say,
+    // on the 20th of a month anchored to the 1st), the first withdrawal only
+    // charges for the part of the period that is left.
+    pub prorate_first: bool,
+    // Normally each period is paid for at its start. When `true`, it's paid
+    // for at its end instead, once the service has actually been provided.
+    pub bill_in_arrears: bool,
+    // Normally only a user account can invoke `withdraw`. When `true`, a
+    // contract can, too (say, a keeper sweeping lots of allowances, or a
+    // smart wallet `Child` collecting its own). The money still only ever
+    // goes to the `Child`.
+    pub allow_contract_invokers: bool,
+    // How much notice (in seconds) the `Parent` has to give when they
+    // `cancel`. Withdrawals that come due before the notice runs out are
+    // still paid.
+    pub cancel_notice_period: u64,
+    // When the `Parent` cancels before `min_periods` withdrawals have been
+    // made, they pay the `Child` an `early_termination_fee` on top, there and
+    // then.
+    pub min_periods: u32,
+    pub early_termination_fee: i128,
+    // When `late_fee_bps` isn't zero, a withdrawal that couldn't be paid
+    // (and was reported with `report_shortfall`) costs that many basis points
+    // more for every full `step` it stays unpaid after that, up to double.
+    pub late_fee_bps: u32,
+    // When `max_failures` isn't zero, the allowance is suspended once that
+    // many shortfalls in a row have been reported (one per withdrawal), and
+    // nothing can be collected until the `Parent` tops up and `reactivate`s.
+    pub max_failures: u32,
+    // When `fee_bps` isn't zero, that many basis points of every withdrawal
+    // (rounded down, in the `Child`'s favour) go to the `fee_collector`
+    // instead, say, the marketplace the allowance was set up through.
+    pub fee_collector: Option<Identifier>,
+    pub fee_bps: u32,
+    // What's left of every withdrawal can be split between several
+    // recipients, as (recipient, share in basis points) pairs that add up to
+    // 10000. The first one gets whatever doesn't divide evenly. Without
+    // them, it all goes to the `Child`.
+    pub recipients: Option<Vec<(Identifier, u32)>>,
+    // How long (in seconds) a `propose_shares` stays open for. `0` means until
+    // it's accepted or replaced.
+    pub shares_window: u64,
+    // When the `Parent`'s allowance can't cover a withdrawal, it's taken from
+    // the `guarantor`'s instead (if they've approved this contract, too).
+    pub guarantor: Option<Identifier>,
+    // Instead of the `Parent` paying for everything, several payers can each
+    // cover a share of every withdrawal (in basis points, adding up to
+    // 10000), each from their own allowance. If any one of them can't, the
+    // withdrawal doesn't go through at all. It doesn't mix with `recipients`
+    // or a `guarantor`.
+    pub payers: Option<Vec<(Identifier, u32)>>,
+    // When one of the `payers` calls `cancel_my_share`, the rest share the
+    // whole thing between them from then on, unless this is set, and the
+    // allowance is suspended until the `Parent` `reactivate`s it instead.
+    pub suspend_on_payer_exit: bool,
+    // A plan name and an off-chain reference (see `Metadata`), which can also
+    // be set later on with `set_metadata`.
+    pub metadata: Option<Metadata>,
+    // Officers who have to agree on changes to the terms (see `Signers`),
+    // rather than the `Parent` making them alone.
+    pub signers: Option<Signers>,
+    // How far short of what it should be the `Child`'s balance can be before
+    // `reconcile` raises the alarm.
+    pub reconcile_tolerance: i128,
+    // For a token that takes a cut of every transfer: when `true`, what a
+    // withdrawal paid is however much the balance it went to actually went up
+    // by, rather than what was sent.
+    pub measure_received: bool,
+    // Pays the `Child` in another token, by swapping what the `Parent` pays
+    // through a pool (see `SwapRoute`). It doesn't mix with `recipients` or
+    // `payers`.
+    pub swap: Option<SwapRoute>,
+    // Other tokens the `Child` can choose to be paid in (see `withdraw_in`),
+    // with what the yearly `Amount` comes to in each. Every withdrawal paid in
+    // one costs that same fraction of its usual price, so trials, discounts
+    // and the like all carry over. It doesn't mix with a `swap`.
+    pub accepted_tokens: Option<Map<BytesN<32>, i128>>,
+    // How many withdrawals beyond the latest one due can go unpaid before
+    // the allowance is `InArrears` (and `is_active_for` stops counting the
+    // `Child` as a paying subscriber). Until then, shortfalls don't count
+    // towards `max_failures` either. (With no grace at all, every one does.)
+    // It makes no difference to what's owed.
+    pub grace_periods: u32,
+    // A directory contract (see `RegistryTrait`) to tell whenever the
+    // allowance is set up, cancelled, paused (once the `Parent` approves it)
+    // or comes to an end, on top of the usual events.
+    pub registry: Option<BytesN<32>>,
+    // The most the regular withdrawals can ever add up to, however the
+    // amount changes later (`0` for no limit). The one that would go over
+    // is paid whatever is left, and after that the allowance is `Expired`.
+    // It doesn't mix with a stream, or `accepted_tokens`.
+    pub lifetime_cap: i128,
+    // How long (in seconds) after one change to the `Amount` the next one
+    // has to wait, and the most a single raise can add, in basis points of
+    // the amount before it (`0` for no limit). Cuts can be as big as you
+    // like.
+    pub amount_change_cooldown: u64,
+    pub max_increase_bps: u32,
+    // How many seconds early a withdrawal can be collected, for ledgers that
+    // close a little before the moment it was expected. Each one is still
+    // for its own period, so this never adds up to extra withdrawals. It has
+    // to be shorter than a `step`, and doesn't mix with a stream.
+    pub tolerance: u64,
+    // When `true`, a withdrawal that isn't collected in its own period lapses
+    // (think of a gym membership), rather than being there to catch up on
+    // later: only the latest one due can be collected. It doesn't mix with a
+    // stream.
+    pub lapse_missed: bool,
+    // When `true`, the `StartEpoch` only says when the first withdrawal can
+    // be collected at the earliest. Whenever it actually is, the schedule
+    // starts over from then, so the next one is a whole `step` later. It
+    // doesn't mix with a calendar `monthly_anchor`, or a stream.
+    pub anchor_on_first_withdraw: bool,
+    // For a contract that deploys this one and then `init`s it itself: the
+    // `salt` it deployed it with. `init` then fails with
+    // `Error::InvalidInvoker` unless that contract is the one invoking it
+    // (see `init_on_behalf` for how it's proven). Deploying and `init`ing in
+    // the same invocation, nobody can get in first unnoticed, either: their
+    // `init` makes this one fail, and the deployment with it.
+    pub deployer_salt: Option<BytesN<32>>,
+}
+
+/// Everything `init` sets up, all in one place, for `verify_config` to check
+/// against.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Config {
+    pub parent: Identifier,
+    pub child: Identifier,
+    pub token_id: BytesN<32>,
+    pub amount: i128,
+    pub step: u64,
+    pub options: Options,
+}
+
+/// Everything there is to know about an allowance, in one go, for an indexer
+/// starting from a snapshot rather than replaying every event (see
+/// `get_state`). Whatever only some allowances have is grouped into the
+/// nested structs, as `Option`s, so a new feature only ever adds a field to
+/// one of those, and a decoder that doesn't know about it yet only loses
+/// that one group.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FullState {
+    pub schema_version: u32,
+    pub status: Status,
+    pub config: Config,
+    pub progress: Progress,
+    pub pending: Pending,
+    pub extras: Extras,
+}
+
+/// How far the allowance has got: where the schedule stands, and what it has
+/// paid so far.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Progress {
+    pub latest: u64,
+    pub start_epoch: u64,
+    pub withdraw_count: u64,
+    pub delivered: i128,
+    pub failure_count: u32,
+    pub ends_at: Option<u64>,
+    pub agreed_amount: i128,
+    pub rate_history: Vec<RateChange>,
+}
+
+/// Everything that's waiting on somebody.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Pending {
+    pub amount: Option<AmountProposal>,
+    pub token: Option<TokenProposal>,
+    pub parent: Option<Identifier>,
+    pub pause: Option<PauseRequest>,
+    pub shares: Option<SharesProposal>,
+    pub action: Option<Approvals>,
+    pub extra: Option<i128>,
+}
+
+/// What's been set up since `init`, by whoever gets to.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Extras {
+    pub admin: Option<Identifier>,
+    pub metadata: Option<Metadata>,
+    pub sponsorship: Option<Sponsorship>,
+    pub assignment: Option<Assignment>,
+    pub collateral: Option<i128>,
+    pub preferred_token: Option<BytesN<32>>,
+    pub payment_hook: Option<BytesN<32>>,
+    pub spend_policy: Option<BytesN<32>>,
+}
+
+/// Everything `withdraw` reads on every call, kept in a single ledger entry
+/// since version `2` of the layout, so that it's one entry to pay for rather
+/// than nine. Each field stands in for the `StorageKey` of the same name.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct State {
+    pub token_id: BytesN<32>,
+    pub parent: Identifier,
+    pub child: Identifier,
+    pub amount: i128,
+    pub step: u64,
+    pub options: Options,
+    pub latest: u64,
+    pub start_epoch: u64,
+    pub withdraw_count: u64,
+}
+
+/// A change to the yearly `Amount` that one side has proposed, and the other
+/// side still has to accept.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AmountProposal {
+    pub amount: i128,
+    pub proposer: Identifier,
+}
+
+/// What the `Child` held, and had been paid here all told, when `reconcile`
+/// last looked.
+#[contracttype]
+#[cfg_attr(feature = "std", derive(::serde::Serialize, ::serde::Deserialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Checkpoint {
+    pub at: u64,
+    pub balance: i128,
+    pub delivered: i128,
+}
+
+/// What `reconcile` found: how much the `Child` has been paid since the last
+/// checkpoint (taken at `since`), what their balance should be because of
+/// it, and what it actually is.
+#[contracttype]
+#[cfg_attr(feature = "std", derive(::serde::Serialize, ::serde::Deserialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReconcileReport {
+    pub since: u64,
+    pub paid: i128,
+    pub expected: i128,
+    pub actual: i128,
+}
+
+/// What was paid for one withdrawal (`period`), and when. `label` says how:
+/// it's the same as the event the withdrawal got (`withdraw`, `trial`,
+/// `stream` and so on). `delivered` is what it paid out: the same as the
+/// `amount` charged, unless it went through a swap, when it's what came out
+/// of the pool, in the `SwapRoute`'s `token_out`. Receipts are numbered in
+/// the order they were written, which `Receipts` keeps the range of.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Receipt {
+    pub period: u64,
+    pub amount: i128,
+    pub delivered: i128,
+    pub paid_at: u64,
+    pub label: Symbol,
+}
+
+/// What a `withdraw` right now would do: pay `periods` withdrawals (the first
+/// one numbered `first`) in `token_id`, `total` in all, of which `fee` goes
+/// to the `fee_collector` and the rest is paid out as `payouts`. Afterwards,
+/// the `Latest` withdrawal would be `latest`.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WithdrawPreview {
+    pub periods: u32,
+    pub first: u64,
+    pub token_id: BytesN<32>,
+    pub total: i128,
+    pub fee: i128,
+    pub payouts: Vec<(Identifier, i128)>,
+    pub latest: u64,
+}
+
+/// A switch to paying in another token, at a new yearly `amount` (since it
+/// probably has different decimals, if nothing else), that the `Parent` has
+/// proposed and the `Child` still has to accept.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TokenProposal {
+    pub token_id: BytesN<32>,
+    pub amount: i128,
+}
+
+/// A new split between the recipients that the `Parent` has proposed. It
+/// takes effect once everybody in `pending` (those whose share it cuts) has
+/// accepted it, as long as that's before `expires_at`.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SharesProposal {
+    pub shares: Vec<(Identifier, u32)>,
+    pub pending: Vec<Identifier>,
+    pub expires_at: u64,
+}
+
+/// What an off-chain system needs to match the allowance up with its own
+/// records: the name of the plan, and its own reference for it (no longer
+/// than 64 bytes).
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Metadata {
+    pub name: Symbol,
+    pub reference: Bytes,
+}
+
+/// `threshold` of these `accounts` have to `approve_action` for a change to
+/// the terms to go through, and they have `approval_window` seconds from the
+/// first approval to get there.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Signers {
+    pub accounts: Vec<AccountId>,
+    pub threshold: u32,
+    pub approval_window: u64,
+}
+
+/// A change to the terms, for the `Signers` to approve. Each one does just
+/// what the function of the same name would.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+    Amount(i128),
+    Cancel,
+    Upgrade(BytesN<32>),
+    Token(TokenProposal),
+    Step(u64),
+}
+
+/// The `Action` the `Signers` are approving (by its hash), who has so far,
+/// and when the approvals lapse.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Approvals {
+    pub action: BytesN<32>,
+    pub approvers: Vec<AccountId>,
+    pub expires_at: u64,
+}
+
+/// Somebody other than the `Parent` paying for the next `periods`
+/// withdrawals.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Sponsorship {
+    pub sponsor: Identifier,
+    pub periods: u32,
+}
+
+/// Somebody the `Child` has sold their next `periods` withdrawals to.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Assignment {
+    pub assignee: Identifier,
+    pub periods: u32,
+}
+
+/// A change to the yearly `Amount` that has been agreed on. It applies to
+/// withdrawals coming due at or after `effective_from`, and anything that came
+/// due before then is still paid at the old rate.
+#[contracttype]
+#[cfg_attr(feature = "std", derive(::serde::Serialize, ::serde::Deserialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RateChange {
+    pub effective_from: u64,
+    pub amount: i128,
 }
 
+/// Some allowances are set in a reference asset ("50 USD worth of XLM a
+/// month") rather than in the token itself. For those, this is the price
+/// oracle contract we ask at `withdraw` time, and how old (in seconds) its
+/// price is allowed to be before we refuse to use it.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Oracle {
+    pub contract_id: BytesN<32>,
+    pub max_age: u64,
+}
+
+/// All we expect of a price oracle is a `lastprice(asset: BytesN<32>)`
+/// function handing this back: what one unit of the token is worth in units of
+/// the reference asset, as a fixed-point number with `decimals` decimal places,
+/// and when that price was last updated.
+#[contracttype]
+#[cfg_attr(feature = "std", derive(::serde::Serialize, ::serde::Deserialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PriceData {
+    pub price: i128,
+    pub decimals: u32,
+    pub timestamp: u64,
+}
+
+/// Where to swap what the `Parent` pays for the `token_out` the `Child` wants,
+/// and the least the `pool` can give for it: `min_price` is the least amount
+/// of `token_out` for every `PRICE_UNIT` of the token paid in, so the two
+/// tokens can be priced however far apart they are.
+/// All we expect of a pool is a `swap(token_in: BytesN<32>, amount_in: i128,
+/// token_out: BytesN<32>, min_out: i128, to: Identifier) -> i128` function:
+/// it has already been sent `amount_in` of `token_in`, it sends `to` what it
+/// gives for that in `token_out`, and it hands back how much that was.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SwapRoute {
+    pub pool: BytesN<32>,
+    pub token_out: BytesN<32>,
+    pub min_price: i128,
+}
+
+/// What a `SwapRoute`'s `min_price` is for: one whole token, at the seven
+/// decimals Stellar assets have.
+pub const PRICE_UNIT: i128 = 10_000_000;
+
+/// A pause the `Child` has asked for. While a pause is in force, the allowance
+/// schedule stands still: no new withdrawals come due between `from` and
+/// `until`, and everything after `until` is pushed back by the length of the
+/// pause.
+#[contracttype]
+#[cfg_attr(feature = "std", derive(::serde::Serialize, ::serde::Deserialize))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PauseRequest {
+    pub from: u64,
+    pub until: u64,
+    pub approved: bool,
+}
+
+/// Where the allowance is in its life, as `get_status` sees it. It's
+/// `Terminating` while the notice on a cancellation runs out, `Suspended`
+/// after too many shortfalls, and `Expired` once it has paid out its
+/// `lifetime_cap`.
+#[contracttype]
+#[cfg_attr(feature = "std", derive(::serde::Serialize, ::serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    Uninitialized,
+    NotStarted,
+    Active,
+    InArrears,
+    Paused,
+    Cancelled,
+    Expired,
+    Terminating,
+    Suspended,
+}
+
+/// Which side of a subscription somebody is on, for `subscriptions_of`.
+#[contracttype]
+#[cfg_attr(feature = "std", derive(::serde::Serialize, ::serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    Parent,
+    Child,
+}
+
+/// What happened to one subscription in a `withdraw_batch`: either it paid
+/// out that many withdrawals, or it failed with that `Error` code, or it
+/// trapped (say, in a token that failed without saying why). Either way, a
+/// failure is undone without touching the others.
+#[contracttype]
+#[cfg_attr(feature = "std", derive(::serde::Serialize, ::serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    Paid(u32),
+    Failed(u32),
+    Trapped,
+}
+
+/// There are 10,000 basis points in 100%.
+const BPS: i128 = 10_000;
+
 /// You know what's a pain? Re-declaring or re-calculating the same value over
 /// and over again. We're going to use the number of seconds in a year more than
 /// once in this contract, so let's use a `const` to declare it once and get it
 /// out of the way.
 const SECONDS_IN_YEAR: u64 = 365 * 24 * 60 * 60; // = 31,536,000 seconds (fyi)
 
+/// The most rate changes we keep around while the `Child` still has periods to
+/// catch up on. It keeps storage (and the fees for reading it) bounded.
+const MAX_RATE_CHANGES: u32 = 8;
+
+/// The most times the escalator compounds. Each one is a step of its own (so
+/// the rounding comes out the same as it always has), and this keeps them
+/// from running past the budget of a single invocation.
+const MAX_ESCALATIONS: u64 = 1000;
+
+/// The version of the storage layout this code writes. It goes up whenever
+/// the layout changes, so code that's been `upgrade`d can tell what it's
+/// looking at. Contracts from before there was a version are at version `0`.
+const SCHEMA_VERSION: u32 = 2;
+
+/// The version of this crate the contract was built from, as (major, minor,
+/// patch).
+const VERSION: (u32, u32, u32) = (
+    parse_version(env!("CARGO_PKG_VERSION_MAJOR")),
+    parse_version(env!("CARGO_PKG_VERSION_MINOR")),
+    parse_version(env!("CARGO_PKG_VERSION_PATCH")),
+);
+
+/// The oldest layout this code still reads as it is. Anything older has to be
+/// brought up to date with `migrate` first. Version `0` only differs from `1`
+/// in how the `Parent` and `Child` are stored, and we take them either way.
+/// Version `2` keeps what used to be separate entries in one `State`, but a
+/// subscription without one is read (and written) the old way, so nobody has
+/// to `migrate` yet.
+const MIN_SCHEMA_VERSION: u32 = 0;
+
+/// The most ids `subscriptions_of` or `due_subscriptions` hands back in one
+/// go, and the most subscriptions `due_subscriptions` looks at.
+const MAX_PAGE: u32 = 50;
+const MAX_SCAN: u64 = 200;
+
+/// The most subscriptions `withdraw_batch`, or one `migrate`, takes on at once.
+const MAX_BATCH: u32 = 20;
+
+/// The most withdrawals `prepay` pays for in advance at once.
+const MAX_PREPAY: u32 = 120;
+
+/// The longest `Metadata` reference, in bytes.
+const MAX_REFERENCE: u32 = 64;
+
+/// The most recipients a withdrawal can be split between.
+const MAX_RECIPIENTS: u32 = 10;
+
+/// The most `accepted_tokens` an allowance can have.
+const MAX_TOKENS: u32 = 10;
+
+/// The most `Signers` an allowance can have.
+const MAX_SIGNERS: u32 = 10;
+
+/// The most `grace_periods` an allowance can have, which keeps
+/// `is_active_for` cheap however far behind the `Child` is.
+const MAX_GRACE_PERIODS: u32 = 52;
+
+/// The XDR `EnvelopeType` the host hashes to give a contract deployed by
+/// another contract its id (see `deployed_id`).
+const ENVELOPE_TYPE_CONTRACT_ID_FROM_CONTRACT: i32 = 9;
+
+/// The most receipts `history` reads at once.
+const MAX_HISTORY: u32 = 20;
+
+/// The most receipts `prune_receipts` deletes at once.
+const MAX_PRUNE: u32 = 10;
+
 pub struct AllowanceContract;
 
 /// Seeing a `trait` may feel familiar. We used one in Quest 4, as well. When
@@ -58,160 +758,5737 @@ pub struct AllowanceContract;
 pub trait AllowanceTrait {
     // When `init`ializing the contract, we must specify some of the data that
     // will be stored (remember the `StorageKey`?) for the contract to reference.
-    // We are using an `AccountId` for the `child` to highlight that a transfer
-    // from one user to another is the intended use-case of this particular
-    // contract. It also makes the Soroban CLI usage a bit cleaner and easier.
+    // The `child` is an `Identifier`, so the allowance can be paid to a
+    // contract (a DAO treasury, a splitter, a smart wallet) as well as to a
+    // user account.
     fn init(
         e: Env,
-        child: AccountId,     // the child account receiving the allowance
+        child: Identifier,    // whoever is receiving the allowance
         token_id: BytesN<32>, // the id of the token being transferred as an allowance
         amount: i128,         // the total allowance amount given for the year
         step: u64,            // how frequently (in seconds) a withdrawal can be made
+        options: Options,     // the optional behaviours to switch on
+    ) -> Result<(), Error>;
+
+    // Paying one user account from another is still what this contract is
+    // mostly for, and `init_for_account` keeps the Soroban CLI usage for that
+    // a bit cleaner and easier. It's just `init` with an `AccountId`.
+    fn init_for_account(
+        e: Env,
+        child: AccountId,
+        token_id: BytesN<32>,
+        amount: i128,
+        step: u64,
+        options: Options,
+    ) -> Result<(), Error>;
+
+    // `init_on_behalf` is `init` for a contract that deploys allowance
+    // contracts for other people (like the factory), and has to set one up
+    // in the same breath so nobody can get to `init` first. It names the
+    // `parent` rather than becoming it, so only the contract that deployed
+    // this one may call it, which it proves with the `salt` it deployed it
+    // with. It's up to that contract to pass along whoever actually asked.
+    fn init_on_behalf(
+        e: Env,
+        salt: BytesN<32>,
+        parent: Identifier,
+        child: Identifier,
+        token_id: BytesN<32>,
+        amount: i128,
+        step: u64,
+        options: Options,
+    ) -> Result<(), Error>;
+
+    // `init_indexed` is just like `init`, except the `amount` is in a
+    // reference asset, and it's converted into the token using the `oracle`'s
+    // price every time a withdrawal is made.
+    fn init_indexed(
+        e: Env,
+        child: Identifier,
+        token_id: BytesN<32>,
+        amount: i128,
+        step: u64,
+        options: Options,
+        oracle: Oracle,
     ) -> Result<(), Error>;
 
+    // Anyone can call `init` on a freshly deployed contract, so somebody
+    // could get there before you do and set it up the way *they* like. A
+    // contract that deployed this one can say so with `deployer_salt` (see
+    // `Options`). Anybody else setting up an allowance can call
+    // `verify_config` straight after `init`, in the same invocation, and it
+    // fails with `Error::ConfigMismatch` (undoing the whole thing) unless the
+    // allowance is exactly the one it `expected`.
+    fn verify_config(e: Env, expected: Config) -> Result<(), Error>;
+
     // When `withdraw` is invoked, a transfer is made from the `Parent` asset
-    // balance to the `Child` asset balance. No signature required!
-    fn withdraw(e: Env) -> Result<(), Error>;
-}
+    // balance to the `Child` asset balance. No signature required! It returns
+    // the number of withdrawals that were paid out. If anybody it would pay
+    // has had their balance frozen on the token, it fails with
+    // `Error::ReceiverCannotReceive` instead, and they can try again once
+    // that's sorted out.
+    fn withdraw(e: Env) -> Result<u32, Error>;
 
-/// When a contract uses "Invoker" authentication, `env.invoker()` returns the
-/// `Address` type. Since we're storing an `AccountId` as the `Parent`, we use
-/// a helper function to convert from one to the other.
-fn to_account(address: Address) -> Result<AccountId, Error> {
-    match address {
-        Address::Account(id) => Ok(id),
-        _ => Err(Error::InvalidInvoker),
-    }
-}
+    // The `Child` can ask for the allowance to be paused between `from` and
+    // `until` (think of a summer job, or a seasonal business).
+    fn request_pause(e: Env, from: u64, until: u64) -> Result<(), Error>;
 
-#[contractimpl]
-impl AllowanceTrait for AllowanceContract {
-    // Remember, before you can invoke `withdraw`, you must invoke `init`
-    fn init(
+    // The `Parent` can approve or reject a pending pause request, as long as
+    // they do so before the pause would start.
+    fn approve_pause(e: Env) -> Result<(), Error>;
+    fn reject_pause(e: Env) -> Result<(), Error>;
+
+    // Every so often the `Child` needs a little extra (new shoes, a school
+    // trip). They can send the `Parent` a one-off invoice for it...
+    fn invoice_extra(e: Env, amount: i128) -> Result<(), Error>;
+
+    // ...which is only ever paid when the `Parent` explicitly approves it.
+    fn approve_extra(e: Env) -> Result<(), Error>;
+
+    // How much allowance the `Parent` needs to have granted this contract for
+    // everything that is due right now to go through.
+    fn required_allowance(e: Env, include_extras: bool) -> Result<i128, Error>;
+
+    // Plans change. Until the first withdrawal has been made, the `Parent` can
+    // move the date the allowance starts on, earlier or later.
+    fn fix_start_epoch(e: Env, start_epoch: u64) -> Result<(), Error>;
+
+    // Either side can propose a new yearly `amount`. A change that goes
+    // against the other side (a raise for the `Parent`, a cut for the `Child`)
+    // only takes effect once they `accept_amount`. Either way, `propose_amount`
+    // says what the amount in effect was.
+    fn propose_amount(e: Env, amount: i128) -> Result<i128, Error>;
+    fn accept_amount(e: Env) -> Result<(), Error>;
+
+    // With metered billing switched on, the `Child` reports the units used,
+    // and they're billed along with the next withdrawal.
+    fn report_usage(e: Env, units: u64) -> Result<(), Error>;
+
+    // When the next `withdraw` will go through (which may already be in the
+    // past, if something is due right now), and how long that is from now.
+    fn next_withdraw_time(e: Env) -> Result<u64, Error>;
+    fn time_until_next(e: Env) -> Result<u64, Error>;
+
+    // Whether `withdraw` would pay something out right now.
+    fn can_withdraw(e: Env) -> bool;
+
+    // Just like `withdraw`, but only pays what came due on or before `until`
+    // (handy for keeping a payment in the right fiscal year). It never pays
+    // anything early, whatever `until` says.
+    fn withdraw_up_to(e: Env, until: u64) -> Result<u32, Error>;
+
+    // Also just like `withdraw`, but pays no more than `max_periods`
+    // withdrawals, so a big backlog can be worked through a bit at a time.
+    fn withdraw_periods(e: Env, max_periods: u32) -> Result<u32, Error>;
+
+    // The total of every withdrawal that comes due in `[from, to)`, priced
+    // exactly the way `withdraw` will price it (as things stand today). In
+    // streaming mode, it's whatever streams over that window.
+    fn forecast(e: Env, from: u64, to: u64) -> Result<i128, Error>;
+
+    // Where the allowance stands right now. This is the one place that says
+    // how pauses, arrears and so on fit together, so clients don't have to
+    // work it out from errors.
+    fn get_status(e: Env) -> Result<Status, Error>;
+
+    // How many withdrawals the `Child` has been paid so far (free trial ones
+    // included).
+    fn withdraw_count(e: Env) -> Result<u64, Error>;
+
+    // Which period (counting from `0` at the start) `timestamp` falls in,
+    // worked out exactly the way `withdraw` does it.
+    fn period_index_of(e: Env, timestamp: u64) -> Result<u64, Error>;
+
+    // The `Parent` can limit who gets to invoke `withdraw` (besides the
+    // `Child`, who always can). An empty list lets anybody invoke it again.
+    fn set_invoker_allowlist(e: Env, invokers: Vec<AccountId>) -> Result<(), Error>;
+
+    // `create` sets up another allowance (a "subscription") in this same
+    // contract, for the invoker to pay, and hands back its id. It's just like
+    // `init`, except that the first withdrawal comes due at `start_epoch`.
+    // Several subscriptions can share a contract, and each one gets its own
+    // `Parent`, `Child`, token and schedule. (Though if one `Parent` pays two
+    // of them in the same token, they share the one allowance on the token.)
+    fn create(
         e: Env,
-        child: AccountId,
+        child: Identifier,
         token_id: BytesN<32>,
+        start_epoch: u64,
         amount: i128,
         step: u64,
-    ) -> Result<(), Error> {
-        // When running `init`, we want to make sure the function hasn't already
-        // been invoked. Although a few different `StorageKey`s are set during
-        // init, it's enough to only check for one.
-        let token_key = StorageKey::TokenId;
-        if e.storage().has(token_key.clone()) {
-            return Err(Error::ContractAlreadyInitialized);
-        }
+        options: Options,
+    ) -> Result<u64, Error>;
 
-        // You can't have a withdraw every 0 seconds. Obviously. Also, you can't
-        // divide by 0. So say the calculators, at least.
-        if step == 0 {
-            return Err(Error::InvalidArguments);
-        }
+    // These work just like the functions they're named after, but for the
+    // subscription with the given `id`. The allowance set up by `init` is
+    // subscription `0`. The signed ones sign over the `id` too, right after
+    // the signer, so a signature for one subscription can't be relayed to
+    // another. (Only subscription `0`'s `signers` can approve an `upgrade`,
+    // since the code is the whole contract's.)
+    fn withdraw_for(e: Env, id: u64) -> Result<u32, Error>;
+    fn can_withdraw_for(e: Env, id: u64) -> bool;
+    fn required_allowance_for(e: Env, id: u64, include_extras: bool) -> Result<i128, Error>;
+    fn propose_amount_for(e: Env, id: u64, amount: i128) -> Result<i128, Error>;
+    fn accept_amount_for(e: Env, id: u64) -> Result<(), Error>;
+    fn get_status_for(e: Env, id: u64) -> Result<Status, Error>;
+    fn request_pause_for(e: Env, id: u64, from: u64, until: u64) -> Result<(), Error>;
+    fn approve_pause_for(e: Env, id: u64) -> Result<(), Error>;
+    fn reject_pause_for(e: Env, id: u64) -> Result<(), Error>;
+    fn cancel_for(e: Env, id: u64) -> Result<(), Error>;
+    fn close_by_child_for(e: Env, id: u64, final_withdraw: bool) -> Result<(), Error>;
+    fn approve_close_for(e: Env, id: u64) -> Result<(), Error>;
+    fn revoke_close_approval_for(e: Env, id: u64) -> Result<(), Error>;
+    fn forgive_arrears_for(e: Env, id: u64, periods: u32) -> Result<u32, Error>;
+    fn prepay_for(e: Env, id: u64, periods: u32) -> Result<i128, Error>;
+    fn fix_start_epoch_for(e: Env, id: u64, start_epoch: u64) -> Result<(), Error>;
+    fn fix_step_for(e: Env, id: u64, step: u64) -> Result<(), Error>;
+    fn reset_for(e: Env, id: u64) -> Result<(), Error>;
+    fn history_for(e: Env, id: u64, offset: u32, limit: u32) -> Result<Vec<Receipt>, Error>;
+    fn prune_receipts_for(e: Env, id: u64, keep_latest: u32) -> Result<u32, Error>;
+    fn get_state_for(e: Env, id: u64) -> Result<FullState, Error>;
+    fn verify_config_for(e: Env, id: u64, expected: Config) -> Result<(), Error>;
+    fn invoice_extra_for(e: Env, id: u64, amount: i128) -> Result<(), Error>;
+    fn approve_extra_for(e: Env, id: u64) -> Result<(), Error>;
+    fn report_usage_for(e: Env, id: u64, units: u64) -> Result<(), Error>;
+    fn next_withdraw_time_for(e: Env, id: u64) -> Result<u64, Error>;
+    fn time_until_next_for(e: Env, id: u64) -> Result<u64, Error>;
+    fn withdraw_up_to_for(e: Env, id: u64, until: u64) -> Result<u32, Error>;
+    fn withdraw_periods_for(e: Env, id: u64, max_periods: u32) -> Result<u32, Error>;
+    fn forecast_for(e: Env, id: u64, from: u64, to: u64) -> Result<i128, Error>;
+    fn withdraw_count_for(e: Env, id: u64) -> Result<u64, Error>;
+    fn period_index_of_for(e: Env, id: u64, timestamp: u64) -> Result<u64, Error>;
+    fn set_invoker_allowlist_for(e: Env, id: u64, invokers: Vec<AccountId>) -> Result<(), Error>;
+    fn set_admin_for(e: Env, id: u64, admin: Identifier) -> Result<(), Error>;
+    fn clear_admin_for(e: Env, id: u64) -> Result<(), Error>;
+    fn propose_parent_for(e: Env, id: u64, new_parent: Identifier) -> Result<(), Error>;
+    fn accept_parent_for(e: Env, id: u64) -> Result<(), Error>;
+    fn propose_skip_for(e: Env, id: u64, period_index: u64) -> Result<(), Error>;
+    fn accept_skip_for(e: Env, id: u64, period_index: u64) -> Result<(), Error>;
+    fn report_shortfall_for(e: Env, id: u64) -> Result<u32, Error>;
+    fn reactivate_for(e: Env, id: u64) -> Result<(), Error>;
+    fn recipients_for(e: Env, id: u64) -> Result<Vec<(Identifier, u32)>, Error>;
+    fn propose_shares_for(e: Env, id: u64, shares: Vec<(Identifier, u32)>) -> Result<(), Error>;
+    fn accept_shares_for(e: Env, id: u64) -> Result<(), Error>;
+    fn cancel_my_share_for(e: Env, id: u64) -> Result<(), Error>;
+    fn payers_for(e: Env, id: u64) -> Result<Vec<(Identifier, u32)>, Error>;
+    fn sponsor_for(e: Env, id: u64, periods: u32) -> Result<(), Error>;
+    fn revoke_sponsorship_for(e: Env, id: u64) -> Result<(), Error>;
+    fn post_collateral_for(e: Env, id: u64, amount: i128) -> Result<(), Error>;
+    fn claim_from_collateral_for(e: Env, id: u64, period_index: u64) -> Result<i128, Error>;
+    fn reclaim_collateral_for(e: Env, id: u64) -> Result<i128, Error>;
+    fn assign_payments_for(
+        e: Env,
+        id: u64,
+        assignee: Identifier,
+        periods: u32,
+    ) -> Result<(), Error>;
+    fn renounce_assignment_for(e: Env, id: u64) -> Result<(), Error>;
+    fn set_metadata_for(e: Env, id: u64, name: Symbol, reference: Bytes) -> Result<(), Error>;
+    fn get_metadata_for(e: Env, id: u64) -> Result<Option<Metadata>, Error>;
+    fn propose_amount_signed_for(
+        e: Env,
+        id: u64,
+        sig: Signature,
+        nonce: i128,
+        amount: i128,
+    ) -> Result<i128, Error>;
+    fn cancel_signed_for(e: Env, id: u64, sig: Signature, nonce: i128) -> Result<(), Error>;
+    fn approve_action_for(e: Env, id: u64, action: Action) -> Result<bool, Error>;
+    fn propose_token_for(e: Env, id: u64, token_id: BytesN<32>, amount: i128) -> Result<(), Error>;
+    fn accept_token_for(e: Env, id: u64) -> Result<(), Error>;
+    fn reconcile_for(e: Env, id: u64) -> Result<ReconcileReport, Error>;
+    fn withdraw_in_for(e: Env, id: u64, token_id: BytesN<32>) -> Result<u32, Error>;
+    fn set_preferred_token_for(e: Env, id: u64, token_id: BytesN<32>) -> Result<(), Error>;
+    fn set_payment_hook_for(e: Env, id: u64, hook: Option<BytesN<32>>) -> Result<(), Error>;
+    fn set_spend_policy_for(e: Env, id: u64, policy: Option<BytesN<32>>) -> Result<(), Error>;
+    fn report_end_for(e: Env, id: u64) -> Result<(), Error>;
+    fn remaining_cap_for(e: Env, id: u64) -> Result<Option<i128>, Error>;
+    fn preview_withdraw_for(e: Env, id: u64) -> Result<WithdrawPreview, Error>;
+    fn outstanding_obligation_for(e: Env, id: u64) -> Result<i128, Error>;
+    fn is_initialized_for(e: Env, id: u64) -> bool;
 
-        // A withdrawal should never be `0`. I mean, really. At that point, why
-        // even go through the trouble of setting this up?
-        if (amount * step as i128) / SECONDS_IN_YEAR as i128 == 0 {
-            return Err(Error::InvalidArguments);
-        }
+    // The ids of the subscriptions `who` is the `Parent` (or `Child`) of,
+    // oldest first, a page at a time: up to `limit` of them (and never more
+    // than 50), skipping the first `offset`. Once a subscription has been
+    // cancelled (and any notice has run out), it's no longer listed.
+    fn subscriptions_of(
+        e: Env,
+        who: Identifier,
+        role: Role,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<u64>, Error>;
 
-        // We are setting up all the data that this contract will store on the
-        // ledger here. Nothing fancy here, just the same thing a few times.
-        e.storage().set(token_key, token_id);
-        e.storage()
-            .set(StorageKey::Parent, to_account(e.invoker()).unwrap()); // the invoker of `init` becomes the `Parent`
-        e.storage().set(StorageKey::Child, child);
-        e.storage().set(StorageKey::Amount, amount);
-        e.storage().set(StorageKey::Step, step);
+    // For keepers: the ids of up to `limit` subscriptions (never more than
+    // 50) that `withdraw_for` would pay out right now, looking from id
+    // `cursor` on, and where to carry on looking next time. Once every
+    // subscription has been looked at, the next cursor is `0`.
+    fn due_subscriptions(e: Env, cursor: u64, limit: u32) -> Result<(Vec<u64>, u64), Error>;
 
-        // As an act of goodwill, we set the `Latest` withdraw to be in the past
-        // and allow the `Child` to immediately make the first withdrawal. Just
-        // to get them started, ya know.
-        let current_ts = e.ledger().timestamp();
-        e.storage().set(StorageKey::Latest, current_ts - step);
-        // This is the first time we've used `Env.ledger()` in these contracts.
-        // The Soroban environment, by design, doesn't have a tremendous amount
-        // of context about the current state of the Stellar network. One of the
-        // few things it does know is the `timestamp()` of the most recently
-        // closed ledger on the network. Check in the list of "Further
-        // Resources" in the README to learn more about this.
+    // Invokes `withdraw_for` on each of (up to 20) `ids`, carrying on past
+    // any that fail, and says what happened to each one, in the same order.
+    fn withdraw_batch(e: Env, ids: Vec<u64>) -> Result<Vec<Outcome>, Error>;
 
-        Ok(())
+    // What `withdraw_batch` invokes for each of its `ids`, once it has
+    // checked its own invoker may withdraw for it. Nobody else can.
+    fn batch_pay(e: Env, id: u64) -> Result<u32, Error>;
+
+    // The `Parent` can swap the contract's code for the installed WASM with
+    // the given `wasm_hash`, to fix a bug without moving every allowance to
+    // a brand new contract. The data stays exactly where it is.
+    fn upgrade(e: Env, wasm_hash: BytesN<32>) -> Result<(), Error>;
+
+    // After an `upgrade` to code with a newer storage layout, `migrate`
+    // brings the data stored so far up to date, one version at a time.
+    // Anyone can invoke it, since it doesn't change what the data means.
+    // With lots of subscriptions, it takes more than one invocation: it
+    // says `true` once everything is up to date, and until then, invoke it
+    // again.
+    fn migrate(e: Env) -> Result<bool, Error>;
+
+    // The `Parent` can hand the day-to-day running of the allowance to an
+    // `admin` (say, an operations key, so the paying key can stay cold). The
+    // `admin` can do anything the `Parent` can, except that the money still
+    // comes out of the `Parent`'s allowance, and extra invoices still need
+    // the `Parent`'s own approval. Either of them can change or `clear_admin`.
+    fn set_admin(e: Env, admin: Identifier) -> Result<(), Error>;
+
+    fn clear_admin(e: Env) -> Result<(), Error>;
+
+    // The `Parent` can hand the allowance over to a `new_parent` (after an
+    // acquisition, say, or to rotate keys), who pays for it from then on.
+    // Nothing changes until the `new_parent` invokes `accept_parent`, so a
+    // typo can't hand it to somebody who doesn't exist. Until then, the
+    // `Parent` keeps paying.
+    fn propose_parent(e: Env, new_parent: Identifier) -> Result<(), Error>;
+
+    fn accept_parent(e: Env) -> Result<(), Error>;
+
+    // The `Child` can walk away from the allowance (a vendor who no longer
+    // serves the customer, say, or an employee who has resigned), and nothing
+    // comes due after that. With `final_withdraw`, whatever is due right now
+    // is paid out first. Without it, it's left behind.
+    fn close_by_child(e: Env, final_withdraw: bool) -> Result<(), Error>;
+
+    // The `Parent` can `cancel` the allowance, giving the notice that was
+    // agreed at `init` (see `Options`), and paying any early termination fee.
+    // Whatever comes due before the notice runs out is still the `Child`'s
+    // to collect. (Pulling the allowance on
+    // the token instead doesn't get the `Parent` out of it quietly: the
+    // `Child`'s `withdraw` fails with `Error::InsufficientAllowance`.)
+    fn cancel(e: Env) -> Result<(), Error>;
+
+    // When both sides agree to call it a day, they each `approve_close`, and
+    // the second approval closes the allowance there and then, paying out
+    // whatever is still due first. Until then, either side can change their
+    // mind with `revoke_close_approval`.
+    fn approve_close(e: Env) -> Result<(), Error>;
+
+    fn revoke_close_approval(e: Env) -> Result<(), Error>;
+
+    // The `Parent` can waive up to `periods` of the withdrawals that are
+    // already due (say, "we were both on vacation, let's skip July"), oldest
+    // first. They're skipped as if they'd been collected, except nothing is
+    // paid and they aren't counted in `withdraw_count`. It returns how many
+    // were actually waived, which is never more than were due.
+    fn forgive_arrears(e: Env, periods: u32) -> Result<u32, Error>;
+
+    // The `Parent` can pay for the next `periods` withdrawals (up to 120 of
+    // them) right now, whether they're due yet or not, and the `Child` won't
+    // be able to collect for them again. It never goes past the end of a
+    // cancelled allowance, and returns how much was paid. (It doesn't work
+    // for a stream.)
+    fn prepay(e: Env, periods: u32) -> Result<i128, Error>;
+
+    // Either side can propose skipping an upcoming withdrawal (say, "closed
+    // for the holidays"), by its number (see `period_index_of`), and once
+    // the other side accepts, it comes due as usual but costs nothing. Only
+    // a withdrawal that hasn't come due yet can be skipped.
+    fn propose_skip(e: Env, period_index: u64) -> Result<(), Error>;
+
+    fn accept_skip(e: Env, period_index: u64) -> Result<(), Error>;
+
+    // A `withdraw` that fails leaves no trace on the ledger, so when the
+    // `Parent` can't cover what's due, anybody can `report_shortfall`
+    // instead. That starts the clock on late fees (see `Options`) for every
+    // withdrawal that's due and unpaid. It returns how many that was, which
+    // is `0` if the `Parent` can actually pay.
+    fn report_shortfall(e: Env) -> Result<u32, Error>;
+
+    // Once the `Parent` has topped up the allowance, they can `reactivate` a
+    // suspended one, and the `Child` can collect everything that's due again.
+    fn reactivate(e: Env) -> Result<(), Error>;
+
+    // Who every withdrawal is paid out to, and their share of it in basis
+    // points. That's just the `Child`, unless the allowance was set up with
+    // `recipients`.
+    fn recipients(e: Env) -> Result<Vec<(Identifier, u32)>, Error>;
+
+    // The `Parent` can propose a new split, say when a collaborator leaves.
+    // Every recipient whose share it cuts (or drops altogether) has to
+    // `accept_shares` before it takes effect; if nobody's share goes down,
+    // it takes effect straight away. Until then, withdrawals are split the
+    // old way.
+    fn propose_shares(e: Env, shares: Vec<(Identifier, u32)>) -> Result<(), Error>;
+    fn accept_shares(e: Env) -> Result<(), Error>;
+
+    // One of the `payers` can stop paying their share. What's left is split
+    // between the others in proportion to what they paid before (see
+    // `Options`), and once the last one leaves, the allowance is closed.
+    fn cancel_my_share(e: Env) -> Result<(), Error>;
+
+    // Who pays for every withdrawal, and their share of it in basis points.
+    // That's just the `Parent`, unless the allowance was set up with `payers`.
+    fn payers(e: Env) -> Result<Vec<(Identifier, u32)>, Error>;
+
+    // Anybody can `sponsor` the next `periods` withdrawals that haven't been
+    // paid yet (think a scholarship, or a gift), and they're paid from the
+    // sponsor's allowance instead, after which it's back to the usual. A
+    // `withdraw` never pays for sponsored and unsponsored periods at once, so
+    // catching up across the end of a sponsorship takes two. There's only
+    // ever one sponsor at a time, and the `Parent` can `revoke_sponsorship`.
+    fn sponsor(e: Env, periods: u32) -> Result<(), Error>;
+    fn revoke_sponsorship(e: Env) -> Result<(), Error>;
+
+    // The `Parent` can lock some of the token up in the contract as
+    // collateral (out of the allowance they've approved it for). When a
+    // withdrawal has been reported short (see `report_shortfall`) and is
+    // still unpaid a whole period after it came due, the `Child` can
+    // `claim_from_collateral` for it instead. Once the allowance has ended
+    // with nothing left owing, the `Parent` can `reclaim_collateral`, and
+    // gets back whatever's left. (It doesn't work for a stream.)
+    fn post_collateral(e: Env, amount: i128) -> Result<(), Error>;
+    fn claim_from_collateral(e: Env, period_index: u64) -> Result<i128, Error>;
+    fn reclaim_collateral(e: Env) -> Result<i128, Error>;
+
+    // The `Child` can assign the next `periods` unpaid withdrawals to
+    // somebody else (say, a financier who's paid them up front), and they're
+    // paid to the `assignee` instead. Just like a sponsorship, a `withdraw`
+    // never spans the end of an assignment. Once periods are assigned, they
+    // stay that way, unless the `assignee` `renounce`s them. (It doesn't work
+    // with `recipients`.)
+    fn assign_payments(e: Env, assignee: Identifier, periods: u32) -> Result<(), Error>;
+    fn renounce_assignment(e: Env) -> Result<(), Error>;
+
+    // The `Parent` can (re)name the plan, and set the reference their own
+    // systems know the allowance by.
+    fn set_metadata(e: Env, name: Symbol, reference: Bytes) -> Result<(), Error>;
+
+    // The `Metadata` set at `init` or by `set_metadata`, if any.
+    fn get_metadata(e: Env) -> Result<Option<Metadata>, Error>;
+
+    // Which build of the contract this is, so integrators juggling several
+    // of them can tell: the crate's version, as (major, minor, patch)...
+    fn version(e: Env) -> (u32, u32, u32);
+
+    // ...and what it can do. That's everything `Options` can switch on, and
+    // `factory` if the `FactoryContract` was built in, too.
+    fn features(e: Env) -> Vec<Symbol>;
+
+    // A `Parent` that can't invoke anything itself (say, a custodian holding
+    // a bare ed25519 key) can sign the arguments to `init` instead, and have
+    // anybody submit them. The signature is over the name `init`, the signer,
+    // the `nonce` and then the rest of the arguments. The `nonce` has to be
+    // the signer's next one, so a signature can only ever be used once.
+    fn init_with_signature(
+        e: Env,
+        sig: Signature,
+        nonce: i128,
+        child: Identifier,
+        token_id: BytesN<32>,
+        amount: i128,
+        step: u64,
+        options: Options,
+    ) -> Result<(), Error>;
+
+    // The nonce `id`'s next signature has to use, which is how many of their
+    // signatures this contract has accepted so far.
+    fn nonce(e: Env, id: Identifier) -> Result<i128, Error>;
+
+    // A `Parent` whose key is kept offline can sign `propose_amount` and
+    // `cancel` too, and have anybody relay them. The signatures work just like
+    // `init_with_signature`'s, over the name `amount` or `cancel`, the
+    // signer, the `nonce` and the rest of the arguments, and only a signature
+    // by the `Parent` (or their `Admin`) will do.
+    fn propose_amount_signed(
+        e: Env,
+        sig: Signature,
+        nonce: i128,
+        amount: i128,
+    ) -> Result<i128, Error>;
+    fn cancel_signed(e: Env, sig: Signature, nonce: i128) -> Result<(), Error>;
+
+    // With `signers`, the `Parent` can't `propose_amount`, `cancel` or
+    // `upgrade` on their own any more. Instead, each of the signers approves
+    // the `action`, and it goes through (just as if the `Parent` had done it)
+    // once there are enough of them, returning `true`. Only one action can be
+    // waiting for approval at a time, so approving a different one (or the
+    // same with other arguments) starts over, as does running out of time.
+    fn approve_action(e: Env, action: Action) -> Result<bool, Error>;
+
+    // The `Parent` can propose paying in another token from now on, at a new
+    // yearly `amount`, and once the `Child` accepts, both switch over at
+    // once. Nothing paid in the old token can still be owing by then (regular
+    // withdrawals, extras or collateral), so it has to be settled first. After
+    // that, the `Parent` approves the contract on the new token, and until
+    // they do, `withdraw` fails with `Error::InsufficientAllowance`.
+    fn propose_token(e: Env, token_id: BytesN<32>, amount: i128) -> Result<(), Error>;
+    fn accept_token(e: Env) -> Result<(), Error>;
+
+    // Compares the `Child`'s balance on the token with what it should be,
+    // going by what this contract has paid them since the last time anybody
+    // asked, and starts over from there. If it's short by more than the
+    // `reconcile_tolerance` (say, the token's admin clawed some back), it
+    // publishes a "drift" event. It never stops a withdrawal either way, and
+    // of course the `Child` spending their own money looks just the same, so
+    // it's really for a `Child` that's a treasury and only ever receives.
+    fn reconcile(e: Env) -> Result<ReconcileReport, Error>;
+
+    // Just like `withdraw`, but paid in `token_id`, which has to be the
+    // allowance's own token or one of its `accepted_tokens`. However far
+    // behind the `Child` is, it's all paid in the same one.
+    fn withdraw_in(e: Env, token_id: BytesN<32>) -> Result<u32, Error>;
+
+    // The `Child` can choose which of those a plain `withdraw` pays in.
+    fn set_preferred_token(e: Env, token_id: BytesN<32>) -> Result<(), Error>;
+
+    // The `Child` can have a contract of theirs told about every withdrawal
+    // once it's been paid, by calling `on_payment(period: u64, amount: i128)`
+    // on it. Whatever the hook does, it can't stop the payment. `None` takes
+    // it off again.
+    fn set_payment_hook(e: Env, hook: Option<BytesN<32>>) -> Result<(), Error>;
+
+    // The `Parent` can have a policy contract of theirs vet every
+    // `withdraw` before it's paid (say, to cap what goes out in a month
+    // across all their vendors). It's asked `approve(amount: i128, period:
+    // u64) -> bool`, for the total and the first withdrawal being paid, and
+    // unless it says `true`, the withdrawal fails with
+    // `Error::PolicyRejected`. `None` takes it off again.
+    fn set_spend_policy(e: Env, policy: Option<BytesN<32>>) -> Result<(), Error>;
+
+    // For other contracts to gate on: whether `receiver` is the `Child` of a
+    // subscription here that's running, and no more than its
+    // `grace_periods` behind. It never traps, whatever is (or isn't) in
+    // storage; anything it can't make sense of is a `false`.
+    fn is_active_for(e: Env, receiver: AccountId) -> bool;
+
+    // Once a cancelled allowance has run out its notice, anybody can invoke
+    // `report_end` to tell the `registry` (see `Options`) it's over, in case
+    // no `withdraw` has since. Either way, it's only ever told once.
+    fn report_end(e: Env) -> Result<(), Error>;
+
+    // Everything about the allowance at once (see `FullState`).
+    fn get_state(e: Env) -> Result<FullState, Error>;
+
+    // What the last withdrawals paid, newest first, a page at a time: up to
+    // `limit` receipts (and never more than 20), skipping the newest
+    // `offset`. Past the oldest one, the page is simply empty.
+    fn history(e: Env, offset: u32, limit: u32) -> Result<Vec<Receipt>, Error>;
+
+    // Receipts cost rent for as long as they're kept, so either side can
+    // delete all but the newest `keep_latest`, the oldest first. It deletes
+    // no more than 10 at a time, and says how many it did, so keep invoking
+    // it until that's `0`. Nothing else the allowance counts is affected.
+    fn prune_receipts(e: Env, keep_latest: u32) -> Result<u32, Error>;
+
+    // How much more the withdrawals can pay out before they reach the
+    // `lifetime_cap` (see `Options`), if there is one.
+    fn remaining_cap(e: Env) -> Result<Option<i128>, Error>;
+
+    // The `Parent` can change how often withdrawals come (the yearly
+    // `Amount` stays the same). Everything that can be collected is paid out
+    // first, at the old `step`. The new one starts when the period we're in
+    // ends, so no withdrawal is suddenly overdue, or pushed further off than
+    // that. It doesn't work for a stream, a calendar month, or billing in
+    // arrears.
+    fn fix_step(e: Env, step: u64) -> Result<(), Error>;
+
+    // Once the allowance is over (`Cancelled` or `Expired`), everything
+    // due has been paid, and any collateral has been reclaimed, the `Parent`
+    // can wipe it, so that `init` can set up a new one on the same contract.
+    // Its receipts have to be `prune_receipts`ed away first.
+    fn reset(e: Env) -> Result<(), Error>;
+
+    // What invoking `withdraw` right now would pay, and to whom (see
+    // `WithdrawPreview`), without paying it. It fails just like `withdraw`
+    // would, except that anybody may invoke it, and the `Parent`'s spend
+    // policy isn't asked.
+    fn preview_withdraw(e: Env) -> Result<WithdrawPreview, Error>;
+
+    // Everything the regular withdrawals still have to pay (whether it's
+    // come due yet or not) before the allowance comes to an end, priced just
+    // like they will be, escalators, rate changes, trials, skips and all. It
+    // only comes to an end once it's been cancelled, or the `lifetime_cap`
+    // is reached. Until then, it's `Unbounded`.
+    fn outstanding_obligation(e: Env) -> Result<i128, Error>;
+
+    // Whether `init` has been invoked (and the allowance hasn't been `reset`
+    // since). Everything that needs an allowance fails with
+    // `Error::ContractNotInitialized` until it has.
+    fn is_initialized(e: Env) -> bool;
+}
+
+/// `env!` only gives us the version numbers as strings, so they're parsed at
+/// compile time.
+const fn parse_version(digits: &str) -> u32 {
+    let digits = digits.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        value = value * 10 + (digits[i] - b'0') as u32;
+        i += 1;
     }
+    value
+}
 
-    fn withdraw(e: Env) -> Result<(), Error> {
-        // Conversely from `init`, we want to make sure the contract *has* been
-        // initialized before a withdraw can be made.
-        let key = StorageKey::TokenId;
-        if !e.storage().has(key.clone()) {
-            return Err(Error::ContractNotInitialized);
-        }
+/// The id of the contract that `deployer` deploys with `salt`, worked out
+/// the way the host does it: the SHA-256 of the XDR `HashIdPreimage` for
+/// `ENVELOPE_TYPE_CONTRACT_ID_FROM_CONTRACT`, which is the envelope type,
+/// then the `deployer`, then the `salt`. Nobody but the `deployer` can come
+/// up with a `salt` that gives the id of a contract it deployed.
+fn deployed_id(e: &Env, deployer: &BytesN<32>, salt: &BytesN<32>) -> BytesN<32> {
+    let mut preimage = Bytes::from_array(e, &ENVELOPE_TYPE_CONTRACT_ID_FROM_CONTRACT.to_be_bytes());
+    preimage.append(&deployer.clone().into());
+    preimage.append(&salt.clone().into());
+    e.compute_hash_sha256(preimage)
+}
 
-        // We create a client to the token contract that we'll be able to use to
-        // make the transfer later on. This should look familiar to Quest 4.
-        let token_id: BytesN<32> = e.storage().get(key).unwrap().unwrap();
-        let client = token::Client::new(&e, token_id);
+/// Makes sure we were invoked by the contract that deployed us, and that it
+/// proved it with the `salt` it deployed us with (see `deployed_id`).
+fn check_deployer(e: &Env, salt: &BytesN<32>) -> Result<(), Error> {
+    let deployer = match e.invoker() {
+        Address::Contract(id) => id,
+        Address::Account(_) => return Err(Error::InvalidInvoker),
+    };
+    if deployed_id(e, &deployer, salt) != e.current_contract() {
+        return Err(Error::InvalidInvoker);
+    }
+    Ok(())
+}
 
-        // This is a simple check to ensure the `withdraw` function has not been
-        // invoked by a contract. For our purposes, it *must* be invoked by a
-        // user account.
-        match e.invoker() {
-            Address::Account(id) => id,
-            _ => return Err(Error::InvalidInvoker),
+/// When a contract uses "Invoker" authentication, `env.invoker()` returns the
+/// `Address` type. Since we're storing the `Parent` and `Child` as
+/// `Identifier`s (either one can be a contract), we use a helper function to
+/// convert from one to the other.
+fn invoker_id(e: &Sub) -> Identifier {
+    match e.invoker() {
+        Address::Account(id) => Identifier::Account(id),
+        Address::Contract(id) => Identifier::Contract(id),
+    }
+}
+
+/// Checks that `sig` was made over `args` to the function called `name`, and
+/// uses up its `nonce`, so the same signature can never be submitted twice.
+/// Nonces belong to the whole contract, so they're kept with subscription
+/// `0`. An invoker doesn't need one (its transaction can't be replayed
+/// anyway), so it always passes `0`.
+fn authorize<T: IntoVal<Env, Vec<RawVal>>>(
+    e: &Sub,
+    sig: &Signature,
+    nonce: i128,
+    name: Symbol,
+    args: T,
+) -> Result<(), Error> {
+    if let Signature::Invoker = sig {
+        return match nonce {
+            0 => Ok(()),
+            _ => Err(Error::InvalidNonce),
         };
+    }
 
-        // This part is one of the contract's really nifty tricks. You may have
-        // noticed we haven't authenticated the invocation of `withdraw` at all.
-        // That's on purpose! By storing the `Child` in our contract data, we
-        // can ensure they are *always* the beneficiary of the withdrawal. No
-        // matter who actually makes the call to the contract, the child is
-        // always taken care of.
-        let child = e.storage().get(StorageKey::Child).unwrap().unwrap();
-        // Note: Technically speaking, *anybody* could invoke the `withdraw`
-        // function in the contract (yes, even your cousin Josh). In practice,
-        // for today's quest, the function **must** be invoked by either the
-        // `Parent` or the `Child`.
-
-        // We do some really quick math to figure out a couple things:
-        // - `iterations` - the number of withdraws that can be made in a year
-        // - `amount` - the withdrawn for every iteration
-
-        let step: u64 = e.storage().get(StorageKey::Step).unwrap().unwrap();
-        let iterations = SECONDS_IN_YEAR / step;
-        let amount: i128 = e.storage().get(StorageKey::Amount).unwrap().unwrap();
-        let withdraw_amount = amount / iterations as i128;
-
-        // Some more quick math to make sure the `Latest` withdraw occurred *at
-        // least* `step` seconds ago. We don't want them draining the piggy bank
-        // all at once, after all.
-        let latest: u64 = e.storage().get(StorageKey::Latest).unwrap().unwrap();
-        if latest + step > e.ledger().timestamp() {
-            return Err(Error::ChildAlreadyWithdrawn);
+    let main = Sub::main(e.env.clone());
+    let key = StorageKey::Nonce(sig.identifier(e));
+    let expected: i128 = read_opt(&main, key.clone())?.unwrap_or(0);
+    if nonce != expected {
+        return Err(Error::InvalidNonce);
+    }
+    // This traps, rather than returning an error, if the signature doesn't
+    // check out.
+    soroban_auth::verify(e, sig, name, args);
+    main.storage()
+        .set(key, expected.checked_add(1).ok_or(Error::Overflow)?);
+    Ok(())
+}
+
+/// What we expect of a `registry`: it's told the contract id and the new
+/// `Status` of an allowance whenever that changes. It can do what it likes
+/// with that, including fail, without getting in the way of the change.
+pub trait RegistryTrait {
+    fn notify(e: Env, contract_id: BytesN<32>, status: Status);
+}
+
+/// One contract can look after lots of allowances, which we call
+/// subscriptions. Each one has its own copy of every `StorageKey`, and a `Sub`
+/// is how we get at one: it stands in for the `Env` everywhere, except that
+/// `storage()` only ever sees that subscription's data. The allowance set up
+/// by `init` is subscription `0`, and it keeps the keys it always had, so
+/// contracts from before there were subscriptions carry on as they are. (The
+/// few keys that belong to the whole contract, like `NextId`, live there too.)
+#[derive(Clone)]
+struct Sub {
+    env: Env,
+    id: u64,
+}
+
+impl Sub {
+    /// The allowance set up by `init`.
+    fn main(env: Env) -> Sub {
+        Sub { env, id: 0 }
+    }
+
+    /// Subscription `id`, which has to have been `create`d. (Whether `init`
+    /// has been invoked for subscription `0` is checked the same way it
+    /// always has been, by whatever is using it.)
+    fn get(env: Env, id: u64) -> Result<Sub, Error> {
+        let sub = Sub { env, id };
+        if id != 0 && !is_initialized(&sub) {
+            return Err(Error::SubscriptionNotFound);
         }
+        Ok(sub)
+    }
 
-        // This is where the magic happens! We use the client we set up for our
-        // token contract earlier to invoke the `xfer_from` function. We're
-        // using *this contract's* approval to spend the asset balance of the
-        // `Parent` account to transfer funds *directly* from the `Parent` to
-        // the `Child`. That's amazing! Think of the implications and
-        // possibilities! They're (and I mean this quite literally) endless!
-        client.xfer_from(
-            &Signature::Invoker,
-            &(0 as i128),
-            &Identifier::Account(e.storage().get(StorageKey::Parent).unwrap().unwrap()),
-            &Identifier::Account(child),
-            &withdraw_amount,
-        );
+    fn storage(&self) -> SubStorage {
+        SubStorage(self)
+    }
+}
 
-        // We quickly set a new `Latest` in our contract data to reflect that
-        // another withdraw has taken place. The astute among you may notice
-        // this isn't based off the ledger's `timestamp()`, but rather the
-        // latest withdraw. This allows the child to "catch up" on any missed
-        // withdrawals. Very kind of you. You're such a good parent!
-        let new_latest = latest + step;
-        e.storage().set(StorageKey::Latest, new_latest);
+impl Deref for Sub {
+    type Target = Env;
 
-        Ok(())
+    fn deref(&self) -> &Env {
+        &self.env
+    }
+}
+
+/// The contract's storage, as seen by one subscription. Every other
+/// subscription's keys are stored as a vector of its id and the `StorageKey`.
+struct SubStorage<'a>(&'a Sub);
+
+impl SubStorage<'_> {
+    fn key(&self, key: StorageKey) -> RawVal {
+        let Sub { env, id } = self.0;
+        if *id == 0 {
+            return key.into_val(env);
+        }
+        vec![env, id.into_val(env), key.into_val(env)].into_val(env)
+    }
+
+    fn get<V: TryFromVal<Env, RawVal>>(&self, key: StorageKey) -> Option<Result<V, V::Error>> {
+        #[cfg(test)]
+        metering::count_read();
+        self.0.env.storage().get(self.key(key))
+    }
+
+    fn has(&self, key: StorageKey) -> bool {
+        #[cfg(test)]
+        metering::count_read();
+        self.0.env.storage().has(self.key(key))
+    }
+
+    fn set<V: IntoVal<Env, RawVal>>(&self, key: StorageKey, value: V) {
+        #[cfg(test)]
+        metering::count_write();
+        self.0.env.storage().set(self.key(key), value)
+    }
+
+    fn remove(&self, key: StorageKey) {
+        #[cfg(test)]
+        metering::count_write();
+        self.0.env.storage().remove(self.key(key))
+    }
+}
+
+/// Reading from storage gives us an `Option` (is anything there?) wrapping a
+/// `Result` (could it be converted to the type we asked for?). Rather than
+/// `unwrap()`ing both and trapping, we turn a missing entry into
+/// `Error::MissingData` and an unreadable one into `Error::CorruptData`.
+fn read<V: TryFromVal<Env, RawVal>>(e: &Sub, key: StorageKey) -> Result<V, Error> {
+    read_opt(e, key)?.ok_or(Error::MissingData)
+}
+
+/// The same as `read`, for entries that are allowed to be absent.
+fn read_opt<V: TryFromVal<Env, RawVal>>(e: &Sub, key: StorageKey) -> Result<Option<V>, Error> {
+    match e.storage().get(key) {
+        Some(Ok(value)) => Ok(Some(value)),
+        Some(Err(_)) => Err(Error::CorruptData),
+        None => Ok(None),
+    }
+}
+
+/// Whether `init` (or `create`) has been invoked for `e`'s subscription.
+fn is_initialized(e: &Sub) -> bool {
+    e.storage().has(StorageKey::State) || e.storage().has(StorageKey::TokenId)
+}
+
+/// Makes sure `init` has been invoked for `e`'s subscription, and that the
+/// contract's data is in a layout we can read.
+fn check_initialized(e: &Sub) -> Result<(), Error> {
+    if !is_initialized(e) {
+        return Err(Error::ContractNotInitialized);
+    }
+    check_schema(e)
+}
+
+/// The version of the layout the contract's data is in. It belongs to the
+/// whole contract, so it's kept with subscription `0`.
+fn schema_version(e: &Sub) -> Result<u32, Error> {
+    let main = Sub::main(e.env.clone());
+    Ok(read_opt(&main, StorageKey::SchemaVersion)?.unwrap_or(0))
+}
+
+/// Data from a layout outside the ones we read needs `migrate`ing first. (Or,
+/// if it's newer than this code, the contract needs `upgrade`ing back.)
+fn check_schema(e: &Sub) -> Result<(), Error> {
+    let version = schema_version(e)?;
+    if !(MIN_SCHEMA_VERSION..=SCHEMA_VERSION).contains(&version) {
+        return Err(Error::MigrationRequired);
+    }
+    Ok(())
+}
+
+/// Brings the contract's data from version `0` of the layout to version `1`,
+/// which stores the `Parent` and `Child` as `Identifier`s.
+fn migrate_from_v0(e: &Sub) -> Result<(), Error> {
+    let (parent, child) = (parent(e)?, child(e)?);
+    e.storage().set(StorageKey::Parent, parent);
+    e.storage().set(StorageKey::Child, child);
+    Ok(())
+}
+
+/// Brings the contract's data from version `1` of the layout to version `2`,
+/// which keeps everything `withdraw` reads in one `State` entry. The version
+/// belongs to the whole contract, so every subscription has to be brought up
+/// to date, but there can be more of them than one invocation gets through.
+/// So they're done `MAX_BATCH` at a time, carrying on from `MigratedTo`, and
+/// this says whether that was the last of them. Until it is, each one is read
+/// whichever way it's stored.
+fn migrate_from_v1(e: &Sub) -> Result<bool, Error> {
+    let next_id: u64 = read_opt(e, StorageKey::NextId)?.unwrap_or(1);
+    let from: u64 = read_opt(e, StorageKey::MigratedTo)?.unwrap_or(0);
+    let until = from.saturating_add(MAX_BATCH as u64).min(next_id);
+    for id in from..until {
+        let sub = Sub {
+            env: e.env.clone(),
+            id,
+        };
+        if !is_initialized(&sub) || sub.storage().has(StorageKey::State) {
+            continue;
+        }
+        let latest = latest(&sub)?;
+        let state = State {
+            token_id: token_id(&sub)?,
+            parent: parent(&sub)?,
+            child: child(&sub)?,
+            amount: amount(&sub)?,
+            step: step(&sub)?,
+            options: options(&sub)?,
+            latest,
+            start_epoch: start_epoch(&sub, latest)?,
+            withdraw_count: withdraw_count(&sub)?,
+        };
+        for key in [
+            StorageKey::TokenId,
+            StorageKey::Parent,
+            StorageKey::Child,
+            StorageKey::Amount,
+            StorageKey::Step,
+            StorageKey::Options,
+            StorageKey::Latest,
+            StorageKey::StartEpoch,
+            StorageKey::WithdrawCount,
+        ] {
+            sub.storage().remove(key);
+        }
+        sub.storage().set(StorageKey::State, state);
+    }
+
+    if until < next_id {
+        e.storage().set(StorageKey::MigratedTo, until);
+        return Ok(false);
+    }
+    e.storage().remove(StorageKey::MigratedTo);
+    Ok(true)
+}
+
+/// Contracts that were set up before `Options` existed don't have any stored,
+/// and they get the defaults, which is exactly how they have always behaved.
+fn options(e: &Sub) -> Result<Options, Error> {
+    match state(e)? {
+        Some(state) => Ok(state.options),
+        None => Ok(read_opt(e, StorageKey::Options)?.unwrap_or_default()),
+    }
+}
+
+/// A pause request is "in force" once the `Parent` has approved it, or once its
+/// `from` time has arrived and the contract was set up to auto-approve.
+fn pause_in_force(e: &Sub, req: &PauseRequest, now: u64) -> Result<bool, Error> {
+    Ok(req.approved || (now >= req.from && options(e)?.auto_approve_child_pause))
+}
+
+/// We keep the `Latest` withdraw on a clock that doesn't tick while a pause is
+/// in force. Once a pause is completely over, we fold its length into `Latest`
+/// (and `StartEpoch`, so the whole schedule moves together) and forget about
+/// it, so the schedule simply carries on where it left off.
+/// A request that was never approved and has reached its `from` time lapses,
+/// and is forgotten about, too.
+fn settle_pause(e: &Sub, now: u64) -> Result<(), Error> {
+    let req: PauseRequest = match read_opt(e, StorageKey::PauseReq)? {
+        Some(req) => req,
+        None => return Ok(()),
+    };
+
+    if pause_in_force(e, &req, now)? {
+        if now >= req.until {
+            let length = req.until - req.from;
+            let latest = latest(e)?;
+            let start = start_epoch(e, latest)?;
+            set_latest(e, latest.checked_add(length).ok_or(Error::Overflow)?)?;
+            set_start_epoch(e, start.checked_add(length).ok_or(Error::Overflow)?)?;
+            e.storage().remove(StorageKey::PauseReq);
+        }
+    } else if now >= req.from {
+        e.storage().remove(StorageKey::PauseReq);
+    }
+
+    Ok(())
+}
+
+/// The `Parent` and `Child` used to be stored as `AccountId`s, and allowances
+/// set up back then still have them that way, so we take them either way.
+fn read_identifier(e: &Sub, key: StorageKey) -> Result<Identifier, Error> {
+    let raw: RawVal = read(e, key)?;
+    if let Ok(id) = Identifier::try_from_val(&e.env, raw) {
+        return Ok(id);
+    }
+    let id = AccountId::try_from_val(&e.env, raw).map_err(|_| Error::CorruptData)?;
+    Ok(Identifier::Account(id))
+}
+
+/// The subscription's `State`, unless it's still stored the old way, one
+/// `StorageKey` at a time.
+fn state(e: &Sub) -> Result<Option<State>, Error> {
+    read_opt(e, StorageKey::State)
+}
+
+/// Changes the subscription's `State`, if it has one. Otherwise, it returns
+/// `false`, and the caller writes to the old keys instead.
+fn update_state(e: &Sub, update: impl FnOnce(&mut State)) -> Result<bool, Error> {
+    match state(e)? {
+        Some(mut state) => {
+            update(&mut state);
+            e.storage().set(StorageKey::State, state);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn token_id(e: &Sub) -> Result<BytesN<32>, Error> {
+    match state(e)? {
+        Some(state) => Ok(state.token_id),
+        None => read(e, StorageKey::TokenId),
+    }
+}
+
+fn set_token_id(e: &Sub, token_id: BytesN<32>) -> Result<(), Error> {
+    if !update_state(e, |state| state.token_id = token_id.clone())? {
+        e.storage().set(StorageKey::TokenId, token_id);
+    }
+    Ok(())
+}
+
+fn parent(e: &Sub) -> Result<Identifier, Error> {
+    match state(e)? {
+        Some(state) => Ok(state.parent),
+        None => read_identifier(e, StorageKey::Parent),
+    }
+}
+
+fn set_parent(e: &Sub, parent: Identifier) -> Result<(), Error> {
+    if !update_state(e, |state| state.parent = parent.clone())? {
+        e.storage().set(StorageKey::Parent, parent);
+    }
+    Ok(())
+}
+
+fn child(e: &Sub) -> Result<Identifier, Error> {
+    match state(e)? {
+        Some(state) => Ok(state.child),
+        None => read_identifier(e, StorageKey::Child),
+    }
+}
+
+/// The yearly `Amount` withdrawals are paid at right now (see
+/// `agreed_amount` for the one they'll be paid at once every change that's
+/// been agreed on has kicked in).
+fn amount(e: &Sub) -> Result<i128, Error> {
+    match state(e)? {
+        Some(state) => Ok(state.amount),
+        None => read(e, StorageKey::Amount),
+    }
+}
+
+fn store_amount(e: &Sub, amount: i128) -> Result<(), Error> {
+    if !update_state(e, |state| state.amount = amount)? {
+        e.storage().set(StorageKey::Amount, amount);
+    }
+    Ok(())
+}
+
+fn step(e: &Sub) -> Result<u64, Error> {
+    match state(e)? {
+        Some(state) => Ok(state.step),
+        None => read(e, StorageKey::Step),
+    }
+}
+
+fn set_step(e: &Sub, step: u64) -> Result<(), Error> {
+    if !update_state(e, |state| state.step = step)? {
+        e.storage().set(StorageKey::Step, step);
+    }
+    Ok(())
+}
+
+fn latest(e: &Sub) -> Result<u64, Error> {
+    match state(e)? {
+        Some(state) => Ok(state.latest),
+        None => read(e, StorageKey::Latest),
+    }
+}
+
+fn set_latest(e: &Sub, latest: u64) -> Result<(), Error> {
+    if !update_state(e, |state| state.latest = latest)? {
+        e.storage().set(StorageKey::Latest, latest);
+    }
+    Ok(())
+}
+
+/// The `StartEpoch` is when the very first withdrawal comes due. Contracts
+/// from before it was stored don't have one, so for them we treat the `Latest`
+/// withdrawal as the start, which means they behave just as they always have.
+fn start_epoch(e: &Sub, latest: u64) -> Result<u64, Error> {
+    match state(e)? {
+        Some(state) => Ok(state.start_epoch),
+        None => Ok(read_opt(e, StorageKey::StartEpoch)?.unwrap_or(latest)),
+    }
+}
+
+fn set_start_epoch(e: &Sub, start_epoch: u64) -> Result<(), Error> {
+    if !update_state(e, |state| state.start_epoch = start_epoch)? {
+        e.storage().set(StorageKey::StartEpoch, start_epoch);
+    }
+    Ok(())
+}
+
+/// Moves the start of `e`'s schedule, so long as nothing has been collected.
+fn fix_start_epoch(e: &Sub, start_epoch: u64) -> Result<(), Error> {
+    check_initialized(e)?;
+    check_manager(e)?;
+
+    // Once the `Child` has collected anything, the schedule is set in
+    // stone. We can tell nothing has been collected yet because `Latest`
+    // is still sitting before the `StartEpoch`.
+    settle_pause(e, e.ledger().timestamp())?;
+    let sched = schedule(e)?;
+    if sched.latest >= sched.start {
+        return Err(Error::AlreadyStarted);
+    }
+
+    // Just like in `init`, `Latest` sits one `step` before the start, so the
+    // first withdrawal comes due exactly at the new `start_epoch`.
+    let latest = Cadence::load(e)?.prev(start_epoch)?;
+    set_start_epoch(e, start_epoch)?;
+    set_latest(e, latest)?;
+
+    Ok(())
+}
+
+/// Allowances from before we kept count have simply not counted yet.
+fn withdraw_count(e: &Sub) -> Result<u64, Error> {
+    match state(e)? {
+        Some(state) => Ok(state.withdraw_count),
+        None => Ok(read_opt(e, StorageKey::WithdrawCount)?.unwrap_or(0)),
+    }
+}
+
+fn set_withdraw_count(e: &Sub, count: u64) -> Result<(), Error> {
+    if !update_state(e, |state| state.withdraw_count = count)? {
+        e.storage().set(StorageKey::WithdrawCount, count);
+    }
+    Ok(())
+}
+
+/// Where the allowance schedule stands right now: when it started, the
+/// `Latest` withdraw, and what time it is on the schedule's clock (which
+/// stands still during a pause, and for good once the allowance has `ended`).
+/// This never writes anything, so views can use it, too.
+struct Schedule {
+    start: u64,
+    latest: u64,
+    now: u64,
+    paused: bool,
+    ended: bool,
+}
+
+fn schedule(e: &Sub) -> Result<Schedule, Error> {
+    // Nothing comes due after the allowance `EndsAt`.
+    let now = e.ledger().timestamp();
+    let (now, ended) = match read_opt::<u64>(e, StorageKey::EndsAt)? {
+        Some(end) => (now.min(end), now >= end),
+        None => (now, false),
+    };
+    let mut latest = latest(e)?;
+    let mut start = start_epoch(e, latest)?;
+    if let Some(req) = read_opt::<PauseRequest>(e, StorageKey::PauseReq)? {
+        if pause_in_force(e, &req, now)? {
+            if now >= req.until {
+                let length = req.until - req.from;
+                latest = latest.checked_add(length).ok_or(Error::Overflow)?;
+                start = start.checked_add(length).ok_or(Error::Overflow)?;
+            } else if now >= req.from {
+                let sched = Schedule {
+                    start,
+                    latest,
+                    now: req.from,
+                    paused: true,
+                    ended,
+                };
+                return lapse(e, anchor(e, sched)?);
+            }
+        }
+    }
+
+    let sched = Schedule {
+        start,
+        latest,
+        now,
+        paused: false,
+        ended,
+    };
+    lapse(e, anchor(e, sched)?)
+}
+
+/// With `anchor_on_first_withdraw`, until the first withdrawal is paid, the
+/// schedule starts whenever it's collected, so that's right now (once the
+/// `StartEpoch` has come). `record` makes it stick.
+fn anchor(e: &Sub, mut sched: Schedule) -> Result<Schedule, Error> {
+    if !options(e)?.anchor_on_first_withdraw || sched.latest >= sched.start {
+        return Ok(sched);
+    }
+    if sched.now > sched.start {
+        let step = step(e)?;
+        sched.start = sched.now;
+        sched.latest = sched.now.checked_sub(step).ok_or(Error::Overflow)?;
+    }
+    Ok(sched)
+}
+
+/// With `lapse_missed`, everything before the latest withdrawal that can be
+/// collected is as good as paid, so the schedule's `latest` moves up to just
+/// before it.
+fn lapse(e: &Sub, mut sched: Schedule) -> Result<Schedule, Error> {
+    let options = options(e)?;
+    if !options.lapse_missed {
+        return Ok(sched);
+    }
+
+    let cadence = Cadence::load(e)?;
+    let now = sched.now.saturating_add(options.tolerance);
+    let mut due = cadence.period_start(sched.start, now)?;
+    if options.bill_in_arrears && due > sched.start {
+        due = cadence.prev(due)?;
+    }
+    if due > sched.start {
+        sched.latest = sched.latest.max(cadence.prev(due)?);
+    }
+    Ok(sched)
+}
+
+/// A withdrawal should never be `0`. I mean, really. At that point, why even go
+/// through the trouble of setting this up? We use checked math here (and
+/// everywhere else we touch amounts or timestamps) so a silly argument comes
+/// back as `Error::Overflow` instead of a trap.
+fn check_amount(amount: i128, step: u64) -> Result<(), Error> {
+    if amount <= 0 {
+        return Err(Error::NonPositiveAmount);
+    }
+    let yearly = amount.checked_mul(step as i128).ok_or(Error::Overflow)?;
+    if yearly / SECONDS_IN_YEAR as i128 == 0 {
+        return Err(Error::NonPositiveAmount);
+    }
+    Ok(())
+}
+
+/// We do some really quick math to figure out what a single withdrawal of a
+/// `yearly` amount comes to: the amount split across the number of withdraws
+/// that can be made in a year, rounded down. The schedule invariant tests work
+/// their expectations out with this too.
+fn per_withdrawal(yearly: i128, step: u64) -> Result<i128, Error> {
+    let iterations = SECONDS_IN_YEAR.checked_div(step).ok_or(Error::Overflow)?;
+    yearly
+        .checked_div(iterations as i128)
+        .ok_or(Error::Overflow)
+}
+
+/// Works out which side of the allowance the invoker is on, refusing anybody
+/// who is neither the `Parent` nor the `Child`. The `Admin` counts as the
+/// `Parent`.
+fn party(e: &Sub) -> Result<(Identifier, Identifier, Identifier), Error> {
+    party_of(e, invoker_id(e))
+}
+
+/// The same as `party`, for `who` instead of the invoker.
+fn party_of(e: &Sub, who: Identifier) -> Result<(Identifier, Identifier, Identifier), Error> {
+    let parent = parent(e)?;
+    let child = child(e)?;
+    let invoker = match who {
+        invoker if manages(e, &invoker)? => parent.clone(),
+        invoker => invoker,
+    };
+    if invoker != parent && invoker != child {
+        return Err(Error::Unauthorized);
+    }
+    Ok((invoker, parent, child))
+}
+
+/// Whether `who` gets to manage the allowance, which is to say, they're the
+/// `Parent` or the `Parent`'s `Admin`.
+fn manages(e: &Sub, who: &Identifier) -> Result<bool, Error> {
+    if *who == parent(e)? {
+        return Ok(true);
+    }
+    let admin: Option<Identifier> = read_opt(e, StorageKey::Admin)?;
+    Ok(admin.as_ref() == Some(who))
+}
+
+/// Refuses anybody who doesn't get to manage the allowance.
+fn check_manager(e: &Sub) -> Result<(), Error> {
+    if !manages(e, &invoker_id(e))? {
+        return Err(Error::Unauthorized);
+    }
+    Ok(())
+}
+
+/// Who signed `sig`, as long as they get to manage the allowance on their
+/// own. (Whether the signature checks out is up to `authorize`.)
+fn signer(e: &Sub, sig: &Signature) -> Result<Identifier, Error> {
+    check_initialized(e)?;
+
+    let signer = sig.identifier(e);
+    if !manages(e, &signer)? {
+        return Err(Error::Unauthorized);
+    }
+    check_acting_alone(e, &signer)?;
+    Ok(signer)
+}
+
+/// How the schedule gets from one withdrawal to the next: every `step`
+/// seconds, or on the `anchor` day of every calendar month.
+#[derive(Clone, Copy)]
+struct Cadence {
+    step: u64,
+    anchor: u32,
+}
+
+impl Cadence {
+    fn load(e: &Sub) -> Result<Cadence, Error> {
+        Ok(Cadence {
+            step: step(e)?,
+            anchor: options(e)?.monthly_anchor,
+        })
+    }
+
+    /// When the withdrawal after the one at `due` comes due.
+    fn next(&self, due: u64) -> Result<u64, Error> {
+        if self.anchor == 0 {
+            return due.checked_add(self.step).ok_or(Error::Overflow);
+        }
+
+        let month = calendar::month_of(due);
+        let this = calendar::day_in_month(month, self.anchor).ok_or(Error::Overflow)?;
+        if this > due {
+            return Ok(this);
+        }
+        calendar::day_in_month(month + 1, self.anchor).ok_or(Error::Overflow)
+    }
+
+    /// When the withdrawal before the one at `due` came due.
+    fn prev(&self, due: u64) -> Result<u64, Error> {
+        if self.anchor == 0 {
+            return due.checked_sub(self.step).ok_or(Error::Overflow);
+        }
+
+        let month = calendar::month_of(due);
+        let this = calendar::day_in_month(month, self.anchor).ok_or(Error::Overflow)?;
+        if this < due {
+            return Ok(this);
+        }
+        let month = month.checked_sub(1).ok_or(Error::Overflow)?;
+        calendar::day_in_month(month, self.anchor).ok_or(Error::Overflow)
+    }
+
+    /// When the period `at` falls in started (or the `start`, if it hasn't
+    /// yet).
+    fn period_start(&self, start: u64, at: u64) -> Result<u64, Error> {
+        if at <= start {
+            return Ok(start);
+        }
+        if self.anchor == 0 {
+            return self
+                .index(start, at)
+                .checked_mul(self.step)
+                .and_then(|offset| start.checked_add(offset))
+                .ok_or(Error::Overflow);
+        }
+
+        let month = calendar::month_of(at);
+        let this = calendar::day_in_month(month, self.anchor).ok_or(Error::Overflow)?;
+        if this <= at {
+            return Ok(this);
+        }
+        self.prev(this)
+    }
+
+    /// Withdrawals are numbered from `0`, starting at the `StartEpoch`. Since
+    /// a pause moves the `StartEpoch` along with everything else, a withdrawal
+    /// keeps its number no matter what. `due` doesn't have to be a due time:
+    /// any moment gets the number of the period it falls in.
+    fn index(&self, start: u64, due: u64) -> u64 {
+        if self.anchor == 0 {
+            return due.saturating_sub(start) / self.step;
+        }
+
+        // Before the anchor day, we're still in last month's period.
+        let mut month = calendar::month_of(due);
+        if calendar::day_in_month(month, self.anchor).map_or(false, |anchor| due < anchor) {
+            month = month.saturating_sub(1);
+        }
+        month.saturating_sub(calendar::month_of(start))
+    }
+}
+
+/// Everything we need to know to put a price on a withdrawal, read from
+/// storage just once.
+struct Pricing {
+    amount: i128,
+    history: Vec<RateChange>,
+    start: u64,
+    cadence: Cadence,
+    escalator_bps: u32,
+    escalator_every: u32,
+    unit_price: i128,
+    included_units: u64,
+    trial_periods: u32,
+    discount_bps: u32,
+    discount_periods: u32,
+    streaming: bool,
+    join_offset: u64,
+    in_arrears: bool,
+    skipped: Vec<u64>,
+    late_fee_bps: u32,
+    tolerance: u64,
+}
+
+impl Pricing {
+    fn load(e: &Sub) -> Result<Pricing, Error> {
+        let options = options(e)?;
+        Ok(Pricing {
+            amount: amount(e)?,
+            history: rate_history(e)?,
+            start: schedule(e)?.start,
+            cadence: Cadence::load(e)?,
+            escalator_bps: options.escalator_bps,
+            escalator_every: options.escalator_every,
+            unit_price: options.unit_price,
+            included_units: options.included_units,
+            trial_periods: options.trial_periods,
+            discount_bps: options.discount_bps,
+            discount_periods: options.discount_periods,
+            streaming: options.streaming,
+            join_offset: read_opt(e, StorageKey::JoinOffset)?.unwrap_or(0),
+            in_arrears: options.bill_in_arrears,
+            skipped: read_opt(e, StorageKey::SkippedPeriods)?.unwrap_or_else(|| Vec::new(e)),
+            late_fee_bps: options.late_fee_bps,
+            tolerance: options.tolerance,
+        })
+    }
+
+    /// Compounds `amount` by the escalator once for every `escalator_every`
+    /// withdrawals that came due before the one at `due`, rounding down each
+    /// time so the `Parent` is never charged a fraction of a stroop extra.
+    /// An amount too small to go up by a whole stroop never will, so we stop
+    /// there.
+    fn escalate(&self, amount: i128, due: u64) -> Result<i128, Error> {
+        if self.escalator_bps == 0 {
+            return Ok(amount);
+        }
+
+        let period = self.cadence.index(self.start, due);
+        let escalations = period / self.escalator_every as u64;
+        let factor = BPS + self.escalator_bps as i128;
+        let mut amount = amount;
+        for n in 0..escalations {
+            if n == MAX_ESCALATIONS {
+                return Err(Error::TooManyEscalations);
+            }
+            let next = amount.checked_mul(factor).ok_or(Error::Overflow)? / BPS;
+            if next == amount {
+                break;
+            }
+            amount = next;
+        }
+        Ok(amount)
+    }
+
+    /// Takes the discount off `amount` if the withdrawal due at `due` is one of
+    /// the first `discount_periods` paid ones, rounding down.
+    fn discount(&self, amount: i128, due: u64) -> Result<i128, Error> {
+        let period = self.cadence.index(self.start, due);
+        let paid = period.saturating_sub(self.trial_periods as u64);
+        if self.discount_bps == 0 || paid >= self.discount_periods as u64 {
+            return Ok(amount);
+        }
+
+        let factor = BPS - self.discount_bps as i128;
+        Ok(amount.checked_mul(factor).ok_or(Error::Overflow)? / BPS)
+    }
+
+    /// If the `Child` joined `join_offset` seconds into the very first period,
+    /// that withdrawal only charges for the rest of it, which is
+    /// `amount * (length - join_offset) / length` (rounded down) for a period
+    /// `length` seconds long. Every later withdrawal is charged in full.
+    fn prorate(&self, amount: i128, due: u64) -> Result<i128, Error> {
+        if self.join_offset == 0 || self.cadence.index(self.start, due) != 0 {
+            return Ok(amount);
+        }
+
+        let length = self.cadence.next(self.start)? - self.start;
+        let left = length.saturating_sub(self.join_offset);
+        Ok(amount.checked_mul(left as i128).ok_or(Error::Overflow)? / length as i128)
+    }
+
+    /// When the `Child` can collect for the period that starts at `due`: right
+    /// away, or (billing in arrears) once the period is over, give or take
+    /// the `tolerance`.
+    fn claimable_at(&self, due: u64) -> Result<u64, Error> {
+        let at = if self.in_arrears {
+            self.cadence.next(due)?
+        } else {
+            due
+        };
+        Ok(at.saturating_sub(self.tolerance))
+    }
+
+    /// Whether the withdrawal due at `due` can be collected at `now`. This is
+    /// the one place that decides, for `withdraw` and every view alike, and
+    /// it's inclusive: a withdrawal can be collected at the very second it
+    /// becomes claimable, not only after.
+    fn is_claimable(&self, due: u64, now: u64) -> Result<bool, Error> {
+        Ok(self.claimable_at(due)? <= now)
+    }
+
+    /// The earliest moment `withdraw` will pay something, for a schedule at
+    /// `latest` that started at `start`. A stream pays as soon as a second has
+    /// gone by since it was last paid.
+    fn next_claim(&self, latest: u64, start: u64) -> Result<u64, Error> {
+        if self.streaming {
+            return latest.max(start).checked_add(1).ok_or(Error::Overflow);
+        }
+        self.claimable_at(self.cadence.next(latest)?)
+    }
+
+    /// Whether the withdrawal due at `due` falls in the free trial.
+    fn is_trial(&self, due: u64) -> bool {
+        self.cadence.index(self.start, due) < self.trial_periods as u64
+    }
+
+    /// What the usage reported for the withdrawal due at `due` costs on top of
+    /// the regular allowance.
+    fn overage(&self, e: &Sub, due: u64) -> Result<i128, Error> {
+        if self.unit_price == 0 {
+            return Ok(0);
+        }
+
+        let period = self.cadence.index(self.start, due);
+        let units: u64 = read_opt(e, StorageKey::Usage(period))?.unwrap_or(0);
+        let over = units.saturating_sub(self.included_units);
+        (over as i128)
+            .checked_mul(self.unit_price)
+            .ok_or(Error::Overflow)
+    }
+
+    /// Everything the withdrawal due at `due` costs: nothing at all during the
+    /// free trial or if both sides agreed to skip it, and otherwise the
+    /// regular amount plus any usage.
+    fn charge(&self, e: &Sub, due: u64) -> Result<i128, Error> {
+        if self.is_trial(due) || self.skipped.contains(self.cadence.index(self.start, due)) {
+            return Ok(0);
+        }
+        self.at(due)?
+            .checked_add(self.overage(e, due)?)
+            .ok_or(Error::Overflow)
+    }
+
+    /// The late fee on top of `charge` for the withdrawal due at `due`, if the
+    /// `Parent` couldn't pay it when a shortfall was reported and it's still
+    /// unpaid at `now`: `late_fee_bps` for every full `step` since, rounded
+    /// down, and never more than the `charge` itself.
+    fn late_fee(&self, e: &Sub, due: u64, charge: i128, now: u64) -> Result<i128, Error> {
+        if self.late_fee_bps == 0 {
+            return Ok(0);
+        }
+        let period = self.cadence.index(self.start, due);
+        let failed_at: u64 = match read_opt(e, StorageKey::FailedAt(period))? {
+            Some(failed_at) => failed_at,
+            None => return Ok(0),
+        };
+
+        let steps = now.saturating_sub(failed_at) / self.cadence.step;
+        let fee = charge
+            .checked_mul(self.late_fee_bps as i128)
+            .and_then(|fee| fee.checked_mul(steps as i128))
+            .ok_or(Error::Overflow)?
+            / BPS;
+        Ok(fee.min(charge))
+    }
+
+    /// The amount paid out for the withdrawal that came due at `due`, from the
+    /// yearly `Amount` in effect at that time.
+    fn at(&self, due: u64) -> Result<i128, Error> {
+        let mut amount = self.amount;
+        for change in self.history.iter() {
+            let change = change.map_err(|_| Error::CorruptData)?;
+            if due < change.effective_from {
+                break;
+            }
+            amount = change.amount;
+        }
+        let amount = per_withdrawal(self.escalate(amount, due)?, self.cadence.step)?;
+        self.prorate(self.discount(amount, due)?, due)
+    }
+}
+
+/// The rate changes that still matter to some unpaid withdrawal, oldest first.
+/// The `Amount` is the rate that was in effect before the first of them.
+fn rate_history(e: &Sub) -> Result<Vec<RateChange>, Error> {
+    Ok(read_opt(e, StorageKey::RateHistory)?.unwrap_or_else(|| Vec::new(e)))
+}
+
+/// The newest change in the history, if there is one.
+fn last_change(history: &Vec<RateChange>) -> Result<Option<RateChange>, Error> {
+    match history.last() {
+        Some(change) => Ok(Some(change.map_err(|_| Error::CorruptData)?)),
+        None => Ok(None),
+    }
+}
+
+/// The yearly `Amount` that has most recently been agreed on, whether or not
+/// it has taken effect yet.
+fn agreed_amount(e: &Sub) -> Result<i128, Error> {
+    match last_change(&rate_history(e)?)? {
+        Some(change) => Ok(change.amount),
+        None => amount(e),
+    }
+}
+
+/// The yearly `Amount` withdrawals coming due right now are paid at, leaving
+/// out any change that hasn't taken effect yet.
+fn amount_in_effect(e: &Sub) -> Result<i128, Error> {
+    let now = e.ledger().timestamp();
+    for change in rate_history(e)?.iter().rev() {
+        let change = change.map_err(|_| Error::CorruptData)?;
+        if change.effective_from <= now {
+            return Ok(change.amount);
+        }
+    }
+    amount(e)
+}
+
+/// Changes the yearly `Amount`, giving however much notice the contract was
+/// set up with, and publishes the old and new amounts. A change that hasn't
+/// taken effect yet is simply replaced, so the old amount is the one in
+/// effect now. Either way, the change goes on the end of the rate history
+/// rather than over the top of the `Amount`, so withdrawals that came due
+/// before it are still paid at the rate they came due at.
+fn set_amount(e: &Sub, amount: i128) -> Result<i128, Error> {
+    let now = e.ledger().timestamp();
+    let effective_from = now
+        .checked_add(options(e)?.amount_notice_period)
+        .ok_or(Error::Overflow)?;
+
+    let old = amount_in_effect(e)?;
+    let mut history = rate_history(e)?;
+    while let Some(change) = last_change(&history)? {
+        if change.effective_from <= now {
+            break;
+        }
+        history.pop_back();
+    }
+    if history.len() >= MAX_RATE_CHANGES {
+        return Err(Error::TooManyRateChanges);
+    }
+    history.push_back(RateChange {
+        effective_from,
+        amount,
+    });
+    e.storage().set(StorageKey::RateHistory, history);
+    e.storage().set(StorageKey::LastAmtChange, now);
+    e.events()
+        .publish((symbol!("amount"), e.id), (old, amount, effective_from));
+    Ok(old)
+}
+
+/// Makes sure a change to `amount` is within the limits `e`'s subscription
+/// was set up with: not too soon after the last one, and not too big a
+/// raise.
+fn check_amount_change(e: &Sub, amount: i128) -> Result<(), Error> {
+    let options = options(e)?;
+    if let Some(last) = read_opt::<u64>(e, StorageKey::LastAmtChange)? {
+        if e.ledger().timestamp() < last.saturating_add(options.amount_change_cooldown) {
+            return Err(Error::TooSoon);
+        }
+    }
+
+    if options.max_increase_bps != 0 {
+        let current = agreed_amount(e)?;
+        let most = (options.max_increase_bps as i128)
+            .checked_mul(current)
+            .and_then(|raise| current.checked_add(raise / BPS))
+            .ok_or(Error::Overflow)?;
+        if amount > most {
+            return Err(Error::RaiseTooLarge);
+        }
+    }
+    Ok(())
+}
+
+/// Once every withdrawal that came due before a rate change has been paid, the
+/// change simply becomes the `Amount` and drops out of the history.
+fn settle_amount(e: &Sub, next_due: u64) -> Result<(), Error> {
+    let mut history = rate_history(e)?;
+    if history.is_empty() {
+        return Ok(());
+    }
+
+    while let Some(change) = history.first() {
+        let change = change.map_err(|_| Error::CorruptData)?;
+        if next_due < change.effective_from {
+            break;
+        }
+        store_amount(e, change.amount)?;
+        history.pop_front();
+    }
+
+    if history.is_empty() {
+        e.storage().remove(StorageKey::RateHistory);
+    } else {
+        e.storage().set(StorageKey::RateHistory, history);
+    }
+    Ok(())
+}
+
+/// The regular withdrawals that are due right now: how many there are (and how
+/// many of those, at the front, are free trial ones), what each of them costs
+/// in the token (at the rate in effect when it came due) and what they add up
+/// to. Once they're paid, `Latest` moves on to `latest`, and `next_due` is the
+/// first withdrawal that hasn't been paid in full. Extras are never part of
+/// this: they have to wait their turn.
+struct Overdue {
+    periods: u32,
+    trial: u32,
+    charges: Vec<i128>,
+    total: i128,
+    latest: u64,
+    next_due: u64,
+}
+
+impl Overdue {
+    /// The same withdrawals, with every charge (rounded down) and the total
+    /// scaled by `to` over `from`.
+    fn in_proportion(self, from: i128, to: i128) -> Result<Overdue, Error> {
+        let mut charges = Vec::new(self.charges.env());
+        let mut total: i128 = 0;
+        for charge in self.charges.iter() {
+            let charge = charge
+                .map_err(|_| Error::CorruptData)?
+                .checked_mul(to)
+                .ok_or(Error::Overflow)?
+                .checked_div(from)
+                .ok_or(Error::Overflow)?;
+            total = total.checked_add(charge).ok_or(Error::Overflow)?;
+            charges.push_back(charge);
+        }
+        Ok(Overdue {
+            charges,
+            total,
+            ..self
+        })
+    }
+
+    /// The same withdrawals, as they arrived when that came to `received` in
+    /// all: every charge scaled to match (rounded down), and whatever the
+    /// rounding leaves over added to the last one.
+    fn as_received(&self, received: i128) -> Result<Overdue, Error> {
+        let mut charges = Vec::new(self.charges.env());
+        let mut left = received;
+        for charge in self.charges.iter() {
+            let charge = charge
+                .map_err(|_| Error::CorruptData)?
+                .checked_mul(received)
+                .ok_or(Error::Overflow)?
+                .checked_div(self.total)
+                .ok_or(Error::Overflow)?;
+            left -= charge;
+            charges.push_back(charge);
+        }
+        if let Some(last) = charges.pop_back() {
+            charges.push_back(last.map_err(|_| Error::CorruptData)? + left);
+        }
+        Ok(Overdue {
+            periods: self.periods,
+            trial: self.trial,
+            charges,
+            total: received,
+            latest: self.latest,
+            next_due: self.next_due,
+        })
+    }
+}
+
+/// No more than `max_periods` withdrawals are counted (for a stream, that's
+/// how many periods it's paid across).
+fn overdue(e: &Sub, sched: &Schedule, max_periods: u32) -> Result<Overdue, Error> {
+    let pricing = Pricing::load(e)?;
+    let mut owed = if pricing.streaming {
+        streamed(e, sched, &pricing, max_periods)?
+    } else {
+        stepped(e, sched, &pricing, max_periods)?
+    };
+
+    // For an allowance set in a reference asset, everything so far is in that
+    // asset, so we convert each withdrawal into the token at today's price.
+    if owed.total > 0 {
+        if let Some(price) = oracle_price(e)? {
+            let mut charges = Vec::new(e);
+            let mut total: i128 = 0;
+            for charge in owed.charges.iter() {
+                let charge = price.convert(charge.map_err(|_| Error::CorruptData)?)?;
+                charges.push_back(charge);
+                total = total.checked_add(charge).ok_or(Error::Overflow)?;
+            }
+            owed.charges = charges;
+            owed.total = total;
+        }
+    }
+    Ok(owed)
+}
+
+/// Every withdrawal that has come due since the `Latest` one, each one paid in
+/// full.
+fn stepped(
+    e: &Sub,
+    sched: &Schedule,
+    pricing: &Pricing,
+    max_periods: u32,
+) -> Result<Overdue, Error> {
+    let mut owed = Overdue {
+        periods: 0,
+        trial: 0,
+        charges: Vec::new(e),
+        total: 0,
+        latest: sched.latest,
+        next_due: pricing.cadence.next(sched.latest)?,
+    };
+    while owed.periods < max_periods && pricing.is_claimable(owed.next_due, sched.now)? {
+        let due = owed.next_due;
+        if pricing.is_trial(due) {
+            owed.trial = owed.trial.checked_add(1).ok_or(Error::Overflow)?;
+        }
+        let charge = pricing.charge(e, due)?;
+        let charge = charge
+            .checked_add(pricing.late_fee(e, due, charge, sched.now)?)
+            .ok_or(Error::Overflow)?;
+        owed.charges.push_back(charge);
+        owed.total = owed.total.checked_add(charge).ok_or(Error::Overflow)?;
+        owed.periods = owed.periods.checked_add(1).ok_or(Error::Overflow)?;
+        owed.latest = due;
+        owed.next_due = pricing.cadence.next(due)?;
+    }
+    Ok(owed)
+}
+
+/// In streaming mode, each withdrawal's amount builds up second by second over
+/// its `step`, and `Latest` is simply the moment the `Child` has been paid up
+/// to. We always work a period's share out from the start of that period, so
+/// rounding down never loses a stroop: it's just paid a little later, and a
+/// whole period streams exactly what a single withdrawal would have paid.
+/// (Streams only ever run on a fixed `step`, never on the calendar.)
+fn streamed(
+    e: &Sub,
+    sched: &Schedule,
+    pricing: &Pricing,
+    max_periods: u32,
+) -> Result<Overdue, Error> {
+    // `Latest` starts out one `step` before the start, same as always, but
+    // nothing streams before the start.
+    let step = pricing.cadence.step;
+    let from = sched.latest.max(sched.start);
+    let share = |charge: i128, seconds: u64| -> Result<i128, Error> {
+        Ok(charge.checked_mul(seconds as i128).ok_or(Error::Overflow)? / step as i128)
+    };
+
+    let period_start = |t: u64| -> Result<u64, Error> {
+        let period = pricing.cadence.index(sched.start, t);
+        period
+            .checked_mul(step)
+            .and_then(|offset| sched.start.checked_add(offset))
+            .ok_or(Error::Overflow)
+    };
+
+    let mut total: i128 = 0;
+    let mut t = from;
+    let mut chunks: u32 = 0;
+    while chunks < max_periods && t < sched.now {
+        let due = period_start(t)?;
+        let end = due.checked_add(step).ok_or(Error::Overflow)?.min(sched.now);
+        let charge = pricing.charge(e, due)?;
+        total = total
+            .checked_add(share(charge, end - due)? - share(charge, t - due)?)
+            .ok_or(Error::Overflow)?;
+        t = end;
+        chunks += 1;
+    }
+
+    let mut charges = Vec::new(e);
+    charges.push_back(total);
+    Ok(Overdue {
+        periods: if t > from { 1 } else { 0 },
+        trial: 0,
+        charges,
+        total,
+        latest: t,
+        next_due: period_start(t)?,
+    })
+}
+
+/// For an allowance set in a reference asset, asks the oracle for the token's
+/// latest price. A price that is too old (or that makes no sense) is never
+/// used. Every other allowance is already in the token, and has no price.
+fn oracle_price(e: &Sub) -> Result<Option<PriceData>, Error> {
+    let oracle: Oracle = match read_opt(e, StorageKey::Oracle)? {
+        Some(oracle) => oracle,
+        None => return Ok(None),
+    };
+
+    let token_id = token_id(e)?;
+    let data: PriceData = e.invoke_contract(
+        &oracle.contract_id,
+        &symbol!("lastprice"),
+        vec![e, token_id.into_val(&e.env)],
+    );
+    let age = e.ledger().timestamp().saturating_sub(data.timestamp);
+    if age > oracle.max_age || data.price <= 0 {
+        return Err(Error::StalePrice);
+    }
+    Ok(Some(data))
+}
+
+impl PriceData {
+    /// Turns `amount` of the reference asset into the token, rounding down.
+    fn convert(&self, amount: i128) -> Result<i128, Error> {
+        let scale = 10i128.checked_pow(self.decimals).ok_or(Error::Overflow)?;
+        amount
+            .checked_mul(scale)
+            .ok_or(Error::Overflow)?
+            .checked_div(self.price)
+            .ok_or(Error::Overflow)
+    }
+}
+
+/// The total of all the regular withdrawals that are due right now.
+fn due_amount(e: &Sub) -> Result<i128, Error> {
+    let sched = schedule(e)?;
+    Ok(within_cap(e, &sched, overdue(e, &sched, u32::MAX)?)?.total)
+}
+
+/// The total of all the regular withdrawals that are still to be paid before
+/// `e`'s subscription ends, as if the `Child` collects every one of them. A
+/// pause that's been approved has its length taken off the end.
+fn outstanding_obligation(e: &Sub) -> Result<i128, Error> {
+    let end = match (read_opt::<u64>(e, StorageKey::EndsAt)?, remaining_cap(e)?) {
+        (Some(end), _) => end,
+        (None, Some(left)) => return Ok(left),
+        (None, None) => return Err(Error::Unbounded),
+    };
+
+    let mut sched = schedule(e)?;
+    if let Some(req) = read_opt::<PauseRequest>(e, StorageKey::PauseReq)? {
+        if pause_in_force(e, &req, end)? && sched.now < req.until {
+            let length = req.until - req.from;
+            sched.latest = sched.latest.checked_add(length).ok_or(Error::Overflow)?;
+            sched.start = sched.start.checked_add(length).ok_or(Error::Overflow)?;
+        }
+    }
+    sched.now = end;
+    sched.paused = false;
+    Ok(within_cap(e, &sched, overdue(e, &sched, u32::MAX)?)?.total)
+}
+
+/// How much more the regular withdrawals can add up to, if there's a
+/// `lifetime_cap`.
+fn remaining_cap(e: &Sub) -> Result<Option<i128>, Error> {
+    let cap = options(e)?.lifetime_cap;
+    if cap == 0 {
+        return Ok(None);
+    }
+    let spent: i128 = read_opt(e, StorageKey::Spent)?.unwrap_or(0);
+    Ok(Some(cap.saturating_sub(spent).max(0)))
+}
+
+/// `owed`, cut down to what's left under the `lifetime_cap`: every withdrawal
+/// that fits, and as much of the one that goes over as there's room for.
+fn within_cap(e: &Sub, sched: &Schedule, owed: Overdue) -> Result<Overdue, Error> {
+    let mut left = match remaining_cap(e)? {
+        Some(left) if owed.total > left => left,
+        _ => return Ok(owed),
+    };
+
+    let cadence = Cadence::load(e)?;
+    let mut capped = Overdue {
+        periods: 0,
+        trial: 0,
+        charges: Vec::new(e),
+        total: 0,
+        latest: sched.latest,
+        next_due: cadence.next(sched.latest)?,
+    };
+    for charge in owed.charges.iter() {
+        if left == 0 {
+            break;
+        }
+        let charge = charge.map_err(|_| Error::CorruptData)?.min(left);
+        left -= charge;
+        capped.charges.push_back(charge);
+        capped.total += charge;
+        capped.periods += 1;
+        capped.latest = capped.next_due;
+        capped.next_due = cadence.next(capped.latest)?;
+    }
+    capped.trial = owed.trial.min(capped.periods);
+    Ok(capped)
+}
+
+/// Everything that comes due from the very first withdrawal up to (but not
+/// including) `until`, whether it's been paid already or not. It goes through
+/// `overdue`, so it's priced just like a withdrawal is.
+fn due_before(e: &Sub, until: u64) -> Result<i128, Error> {
+    // A stream is paid up to `now`, but a withdrawal due at `now` is already
+    // claimable there, so we stop a second short.
+    let now = if options(e)?.streaming {
+        until
+    } else {
+        match until.checked_sub(1) {
+            Some(now) => now,
+            None => return Ok(0),
+        }
+    };
+
+    let now = match read_opt::<u64>(e, StorageKey::EndsAt)? {
+        Some(end) => now.min(end),
+        None => now,
+    };
+
+    let start = start_epoch(e, latest(e)?)?;
+    let sched = Schedule {
+        start,
+        latest: Cadence::load(e)?.prev(start)?,
+        now,
+        paused: false,
+        ended: false,
+    };
+    Ok(overdue(e, &sched, u32::MAX)?.total)
+}
+
+/// Everything `withdraw` checks before it pays out, in one place, so that
+/// `can_withdraw` can never disagree with it. Nothing after `cutoff` is
+/// counted, nor more than `max_periods` withdrawals, and this doesn't write
+/// anything.
+fn withdrawable(
+    e: &Sub,
+    client: &token::Client,
+    yearly: Option<i128>,
+    cutoff: u64,
+    max_periods: u32,
+) -> Result<(Schedule, Overdue), Error> {
+    // Some more quick math to work out every withdrawal that has come due
+    // since the `Latest` one. A new one only comes along every `step` seconds.
+    // We don't want them draining the piggy bank all at once, after all. (In
+    // streaming mode, it's whatever has built up since.)
+    if e.storage().has(StorageKey::Suspended) {
+        return Err(Error::Suspended);
+    }
+
+    let mut sched = schedule(e)?;
+    sched.now = sched.now.min(cutoff);
+    let max_periods = match read_opt::<Sponsorship>(e, StorageKey::Sponsorship)? {
+        Some(sponsorship) => max_periods.min(sponsorship.periods),
+        None => max_periods,
+    };
+    let max_periods = match read_opt::<Assignment>(e, StorageKey::Assignment)? {
+        Some(assignment) => max_periods.min(assignment.periods),
+        None => max_periods,
+    };
+    if remaining_cap(e)? == Some(0) {
+        return Err(Error::SubscriptionExpired);
+    }
+    let owed = within_cap(e, &sched, overdue(e, &sched, max_periods)?)?;
+    if owed.periods == 0 {
+        if sched.ended {
+            return Err(Error::SubscriptionCancelled);
+        }
+        if sched.paused {
+            return Err(Error::Paused);
+        }
+        if sched.latest >= sched.start {
+            return Err(Error::ChildAlreadyWithdrawn);
+        }
+        return Err(Error::NothingDue);
+    }
+
+    // Paid in another token, every withdrawal costs the same fraction of its
+    // usual price.
+    let owed = match yearly {
+        Some(yearly) => owed.in_proportion(amount(e)?, yearly)?,
+        None => owed,
+    };
+
+    // Rather than letting the token contract trap on us, we check up front
+    // that the `Parent` (or failing that, the guarantor) has left us enough
+    // allowance to cover everything that's due, and say so clearly if they
+    // haven't.
+    check_funded(e, client, &owed)?;
+
+    Ok((sched, owed))
+}
+
+/// Makes sure whoever pays for `owed` has left us enough allowance to, and
+/// has the money to back it.
+fn check_funded(e: &Sub, client: &token::Client, owed: &Overdue) -> Result<(), Error> {
+    if let Some(sponsorship) = read_opt::<Sponsorship>(e, StorageKey::Sponsorship)? {
+        return covers(e, client, &sponsorship.sponsor, owed.total);
+    }
+    match read_opt::<Vec<(Identifier, u32)>>(e, StorageKey::Payers)? {
+        Some(payers) => {
+            for part in payer_parts(e, &payers, owed)?.iter() {
+                let (payer, net, fee) = part.map_err(|_| Error::CorruptData)?;
+                covers(e, client, &payer, net + fee)?;
+            }
+            Ok(())
+        }
+        None => payer(e, client, owed.total).map(|_| ()),
+    }
+}
+
+/// Whether `who` can pay `total` through us: an allowance alone isn't enough,
+/// since the token traps on a transfer the balance can't cover.
+fn covers(e: &Sub, client: &token::Client, who: &Identifier, total: i128) -> Result<(), Error> {
+    let contract = Identifier::Contract(e.current_contract());
+    if client.allowance(who, &contract) < total {
+        return Err(Error::InsufficientAllowance);
+    }
+    if client.balance(who) < total {
+        return Err(Error::InsufficientBalance);
+    }
+    Ok(())
+}
+
+/// Divides `amount` between `shares`, with the remainder going to the first.
+fn portions(e: &Sub, shares: &Vec<(Identifier, u32)>, amount: i128) -> Result<Vec<i128>, Error> {
+    let mut parts = Vec::new(e);
+    let mut left = amount;
+    for entry in shares.iter() {
+        let (_, share) = entry.map_err(|_| Error::CorruptData)?;
+        let part = amount.checked_mul(share as i128).ok_or(Error::Overflow)? / BPS;
+        left -= part;
+        parts.push_back(part);
+    }
+    if let Some(first) = parts.get(0) {
+        parts.set(0, first.map_err(|_| Error::CorruptData)? + left);
+    }
+    Ok(parts)
+}
+
+/// What each of the `payers` pays towards `owed`: their part of what goes to
+/// the `Child`, and of the fee.
+fn payer_parts(
+    e: &Sub,
+    payers: &Vec<(Identifier, u32)>,
+    owed: &Overdue,
+) -> Result<Vec<(Identifier, i128, i128)>, Error> {
+    let fee = fee_total(&options(e)?, &owed.charges)?;
+    let nets = portions(e, payers, owed.total - fee)?;
+    let fees = portions(e, payers, fee)?;
+    let mut parts = Vec::new(e);
+    for (i, entry) in payers.iter().enumerate() {
+        let (payer, _) = entry.map_err(|_| Error::CorruptData)?;
+        let i = i as u32;
+        parts.push_back((
+            payer,
+            nets.get(i)
+                .ok_or(Error::CorruptData)?
+                .map_err(|_| Error::CorruptData)?,
+            fees.get(i)
+                .ok_or(Error::CorruptData)?
+                .map_err(|_| Error::CorruptData)?,
+        ));
+    }
+    Ok(parts)
+}
+
+/// Who pays `total`: the `Parent`, unless they can't cover it and the
+/// guarantor can.
+fn payer(e: &Sub, client: &token::Client, total: i128) -> Result<Identifier, Error> {
+    let parent = parent(e)?;
+    let short = match covers(e, client, &parent, total) {
+        Ok(()) => return Ok(parent),
+        Err(err) => err,
+    };
+    if let Some(guarantor) = options(e)?.guarantor {
+        if covers(e, client, &guarantor, total).is_ok() {
+            return Ok(guarantor);
+        }
+    }
+    Err(short)
+}
+
+/// When the next `withdraw` will go through. If a pause is going to stop the
+/// clock before then, everything is pushed back by its length, just like it
+/// will be once the pause is over.
+fn next_withdraw_time(e: &Sub) -> Result<u64, Error> {
+    let sched = schedule(e)?;
+    if sched.ended {
+        return Err(Error::SubscriptionCancelled);
+    }
+    let pricing = Pricing::load(e)?;
+    let at = pricing.next_claim(sched.latest, sched.start)?;
+
+    // A pause that's already over has been taken care of by `schedule`.
+    let req: PauseRequest = match read_opt(e, StorageKey::PauseReq)? {
+        Some(req) => req,
+        None => return Ok(at),
+    };
+    let will_pause = req.approved || options(e)?.auto_approve_child_pause;
+    if !will_pause || e.ledger().timestamp() >= req.until || at <= req.from {
+        return Ok(at);
+    }
+
+    let length = req.until - req.from;
+    pricing.next_claim(
+        sched.latest.checked_add(length).ok_or(Error::Overflow)?,
+        sched.start.checked_add(length).ok_or(Error::Overflow)?,
+    )
+}
+
+/// Whether more than `periods` withdrawals beyond the first one due are
+/// unpaid, as if they were being paid one at a time.
+fn behind_by_more_than(sched: &Schedule, pricing: &Pricing, periods: u32) -> Result<bool, Error> {
+    if pricing.streaming {
+        let from = sched.latest.max(sched.start);
+        let allowed = pricing.cadence.step.saturating_mul(u64::from(periods) + 1);
+        return Ok(sched.now.saturating_sub(from) > allowed);
+    }
+
+    let mut due = pricing.cadence.next(pricing.cadence.next(sched.latest)?)?;
+    for _ in 0..periods {
+        due = pricing.cadence.next(due)?;
+    }
+    pricing.is_claimable(due, sched.now)
+}
+
+/// How much this contract is still allowed to spend from the `Parent`.
+fn parent_allowance(e: &Sub, client: &token::Client) -> Result<i128, Error> {
+    Ok(client.allowance(&parent(e)?, &Identifier::Contract(e.current_contract())))
+}
+
+/// Returns the pause request that is still waiting on the `Parent`, if any.
+/// The `Child` of `e`'s subscription asks for a pause.
+fn request_pause(e: &Sub, from: u64, until: u64) -> Result<(), Error> {
+    check_initialized(e)?;
+
+    // Only the `Child` gets to ask for a pause. It's their allowance!
+    if invoker_id(e) != child(e)? {
+        return Err(Error::Unauthorized);
+    }
+
+    // The pause has to actually last a while, and it can't start in the
+    // past, since the `Parent` needs a chance to weigh in before `from`.
+    let now = e.ledger().timestamp();
+    if from < now || until <= from {
+        return Err(Error::InvalidArguments);
+    }
+
+    // One pause at a time, please. A request that has lapsed, or a pause
+    // that is fully over, is cleaned up here and doesn't get in the way.
+    settle_pause(e, now)?;
+    if e.storage().has(StorageKey::PauseReq) {
+        return Err(Error::InvalidArguments);
+    }
+
+    e.storage().set(
+        StorageKey::PauseReq,
+        PauseRequest {
+            from,
+            until,
+            approved: false,
+        },
+    );
+
+    Ok(())
+}
+
+fn approve_pause(e: &Sub) -> Result<(), Error> {
+    check_initialized(e)?;
+    check_manager(e)?;
+
+    let mut req = pending_pause(e, e.ledger().timestamp())?;
+    req.approved = true;
+    e.storage().set(StorageKey::PauseReq, req);
+    report(e, Status::Paused)
+}
+
+fn reject_pause(e: &Sub) -> Result<(), Error> {
+    check_initialized(e)?;
+    check_manager(e)?;
+
+    pending_pause(e, e.ledger().timestamp())?;
+    e.storage().remove(StorageKey::PauseReq);
+
+    Ok(())
+}
+
+fn pending_pause(e: &Sub, now: u64) -> Result<PauseRequest, Error> {
+    match read_opt::<PauseRequest>(e, StorageKey::PauseReq)? {
+        Some(req) if !req.approved && now < req.from => Ok(req),
+        _ => Err(Error::NoPauseRequest),
+    }
+}
+
+/// Whether `invoker` is on the `Parent`'s list of who may invoke `withdraw`.
+/// Without a list, anybody may.
+fn may_invoke(e: &Sub, invoker: &Identifier) -> Result<bool, Error> {
+    let invokers: Vec<AccountId> = match read_opt(e, StorageKey::Invokers)? {
+        Some(invokers) => invokers,
+        None => return Ok(true),
+    };
+    for account in invokers.iter() {
+        let account = account.map_err(|_| Error::CorruptData)?;
+        if *invoker == Identifier::Account(account) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Does the actual work of `withdraw`, paying out everything that came due up
+/// to `cutoff` (or up to now, if that's sooner), but no more than `max_periods`
+/// withdrawals' worth.
+fn pay_out(e: &Sub, cutoff: u64, max_periods: u32) -> Result<u32, Error> {
+    pay_out_in(e, None, cutoff, max_periods)
+}
+
+/// The same as `pay_out`, paying in the `choice` of token (see `pay_in`).
+fn pay_out_in(
+    e: &Sub,
+    choice: Option<BytesN<32>>,
+    cutoff: u64,
+    max_periods: u32,
+) -> Result<u32, Error> {
+    // Conversely from `init`, we want to make sure the contract *has* been
+    // initialized before a withdraw can be made.
+    check_initialized(e)?;
+    let options = options(e)?;
+    check_withdrawer(e, &options)?;
+
+    collect(e, &options, choice, cutoff, max_periods)
+}
+
+/// Makes sure whoever invoked us may have `e`'s subscription pay out.
+fn check_withdrawer(e: &Sub, options: &Options) -> Result<(), Error> {
+    // This is a simple check to ensure the `withdraw` function has not been
+    // invoked by a contract. For our purposes, it *must* be invoked by a
+    // user account, unless the contract was set up to allow contracts.
+    if !options.allow_contract_invokers {
+        match e.invoker() {
+            Address::Account(id) => id,
+            _ => return Err(Error::InvalidInvoker),
+        };
+    }
+
+    // This part is one of the contract's really nifty tricks. You may have
+    // noticed we haven't authenticated the invocation of `withdraw` at all.
+    // That's on purpose! By storing the `Child` in our contract data, we
+    // can ensure they are *always* the beneficiary of the withdrawal. No
+    // matter who actually makes the call to the contract, the child is
+    // always taken care of.
+    let child = child(e)?;
+    // Note: Technically speaking, *anybody* could invoke the `withdraw`
+    // function in the contract (yes, even your cousin Josh). In practice,
+    // for today's quest, the function **must** be invoked by either the
+    // `Parent` or the `Child`.
+
+    // That is, unless the `Parent` has made a list of who may invoke it. The
+    // `Child` is always welcome to collect their own allowance, though.
+    let invoker = invoker_id(e);
+    if invoker != child && !may_invoke(e, &invoker)? {
+        return Err(Error::Unauthorized);
+    }
+    Ok(())
+}
+
+/// Pays out what's due from `e`'s subscription just like `pay_out_in`, only
+/// without asking who invoked us. That's for settling up on the way to
+/// something else the invoker has already been checked for, like `fix_step`.
+fn collect(
+    e: &Sub,
+    options: &Options,
+    choice: Option<BytesN<32>>,
+    cutoff: u64,
+    max_periods: u32,
+) -> Result<u32, Error> {
+    // We create a client to the token contract that we'll be able to use to
+    // make the transfer later on. This should look familiar to Quest 4.
+    let (token_id, yearly) = pay_in(e, options, choice)?;
+    let client = token::Client::new(e, &token_id);
+
+    // If a pause has come and gone, we first bring `Latest` up to date. If
+    // we're in the middle of one, the schedule is frozen at its `from`
+    // time, so anything that came due before the pause can still be
+    // collected, but nothing new.
+    settle_pause(e, e.ledger().timestamp())?;
+
+    // There are a few flavours of "nothing to pay": we're in a pause, the
+    // `Child` already collected for the current period, or the schedule
+    // simply hasn't reached the next withdrawal yet (say, it hasn't
+    // started). In lenient mode, none of those are errors.
+    let (sched, owed) = match withdrawable(e, &client, yearly, cutoff, max_periods) {
+        Err(Error::Paused | Error::ChildAlreadyWithdrawn | Error::NothingDue)
+            if options.lenient =>
+        {
+            return Ok(0)
+        }
+        result => result?,
+    };
+
+    // The `Parent`'s spend policy (if they have one) gets to say no, and if
+    // it can't be asked, that's a no, too.
+    let cadence = Cadence::load(e)?;
+    let first = cadence.index(sched.start, cadence.next(sched.latest)?);
+    if let Some(policy) = read_opt::<BytesN<32>>(e, StorageKey::SpendPolicy)? {
+        let args = vec![e, owed.total.into_val(&e.env), first.into_val(&e.env)];
+        let result =
+            e.try_invoke_contract::<bool, soroban_sdk::Status>(&policy, &symbol!("approve"), args);
+        if !matches!(result, Ok(Ok(true))) {
+            return Err(Error::PolicyRejected);
+        }
+    }
+
+    // Every withdrawal this pays is paid in the one token, which gets an
+    // event of its own unless it's the usual one.
+    if yearly.is_some() {
+        e.events()
+            .publish((symbol!("paid_in"), e.id, first), (token_id, owed.periods));
+    }
+    let periods = pay(e, &client, &sched, &owed, symbol!("withdraw"))?;
+    if sched.ended {
+        report(e, Status::Cancelled)?;
+    }
+    Ok(periods)
+}
+
+/// Which token to pay `e`'s subscription in, and what the yearly `Amount`
+/// comes to in it, if it's not the allowance's own. That's the `choice`
+/// made for this withdrawal, or else the `Child`'s preferred token.
+fn pay_in(
+    e: &Sub,
+    options: &Options,
+    choice: Option<BytesN<32>>,
+) -> Result<(BytesN<32>, Option<i128>), Error> {
+    let own = token_id(e)?;
+    let choice = match choice {
+        Some(choice) => choice,
+        None => match read_opt(e, StorageKey::PreferredToken)? {
+            Some(preferred) => preferred,
+            None => return Ok((own, None)),
+        },
+    };
+    if choice == own {
+        return Ok((own, None));
+    }
+    let yearly = options
+        .accepted_tokens
+        .as_ref()
+        .and_then(|tokens| tokens.get(choice.clone()))
+        .ok_or(Error::InvalidArguments)?
+        .map_err(|_| Error::CorruptData)?;
+    Ok((choice, Some(yearly)))
+}
+
+/// Who the `Child`'s withdrawals go to: the `Child`, unless they've assigned
+/// them to somebody else.
+fn payee(e: &Sub) -> Result<Identifier, Error> {
+    match read_opt::<Assignment>(e, StorageKey::Assignment)? {
+        Some(assignment) => Ok(assignment.assignee),
+        None => child(e),
+    }
+}
+
+/// The platform's cut of a single `charge`, rounded down.
+fn fee_on(options: &Options, charge: i128) -> Result<i128, Error> {
+    Ok(charge
+        .checked_mul(options.fee_bps as i128)
+        .ok_or(Error::Overflow)?
+        / BPS)
+}
+
+/// The platform's cut of all of `charges`, each rounded down on its own.
+fn fee_total(options: &Options, charges: &Vec<i128>) -> Result<i128, Error> {
+    let mut total: i128 = 0;
+    for charge in charges.iter() {
+        let fee = fee_on(options, charge.map_err(|_| Error::CorruptData)?)?;
+        total = total.checked_add(fee).ok_or(Error::Overflow)?;
+    }
+    Ok(total)
+}
+
+fn publish_fee(e: &Sub, period: u64, fee: i128) {
+    if fee > 0 {
+        e.events().publish((symbol!("fee"), e.id, period), fee);
+    }
+}
+
+/// The shares have to add up to everything, exactly, and there can't be too
+/// many of them to pay out in one go.
+fn check_shares(shares: &Vec<(Identifier, u32)>) -> Result<(), Error> {
+    if shares.is_empty() || shares.len() > MAX_RECIPIENTS {
+        return Err(Error::InvalidArguments);
+    }
+    let mut total: u32 = 0;
+    for recipient in shares.iter() {
+        let (_, share) = recipient.map_err(|_| Error::InvalidArguments)?;
+        if share == 0 {
+            return Err(Error::InvalidArguments);
+        }
+        total = total.saturating_add(share);
+    }
+    if total as i128 != BPS {
+        return Err(Error::InvalidArguments);
+    }
+    Ok(())
+}
+
+/// A reference much longer than an id is just storage somebody has to pay
+/// for.
+fn check_metadata(metadata: &Metadata) -> Result<(), Error> {
+    if metadata.reference.len() > MAX_REFERENCE {
+        return Err(Error::InvalidArguments);
+    }
+    Ok(())
+}
+
+/// Nobody can sign twice, and it has to be possible to reach the threshold
+/// at all.
+fn check_signers(signers: &Signers) -> Result<(), Error> {
+    let count = signers.accounts.len();
+    if count > MAX_SIGNERS || signers.threshold == 0 || signers.threshold > count {
+        return Err(Error::InvalidArguments);
+    }
+    if signers.approval_window == 0 {
+        return Err(Error::InvalidArguments);
+    }
+    for (i, account) in signers.accounts.iter().enumerate() {
+        let account = account.map_err(|_| Error::InvalidArguments)?;
+        if signers.accounts.first_index_of(&account) != Some(i as u32) {
+            return Err(Error::InvalidArguments);
+        }
+    }
+    Ok(())
+}
+
+/// Refuses `who` changing the terms on their own, when it's up to the
+/// `Signers` (see `approve_action`).
+fn check_acting_alone(e: &Sub, who: &Identifier) -> Result<(), Error> {
+    if options(e)?.signers.is_some() && manages(e, who)? {
+        return Err(Error::ApprovalRequired);
+    }
+    Ok(())
+}
+
+/// `who`'s share of every withdrawal, in basis points.
+fn share_of(shares: &Vec<(Identifier, u32)>, who: &Identifier) -> Result<u32, Error> {
+    for recipient in shares.iter() {
+        let (id, share) = recipient.map_err(|_| Error::CorruptData)?;
+        if id == *who {
+            return Ok(share);
+        }
+    }
+    Ok(0)
+}
+
+fn current_shares(e: &Sub) -> Result<Vec<(Identifier, u32)>, Error> {
+    match read_opt(e, StorageKey::Recipients)? {
+        Some(recipients) => Ok(recipients),
+        None => Ok(Vec::from_array(e, [(child(e)?, BPS as u32)])),
+    }
+}
+
+fn apply_shares(e: &Sub, shares: Vec<(Identifier, u32)>) {
+    e.storage().set(StorageKey::Recipients, shares.clone());
+    e.storage().remove(StorageKey::SharesProposal);
+    e.events().publish((symbol!("shares"), e.id), shares);
+}
+
+/// How much each of the `recipients` gets of `charges`, once the fee is taken
+/// out. Every withdrawal is split on its own, with the remainder going to the
+/// first recipient, so the totals add up to exactly what was charged.
+fn split(
+    e: &Sub,
+    options: &Options,
+    charges: &Vec<i128>,
+) -> Result<Vec<(Identifier, i128)>, Error> {
+    let recipients: Vec<(Identifier, u32)> = read(e, StorageKey::Recipients)?;
+    let mut totals: Vec<i128> = Vec::new(e);
+    for _ in 0..recipients.len() {
+        totals.push_back(0 as i128);
+    }
+    for charge in charges.iter() {
+        let charge = charge.map_err(|_| Error::CorruptData)?;
+        let net = charge - fee_on(options, charge)?;
+        let mut left = net;
+        for (i, recipient) in recipients.iter().enumerate() {
+            let (_, share) = recipient.map_err(|_| Error::CorruptData)?;
+            let part = net.checked_mul(share as i128).ok_or(Error::Overflow)? / BPS;
+            left -= part;
+            let total = totals
+                .get(i as u32)
+                .ok_or(Error::CorruptData)?
+                .map_err(|_| Error::CorruptData)?;
+            totals.set(i as u32, total + part);
+        }
+        let first = totals
+            .get(0)
+            .ok_or(Error::CorruptData)?
+            .map_err(|_| Error::CorruptData)?;
+        totals.set(0, first + left);
+    }
+
+    let mut split = Vec::new(e);
+    for (recipient, total) in recipients.iter().zip(totals.iter()) {
+        let (recipient, _) = recipient.map_err(|_| Error::CorruptData)?;
+        split.push_back((recipient, total.map_err(|_| Error::CorruptData)?));
+    }
+    Ok(split)
+}
+
+/// Pays out what `withdrawable` (or `prepay`) worked out, and brings the
+/// schedule up to date. The event for each withdrawal is named `label`.
+fn pay(
+    e: &Sub,
+    client: &token::Client,
+    sched: &Schedule,
+    owed: &Overdue,
+    label: Symbol,
+) -> Result<u32, Error> {
+    // This is where the magic happens! We use the client we set up for our
+    // token contract earlier to invoke the `xfer_from` function (see
+    // `deliver`). We're using *this contract's* approval to spend the asset
+    // balance of the `Parent` account to transfer funds *directly* from the
+    // `Parent` to the `Child`. That's amazing! Think of the implications and
+    // possibilities! They're (and I mean this quite literally) endless!
+    // (If the `Parent` can't cover it, the guarantor does.)
+    // (If everything due was free, there's nothing to move.) Any fee is
+    // taken out of each withdrawal separately, so the rounding always goes
+    // the `Child`'s way.
+    let options = options(e)?;
+    check_receivers(e, client, &options)?;
+    let fee_total = fee_total(&options, &owed.charges)?;
+    // A sponsor takes the place of whoever would have paid.
+    let sponsorship: Option<Sponsorship> = read_opt(e, StorageKey::Sponsorship)?;
+    let payers: Option<Vec<(Identifier, u32)>> = match sponsorship {
+        Some(_) => None,
+        None => read_opt(e, StorageKey::Payers)?,
+    };
+    let from = match (&sponsorship, &payers) {
+        (Some(sponsorship), _) => Some(sponsorship.sponsor.clone()),
+        (None, Some(_)) => None,
+        (None, None) => Some(payer(e, client, owed.total)?),
+    };
+    // What the token kept for itself along the way, when we're measuring,
+    // and what came out of the pool, when we're swapping.
+    let mut lost: i128 = 0;
+    let mut swapped = None;
+    if let Some(payers) = &payers {
+        // Each payer sends their part straight to where it's going, too.
+        let child = payee(e)?;
+        for part in payer_parts(e, payers, owed)?.iter() {
+            let (payer, net, fee) = part.map_err(|_| Error::CorruptData)?;
+            if net > 0 {
+                lost += net - deliver(e, client, &options, &payer, &child, net)?;
+            }
+            if let (true, Some(collector)) = (fee > 0, &options.fee_collector) {
+                lost += fee - deliver(e, client, &options, &payer, collector, fee)?;
+            }
+        }
+    }
+    if let Some(from) = &from {
+        if !e.storage().has(StorageKey::Recipients) {
+            if owed.total > fee_total {
+                let net = owed.total - fee_total;
+                match &options.swap {
+                    Some(route) => swapped = Some(swap(e, client, route, from, &payee(e)?, net)?),
+                    None => lost += net - deliver(e, client, &options, from, &payee(e)?, net)?,
+                }
+            }
+        } else {
+            for part in split(e, &options, &owed.charges)?.iter() {
+                let (recipient, amount) = part.map_err(|_| Error::CorruptData)?;
+                if amount > 0 {
+                    lost += amount - deliver(e, client, &options, from, &recipient, amount)?;
+                }
+            }
+        }
+        if let (true, Some(collector)) = (fee_total > 0, &options.fee_collector) {
+            lost += fee_total - deliver(e, client, &options, from, collector, fee_total)?;
+        }
+    }
+    // From here on, the receipts, the events, what's been `Spent` and the
+    // fee all go by what arrived, not by what was sent.
+    let measured;
+    let owed = match lost {
+        0 => owed,
+        lost => {
+            measured = owed.as_received(owed.total - lost)?;
+            &measured
+        }
+    };
+
+    // Whoever stepped in for the `Parent` gets an event for it, too.
+    let cadence = Cadence::load(e)?;
+    let first = cadence.index(sched.start, cadence.next(sched.latest)?);
+    if let Some(mut sponsorship) = sponsorship {
+        e.events().publish(
+            (symbol!("sponsored"), e.id, first),
+            (sponsorship.sponsor.clone(), owed.periods),
+        );
+        sponsorship.periods = sponsorship.periods.saturating_sub(owed.periods);
+        if sponsorship.periods == 0 {
+            e.storage().remove(StorageKey::Sponsorship);
+        } else {
+            e.storage().set(StorageKey::Sponsorship, sponsorship);
+        }
+    }
+    if let Some(guarantor) = from.filter(|from| options.guarantor.as_ref() == Some(from)) {
+        let draws: u32 = read_opt(e, StorageKey::GuarantorDraws)?.unwrap_or(0);
+        e.storage()
+            .set(StorageKey::GuarantorDraws, draws.saturating_add(1));
+        e.events().publish(
+            (symbol!("guarantee"), e.id, first),
+            (guarantor, owed.periods),
+        );
+    }
+
+    let paid = record(e, &options, sched, owed, swapped, label)?;
+    notify(e, &options, first, owed)?;
+    Ok(paid)
+}
+
+/// Lets the `Child`'s payment hook (if they've set one) know about each
+/// withdrawal that was just paid, and how much it paid out. A hook that fails
+/// only gets a "hookfailed" event: the payment has been made either way.
+fn notify(e: &Sub, options: &Options, first: u64, owed: &Overdue) -> Result<(), Error> {
+    let hook: BytesN<32> = match read_opt(e, StorageKey::PaymentHook)? {
+        Some(hook) => hook,
+        None => return Ok(()),
+    };
+    for (i, charge) in owed.charges.iter().enumerate() {
+        let charge = charge.map_err(|_| Error::CorruptData)?;
+        let period = first + i as u64;
+        let amount = charge
+            .checked_sub(fee_on(options, charge)?)
+            .ok_or(Error::Overflow)?;
+        let args = vec![e, period.into_val(&e.env), amount.into_val(&e.env)];
+        let result =
+            e.try_invoke_contract::<(), soroban_sdk::Status>(&hook, &symbol!("on_payment"), args);
+        if !matches!(result, Ok(Ok(()))) {
+            e.events()
+                .publish((symbol!("hookfailed"), e.id, period), hook.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Tells the `registry` (if `e`'s subscription has one) that it's now
+/// `status`. If the registry fails, that's published and otherwise ignored.
+fn report(e: &Sub, status: Status) -> Result<(), Error> {
+    // Once it's over, nobody needs to find it by its `Parent` or `Child`
+    // any more. (While a notice period runs, it's still there.)
+    if status == Status::Cancelled {
+        unlist_subscription(e, StorageKey::ByParent(parent(e)?))?;
+        unlist_subscription(e, StorageKey::ByChild(child(e)?))?;
+    }
+
+    let registry = match options(e)?.registry {
+        Some(registry) => registry,
+        None => return Ok(()),
+    };
+
+    // There's more than one way to find out an allowance has ended, but the
+    // registry only hears about it the once.
+    if status == Status::Cancelled {
+        if e.storage().has(StorageKey::EndReported) {
+            return Ok(());
+        }
+        e.storage().set(StorageKey::EndReported, true);
+    }
+
+    let args = vec![
+        e,
+        e.current_contract().into_val(&e.env),
+        status.into_val(&e.env),
+    ];
+    let result =
+        e.try_invoke_contract::<(), soroban_sdk::Status>(&registry, &symbol!("notify"), args);
+    if !matches!(result, Ok(Ok(()))) {
+        e.events()
+            .publish((symbol!("regfailed"), e.id), (registry, status));
+    }
+    Ok(())
+}
+
+/// Has `from` pay `amount` to `to` out of the allowance they've given us, and
+/// says how much `to` got. With `measure_received`, it's `to`'s balance that
+/// says so, and there's a "received" event with both figures.
+fn deliver(
+    e: &Sub,
+    client: &token::Client,
+    options: &Options,
+    from: &Identifier,
+    to: &Identifier,
+    amount: i128,
+) -> Result<i128, Error> {
+    // `reconcile` only looks at the allowance's own token.
+    let own = client.contract_id == token_id(e)?;
+    if !options.measure_received {
+        client.xfer_from(&Signature::Invoker, &(0 as i128), from, to, &amount);
+        if own {
+            count_delivered(e, to, amount)?;
+        }
+        return Ok(amount);
+    }
+
+    let before = client.balance(to);
+    client.xfer_from(&Signature::Invoker, &(0 as i128), from, to, &amount);
+    let received = client
+        .balance(to)
+        .checked_sub(before)
+        .ok_or(Error::Overflow)?;
+    e.events()
+        .publish((symbol!("received"), e.id), (to.clone(), amount, received));
+    if own {
+        count_delivered(e, to, received)?;
+    }
+    Ok(received)
+}
+
+/// Has `from` pay `amount` to `to` through the `route`'s pool, so that `to`
+/// gets the other token instead, with a "swapped" event saying how much went
+/// in and how much came out, and returns the latter. If that's too little,
+/// nothing is paid at all.
+fn swap(
+    e: &Sub,
+    client: &token::Client,
+    route: &SwapRoute,
+    from: &Identifier,
+    to: &Identifier,
+    amount: i128,
+) -> Result<i128, Error> {
+    let min_out = amount.checked_mul(route.min_price).ok_or(Error::Overflow)? / PRICE_UNIT;
+    let pool = Identifier::Contract(route.pool.clone());
+    client.xfer_from(&Signature::Invoker, &(0 as i128), from, &pool, &amount);
+    let out: i128 = e.invoke_contract(
+        &route.pool,
+        &symbol!("swap"),
+        vec![
+            e,
+            client.contract_id.clone().into_val(&e.env),
+            amount.into_val(&e.env),
+            route.token_out.clone().into_val(&e.env),
+            min_out.into_val(&e.env),
+            to.clone().into_val(&e.env),
+        ],
+    );
+    if out < min_out {
+        return Err(Error::SlippageExceeded);
+    }
+    e.events()
+        .publish((symbol!("swapped"), e.id), (to.clone(), amount, out));
+    Ok(out)
+}
+
+/// Adds `amount` to everything the `Child` has been paid here, if that's who
+/// it went to, for `reconcile` to check their balance against.
+fn count_delivered(e: &Sub, to: &Identifier, amount: i128) -> Result<(), Error> {
+    if *to != child(e)? {
+        return Ok(());
+    }
+    let delivered: i128 = read_opt(e, StorageKey::Delivered)?.unwrap_or(0);
+    e.storage().set(
+        StorageKey::Delivered,
+        delivered.checked_add(amount).ok_or(Error::Overflow)?,
+    );
+    Ok(())
+}
+
+/// The token traps on a transfer to somebody whose balance the issuer has
+/// frozen, which tells nobody what went wrong. Checking first means whoever
+/// it is can get it sorted out and try again, with nothing lost.
+fn check_receivers(e: &Sub, client: &token::Client, options: &Options) -> Result<(), Error> {
+    let mut receivers = match read_opt::<Vec<(Identifier, u32)>>(e, StorageKey::Recipients)? {
+        Some(recipients) => {
+            let mut receivers = Vec::new(e);
+            for recipient in recipients.iter() {
+                let (id, _) = recipient.map_err(|_| Error::CorruptData)?;
+                receivers.push_back(id);
+            }
+            receivers
+        }
+        // A swap pays out in the pool's token, which is its own business.
+        None if options.swap.is_some() => Vec::new(e),
+        None => vec![e, payee(e)?],
+    };
+    if let (true, Some(collector)) = (options.fee_bps > 0, &options.fee_collector) {
+        receivers.push_back(collector.clone());
+    }
+    for receiver in receivers.iter() {
+        if client.is_frozen(&receiver.map_err(|_| Error::CorruptData)?) {
+            return Err(Error::ReceiverCannotReceive);
+        }
+    }
+    Ok(())
+}
+
+/// Brings the schedule up to date once `owed` has been paid, one way or
+/// another, with an event for each withdrawal named `label`. If it was paid
+/// through a swap, `swapped` is what came out of the pool.
+fn record(
+    e: &Sub,
+    options: &Options,
+    sched: &Schedule,
+    owed: &Overdue,
+    swapped: Option<i128>,
+    label: Symbol,
+) -> Result<u32, Error> {
+    // Every withdrawal gets an event with what it actually cost, and how
+    // many withdrawals there have been so far (so an indexer can tell if it
+    // missed one). Free trial ones get an event of their own, so anybody
+    // watching can tell they were skipped on purpose, not that a transfer
+    // failed. A stream has just the one event, for the period it has now
+    // been paid up into. Whatever went to the `fee_collector` gets a "fee"
+    // event alongside.
+    let cadence = Cadence::load(e)?;
+    let first = cadence.index(sched.start, cadence.next(sched.latest)?);
+    if let Some(mut assignment) = read_opt::<Assignment>(e, StorageKey::Assignment)? {
+        e.events().publish(
+            (symbol!("assigned"), e.id, first),
+            (assignment.assignee.clone(), owed.periods),
+        );
+        assignment.periods = assignment.periods.saturating_sub(owed.periods);
+        if assignment.periods == 0 {
+            e.storage().remove(StorageKey::Assignment);
+        } else {
+            e.storage().set(StorageKey::Assignment, assignment);
+        }
+    }
+    let mut count = withdraw_count(e)?;
+    if options.anchor_on_first_withdraw && latest(e)? < start_epoch(e, latest(e)?)? {
+        set_start_epoch(e, sched.start)?;
+    }
+    // Through a swap, each withdrawal delivered its share of what came out of
+    // the pool.
+    let delivered = match swapped {
+        Some(out) => owed.as_received(out)?.charges,
+        None => owed.charges.clone(),
+    };
+    let (oldest, mut next) = receipts(e)?;
+    if options.streaming {
+        count = count
+            .checked_add(owed.periods as u64)
+            .ok_or(Error::Overflow)?;
+        let period = cadence.index(sched.start, owed.latest);
+        e.events()
+            .publish((symbol!("stream"), e.id, period), (owed.total, count));
+        publish_fee(e, period, fee_total(options, &owed.charges)?);
+        let delivered = swapped.unwrap_or(owed.total);
+        next = store_receipt(e, next, period, owed.total, delivered, symbol!("stream"))?;
+    } else {
+        for (i, (charge, delivered)) in owed.charges.iter().zip(delivered.iter()).enumerate() {
+            let charge = charge.map_err(|_| Error::CorruptData)?;
+            let delivered = delivered.map_err(|_| Error::CorruptData)?;
+            let period = first + i as u64;
+            count = count.checked_add(1).ok_or(Error::Overflow)?;
+            e.storage().remove(StorageKey::FailedAt(period));
+            let label = if (i as u32) < owed.trial {
+                symbol!("trial")
+            } else {
+                label
+            };
+            e.events().publish((label, e.id, period), (charge, count));
+            publish_fee(e, period, fee_on(options, charge)?);
+            next = store_receipt(e, next, period, charge, delivered, label)?;
+        }
+    }
+    e.storage().set(StorageKey::Receipts, (oldest, next));
+    set_withdraw_count(e, count)?;
+    if options.lifetime_cap != 0 {
+        let spent: i128 = read_opt(e, StorageKey::Spent)?.unwrap_or(0);
+        let spent = spent.checked_add(owed.total).ok_or(Error::Overflow)?;
+        e.storage().set(StorageKey::Spent, spent);
+    }
+    e.storage().remove(StorageKey::FailureCount);
+
+    // We quickly set a new `Latest` in our contract data to reflect the
+    // withdrawals that have taken place. The astute among you may notice
+    // this isn't based off the ledger's `timestamp()`, but rather the
+    // latest withdraw. This allows the child to "catch up" on any missed
+    // withdrawals, each paid at the rate it came due at. Very kind of you.
+    // You're such a good parent!
+    set_latest(e, owed.latest)?;
+    settle_amount(e, owed.next_due)?;
+
+    // Usage is always reported against the next withdrawal, so that's the
+    // only counter there can be. It's been billed now, so it's done with,
+    // and anything reported from here on goes towards the next one. (So is
+    // one left behind by withdrawals that lapsed, and so are their
+    // shortfalls.)
+    e.storage().remove(StorageKey::Usage(first));
+    let next = first + owed.periods as u64;
+    forget_periods(e, StorageKey::UsedPeriods, StorageKey::Usage, |p| p >= next)?;
+    forget_periods(e, StorageKey::FailedPeriods, StorageKey::FailedAt, |p| {
+        p >= next
+    })?;
+
+    Ok(owed.periods)
+}
+
+/// A page of `e`'s receipts, newest first.
+fn history(e: &Sub, offset: u32, limit: u32) -> Result<Vec<Receipt>, Error> {
+    check_initialized(e)?;
+
+    let (oldest, next) = receipts(e)?;
+    let mut page = Vec::new(e);
+    let mut n = next.saturating_sub(offset as u64);
+    while n > oldest && page.len() < limit.min(MAX_HISTORY) {
+        n -= 1;
+        page.push_back(read::<Receipt>(e, StorageKey::Receipt(n))?);
+    }
+    Ok(page)
+}
+
+fn prune_receipts(e: &Sub, keep_latest: u32) -> Result<u32, Error> {
+    check_initialized(e)?;
+    party(e)?;
+
+    let (mut oldest, next) = receipts(e)?;
+    let keep_from = next.saturating_sub(keep_latest as u64);
+    let mut pruned = 0;
+    while oldest < keep_from && pruned < MAX_PRUNE {
+        e.storage().remove(StorageKey::Receipt(oldest));
+        oldest += 1;
+        pruned += 1;
+    }
+    e.storage().set(StorageKey::Receipts, (oldest, next));
+    Ok(pruned)
+}
+
+/// The receipts `e`'s subscription still has are numbered from the first to
+/// just before the second of these.
+fn receipts(e: &Sub) -> Result<(u64, u64), Error> {
+    Ok(read_opt(e, StorageKey::Receipts)?.unwrap_or((0, 0)))
+}
+
+/// Keeps receipt number `n`, for withdrawal `period`, returning the next
+/// number.
+fn store_receipt(
+    e: &Sub,
+    n: u64,
+    period: u64,
+    amount: i128,
+    delivered: i128,
+    label: Symbol,
+) -> Result<u64, Error> {
+    e.storage().set(
+        StorageKey::Receipt(n),
+        Receipt {
+            period,
+            amount,
+            delivered,
+            paid_at: e.ledger().timestamp(),
+            label,
+        },
+    );
+    n.checked_add(1).ok_or(Error::Overflow)
+}
+
+/// What `required_allowance` works out, for any subscription.
+fn required_allowance(e: &Sub, include_extras: bool) -> Result<i128, Error> {
+    check_initialized(e)?;
+
+    let mut required = due_amount(e)?;
+    if include_extras {
+        if let Some(extra) = read_opt::<i128>(e, StorageKey::Extra)? {
+            required = required.checked_add(extra).ok_or(Error::Overflow)?;
+        }
+    }
+
+    Ok(required)
+}
+
+/// Proposes a new `Amount` for `e`'s subscription, on behalf of `proposer`,
+/// and says what the amount in effect was.
+fn propose_amount(e: &Sub, proposer: Identifier, amount: i128) -> Result<i128, Error> {
+    check_initialized(e)?;
+
+    let (invoker, parent, child) = party_of(e, proposer)?;
+    check_amount(amount, step(e)?)?;
+
+    // Proposing the amount that's already agreed only makes sense to take
+    // back a proposal that's still waiting.
+    if amount == agreed_amount(e)? && !e.storage().has(StorageKey::PendingAmt) {
+        return Err(Error::AmountUnchanged);
+    }
+    check_amount_change(e, amount)?;
+
+    // Who has to agree to this? A raise costs the `Parent`, and a cut costs
+    // the `Child` (if the contract was set up to protect them from cuts).
+    // If that's the very person proposing it, or nobody, it goes straight
+    // through. Otherwise, it waits for them to accept.
+    let current = agreed_amount(e)?;
+    let needed_from = if amount > current {
+        Some(parent)
+    } else if amount < current && options(e)?.decreases_need_acceptance {
+        Some(child)
+    } else {
+        None
+    };
+
+    match needed_from {
+        Some(account) if account != invoker => {
+            e.storage().set(
+                StorageKey::PendingAmt,
+                AmountProposal {
+                    amount,
+                    proposer: invoker,
+                },
+            );
+            amount_in_effect(e)
+        }
+        _ => {
+            let old = set_amount(e, amount)?;
+            e.storage().remove(StorageKey::PendingAmt);
+            Ok(old)
+        }
+    }
+}
+
+/// Writes off up to `periods` of what `e`'s `Child` is owed.
+fn forgive_arrears(e: &Sub, periods: u32) -> Result<u32, Error> {
+    check_initialized(e)?;
+    check_manager(e)?;
+
+    settle_pause(e, e.ledger().timestamp())?;
+    let sched = schedule(e)?;
+    let owed = overdue(e, &sched, periods)?;
+    if owed.periods == 0 {
+        return Ok(0);
+    }
+
+    // Everything `withdraw` does after it pays, and nothing else. (That
+    // includes wiping the shortfalls, so they can't turn up as late fees.)
+    let cadence = Cadence::load(e)?;
+    let first = cadence.index(sched.start, cadence.next(sched.latest)?);
+    let next = first + owed.periods as u64;
+    for period in first..next {
+        e.storage().remove(StorageKey::FailedAt(period));
+    }
+    set_latest(e, owed.latest)?;
+    settle_amount(e, owed.next_due)?;
+    e.storage().remove(StorageKey::Usage(first));
+    forget_periods(e, StorageKey::UsedPeriods, StorageKey::Usage, |p| p >= next)?;
+    forget_periods(e, StorageKey::FailedPeriods, StorageKey::FailedAt, |p| {
+        p >= next
+    })?;
+    e.events().publish(
+        (symbol!("forgive"), e.id, first),
+        (owed.periods, owed.total),
+    );
+
+    Ok(owed.periods)
+}
+
+/// The `Parent` of `e`'s subscription pays `periods` withdrawals ahead.
+fn prepay(e: &Sub, periods: u32) -> Result<i128, Error> {
+    check_initialized(e)?;
+
+    if invoker_id(e) != parent(e)? {
+        return Err(Error::Unauthorized);
+    }
+    // (While there's a sponsor, they're the one paying.)
+    if periods == 0
+        || periods > MAX_PREPAY
+        || options(e)?.streaming
+        || e.storage().has(StorageKey::Sponsorship)
+    {
+        return Err(Error::InvalidArguments);
+    }
+
+    settle_pause(e, e.ledger().timestamp())?;
+    let mut sched = schedule(e)?;
+    if sched.paused {
+        return Err(Error::Paused);
+    }
+
+    // We put the schedule's clock forward to when the last of them can be
+    // collected (but not past the end), and work out what's owed then.
+    let pricing = Pricing::load(e)?;
+    let mut due = sched.latest;
+    for _ in 0..periods {
+        due = pricing.cadence.next(due)?;
+    }
+    let mut until = pricing.claimable_at(due)?;
+    if let Some(end) = read_opt::<u64>(e, StorageKey::EndsAt)? {
+        until = until.min(end);
+    }
+    sched.now = sched.now.max(until);
+
+    // A prepayment counts towards the `lifetime_cap` just like any other
+    // withdrawal, so it stops where the cap does.
+    if remaining_cap(e)? == Some(0) {
+        return Err(Error::SubscriptionExpired);
+    }
+    let owed = within_cap(e, &sched, overdue(e, &sched, periods)?)?;
+    if owed.periods == 0 {
+        return Err(if sched.ended {
+            Error::SubscriptionCancelled
+        } else {
+            Error::NothingDue
+        });
+    }
+
+    let token_id = token_id(e)?;
+    let client = token::Client::new(e, token_id);
+    if parent_allowance(e, &client)? < owed.total {
+        return Err(Error::InsufficientAllowance);
+    }
+
+    pay(e, &client, &sched, &owed, symbol!("prepay"))?;
+    Ok(owed.total)
+}
+
+/// Cancels `e`'s subscription for the `Parent`, once it's been checked that
+/// whoever is asking gets to.
+fn cancel(e: &Sub) -> Result<(), Error> {
+    if e.storage().has(StorageKey::EndsAt) {
+        return Err(Error::Cancelled);
+    }
+
+    // Cancelling before the commitment is up costs a fee. It has to be
+    // paid out of what the allowance has left over once the regular
+    // withdrawals due are set aside (just like an extra invoice), or the
+    // cancel doesn't go through at all.
+    let options = options(e)?;
+    let count = withdraw_count(e)?;
+    if count < options.min_periods as u64 && options.early_termination_fee > 0 {
+        let fee = options.early_termination_fee;
+        let token_id = token_id(e)?;
+        let client = token::Client::new(e, token_id);
+        let parent = parent(e)?;
+        let available = parent_allowance(e, &client)?
+            .checked_sub(due_amount(e)?)
+            .ok_or(Error::Overflow)?;
+        if available < fee || client.balance(&parent) < fee {
+            return Err(Error::PenaltyPaymentFailed);
+        }
+
+        // It's paid and counted just like a withdrawal, to whoever the
+        // `Child`'s withdrawals go to, with a receipt of its own.
+        let paid = deliver(e, &client, &options, &parent, &payee(e)?, fee)?;
+        let sched = schedule(e)?;
+        let cadence = Cadence::load(e)?;
+        let period = cadence.index(sched.start, cadence.next(sched.latest)?);
+        let (oldest, next) = receipts(e)?;
+        let next = store_receipt(e, next, period, paid, paid, symbol!("penalty"))?;
+        e.storage().set(StorageKey::Receipts, (oldest, next));
+        e.events()
+            .publish((symbol!("penalty"), e.id), (paid, count));
+    }
+
+    let ends_at = e
+        .ledger()
+        .timestamp()
+        .checked_add(options.cancel_notice_period)
+        .ok_or(Error::Overflow)?;
+    e.storage().set(StorageKey::EndsAt, ends_at);
+    e.events()
+        .publish((symbol!("cancel"), e.id), (Role::Parent, ends_at));
+    report(e, get_status(e)?)
+}
+
+/// Changes `e`'s `Step`, once everything that can be collected has been
+/// paid, at the old one. The new step starts where the period we're in ends,
+/// and the schedule is moved so that withdrawals keep their numbers.
+fn fix_step(e: &Sub, step: u64) -> Result<(), Error> {
+    if step == 0 {
+        return Err(Error::ZeroStep);
+    }
+    let options = options(e)?;
+    if step <= options.tolerance
+        || options.streaming
+        || options.monthly_anchor != 0
+        || options.bill_in_arrears
+    {
+        return Err(Error::InvalidArguments);
+    }
+    check_amount(agreed_amount(e)?, step)?;
+    if e.storage().has(StorageKey::EndsAt) {
+        return Err(Error::Cancelled);
+    }
+
+    match collect(e, &options, None, u64::MAX, u32::MAX) {
+        Ok(_) | Err(Error::NothingDue | Error::ChildAlreadyWithdrawn) => {}
+        Err(err) => return Err(err),
+    }
+    let sched = schedule(e)?;
+    if sched.paused {
+        return Err(Error::Paused);
+    }
+    if overdue(e, &sched, 1)?.periods != 0 {
+        return Err(Error::ArrearsOutstanding);
+    }
+
+    let cadence = Cadence::load(e)?;
+    let boundary = if sched.now < sched.start {
+        sched.start
+    } else {
+        cadence.next(cadence.period_start(sched.start, sched.now)?)?
+    };
+    let start = cadence
+        .index(sched.start, boundary)
+        .checked_mul(step)
+        .and_then(|offset| boundary.checked_sub(offset))
+        .ok_or(Error::Overflow)?;
+    set_start_epoch(e, start)?;
+    set_latest(e, boundary.checked_sub(step).ok_or(Error::Overflow)?)?;
+    set_step(e, step)?;
+    e.events()
+        .publish((symbol!("step"), e.id), (step, boundary));
+
+    Ok(())
+}
+
+/// Switches the contract over to the code installed as `wasm_hash`.
+fn upgrade(e: &Sub, wasm_hash: BytesN<32>) -> Result<(), Error> {
+    // The host doesn't tell us which WASM we're running, so we remember the
+    // last one we were upgraded to. There isn't one the first time.
+    let old: Option<BytesN<32>> = read_opt(e, StorageKey::WasmHash)?;
+    e.update_current_contract_wasm(&wasm_hash);
+    e.storage().set(StorageKey::WasmHash, wasm_hash.clone());
+    e.events().publish((symbol!("upgrade"),), (old, wasm_hash));
+
+    Ok(())
+}
+
+/// Proposes paying `e`'s subscription in another token, on the `Parent`'s
+/// behalf.
+fn propose_token(e: &Sub, proposal: TokenProposal) -> Result<(), Error> {
+    // The same checks `init` makes.
+    if proposal.token_id == BytesN::from_array(e, &[0; 32]) || proposal.token_id == token_id(e)? {
+        return Err(Error::InvalidArguments);
+    }
+    check_amount(proposal.amount, step(e)?)?;
+
+    e.storage().set(StorageKey::PendingToken, proposal);
+    Ok(())
+}
+
+/// Accepts the other side's pending `Amount` proposal for `e`'s subscription.
+fn accept_amount(e: &Sub) -> Result<(), Error> {
+    check_initialized(e)?;
+
+    // With `Signers`, the `Parent`'s side accepts by approving an
+    // `Action::Amount` for the same amount.
+    let (invoker, _, _) = party(e)?;
+    check_acting_alone(e, &invoker)?;
+    let proposal: AmountProposal =
+        read_opt(e, StorageKey::PendingAmt)?.ok_or(Error::NoAmountProposal)?;
+
+    // You can't accept your own proposal. That's the whole point!
+    if proposal.proposer == invoker {
+        return Err(Error::Unauthorized);
+    }
+
+    check_amount_change(e, proposal.amount)?;
+    set_amount(e, proposal.amount)?;
+    e.storage().remove(StorageKey::PendingAmt);
+
+    Ok(())
+}
+
+/// Whether a withdrawal from `e`'s subscription would go through right now.
+fn can_withdraw(e: &Sub) -> bool {
+    if check_initialized(e).is_err() {
+        return false;
+    }
+    let (token_id, yearly) = match options(e).and_then(|options| pay_in(e, &options, None)) {
+        Ok(pay_in) => pay_in,
+        Err(_) => return false,
+    };
+    let client = token::Client::new(e, token_id);
+    withdrawable(e, &client, yearly, u64::MAX, u32::MAX).is_ok()
+}
+
+/// What `pay_out` would pay from `e`'s subscription right now, worked out the
+/// same way, but without writing anything or moving any money.
+fn preview_withdraw(e: &Sub) -> Result<WithdrawPreview, Error> {
+    check_initialized(e)?;
+
+    let options = options(e)?;
+    let (token_id, yearly) = pay_in(e, &options, None)?;
+    let client = token::Client::new(e, &token_id);
+    let cadence = Cadence::load(e)?;
+    let (sched, owed) = match withdrawable(e, &client, yearly, u64::MAX, u32::MAX) {
+        Err(Error::Paused | Error::ChildAlreadyWithdrawn | Error::NothingDue)
+            if options.lenient =>
+        {
+            let sched = schedule(e)?;
+            return Ok(WithdrawPreview {
+                periods: 0,
+                first: cadence.index(sched.start, cadence.next(sched.latest)?),
+                token_id,
+                total: 0,
+                fee: 0,
+                payouts: Vec::new(e),
+                latest: sched.latest,
+            });
+        }
+        result => result?,
+    };
+    check_receivers(e, &client, &options)?;
+
+    // However many payers there are, what's left after the fee goes to the
+    // `Child` (or whoever they've assigned it to), unless it's split.
+    let fee = fee_total(&options, &owed.charges)?;
+    let legs = if e.storage().has(StorageKey::Recipients) {
+        split(e, &options, &owed.charges)?
+    } else {
+        vec![e, (payee(e)?, owed.total - fee)]
+    };
+    let mut payouts = Vec::new(e);
+    for leg in legs.iter() {
+        let (receiver, amount) = leg.map_err(|_| Error::CorruptData)?;
+        if amount > 0 {
+            payouts.push_back((receiver, amount));
+        }
+    }
+
+    Ok(WithdrawPreview {
+        periods: owed.periods,
+        first: cadence.index(sched.start, cadence.next(sched.latest)?),
+        token_id,
+        total: owed.total,
+        fee,
+        payouts,
+        latest: owed.latest,
+    })
+}
+
+/// Everything stored for `e`'s subscription, in one go.
+fn get_state(e: &Sub) -> Result<FullState, Error> {
+    check_initialized(e)?;
+
+    Ok(FullState {
+        schema_version: schema_version(e)?,
+        status: get_status(e)?,
+        config: Config {
+            parent: parent(e)?,
+            child: child(e)?,
+            token_id: token_id(e)?,
+            amount: amount(e)?,
+            step: step(e)?,
+            options: options(e)?,
+        },
+        progress: Progress {
+            latest: latest(e)?,
+            start_epoch: start_epoch(e, latest(e)?)?,
+            withdraw_count: withdraw_count(e)?,
+            delivered: read_opt(e, StorageKey::Delivered)?.unwrap_or(0),
+            failure_count: read_opt(e, StorageKey::FailureCount)?.unwrap_or(0),
+            ends_at: read_opt(e, StorageKey::EndsAt)?,
+            agreed_amount: agreed_amount(e)?,
+            rate_history: rate_history(e)?,
+        },
+        pending: Pending {
+            amount: read_opt(e, StorageKey::PendingAmt)?,
+            token: read_opt(e, StorageKey::PendingToken)?,
+            parent: read_opt(e, StorageKey::PendingParent)?,
+            pause: read_opt(e, StorageKey::PauseReq)?,
+            shares: read_opt(e, StorageKey::SharesProposal)?,
+            action: read_opt(e, StorageKey::PendingAction)?,
+            extra: read_opt(e, StorageKey::Extra)?,
+        },
+        extras: Extras {
+            admin: read_opt(e, StorageKey::Admin)?,
+            metadata: read_opt(e, StorageKey::Metadata)?,
+            sponsorship: read_opt(e, StorageKey::Sponsorship)?,
+            assignment: read_opt(e, StorageKey::Assignment)?,
+            collateral: read_opt(e, StorageKey::Collateral)?,
+            preferred_token: read_opt(e, StorageKey::PreferredToken)?,
+            payment_hook: read_opt(e, StorageKey::PaymentHook)?,
+            spend_policy: read_opt(e, StorageKey::SpendPolicy)?,
+        },
+    })
+}
+
+/// Where `e`'s subscription stands right now.
+fn get_status(e: &Sub) -> Result<Status, Error> {
+    if !is_initialized(e) {
+        return Ok(Status::Uninitialized);
+    }
+    check_schema(e)?;
+
+    // A pause trumps everything else (bar the end): nothing comes due while
+    // it lasts.
+    let sched = schedule(e)?;
+    if sched.ended {
+        return Ok(Status::Cancelled);
+    }
+    if remaining_cap(e)? == Some(0) {
+        return Ok(Status::Expired);
+    }
+    if e.storage().has(StorageKey::EndsAt) {
+        return Ok(Status::Terminating);
+    }
+    if e.storage().has(StorageKey::Suspended) {
+        return Ok(Status::Suspended);
+    }
+    if sched.paused {
+        return Ok(Status::Paused);
+    }
+    if sched.now < sched.start {
+        return Ok(Status::NotStarted);
+    }
+    if behind_by_more_than(&sched, &Pricing::load(e)?, options(e)?.grace_periods)? {
+        return Ok(Status::InArrears);
+    }
+    Ok(Status::Active)
+}
+
+/// Whether `e`'s `Child` counts as a paying subscriber: the subscription is
+/// running (or working out its notice), and no more than its `grace_periods`
+/// behind.
+fn is_active(e: &Sub) -> Result<bool, Error> {
+    match get_status(e)? {
+        Status::Active | Status::Terminating => {}
+        _ => return Ok(false),
+    }
+    let grace = options(e)?.grace_periods;
+    Ok(!behind_by_more_than(
+        &schedule(e)?,
+        &Pricing::load(e)?,
+        grace,
+    )?)
+}
+
+/// The number of the first withdrawal that can still be skipped: one that
+/// hasn't been paid, and hasn't come due yet either.
+fn first_skippable(e: &Sub) -> Result<u64, Error> {
+    let sched = schedule(e)?;
+    let cadence = Cadence::load(e)?;
+    let unpaid = cadence.index(sched.start, cadence.next(sched.latest)?);
+    if sched.now < sched.start {
+        return Ok(unpaid);
+    }
+    Ok(unpaid.max(cadence.index(sched.start, sched.now) + 1))
+}
+
+/// Either side of `e`'s subscription agrees to close it, and once both have,
+/// it's closed.
+fn approve_close(e: &Sub) -> Result<(), Error> {
+    check_initialized(e)?;
+
+    let (invoker, parent, _) = party(e)?;
+    let (by, other) = if invoker == parent {
+        (Role::Parent, Role::Child)
+    } else {
+        (Role::Child, Role::Parent)
+    };
+    if e.storage().has(StorageKey::EndsAt) {
+        return Err(Error::Cancelled);
+    }
+
+    if !e.storage().has(StorageKey::CloseApproval(other)) {
+        e.storage().set(StorageKey::CloseApproval(by), true);
+        return Ok(());
+    }
+
+    e.storage().remove(StorageKey::CloseApproval(Role::Parent));
+    e.storage().remove(StorageKey::CloseApproval(Role::Child));
+    close(e, by, true)
+}
+
+fn revoke_close_approval(e: &Sub) -> Result<(), Error> {
+    check_initialized(e)?;
+
+    let (invoker, parent, _) = party(e)?;
+    let by = if invoker == parent {
+        Role::Parent
+    } else {
+        Role::Child
+    };
+    e.storage().remove(StorageKey::CloseApproval(by));
+
+    Ok(())
+}
+
+/// Ends `e`'s subscription where the `Child` was last paid up to, on behalf
+/// of `by`, so whatever else was due stays unpaid. With `final_withdraw`,
+/// everything due right now is paid out first.
+fn close(e: &Sub, by: Role, final_withdraw: bool) -> Result<(), Error> {
+    if e.storage().has(StorageKey::EndsAt) {
+        return Err(Error::Cancelled);
+    }
+
+    // Having nothing to collect is no reason not to close.
+    let mut paid = 0;
+    if final_withdraw {
+        paid = match pay_out(e, u64::MAX, u32::MAX) {
+            Ok(periods) => periods,
+            Err(Error::NothingDue | Error::ChildAlreadyWithdrawn | Error::Paused) => 0,
+            Err(err) => return Err(err),
+        };
+    }
+
+    e.storage().set(StorageKey::EndsAt, schedule(e)?.latest);
+    e.events().publish((symbol!("close"), e.id), (by, paid));
+    report(e, Status::Cancelled)
+}
+
+/// Wipes `e`'s subscription once it's over and settled, so `init` can set up
+/// a new one in its place. The keys that belong to the whole contract (and
+/// the nonces, so old signatures can't be replayed) stay where they are.
+fn reset(e: &Sub) -> Result<(), Error> {
+    match get_status(e)? {
+        Status::Cancelled | Status::Expired => {}
+        _ => return Err(Error::NotEnded),
+    }
+    let sched = schedule(e)?;
+    if within_cap(e, &sched, overdue(e, &sched, 1)?)?.periods != 0 {
+        return Err(Error::ArrearsOutstanding);
+    }
+    if read_opt::<i128>(e, StorageKey::Collateral)?.unwrap_or(0) > 0 {
+        return Err(Error::CollateralHeld);
+    }
+
+    // There can be more receipts than one invocation can remove, so they
+    // have to be pruned first.
+    let (oldest, next) = receipts(e)?;
+    if oldest != next {
+        return Err(Error::ReceiptsNotPruned);
+    }
+
+    // Every key numbered by period is on a list, so we know exactly which
+    // ones there are.
+    forget_periods(e, StorageKey::UsedPeriods, StorageKey::Usage, |_| false)?;
+    forget_periods(e, StorageKey::SkipReqs, StorageKey::SkipReq, |_| false)?;
+    forget_periods(e, StorageKey::FailedPeriods, StorageKey::FailedAt, |_| {
+        false
+    })?;
+
+    unlist_subscription(e, StorageKey::ByParent(parent(e)?))?;
+    unlist_subscription(e, StorageKey::ByChild(child(e)?))?;
+
+    for key in [
+        StorageKey::Parent,
+        StorageKey::Child,
+        StorageKey::TokenId,
+        StorageKey::Amount,
+        StorageKey::Step,
+        StorageKey::Latest,
+        StorageKey::Options,
+        StorageKey::PauseReq,
+        StorageKey::Extra,
+        StorageKey::StartEpoch,
+        StorageKey::PendingAmt,
+        StorageKey::RateHistory,
+        StorageKey::Oracle,
+        StorageKey::JoinOffset,
+        StorageKey::WithdrawCount,
+        StorageKey::Invokers,
+        StorageKey::Admin,
+        StorageKey::PendingParent,
+        StorageKey::EndsAt,
+        StorageKey::CloseApproval(Role::Parent),
+        StorageKey::CloseApproval(Role::Child),
+        StorageKey::SkippedPeriods,
+        StorageKey::FailureCount,
+        StorageKey::Suspended,
+        StorageKey::Recipients,
+        StorageKey::SharesProposal,
+        StorageKey::GuarantorDraws,
+        StorageKey::Payers,
+        StorageKey::Sponsorship,
+        StorageKey::Collateral,
+        StorageKey::Assignment,
+        StorageKey::Metadata,
+        StorageKey::State,
+        StorageKey::PendingAction,
+        StorageKey::PendingToken,
+        StorageKey::Delivered,
+        StorageKey::Checkpoint,
+        StorageKey::PreferredToken,
+        StorageKey::PaymentHook,
+        StorageKey::SpendPolicy,
+        StorageKey::EndReported,
+        StorageKey::Receipts,
+        StorageKey::Spent,
+        StorageKey::LastAmtChange,
+    ] {
+        e.storage().remove(key);
+    }
+    e.events().publish((symbol!("reset"), e.id), ());
+
+    Ok(())
+}
+
+/// Adds `e`'s subscription to the list of ids kept under `key`. Lists belong
+/// to the whole contract, so they're kept alongside subscription `0`.
+fn list_subscription(e: &Sub, key: StorageKey) -> Result<(), Error> {
+    let main = Sub::main(e.env.clone());
+    let mut ids: Vec<u64> = read_opt(&main, key.clone())?.unwrap_or_else(|| Vec::new(e));
+    ids.push_back(e.id);
+    main.storage().set(key, ids);
+    Ok(())
+}
+
+/// Takes `e`'s subscription back off the list of ids kept under `key`.
+fn unlist_subscription(e: &Sub, key: StorageKey) -> Result<(), Error> {
+    let main = Sub::main(e.env.clone());
+    let ids: Vec<u64> = read_opt(&main, key.clone())?.unwrap_or_else(|| Vec::new(e));
+    let mut kept = Vec::new(e);
+    for id in ids.iter() {
+        let id = id.map_err(|_| Error::CorruptData)?;
+        if id != e.id {
+            kept.push_back(id);
+        }
+    }
+    main.storage().set(key, kept);
+    Ok(())
+}
+
+/// Notes `period` on the list kept under `list`, of the periods that have a
+/// key of their own (a `Usage`, `SkipReq` or `FailedAt`), so that `reset` can
+/// find every one of them. Says whether it could, since the list never holds
+/// more than `MAX_SCAN` periods.
+fn note_period(e: &Sub, list: StorageKey, period: u64) -> Result<bool, Error> {
+    let mut periods: Vec<u64> = read_opt(e, list.clone())?.unwrap_or_else(|| Vec::new(e));
+    if periods.contains(&period) {
+        return Ok(true);
+    }
+    if periods.len() as u64 >= MAX_SCAN {
+        return Ok(false);
+    }
+    periods.push_back(period);
+    e.storage().set(list, periods);
+    Ok(true)
+}
+
+/// Removes the `key` for every period on the list kept under `list` that we
+/// don't `keep`, and takes them off the list.
+fn forget_periods(
+    e: &Sub,
+    list: StorageKey,
+    key: fn(u64) -> StorageKey,
+    keep: impl Fn(u64) -> bool,
+) -> Result<(), Error> {
+    let periods: Vec<u64> = match read_opt(e, list.clone())? {
+        Some(periods) => periods,
+        None => return Ok(()),
+    };
+    let mut kept = Vec::new(e);
+    for period in periods.iter() {
+        let period = period.map_err(|_| Error::CorruptData)?;
+        if keep(period) {
+            kept.push_back(period);
+        } else {
+            e.storage().remove(key(period));
+        }
+    }
+    if kept.is_empty() {
+        e.storage().remove(list);
+    } else {
+        e.storage().set(list, kept);
+    }
+    Ok(())
+}
+
+/// Does the actual work of `init` (and `create`), setting up a brand new
+/// allowance in `e`'s subscription, paid for by `parent`. The first
+/// withdrawal comes due at `start_epoch`, or right away if there isn't one.
+#[allow(clippy::too_many_arguments)]
+fn set_up(
+    e: &Sub,
+    start_epoch: Option<u64>,
+    parent: Identifier,
+    child: Identifier,
+    token_id: BytesN<32>,
+    amount: i128,
+    step: u64,
+    options: Options,
+) -> Result<(), Error> {
+    // When running `init`, we want to make sure the function hasn't already
+    // been invoked. Although a few different `StorageKey`s are set during
+    // init, it's enough to only check for one.
+    if is_initialized(e) {
+        return Err(Error::ContractAlreadyInitialized);
+    }
+    if let Some(salt) = &options.deployer_salt {
+        check_deployer(e, salt)?;
+    }
+
+    // On the calendar, a month always has an anchor day if we stick to
+    // days 1 to 28, and there are exactly twelve withdrawals a year,
+    // whatever `step` says.
+    if options.monthly_anchor > 28 || (options.monthly_anchor != 0 && options.streaming) {
+        return Err(Error::InvalidArguments);
+    }
+
+    // A stream is paid as it goes, so it's neither in advance nor in
+    // arrears.
+    if options.streaming && options.bill_in_arrears {
+        return Err(Error::InvalidArguments);
+    }
+    let step = if options.monthly_anchor != 0 {
+        SECONDS_IN_YEAR / 12
+    } else {
+        step
+    };
+
+    // You can't have a withdraw every 0 seconds. Obviously. Also, you can't
+    // divide by 0. So say the calculators, at least.
+    if step == 0 {
+        return Err(Error::ZeroStep);
+    }
+
+    // Or a withdraw of 0 stroops, for that matter.
+    check_amount(amount, step)?;
+
+    // Any more tolerance than that, and a period could be collected before
+    // the one before it is even due.
+    if options.tolerance != 0 && (options.tolerance >= step || options.streaming) {
+        return Err(Error::InvalidArguments);
+    }
+    if options.lapse_missed && options.streaming {
+        return Err(Error::InvalidArguments);
+    }
+    if options.anchor_on_first_withdraw && (options.monthly_anchor != 0 || options.streaming) {
+        return Err(Error::InvalidArguments);
+    }
+
+    // We work out the `Latest` sentinel (see below) before we write
+    // anything, so an absurd `step` (think `u64::MAX`) is rejected up front
+    // rather than bricking every future `withdraw`.
+    // On the calendar, the schedule starts on the most recent anchor day.
+    let current_ts = e.ledger().timestamp();
+    let cadence = Cadence {
+        step,
+        anchor: options.monthly_anchor,
+    };
+    let start = match start_epoch {
+        Some(start_epoch) => start_epoch,
+        None if cadence.anchor == 0 => current_ts,
+        None => cadence.prev(current_ts.checked_add(1).ok_or(Error::Overflow)?)?,
+    };
+    let latest = cadence.prev(start)?;
+
+    // A `step` longer than a year would mean zero withdrawals per year, and
+    // `withdraw` would end up dividing by zero. Nope.
+    if SECONDS_IN_YEAR / step == 0 {
+        return Err(Error::InvalidArguments);
+    }
+
+    // Paying yourself an allowance is a great way to burn fees and not much
+    // else, so that's almost certainly a copy-paste mistake. The same goes
+    // for an all-zero token id.
+    if parent == child || token_id == BytesN::from_array(e, &[0; 32]) {
+        return Err(Error::InvalidArguments);
+    }
+
+    // An escalator has to say how often it kicks in.
+    if options.escalator_bps != 0 && options.escalator_every == 0 {
+        return Err(Error::InvalidArguments);
+    }
+
+    // And nobody gets paid to use a product, or gets more than 100% off.
+    if options.unit_price < 0 || options.discount_bps as i128 > BPS {
+        return Err(Error::InvalidArguments);
+    }
+
+    // Nor does the `Child` pay to be let go.
+    if options.early_termination_fee < 0 {
+        return Err(Error::InvalidArguments);
+    }
+
+    // A fee needs somebody to collect it, and can't be more than everything.
+    if options.fee_bps as i128 > BPS || (options.fee_bps != 0 && options.fee_collector.is_none()) {
+        return Err(Error::InvalidArguments);
+    }
+
+    if let Some(recipients) = &options.recipients {
+        check_shares(recipients)?;
+    }
+
+    // A guarantor is somebody else.
+    if let Some(guarantor) = &options.guarantor {
+        if *guarantor == parent || *guarantor == child {
+            return Err(Error::InvalidArguments);
+        }
+    }
+
+    if let Some(metadata) = &options.metadata {
+        check_metadata(metadata)?;
+    }
+
+    if let Some(signers) = &options.signers {
+        check_signers(signers)?;
+    }
+
+    // Several payers are a different way of paying altogether.
+    if let Some(payers) = &options.payers {
+        check_shares(payers)?;
+        if options.recipients.is_some() || options.guarantor.is_some() {
+            return Err(Error::InvalidArguments);
+        }
+    }
+
+    if options.swap.is_some() && (options.recipients.is_some() || options.payers.is_some()) {
+        return Err(Error::InvalidArguments);
+    }
+
+    // Each token gets the same checks as the allowance's own.
+    if let Some(tokens) = &options.accepted_tokens {
+        if tokens.len() > MAX_TOKENS || options.swap.is_some() {
+            return Err(Error::InvalidArguments);
+        }
+        for entry in tokens.iter() {
+            let (other, amount) = entry.map_err(|_| Error::InvalidArguments)?;
+            if other == token_id || other == BytesN::from_array(e, &[0; 32]) {
+                return Err(Error::InvalidArguments);
+            }
+            check_amount(amount, step)?;
+        }
+    }
+
+    // Usage is billed a whole period at a time, which doesn't mix with a
+    // stream.
+    if options.streaming && options.unit_price != 0 {
+        return Err(Error::InvalidArguments);
+    }
+
+    if options.grace_periods > MAX_GRACE_PERIODS {
+        return Err(Error::InvalidArguments);
+    }
+
+    if options.lifetime_cap < 0
+        || (options.lifetime_cap != 0 && (options.streaming || options.accepted_tokens.is_some()))
+    {
+        return Err(Error::InvalidArguments);
+    }
+
+    // A brand new contract gets the latest layout. One that's been around
+    // since an older layout keeps it for every new subscription, too, until
+    // they're all `migrate`d together.
+    let main = Sub::main(e.env.clone());
+    if !main.storage().has(StorageKey::SchemaVersion) && !is_initialized(&main) {
+        main.storage()
+            .set(StorageKey::SchemaVersion, SCHEMA_VERSION);
+    }
+
+    // We are setting up all the data that this contract will store on the
+    // ledger here. Nothing fancy here, just the same thing a few times.
+    // As an act of goodwill, we set the `Latest` withdraw to be in the past
+    // and allow the `Child` to immediately make the first withdrawal. Just
+    // to get them started, ya know.
+    if schema_version(e)? >= 2 {
+        e.storage().set(
+            StorageKey::State,
+            State {
+                token_id,
+                parent: parent.clone(),
+                child: child.clone(),
+                amount,
+                step,
+                options: options.clone(),
+                latest,
+                start_epoch: start,
+                withdraw_count: 0,
+            },
+        );
+    } else {
+        e.storage().set(StorageKey::TokenId, token_id);
+        e.storage().set(StorageKey::Parent, parent.clone());
+        e.storage().set(StorageKey::Child, child.clone());
+        e.storage().set(StorageKey::Amount, amount);
+        e.storage().set(StorageKey::Step, step);
+        e.storage().set(StorageKey::Options, options.clone());
+        e.storage().set(StorageKey::Latest, latest);
+        e.storage().set(StorageKey::StartEpoch, start);
+        e.storage().set(StorageKey::WithdrawCount, 0u64);
+    }
+    if let Some(recipients) = &options.recipients {
+        e.storage().set(StorageKey::Recipients, recipients.clone());
+    }
+    if let Some(payers) = &options.payers {
+        e.storage().set(StorageKey::Payers, payers.clone());
+    }
+    if let Some(metadata) = &options.metadata {
+        e.storage().set(StorageKey::Metadata, metadata.clone());
+    }
+
+    // This is the first time we've used `Env.ledger()` in these contracts.
+    // The Soroban environment, by design, doesn't have a tremendous amount
+    // of context about the current state of the Stellar network. One of the
+    // few things it does know is the `timestamp()` of the most recently
+    // closed ledger on the network. Check in the list of "Further
+    // Resources" in the README to learn more about this.
+
+    // Both sides can look this subscription up later.
+    list_subscription(e, StorageKey::ByParent(parent))?;
+    list_subscription(e, StorageKey::ByChild(child))?;
+
+    // If we're prorating, we remember how far into the first period we are.
+    // A schedule that hasn't started yet is joined right at its start.
+    if options.prorate_first {
+        e.storage()
+            .set(StorageKey::JoinOffset, current_ts.saturating_sub(start));
+    }
+
+    report(e, get_status(e)?)
+}
+
+#[contractimpl]
+impl AllowanceTrait for AllowanceContract {
+    // Remember, before you can invoke `withdraw`, you must invoke `init`
+    fn init(
+        e: Env,
+        child: Identifier,
+        token_id: BytesN<32>,
+        amount: i128,
+        step: u64,
+        options: Options,
+    ) -> Result<(), Error> {
+        // The invoker of `init` becomes the `Parent`. That can be a user
+        // account or another contract (say, a DAO's treasury), which pays
+        // through the allowance it gave us on the token.
+        let e = Sub::main(e);
+        set_up(
+            &e,
+            None,
+            invoker_id(&e),
+            child,
+            token_id,
+            amount,
+            step,
+            options,
+        )
+    }
+
+    fn init_for_account(
+        e: Env,
+        child: AccountId,
+        token_id: BytesN<32>,
+        amount: i128,
+        step: u64,
+        options: Options,
+    ) -> Result<(), Error> {
+        let child = Identifier::Account(child);
+        Self::init(e, child, token_id, amount, step, options)
+    }
+
+    fn init_on_behalf(
+        e: Env,
+        salt: BytesN<32>,
+        parent: Identifier,
+        child: Identifier,
+        token_id: BytesN<32>,
+        amount: i128,
+        step: u64,
+        options: Options,
+    ) -> Result<(), Error> {
+        // Anybody else could name whoever they like as the `Parent` (say,
+        // somebody who has already approved this contract), and then pay
+        // themselves from their allowance.
+        check_deployer(&e, &salt)?;
+        set_up(
+            &Sub::main(e),
+            None,
+            parent,
+            child,
+            token_id,
+            amount,
+            step,
+            options,
+        )
+    }
+
+    fn init_indexed(
+        e: Env,
+        child: Identifier,
+        token_id: BytesN<32>,
+        amount: i128,
+        step: u64,
+        options: Options,
+        oracle: Oracle,
+    ) -> Result<(), Error> {
+        // Everything `init` checks and stores applies here, too. The only
+        // difference is that we remember the oracle.
+        let e = Sub::main(e);
+        set_up(
+            &e,
+            None,
+            invoker_id(&e),
+            child,
+            token_id,
+            amount,
+            step,
+            options,
+        )?;
+        e.storage().set(StorageKey::Oracle, oracle);
+
+        Ok(())
+    }
+
+    fn verify_config(e: Env, expected: Config) -> Result<(), Error> {
+        Self::verify_config_for(e, 0, expected)
+    }
+
+    fn verify_config_for(e: Env, id: u64, expected: Config) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let config = Config {
+            parent: parent(&e)?,
+            child: child(&e)?,
+            token_id: token_id(&e)?,
+            amount: amount(&e)?,
+            step: step(&e)?,
+            options: options(&e)?,
+        };
+        if config != expected {
+            return Err(Error::ConfigMismatch);
+        }
+        Ok(())
+    }
+
+    fn withdraw(e: Env) -> Result<u32, Error> {
+        let e = Sub::main(e);
+        pay_out(&e, u64::MAX, u32::MAX)
+    }
+
+    fn request_pause(e: Env, from: u64, until: u64) -> Result<(), Error> {
+        request_pause(&Sub::main(e), from, until)
+    }
+
+    fn approve_pause(e: Env) -> Result<(), Error> {
+        approve_pause(&Sub::main(e))
+    }
+
+    fn reject_pause(e: Env) -> Result<(), Error> {
+        reject_pause(&Sub::main(e))
+    }
+
+    fn invoice_extra(e: Env, amount: i128) -> Result<(), Error> {
+        Self::invoice_extra_for(e, 0, amount)
+    }
+
+    fn invoice_extra_for(e: Env, id: u64, amount: i128) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        if invoker_id(&e) != child(&e)? {
+            return Err(Error::Unauthorized);
+        }
+
+        // One invoice at a time, and it has to actually be for something.
+        if amount <= 0 || e.storage().has(StorageKey::Extra) {
+            return Err(Error::InvalidArguments);
+        }
+
+        e.storage().set(StorageKey::Extra, amount);
+
+        Ok(())
+    }
+
+    fn approve_extra(e: Env) -> Result<(), Error> {
+        Self::approve_extra_for(e, 0)
+    }
+
+    fn approve_extra_for(e: Env, id: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let parent = parent(&e)?;
+        if invoker_id(&e) != parent {
+            return Err(Error::Unauthorized);
+        }
+
+        let extra: i128 = read_opt(&e, StorageKey::Extra)?.ok_or(Error::NoExtraInvoice)?;
+
+        // Here's the rule when money is tight: the regular allowance always
+        // comes first. Whatever is due on the schedule right now is set aside
+        // out of the `Parent`'s allowance, and the extra only goes through if
+        // it fits in what's left. If it doesn't, nothing changes and the
+        // invoice stays open until the `Parent` tops up the allowance.
+        let token_id = token_id(&e)?;
+        let client = token::Client::new(&e, token_id);
+        let available = parent_allowance(&e, &client)?
+            .checked_sub(due_amount(&e)?)
+            .ok_or(Error::Overflow)?;
+        if available < extra {
+            return Err(Error::InsufficientAllowance);
+        }
+
+        client.xfer_from(
+            &Signature::Invoker,
+            &(0 as i128),
+            &parent,
+            &child(&e)?,
+            &extra,
+        );
+        count_delivered(&e, &child(&e)?, extra)?;
+        e.storage().remove(StorageKey::Extra);
+
+        Ok(())
+    }
+
+    fn required_allowance(e: Env, include_extras: bool) -> Result<i128, Error> {
+        required_allowance(&Sub::main(e), include_extras)
+    }
+
+    fn fix_start_epoch(e: Env, start_epoch: u64) -> Result<(), Error> {
+        fix_start_epoch(&Sub::main(e), start_epoch)
+    }
+
+    fn propose_amount(e: Env, amount: i128) -> Result<i128, Error> {
+        let e = Sub::main(e);
+        check_initialized(&e)?;
+        check_acting_alone(&e, &invoker_id(&e))?;
+        propose_amount(&e, invoker_id(&e), amount)
+    }
+
+    fn accept_amount(e: Env) -> Result<(), Error> {
+        accept_amount(&Sub::main(e))
+    }
+
+    fn report_usage(e: Env, units: u64) -> Result<(), Error> {
+        Self::report_usage_for(e, 0, units)
+    }
+
+    fn report_usage_for(e: Env, id: u64, units: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        if invoker_id(&e) != child(&e)? {
+            return Err(Error::Unauthorized);
+        }
+
+        let options = options(&e)?;
+        if options.unit_price == 0 || units == 0 {
+            return Err(Error::InvalidArguments);
+        }
+
+        // Usage goes towards the next withdrawal that hasn't been paid yet.
+        // Once a withdrawal is paid, its usage is final, so anything reported
+        // late simply lands on the one after.
+        settle_pause(&e, e.ledger().timestamp())?;
+        let sched = schedule(&e)?;
+        let cadence = Cadence::load(&e)?;
+        let next_due = cadence.next(sched.latest)?;
+        let period = cadence.index(sched.start, next_due);
+        let key = StorageKey::Usage(period);
+
+        // A vendor reporting a silly number of units (by mistake or not) is
+        // stopped at the cap, rather than emptying the `Parent`'s wallet.
+        let used: u64 = read_opt(&e, key.clone())?.unwrap_or(0);
+        let used = used.checked_add(units).ok_or(Error::Overflow)?;
+        let cap = options
+            .included_units
+            .checked_add(options.max_overage)
+            .ok_or(Error::Overflow)?;
+        if used > cap {
+            return Err(Error::UsageCapExceeded);
+        }
+
+        // Any counter before this one belongs to a withdrawal that lapsed
+        // unpaid, so it's done with, too.
+        forget_periods(&e, StorageKey::UsedPeriods, StorageKey::Usage, |p| {
+            p >= period
+        })?;
+        if !note_period(&e, StorageKey::UsedPeriods, period)? {
+            return Err(Error::UsageCapExceeded);
+        }
+        e.storage().set(key, used);
+
+        Ok(())
+    }
+
+    fn next_withdraw_time(e: Env) -> Result<u64, Error> {
+        Self::next_withdraw_time_for(e, 0)
+    }
+
+    fn next_withdraw_time_for(e: Env, id: u64) -> Result<u64, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        next_withdraw_time(&e)
+    }
+
+    fn time_until_next(e: Env) -> Result<u64, Error> {
+        Self::time_until_next_for(e, 0)
+    }
+
+    fn time_until_next_for(e: Env, id: u64) -> Result<u64, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        Ok(next_withdraw_time(&e)?.saturating_sub(e.ledger().timestamp()))
+    }
+
+    fn can_withdraw(e: Env) -> bool {
+        can_withdraw(&Sub::main(e))
+    }
+
+    fn withdraw_up_to(e: Env, until: u64) -> Result<u32, Error> {
+        Self::withdraw_up_to_for(e, 0, until)
+    }
+
+    fn withdraw_up_to_for(e: Env, id: u64, until: u64) -> Result<u32, Error> {
+        let e = Sub::get(e, id)?;
+        // If nothing had come due by `until`, that's all there is to say, even
+        // if the `Child` has since collected for it.
+        match pay_out(&e, until, u32::MAX) {
+            Err(Error::ChildAlreadyWithdrawn) => Err(Error::NothingDue),
+            result => result,
+        }
+    }
+
+    fn withdraw_periods(e: Env, max_periods: u32) -> Result<u32, Error> {
+        Self::withdraw_periods_for(e, 0, max_periods)
+    }
+
+    fn withdraw_periods_for(e: Env, id: u64, max_periods: u32) -> Result<u32, Error> {
+        let e = Sub::get(e, id)?;
+        if max_periods == 0 {
+            return Err(Error::InvalidArguments);
+        }
+
+        pay_out(&e, u64::MAX, max_periods)
+    }
+
+    fn forecast(e: Env, from: u64, to: u64) -> Result<i128, Error> {
+        Self::forecast_for(e, 0, from, to)
+    }
+
+    fn forecast_for(e: Env, id: u64, from: u64, to: u64) -> Result<i128, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        if from >= to {
+            return Err(Error::InvalidArguments);
+        }
+
+        due_before(&e, to)?
+            .checked_sub(due_before(&e, from)?)
+            .ok_or(Error::Overflow)
+    }
+
+    fn get_status(e: Env) -> Result<Status, Error> {
+        get_status(&Sub::main(e))
+    }
+
+    fn withdraw_count(e: Env) -> Result<u64, Error> {
+        Self::withdraw_count_for(e, 0)
+    }
+
+    fn withdraw_count_for(e: Env, id: u64) -> Result<u64, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        withdraw_count(&e)
+    }
+
+    fn period_index_of(e: Env, timestamp: u64) -> Result<u64, Error> {
+        Self::period_index_of_for(e, 0, timestamp)
+    }
+
+    fn period_index_of_for(e: Env, id: u64, timestamp: u64) -> Result<u64, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let sched = schedule(&e)?;
+        if timestamp < sched.start {
+            return Err(Error::InvalidArguments);
+        }
+        Ok(Cadence::load(&e)?.index(sched.start, timestamp))
+    }
+
+    fn set_invoker_allowlist(e: Env, invokers: Vec<AccountId>) -> Result<(), Error> {
+        Self::set_invoker_allowlist_for(e, 0, invokers)
+    }
+
+    fn set_invoker_allowlist_for(e: Env, id: u64, invokers: Vec<AccountId>) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        check_manager(&e)?;
+
+        if invokers.is_empty() {
+            e.storage().remove(StorageKey::Invokers);
+        } else {
+            e.storage().set(StorageKey::Invokers, invokers);
+        }
+
+        Ok(())
+    }
+
+    fn create(
+        e: Env,
+        child: Identifier,
+        token_id: BytesN<32>,
+        start_epoch: u64,
+        amount: i128,
+        step: u64,
+        options: Options,
+    ) -> Result<u64, Error> {
+        // Ids start at `1`, since `0` is the allowance `init` sets up, and
+        // they're never handed out twice.
+        let main = Sub::main(e.clone());
+        let id: u64 = read_opt(&main, StorageKey::NextId)?.unwrap_or(1);
+        let next = id.checked_add(1).ok_or(Error::Overflow)?;
+
+        let sub = Sub { env: e, id };
+        set_up(
+            &sub,
+            Some(start_epoch),
+            invoker_id(&sub),
+            child,
+            token_id,
+            amount,
+            step,
+            options,
+        )?;
+
+        main.storage().set(StorageKey::NextId, next);
+        Ok(id)
+    }
+
+    fn withdraw_for(e: Env, id: u64) -> Result<u32, Error> {
+        pay_out(&Sub::get(e, id)?, u64::MAX, u32::MAX)
+    }
+
+    fn can_withdraw_for(e: Env, id: u64) -> bool {
+        match Sub::get(e, id) {
+            Ok(sub) => can_withdraw(&sub),
+            Err(_) => false,
+        }
+    }
+
+    fn required_allowance_for(e: Env, id: u64, include_extras: bool) -> Result<i128, Error> {
+        required_allowance(&Sub::get(e, id)?, include_extras)
+    }
+
+    fn propose_amount_for(e: Env, id: u64, amount: i128) -> Result<i128, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        check_acting_alone(&e, &invoker_id(&e))?;
+        propose_amount(&e, invoker_id(&e), amount)
+    }
+
+    fn accept_amount_for(e: Env, id: u64) -> Result<(), Error> {
+        accept_amount(&Sub::get(e, id)?)
+    }
+
+    fn get_status_for(e: Env, id: u64) -> Result<Status, Error> {
+        get_status(&Sub::get(e, id)?)
+    }
+
+    fn request_pause_for(e: Env, id: u64, from: u64, until: u64) -> Result<(), Error> {
+        request_pause(&Sub::get(e, id)?, from, until)
+    }
+
+    fn approve_pause_for(e: Env, id: u64) -> Result<(), Error> {
+        approve_pause(&Sub::get(e, id)?)
+    }
+
+    fn reject_pause_for(e: Env, id: u64) -> Result<(), Error> {
+        reject_pause(&Sub::get(e, id)?)
+    }
+
+    fn cancel_for(e: Env, id: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        check_manager(&e)?;
+        check_acting_alone(&e, &invoker_id(&e))?;
+        cancel(&e)
+    }
+
+    fn close_by_child_for(e: Env, id: u64, final_withdraw: bool) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        if invoker_id(&e) != child(&e)? {
+            return Err(Error::Unauthorized);
+        }
+        close(&e, Role::Child, final_withdraw)
+    }
+
+    fn approve_close_for(e: Env, id: u64) -> Result<(), Error> {
+        approve_close(&Sub::get(e, id)?)
+    }
+
+    fn revoke_close_approval_for(e: Env, id: u64) -> Result<(), Error> {
+        revoke_close_approval(&Sub::get(e, id)?)
+    }
+
+    fn forgive_arrears_for(e: Env, id: u64, periods: u32) -> Result<u32, Error> {
+        forgive_arrears(&Sub::get(e, id)?, periods)
+    }
+
+    fn prepay_for(e: Env, id: u64, periods: u32) -> Result<i128, Error> {
+        prepay(&Sub::get(e, id)?, periods)
+    }
+
+    fn fix_start_epoch_for(e: Env, id: u64, start_epoch: u64) -> Result<(), Error> {
+        fix_start_epoch(&Sub::get(e, id)?, start_epoch)
+    }
+
+    fn fix_step_for(e: Env, id: u64, step: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        check_manager(&e)?;
+        check_acting_alone(&e, &invoker_id(&e))?;
+        fix_step(&e, step)
+    }
+
+    fn reset_for(e: Env, id: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        if invoker_id(&e) != parent(&e)? {
+            return Err(Error::Unauthorized);
+        }
+        reset(&e)
+    }
+
+    fn history_for(e: Env, id: u64, offset: u32, limit: u32) -> Result<Vec<Receipt>, Error> {
+        history(&Sub::get(e, id)?, offset, limit)
+    }
+
+    fn prune_receipts_for(e: Env, id: u64, keep_latest: u32) -> Result<u32, Error> {
+        prune_receipts(&Sub::get(e, id)?, keep_latest)
+    }
+
+    fn get_state_for(e: Env, id: u64) -> Result<FullState, Error> {
+        get_state(&Sub::get(e, id)?)
+    }
+
+    fn subscriptions_of(
+        e: Env,
+        who: Identifier,
+        role: Role,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<u64>, Error> {
+        let key = match role {
+            Role::Parent => StorageKey::ByParent(who),
+            Role::Child => StorageKey::ByChild(who),
+        };
+        let e = Sub::main(e);
+        let ids: Vec<u64> = match read_opt(&e, key)? {
+            Some(ids) => ids,
+            None => return Ok(Vec::new(&e)),
+        };
+
+        let mut page = Vec::new(&e);
+        let end = offset.saturating_add(limit.min(MAX_PAGE)).min(ids.len());
+        for i in offset..end {
+            page.push_back(
+                ids.get(i)
+                    .ok_or(Error::CorruptData)?
+                    .map_err(|_| Error::CorruptData)?,
+            );
+        }
+        Ok(page)
+    }
+
+    fn due_subscriptions(e: Env, cursor: u64, limit: u32) -> Result<(Vec<u64>, u64), Error> {
+        let main = Sub::main(e.clone());
+        let next_id: u64 = read_opt(&main, StorageKey::NextId)?.unwrap_or(1);
+        let limit = limit.min(MAX_PAGE);
+
+        // We never look at more than `MAX_SCAN` subscriptions, so a long run
+        // of ones with nothing due can't blow the budget.
+        let mut due = Vec::new(&e);
+        let mut id = cursor;
+        let last = cursor.saturating_add(MAX_SCAN).min(next_id);
+        while id < last && due.len() < limit {
+            if can_withdraw(&Sub { env: e.clone(), id }) {
+                due.push_back(id);
+            }
+            id += 1;
+        }
+
+        Ok((due, if id >= next_id { 0 } else { id }))
+    }
+
+    fn withdraw_batch(e: Env, ids: Vec<u64>) -> Result<Vec<Outcome>, Error> {
+        if ids.len() > MAX_BATCH {
+            return Err(Error::InvalidArguments);
+        }
+
+        // A withdrawal can fail after it has moved some of the money (a swap
+        // that comes out short, say, or the second of several payers), so
+        // each one is paid out in an invocation of its own, which is undone
+        // on its own if it fails. That invocation is by this contract, so
+        // whoever invoked the batch is checked here instead.
+        let mut outcomes = Vec::new(&e);
+        for id in ids.iter() {
+            let id = id.map_err(|_| Error::CorruptData)?;
+            let checked = Sub::get(e.clone(), id).and_then(|sub| {
+                check_initialized(&sub)?;
+                check_withdrawer(&sub, &options(&sub)?)
+            });
+            let outcome = match checked {
+                Ok(()) => match e.try_invoke_contract::<u32, Error>(
+                    &e.current_contract(),
+                    &symbol!("batch_pay"),
+                    vec![&e, id.into_val(&e)],
+                ) {
+                    Ok(Ok(periods)) => Outcome::Paid(periods),
+                    Err(Ok(err)) => Outcome::Failed(err as u32),
+                    _ => Outcome::Trapped,
+                },
+                Err(err) => Outcome::Failed(err as u32),
+            };
+            outcomes.push_back(outcome);
+        }
+
+        Ok(outcomes)
+    }
+
+    fn batch_pay(e: Env, id: u64) -> Result<u32, Error> {
+        if e.invoker() != Address::Contract(e.current_contract()) {
+            return Err(Error::InvalidInvoker);
+        }
+        let e = Sub::get(e, id)?;
+        collect(&e, &options(&e)?, None, u64::MAX, u32::MAX)
+    }
+
+    fn upgrade(e: Env, wasm_hash: BytesN<32>) -> Result<(), Error> {
+        // This is the way out of a layout we can't read, so it doesn't check
+        // the schema version.
+        let e = Sub::main(e);
+        if !is_initialized(&e) {
+            return Err(Error::ContractNotInitialized);
+        }
+        check_manager(&e)?;
+        check_acting_alone(&e, &invoker_id(&e))?;
+
+        upgrade(&e, wasm_hash)
+    }
+
+    fn migrate(e: Env) -> Result<bool, Error> {
+        let e = Sub::main(e);
+        if !is_initialized(&e) {
+            return Err(Error::ContractNotInitialized);
+        }
+
+        // There's no going back to an older layout.
+        let mut version = schema_version(&e)?;
+        if version > SCHEMA_VERSION {
+            return Err(Error::MigrationRequired);
+        }
+
+        while version < SCHEMA_VERSION {
+            let done = match version {
+                0 => {
+                    migrate_from_v0(&e)?;
+                    true
+                }
+                1 => migrate_from_v1(&e)?,
+                _ => return Err(Error::CorruptData),
+            };
+            if !done {
+                return Ok(false);
+            }
+            version += 1;
+            e.storage().set(StorageKey::SchemaVersion, version);
+        }
+
+        Ok(true)
+    }
+
+    fn set_admin(e: Env, admin: Identifier) -> Result<(), Error> {
+        Self::set_admin_for(e, 0, admin)
+    }
+
+    fn set_admin_for(e: Env, id: u64, admin: Identifier) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        check_manager(&e)?;
+
+        // The `Child` would end up approving its own pauses.
+        if admin == parent(&e)? || admin == child(&e)? {
+            return Err(Error::InvalidArguments);
+        }
+
+        e.storage().set(StorageKey::Admin, admin);
+
+        Ok(())
+    }
+
+    fn clear_admin(e: Env) -> Result<(), Error> {
+        Self::clear_admin_for(e, 0)
+    }
+
+    fn clear_admin_for(e: Env, id: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        check_manager(&e)?;
+
+        e.storage().remove(StorageKey::Admin);
+
+        Ok(())
+    }
+
+    fn propose_parent(e: Env, new_parent: Identifier) -> Result<(), Error> {
+        Self::propose_parent_for(e, 0, new_parent)
+    }
+
+    fn propose_parent_for(e: Env, id: u64, new_parent: Identifier) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        // This one is the `Parent`'s alone: it's their money.
+        let parent = parent(&e)?;
+        if invoker_id(&e) != parent {
+            return Err(Error::Unauthorized);
+        }
+
+        if new_parent == parent || new_parent == child(&e)? {
+            return Err(Error::InvalidArguments);
+        }
+
+        e.storage().set(StorageKey::PendingParent, new_parent);
+
+        Ok(())
+    }
+
+    fn accept_parent(e: Env) -> Result<(), Error> {
+        Self::accept_parent_for(e, 0)
+    }
+
+    fn accept_parent_for(e: Env, id: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let new_parent: Identifier =
+            read_opt(&e, StorageKey::PendingParent)?.ok_or(Error::NoParentProposal)?;
+        if invoker_id(&e) != new_parent {
+            return Err(Error::Unauthorized);
+        }
+
+        unlist_subscription(&e, StorageKey::ByParent(parent(&e)?))?;
+        list_subscription(&e, StorageKey::ByParent(new_parent.clone()))?;
+        set_parent(&e, new_parent)?;
+        e.storage().remove(StorageKey::PendingParent);
+
+        // The old `Parent`'s admin was theirs, not the new one's.
+        e.storage().remove(StorageKey::Admin);
+
+        Ok(())
+    }
+
+    fn close_by_child(e: Env, final_withdraw: bool) -> Result<(), Error> {
+        let e = Sub::main(e);
+        check_initialized(&e)?;
+
+        if invoker_id(&e) != child(&e)? {
+            return Err(Error::Unauthorized);
+        }
+        close(&e, Role::Child, final_withdraw)
+    }
+
+    fn cancel(e: Env) -> Result<(), Error> {
+        let e = Sub::main(e);
+        check_initialized(&e)?;
+        check_manager(&e)?;
+        check_acting_alone(&e, &invoker_id(&e))?;
+
+        cancel(&e)
+    }
+
+    fn approve_close(e: Env) -> Result<(), Error> {
+        approve_close(&Sub::main(e))
+    }
+
+    fn revoke_close_approval(e: Env) -> Result<(), Error> {
+        revoke_close_approval(&Sub::main(e))
+    }
+
+    fn forgive_arrears(e: Env, periods: u32) -> Result<u32, Error> {
+        forgive_arrears(&Sub::main(e), periods)
+    }
+
+    fn prepay(e: Env, periods: u32) -> Result<i128, Error> {
+        prepay(&Sub::main(e), periods)
+    }
+
+    fn propose_skip(e: Env, period_index: u64) -> Result<(), Error> {
+        Self::propose_skip_for(e, 0, period_index)
+    }
+
+    fn propose_skip_for(e: Env, id: u64, period_index: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let (invoker, _, _) = party(&e)?;
+        let first = first_skippable(&e)?;
+        if options(&e)?.streaming || period_index < first {
+            return Err(Error::InvalidArguments);
+        }
+
+        // Proposals for withdrawals that have come due since can't be
+        // accepted any more, so they make room for new ones.
+        forget_periods(&e, StorageKey::SkipReqs, StorageKey::SkipReq, |p| {
+            p >= first
+        })?;
+        if !note_period(&e, StorageKey::SkipReqs, period_index)? {
+            return Err(Error::InvalidArguments);
+        }
+        e.storage().set(StorageKey::SkipReq(period_index), invoker);
+
+        Ok(())
+    }
+
+    fn accept_skip(e: Env, period_index: u64) -> Result<(), Error> {
+        Self::accept_skip_for(e, 0, period_index)
+    }
+
+    fn accept_skip_for(e: Env, id: u64, period_index: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let (invoker, _, _) = party(&e)?;
+        let proposer: Identifier =
+            read_opt(&e, StorageKey::SkipReq(period_index))?.ok_or(Error::NoSkipProposal)?;
+        if proposer == invoker {
+            return Err(Error::Unauthorized);
+        }
+
+        // It might have come due while it was waiting.
+        let first = first_skippable(&e)?;
+        if period_index < first {
+            return Err(Error::InvalidArguments);
+        }
+
+        // Skips that are behind us don't matter any more, so we let them go.
+        let skipped: Vec<u64> =
+            read_opt(&e, StorageKey::SkippedPeriods)?.unwrap_or_else(|| Vec::new(&e));
+        let mut kept = Vec::new(&e);
+        for index in skipped.iter() {
+            let index = index.map_err(|_| Error::CorruptData)?;
+            if index >= first {
+                kept.push_back(index);
+            }
+        }
+        kept.push_back(period_index);
+        e.storage().set(StorageKey::SkippedPeriods, kept);
+        forget_periods(&e, StorageKey::SkipReqs, StorageKey::SkipReq, |p| {
+            p != period_index
+        })?;
+
+        Ok(())
+    }
+
+    fn report_shortfall(e: Env) -> Result<u32, Error> {
+        Self::report_shortfall_for(e, 0)
+    }
+
+    fn report_shortfall_for(e: Env, id: u64) -> Result<u32, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        settle_pause(&e, e.ledger().timestamp())?;
+        let sched = schedule(&e)?;
+        let owed = overdue(&e, &sched, u32::MAX)?;
+        if owed.periods == 0 {
+            return Err(Error::NothingDue);
+        }
+
+        // The allowance isn't the only thing that can run short: `xfer_from`
+        // needs the balance, too.
+        let token_id = token_id(&e)?;
+        let client = token::Client::new(&e, token_id);
+        let parent = parent(&e)?;
+        if parent_allowance(&e, &client)? >= owed.total && client.balance(&parent) >= owed.total {
+            return Ok(0);
+        }
+
+        // Only the first shortfall counts for each withdrawal. Those before
+        // the first unpaid one lapsed, and don't count any more. At most
+        // `MAX_SCAN` withdrawals are kept track of at once, and any after
+        // that aren't marked short until the ones before them are paid.
+        let cadence = Cadence::load(&e)?;
+        let first = cadence.index(sched.start, cadence.next(sched.latest)?);
+        forget_periods(&e, StorageKey::FailedPeriods, StorageKey::FailedAt, |p| {
+            p >= first
+        })?;
+        let mut new = false;
+        for period in first..first + owed.periods as u64 {
+            if !e.storage().has(StorageKey::FailedAt(period)) {
+                if !note_period(&e, StorageKey::FailedPeriods, period)? {
+                    break;
+                }
+                e.storage()
+                    .set(StorageKey::FailedAt(period), e.ledger().timestamp());
+                new = true;
+            }
+        }
+        e.events().publish(
+            (symbol!("shortfall"), e.id, first),
+            (owed.periods, owed.total),
+        );
+
+        // Reporting the same shortfall over and over doesn't make it any more
+        // of one, so only a withdrawal that's newly short counts towards
+        // suspension, and only once the grace has run out.
+        let options = options(&e)?;
+        let max_failures = options.max_failures;
+        let late = options.grace_periods == 0
+            || behind_by_more_than(&sched, &Pricing::load(&e)?, options.grace_periods)?;
+        if new && late && max_failures != 0 {
+            let failures: u32 = read_opt(&e, StorageKey::FailureCount)?.unwrap_or(0);
+            let failures = failures.checked_add(1).ok_or(Error::Overflow)?;
+            e.storage().set(StorageKey::FailureCount, failures);
+            if failures >= max_failures && !e.storage().has(StorageKey::Suspended) {
+                e.storage().set(StorageKey::Suspended, true);
+                e.events().publish((symbol!("suspend"), e.id), failures);
+            }
+        }
+
+        Ok(owed.periods)
+    }
+
+    fn reactivate(e: Env) -> Result<(), Error> {
+        Self::reactivate_for(e, 0)
+    }
+
+    fn reactivate_for(e: Env, id: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        check_manager(&e)?;
+
+        if !e.storage().has(StorageKey::Suspended) {
+            return Err(Error::InvalidArguments);
+        }
+
+        // Topping up comes first.
+        let token_id = token_id(&e)?;
+        let client = token::Client::new(&e, token_id);
+        check_funded(&e, &client, &overdue(&e, &schedule(&e)?, u32::MAX)?)?;
+
+        e.storage().remove(StorageKey::Suspended);
+        e.storage().remove(StorageKey::FailureCount);
+        e.events().publish((symbol!("reactivate"), e.id), ());
+
+        Ok(())
+    }
+
+    fn recipients(e: Env) -> Result<Vec<(Identifier, u32)>, Error> {
+        Self::recipients_for(e, 0)
+    }
+
+    fn recipients_for(e: Env, id: u64) -> Result<Vec<(Identifier, u32)>, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        current_shares(&e)
+    }
+
+    fn propose_shares(e: Env, shares: Vec<(Identifier, u32)>) -> Result<(), Error> {
+        Self::propose_shares_for(e, 0, shares)
+    }
+
+    fn propose_shares_for(e: Env, id: u64, shares: Vec<(Identifier, u32)>) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        check_manager(&e)?;
+        check_shares(&shares)?;
+
+        // Only the recipients losing out have a say.
+        let current = current_shares(&e)?;
+        let mut pending = Vec::new(&e);
+        for recipient in current.iter() {
+            let (id, share) = recipient.map_err(|_| Error::CorruptData)?;
+            if share_of(&shares, &id)? < share {
+                pending.push_back(id);
+            }
+        }
+        if pending.is_empty() {
+            apply_shares(&e, shares);
+            return Ok(());
+        }
+
+        let window = options(&e)?.shares_window;
+        let expires_at = if window == 0 {
+            u64::MAX
+        } else {
+            e.ledger()
+                .timestamp()
+                .checked_add(window)
+                .ok_or(Error::Overflow)?
+        };
+        e.storage().set(
+            StorageKey::SharesProposal,
+            SharesProposal {
+                shares,
+                pending,
+                expires_at,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn accept_shares(e: Env) -> Result<(), Error> {
+        Self::accept_shares_for(e, 0)
+    }
+
+    fn accept_shares_for(e: Env, id: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let mut proposal: SharesProposal =
+            read_opt(&e, StorageKey::SharesProposal)?.ok_or(Error::NoShareProposal)?;
+        if e.ledger().timestamp() >= proposal.expires_at {
+            e.storage().remove(StorageKey::SharesProposal);
+            return Err(Error::NoShareProposal);
+        }
+
+        let invoker = invoker_id(&e);
+        let i = proposal
+            .pending
+            .first_index_of(&invoker)
+            .ok_or(Error::Unauthorized)?;
+        proposal.pending.remove(i);
+        if proposal.pending.is_empty() {
+            apply_shares(&e, proposal.shares);
+        } else {
+            e.storage().set(StorageKey::SharesProposal, proposal);
+        }
+
+        Ok(())
+    }
+
+    fn cancel_my_share(e: Env) -> Result<(), Error> {
+        Self::cancel_my_share_for(e, 0)
+    }
+
+    fn cancel_my_share_for(e: Env, id: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let invoker = invoker_id(&e);
+        let payers: Vec<(Identifier, u32)> =
+            read_opt(&e, StorageKey::Payers)?.ok_or(Error::Unauthorized)?;
+        let mut kept = Vec::new(&e);
+        let mut left: u32 = 0;
+        for entry in payers.iter() {
+            let (payer, share) = entry.map_err(|_| Error::CorruptData)?;
+            if payer != invoker {
+                kept.push_back((payer, share));
+                left += share;
+            }
+        }
+        if kept.len() == payers.len() {
+            return Err(Error::Unauthorized);
+        }
+        e.events().publish((symbol!("unshare"), e.id), invoker);
+
+        if kept.is_empty() {
+            e.storage().remove(StorageKey::Payers);
+            return close(&e, Role::Parent, false);
+        }
+
+        // The rest scale their shares back up to everything, with the
+        // remainder going to the first of them.
+        let mut shares = Vec::new(&e);
+        let mut total: u32 = 0;
+        for entry in kept.iter() {
+            let (payer, share) = entry.map_err(|_| Error::CorruptData)?;
+            let share = (share as i128 * BPS / left as i128) as u32;
+            total += share;
+            shares.push_back((payer, share));
+        }
+        let (first, share) = shares
+            .get(0)
+            .ok_or(Error::CorruptData)?
+            .map_err(|_| Error::CorruptData)?;
+        shares.set(0, (first, share + (BPS as u32 - total)));
+        e.storage().set(StorageKey::Payers, shares);
+
+        if options(&e)?.suspend_on_payer_exit {
+            e.storage().set(StorageKey::Suspended, true);
+            e.events().publish((symbol!("suspend"), e.id), 0_u32);
+        }
+
+        Ok(())
+    }
+
+    fn payers(e: Env) -> Result<Vec<(Identifier, u32)>, Error> {
+        Self::payers_for(e, 0)
+    }
+
+    fn payers_for(e: Env, id: u64) -> Result<Vec<(Identifier, u32)>, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        match read_opt(&e, StorageKey::Payers)? {
+            Some(payers) => Ok(payers),
+            None => Ok(Vec::from_array(&e, [(parent(&e)?, BPS as u32)])),
+        }
+    }
+
+    fn sponsor(e: Env, periods: u32) -> Result<(), Error> {
+        Self::sponsor_for(e, 0, periods)
+    }
+
+    fn sponsor_for(e: Env, id: u64, periods: u32) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        if periods == 0 || schedule(&e)?.ended {
+            return Err(Error::InvalidArguments);
+        }
+        if e.storage().has(StorageKey::Sponsorship) {
+            return Err(Error::AlreadySponsored);
+        }
+
+        let sponsor = invoker_id(&e);
+        e.storage().set(
+            StorageKey::Sponsorship,
+            Sponsorship {
+                sponsor: sponsor.clone(),
+                periods,
+            },
+        );
+        e.events()
+            .publish((symbol!("sponsor"), e.id), (sponsor, periods));
+
+        Ok(())
+    }
+
+    fn revoke_sponsorship(e: Env) -> Result<(), Error> {
+        Self::revoke_sponsorship_for(e, 0)
+    }
+
+    fn revoke_sponsorship_for(e: Env, id: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        check_manager(&e)?;
+
+        if !e.storage().has(StorageKey::Sponsorship) {
+            return Err(Error::InvalidArguments);
+        }
+        e.storage().remove(StorageKey::Sponsorship);
+        e.events().publish((symbol!("unsponsor"), e.id), ());
+
+        Ok(())
+    }
+
+    fn post_collateral(e: Env, amount: i128) -> Result<(), Error> {
+        Self::post_collateral_for(e, 0, amount)
+    }
+
+    fn post_collateral_for(e: Env, id: u64, amount: i128) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        if invoker_id(&e) != parent(&e)? {
+            return Err(Error::Unauthorized);
+        }
+        if amount <= 0 || options(&e)?.streaming {
+            return Err(Error::InvalidArguments);
+        }
+        if e.storage().has(StorageKey::EndsAt) {
+            return Err(Error::Cancelled);
+        }
+
+        let token_id = token_id(&e)?;
+        let client = token::Client::new(&e, token_id);
+        let contract = Identifier::Contract(e.current_contract());
+        if parent_allowance(&e, &client)? < amount {
+            return Err(Error::InsufficientAllowance);
+        }
+        client.xfer_from(
+            &Signature::Invoker,
+            &(0 as i128),
+            &parent(&e)?,
+            &contract,
+            &amount,
+        );
+
+        let collateral: i128 = read_opt(&e, StorageKey::Collateral)?.unwrap_or(0);
+        let collateral = collateral.checked_add(amount).ok_or(Error::Overflow)?;
+        e.storage().set(StorageKey::Collateral, collateral);
+        e.events()
+            .publish((symbol!("collateral"), e.id), (amount, collateral));
+
+        Ok(())
+    }
+
+    fn claim_from_collateral(e: Env, period_index: u64) -> Result<i128, Error> {
+        Self::claim_from_collateral_for(e, 0, period_index)
+    }
+
+    fn claim_from_collateral_for(e: Env, id: u64, period_index: u64) -> Result<i128, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let child = child(&e)?;
+        if invoker_id(&e) != child {
+            return Err(Error::Unauthorized);
+        }
+        let options = options(&e)?;
+        if options.streaming {
+            return Err(Error::InvalidArguments);
+        }
+
+        // Withdrawals are paid in order, so it has to be the first unpaid
+        // one, and it has to have been reported short.
+        settle_pause(&e, e.ledger().timestamp())?;
+        let sched = schedule(&e)?;
+        let cadence = Cadence::load(&e)?;
+        let due = cadence.next(sched.latest)?;
+        let first = cadence.index(sched.start, due);
+        if period_index < first {
+            return Err(Error::ChildAlreadyWithdrawn);
+        }
+        if period_index > first || !e.storage().has(StorageKey::FailedAt(period_index)) {
+            return Err(Error::InvalidArguments);
+        }
+
+        // And it has to have stayed unpaid for another whole period.
+        if e.ledger().timestamp() < cadence.next(due)? {
+            return Err(Error::NothingDue);
+        }
+        let owed = within_cap(&e, &sched, overdue(&e, &sched, 1)?)?;
+        if owed.periods == 0 {
+            return Err(Error::NothingDue);
+        }
+
+        let collateral: i128 = read_opt(&e, StorageKey::Collateral)?.unwrap_or(0);
+        if collateral < owed.total {
+            return Err(Error::InsufficientCollateral);
+        }
+        let token_id = token_id(&e)?;
+        let client = token::Client::new(&e, token_id);
+        if owed.total > 0 {
+            client.xfer(&Signature::Invoker, &(0 as i128), &payee(&e)?, &owed.total);
+            count_delivered(&e, &payee(&e)?, owed.total)?;
+        }
+        e.storage()
+            .set(StorageKey::Collateral, collateral - owed.total);
+
+        // No fee comes out of the collateral.
+        let options = Options {
+            fee_bps: 0,
+            ..options
+        };
+        record(&e, &options, &sched, &owed, None, symbol!("slash"))?;
+
+        Ok(owed.total)
+    }
+
+    fn reclaim_collateral(e: Env) -> Result<i128, Error> {
+        Self::reclaim_collateral_for(e, 0)
+    }
+
+    fn reclaim_collateral_for(e: Env, id: u64) -> Result<i128, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let parent = parent(&e)?;
+        if invoker_id(&e) != parent {
+            return Err(Error::Unauthorized);
+        }
+        let sched = schedule(&e)?;
+        if !sched.ended || overdue(&e, &sched, 1)?.periods != 0 {
+            return Err(Error::InvalidArguments);
+        }
+
+        let collateral: i128 = read_opt(&e, StorageKey::Collateral)?.unwrap_or(0);
+        if collateral > 0 {
+            let token_id = token_id(&e)?;
+            let client = token::Client::new(&e, token_id);
+            client.xfer(&Signature::Invoker, &(0 as i128), &parent, &collateral);
+        }
+        e.storage().remove(StorageKey::Collateral);
+        e.events().publish((symbol!("reclaim"), e.id), collateral);
+
+        Ok(collateral)
+    }
+
+    fn assign_payments(e: Env, assignee: Identifier, periods: u32) -> Result<(), Error> {
+        Self::assign_payments_for(e, 0, assignee, periods)
+    }
+
+    fn assign_payments_for(
+        e: Env,
+        id: u64,
+        assignee: Identifier,
+        periods: u32,
+    ) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let child = child(&e)?;
+        if invoker_id(&e) != child {
+            return Err(Error::Unauthorized);
+        }
+        if periods == 0
+            || assignee == child
+            || e.storage().has(StorageKey::Recipients)
+            || schedule(&e)?.ended
+        {
+            return Err(Error::InvalidArguments);
+        }
+        if e.storage().has(StorageKey::Assignment) {
+            return Err(Error::AlreadyAssigned);
+        }
+
+        e.storage().set(
+            StorageKey::Assignment,
+            Assignment {
+                assignee: assignee.clone(),
+                periods,
+            },
+        );
+        e.events()
+            .publish((symbol!("assign"), e.id), (assignee, periods));
+
+        Ok(())
+    }
+
+    fn renounce_assignment(e: Env) -> Result<(), Error> {
+        Self::renounce_assignment_for(e, 0)
+    }
+
+    fn renounce_assignment_for(e: Env, id: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let assignment: Assignment =
+            read_opt(&e, StorageKey::Assignment)?.ok_or(Error::InvalidArguments)?;
+        if invoker_id(&e) != assignment.assignee {
+            return Err(Error::Unauthorized);
+        }
+        e.storage().remove(StorageKey::Assignment);
+        e.events()
+            .publish((symbol!("renounce"), e.id), assignment.periods);
+
+        Ok(())
+    }
+
+    fn set_metadata(e: Env, name: Symbol, reference: Bytes) -> Result<(), Error> {
+        Self::set_metadata_for(e, 0, name, reference)
+    }
+
+    fn set_metadata_for(e: Env, id: u64, name: Symbol, reference: Bytes) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        check_manager(&e)?;
+
+        let metadata = Metadata { name, reference };
+        check_metadata(&metadata)?;
+        e.storage().set(StorageKey::Metadata, metadata.clone());
+        e.events().publish((symbol!("metadata"), e.id), metadata);
+
+        Ok(())
+    }
+
+    fn get_metadata(e: Env) -> Result<Option<Metadata>, Error> {
+        Self::get_metadata_for(e, 0)
+    }
+
+    fn get_metadata_for(e: Env, id: u64) -> Result<Option<Metadata>, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        read_opt(&e, StorageKey::Metadata)
+    }
+
+    fn version(_e: Env) -> (u32, u32, u32) {
+        VERSION
+    }
+
+    fn features(e: Env) -> Vec<Symbol> {
+        let mut features = vec![
+            &e,
+            symbol!("multisub"),
+            symbol!("calendar"),
+            symbol!("streaming"),
+            symbol!("metered"),
+            symbol!("splits"),
+            symbol!("payers"),
+            symbol!("sponsor"),
+            symbol!("collateral"),
+            symbol!("signed"),
+            symbol!("multisig"),
+            symbol!("tokenswap"),
+            symbol!("measured"),
+            symbol!("swap"),
+            symbol!("multitoken"),
+            symbol!("hooks"),
+            symbol!("policy"),
+            symbol!("gate"),
+            symbol!("registry"),
+            symbol!("state"),
+            symbol!("receipts"),
+            symbol!("cap"),
+            symbol!("ratelimit"),
+            symbol!("tolerance"),
+            symbol!("lapse"),
+            symbol!("anchor"),
+            symbol!("reset"),
+            symbol!("preview"),
+            symbol!("obligation"),
+        ];
+        if cfg!(feature = "factory") {
+            features.push_back(symbol!("factory"));
+        }
+        features
+    }
+
+    fn init_with_signature(
+        e: Env,
+        sig: Signature,
+        nonce: i128,
+        child: Identifier,
+        token_id: BytesN<32>,
+        amount: i128,
+        step: u64,
+        options: Options,
+    ) -> Result<(), Error> {
+        let e = Sub::main(e);
+        let parent = sig.identifier(&e);
+        let args = (
+            parent.clone(),
+            nonce,
+            child.clone(),
+            token_id.clone(),
+            amount,
+            step,
+            options.clone(),
+        );
+        authorize(&e, &sig, nonce, symbol!("init"), args)?;
+        set_up(&e, None, parent, child, token_id, amount, step, options)
+    }
+
+    fn nonce(e: Env, id: Identifier) -> Result<i128, Error> {
+        let e = Sub::main(e);
+        Ok(read_opt(&e, StorageKey::Nonce(id))?.unwrap_or(0))
+    }
+
+    fn propose_amount_signed(
+        e: Env,
+        sig: Signature,
+        nonce: i128,
+        amount: i128,
+    ) -> Result<i128, Error> {
+        let e = Sub::main(e);
+        let signer = signer(&e, &sig)?;
+        let args = (signer.clone(), nonce, amount);
+        authorize(&e, &sig, nonce, symbol!("amount"), args)?;
+        propose_amount(&e, signer, amount)
+    }
+
+    fn propose_amount_signed_for(
+        e: Env,
+        id: u64,
+        sig: Signature,
+        nonce: i128,
+        amount: i128,
+    ) -> Result<i128, Error> {
+        let e = Sub::get(e, id)?;
+        let signer = signer(&e, &sig)?;
+        let args = (signer.clone(), id, nonce, amount);
+        authorize(&e, &sig, nonce, symbol!("amount"), args)?;
+        propose_amount(&e, signer, amount)
+    }
+
+    fn cancel_signed(e: Env, sig: Signature, nonce: i128) -> Result<(), Error> {
+        let e = Sub::main(e);
+        let signer = signer(&e, &sig)?;
+        authorize(&e, &sig, nonce, symbol!("cancel"), (signer, nonce))?;
+        cancel(&e)
+    }
+
+    fn cancel_signed_for(e: Env, id: u64, sig: Signature, nonce: i128) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        let signer = signer(&e, &sig)?;
+        authorize(&e, &sig, nonce, symbol!("cancel"), (signer, id, nonce))?;
+        cancel(&e)
+    }
+
+    fn approve_action(e: Env, action: Action) -> Result<bool, Error> {
+        Self::approve_action_for(e, 0, action)
+    }
+
+    fn approve_action_for(e: Env, id: u64, action: Action) -> Result<bool, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let signers = options(&e)?.signers.ok_or(Error::Unauthorized)?;
+        let signer = match e.invoker() {
+            Address::Account(id) if signers.accounts.contains(&id) => id,
+            _ => return Err(Error::Unauthorized),
+        };
+        // The code belongs to the whole contract, so only subscription `0`'s
+        // signers get a say in it, just as only its `Parent` can `upgrade`.
+        if e.id != 0 && matches!(action, Action::Upgrade(_)) {
+            return Err(Error::Unauthorized);
+        }
+
+        // The approvals are for exactly this action, arguments and all.
+        let hash = e.compute_hash_sha256(action.clone().serialize(&e));
+        let now = e.ledger().timestamp();
+        let mut approvals = match read_opt::<Approvals>(&e, StorageKey::PendingAction)? {
+            Some(approvals) if approvals.action == hash && approvals.expires_at > now => approvals,
+            _ => Approvals {
+                action: hash,
+                approvers: Vec::new(&e),
+                expires_at: now
+                    .checked_add(signers.approval_window)
+                    .ok_or(Error::Overflow)?,
+            },
+        };
+        if !approvals.approvers.contains(&signer) {
+            approvals.approvers.push_back(signer.clone());
+        }
+        let count = approvals.approvers.len();
+        e.events()
+            .publish((symbol!("approval"), e.id), (signer, count));
+
+        if count < signers.threshold {
+            e.storage().set(StorageKey::PendingAction, approvals);
+            return Ok(false);
+        }
+        e.storage().remove(StorageKey::PendingAction);
+        match action {
+            Action::Amount(amount) => {
+                propose_amount(&e, parent(&e)?, amount)?;
+            }
+            Action::Cancel => cancel(&e)?,
+            Action::Upgrade(wasm_hash) => upgrade(&e, wasm_hash)?,
+            Action::Token(proposal) => propose_token(&e, proposal)?,
+            Action::Step(step) => fix_step(&e, step)?,
+        }
+        Ok(true)
+    }
+
+    fn propose_token(e: Env, token_id: BytesN<32>, amount: i128) -> Result<(), Error> {
+        Self::propose_token_for(e, 0, token_id, amount)
+    }
+
+    fn propose_token_for(e: Env, id: u64, token_id: BytesN<32>, amount: i128) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        check_manager(&e)?;
+        check_acting_alone(&e, &invoker_id(&e))?;
+
+        propose_token(&e, TokenProposal { token_id, amount })
+    }
+
+    fn accept_token(e: Env) -> Result<(), Error> {
+        Self::accept_token_for(e, 0)
+    }
+
+    fn accept_token_for(e: Env, id: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        if invoker_id(&e) != child(&e)? {
+            return Err(Error::Unauthorized);
+        }
+        let proposal: TokenProposal =
+            read_opt(&e, StorageKey::PendingToken)?.ok_or(Error::NoTokenProposal)?;
+
+        // Whatever is owed so far is owed in the old token.
+        let collateral: i128 = read_opt(&e, StorageKey::Collateral)?.unwrap_or(0);
+        if due_amount(&e)? != 0 || e.storage().has(StorageKey::Extra) || collateral != 0 {
+            return Err(Error::ArrearsOutstanding);
+        }
+
+        // Any change to the `Amount` that was in the works is priced in the
+        // old token, too, so it goes.
+        let old = token_id(&e)?;
+        set_token_id(&e, proposal.token_id.clone())?;
+        store_amount(&e, proposal.amount)?;
+        e.storage().remove(StorageKey::RateHistory);
+        e.storage().remove(StorageKey::PendingAmt);
+        e.storage().remove(StorageKey::PendingToken);
+        e.events().publish(
+            (symbol!("token"), e.id),
+            (old, proposal.token_id, proposal.amount),
+        );
+
+        Ok(())
+    }
+
+    fn reconcile(e: Env) -> Result<ReconcileReport, Error> {
+        Self::reconcile_for(e, 0)
+    }
+
+    fn reconcile_for(e: Env, id: u64) -> Result<ReconcileReport, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        let client = token::Client::new(&e, token_id(&e)?);
+        let actual = client.balance(&child(&e)?);
+        let delivered: i128 = read_opt(&e, StorageKey::Delivered)?.unwrap_or(0);
+        let now = e.ledger().timestamp();
+
+        // The very first time, there's nothing to compare against yet.
+        let last = read_opt::<Checkpoint>(&e, StorageKey::Checkpoint)?.unwrap_or(Checkpoint {
+            at: now,
+            balance: actual,
+            delivered,
+        });
+        let paid = delivered
+            .checked_sub(last.delivered)
+            .ok_or(Error::Overflow)?;
+        let expected = last.balance.checked_add(paid).ok_or(Error::Overflow)?;
+        let report = ReconcileReport {
+            since: last.at,
+            paid,
+            expected,
+            actual,
+        };
+
+        let short = expected.checked_sub(actual).ok_or(Error::Overflow)?;
+        if short > options(&e)?.reconcile_tolerance {
+            e.events()
+                .publish((symbol!("drift"), e.id), (expected, actual));
+        }
+        e.storage().set(
+            StorageKey::Checkpoint,
+            Checkpoint {
+                at: now,
+                balance: actual,
+                delivered,
+            },
+        );
+
+        Ok(report)
+    }
+
+    fn withdraw_in(e: Env, token_id: BytesN<32>) -> Result<u32, Error> {
+        Self::withdraw_in_for(e, 0, token_id)
+    }
+
+    fn withdraw_in_for(e: Env, id: u64, token_id: BytesN<32>) -> Result<u32, Error> {
+        let e = Sub::get(e, id)?;
+        pay_out_in(&e, Some(token_id), u64::MAX, u32::MAX)
+    }
+
+    fn set_preferred_token(e: Env, token_id: BytesN<32>) -> Result<(), Error> {
+        Self::set_preferred_token_for(e, 0, token_id)
+    }
+
+    fn set_preferred_token_for(e: Env, id: u64, token_id: BytesN<32>) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        if invoker_id(&e) != child(&e)? {
+            return Err(Error::Unauthorized);
+        }
+        // Checks it's one we can actually pay in.
+        pay_in(&e, &options(&e)?, Some(token_id.clone()))?;
+        e.storage().set(StorageKey::PreferredToken, token_id);
+
+        Ok(())
+    }
+
+    fn set_payment_hook(e: Env, hook: Option<BytesN<32>>) -> Result<(), Error> {
+        Self::set_payment_hook_for(e, 0, hook)
+    }
+
+    fn set_payment_hook_for(e: Env, id: u64, hook: Option<BytesN<32>>) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        if invoker_id(&e) != child(&e)? {
+            return Err(Error::Unauthorized);
+        }
+        match hook {
+            Some(hook) => e.storage().set(StorageKey::PaymentHook, hook),
+            None => e.storage().remove(StorageKey::PaymentHook),
+        }
+
+        Ok(())
+    }
+
+    fn set_spend_policy(e: Env, policy: Option<BytesN<32>>) -> Result<(), Error> {
+        Self::set_spend_policy_for(e, 0, policy)
+    }
+
+    fn set_spend_policy_for(e: Env, id: u64, policy: Option<BytesN<32>>) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+        check_manager(&e)?;
+
+        match policy {
+            Some(policy) => e.storage().set(StorageKey::SpendPolicy, policy),
+            None => e.storage().remove(StorageKey::SpendPolicy),
+        }
+
+        Ok(())
+    }
+
+    fn is_active_for(e: Env, receiver: AccountId) -> bool {
+        let child_id = Identifier::Account(receiver);
+        let main = Sub::main(e.clone());
+
+        // Subscription `0` might be from before subscriptions were listed.
+        if matches!(child(&main), Ok(id) if id == child_id) && is_active(&main) == Ok(true) {
+            return true;
+        }
+
+        let ids: Vec<u64> = match read_opt(&main, StorageKey::ByChild(child_id)) {
+            Ok(Some(ids)) => ids,
+            _ => return false,
+        };
+        // Like `due_subscriptions`, we never look at more than `MAX_SCAN`.
+        for id in ids.iter().take(MAX_SCAN as usize) {
+            match id {
+                Ok(id) if id != 0 => {
+                    if is_active(&Sub { env: e.clone(), id }) == Ok(true) {
+                        return true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    fn report_end(e: Env) -> Result<(), Error> {
+        Self::report_end_for(e, 0)
+    }
+
+    fn report_end_for(e: Env, id: u64) -> Result<(), Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        if get_status(&e)? != Status::Cancelled {
+            return Err(Error::NotEnded);
+        }
+        report(&e, Status::Cancelled)
+    }
+
+    fn get_state(e: Env) -> Result<FullState, Error> {
+        get_state(&Sub::main(e))
+    }
+
+    fn history(e: Env, offset: u32, limit: u32) -> Result<Vec<Receipt>, Error> {
+        history(&Sub::main(e), offset, limit)
+    }
+
+    fn prune_receipts(e: Env, keep_latest: u32) -> Result<u32, Error> {
+        prune_receipts(&Sub::main(e), keep_latest)
+    }
+
+    fn remaining_cap(e: Env) -> Result<Option<i128>, Error> {
+        Self::remaining_cap_for(e, 0)
+    }
+
+    fn remaining_cap_for(e: Env, id: u64) -> Result<Option<i128>, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        remaining_cap(&e)
+    }
+
+    fn fix_step(e: Env, step: u64) -> Result<(), Error> {
+        let e = Sub::main(e);
+        check_initialized(&e)?;
+        check_manager(&e)?;
+        check_acting_alone(&e, &invoker_id(&e))?;
+
+        fix_step(&e, step)
+    }
+
+    fn reset(e: Env) -> Result<(), Error> {
+        let e = Sub::main(e);
+        check_initialized(&e)?;
+
+        if invoker_id(&e) != parent(&e)? {
+            return Err(Error::Unauthorized);
+        }
+
+        reset(&e)
+    }
+
+    fn preview_withdraw(e: Env) -> Result<WithdrawPreview, Error> {
+        preview_withdraw(&Sub::main(e))
+    }
+
+    fn preview_withdraw_for(e: Env, id: u64) -> Result<WithdrawPreview, Error> {
+        preview_withdraw(&Sub::get(e, id)?)
+    }
+
+    fn outstanding_obligation(e: Env) -> Result<i128, Error> {
+        Self::outstanding_obligation_for(e, 0)
+    }
+
+    fn outstanding_obligation_for(e: Env, id: u64) -> Result<i128, Error> {
+        let e = Sub::get(e, id)?;
+        check_initialized(&e)?;
+
+        outstanding_obligation(&e)
+    }
+
+    fn is_initialized(e: Env) -> bool {
+        is_initialized(&Sub::main(e))
+    }
+
+    fn is_initialized_for(e: Env, id: u64) -> bool {
+        is_initialized(&Sub { env: e, id })
     }
 }
 
+mod calendar;
+#[cfg(feature = "factory")]
+mod factory;
+#[cfg(feature = "std")]
+pub mod host;
+mod invariants;
+mod legacy;
+mod metering;
 mod test;
+mod testlib;
@@ -0,0 +1,126 @@
+#![cfg(test)]
+
+//! Every feature that's been added to `withdraw` costs a little more to run,
+//! even when it's switched off, and a transaction on the network can only
+//! afford so much. This module counts what `withdraw` costs (host CPU
+//! instructions, and the reads and writes it makes to the contract's
+//! storage) for a plain allowance, and the tests at the bottom hold it to
+//! ceilings. If one of them fails, it says what was measured: either pay for
+//! it, and raise the ceiling here on purpose, or find what got expensive.
+
+extern crate std;
+
+use core::cell::Cell;
+use std::thread_local;
+
+use soroban_auth::Identifier;
+use soroban_sdk::{testutils::Accounts, Env};
+
+use crate::testlib::{set_timestamp, setup_token, START, WEEK};
+use crate::{AllowanceContract, AllowanceContractClient, Options};
+
+// Each test runs on a thread of its own, so they can't count each other's.
+thread_local! {
+    static READS: Cell<u32> = Cell::new(0);
+    static WRITES: Cell<u32> = Cell::new(0);
+}
+
+/// Counts one read (`get` or `has`) of the contract's storage.
+pub(crate) fn count_read() {
+    READS.with(|reads| reads.set(reads.get() + 1));
+}
+
+/// Counts one write (`set` or `remove`) to the contract's storage.
+pub(crate) fn count_write() {
+    WRITES.with(|writes| writes.set(writes.get() + 1));
+}
+
+/// What one invocation cost.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Cost {
+    cpu_insns: u64,
+    reads: u32,
+    writes: u32,
+}
+
+/// Runs `f`, counting from zero.
+fn measure(env: &Env, f: impl FnOnce()) -> Cost {
+    READS.with(|reads| reads.set(0));
+    WRITES.with(|writes| writes.set(0));
+    env.budget().reset_default();
+    f();
+    Cost {
+        cpu_insns: env.budget().cpu_instruction_cost(),
+        reads: READS.with(|reads| reads.get()),
+        writes: WRITES.with(|writes| writes.get()),
+    }
+}
+
+fn check(what: &str, cost: Cost, ceiling: Cost) {
+    assert!(
+        cost.cpu_insns <= ceiling.cpu_insns
+            && cost.reads <= ceiling.reads
+            && cost.writes <= ceiling.writes,
+        "{} measured {:?}, over the ceiling of {:?}",
+        what,
+        cost,
+        ceiling
+    );
+}
+
+/// A weekly allowance with nothing switched on, set up and paid once, so
+/// what's measured next is a withdrawal like any other.
+fn setup(env: &Env) -> AllowanceContractClient {
+    set_timestamp(env, START);
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(env, &contract_id);
+    let (id, _) = setup_token(env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client.withdraw();
+    client
+}
+
+#[test]
+fn test_withdraw_cost() {
+    let env = Env::default();
+    let client = setup(&env);
+
+    set_timestamp(&env, START + WEEK);
+    let cost = measure(&env, || assert_eq!(client.withdraw(), 1));
+    check(
+        "A one-period withdraw",
+        cost,
+        Cost {
+            cpu_insns: 10_000_000,
+            reads: 60,
+            writes: 12,
+        },
+    );
+}
+
+#[test]
+fn test_catch_up_cost() {
+    let env = Env::default();
+    let client = setup(&env);
+
+    set_timestamp(&env, START + 10 * WEEK);
+    let cost = measure(&env, || assert_eq!(client.withdraw(), 10));
+    check(
+        "A ten-period withdraw",
+        cost,
+        Cost {
+            cpu_insns: 25_000_000,
+            reads: 100,
+            writes: 30,
+        },
+    );
+}
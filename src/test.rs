@@ -1,10 +1,15 @@
 #![cfg(test)]
 
 use super::*;
+use crate::legacy::{self, V0Params};
+use crate::testlib::{
+    set_timestamp, setup_token, setup_token_at, Config, Fixture, Transfer, START, WEEK,
+};
 
 use soroban_sdk::{
-    testutils::{Accounts, Ledger, LedgerInfo},
-    BytesN, Env, IntoVal,
+    symbol,
+    testutils::{Accounts, Events, Ledger, LedgerInfo},
+    AccountId, BytesN, Env, IntoVal,
 };
 
 /// The first test function, `test_valid_sequence()`, we test the contract
@@ -91,10 +96,11 @@ fn test_valid_sequence() {
     // starting arguments. These values result in a weekly allowance of
     // 9,615,384 stroops (== 0.9615384 units). Why, you big spender!
     client.with_source_account(&u1).init(
-        &u2,                 // our `Child` account
-        &id,                 // our token contract id
-        &500000000,          // 500000000 stroops == 50 units allowance for the year
+        &Identifier::Account(u2.clone()), // our `Child` account
+        &id,                              // our token contract id
+        &500000000,                       // 500000000 stroops == 50 units allowance for the year
         &(7 * 24 * 60 * 60), // 1 withdraw per week (7 days * 24 hours * 60 minutes * 60 seconds)
+        &Options::default(), // no optional behaviours
     );
 
     // We set new ledger state to simulate time passing. Here, we have increased
@@ -151,260 +157,7251 @@ fn test_valid_sequence() {
 #[test]
 #[should_panic(expected = "Status(ContractError(4))")] // We want this test to panic since it is withdrawing too quickly.
 fn test_invalid_sequence() {
-    // Almost everything in this test is identical to the previous one. We'll
-    // drop a comment to let you know when things are getting interesting again.
+    // Everything up to here is identical to the previous test, so from now on
+    // a `Fixture` does it for us: the same ledger, accounts, token, approval
+    // and `init`.
+    let f = Fixture::new(Config::default());
+
+    f.advance_time(1);
+    f.client.withdraw();
+    f.assert_balance(&f.child_id(), 9615384);
+
+    f.advance_time(WEEK + 1);
+    f.client.withdraw();
+    f.assert_balance(&f.child_id(), 9615384 * 2);
+
+    // Ok, stop here! This time, for our third `withdraw` invocation, we are
+    // only adding 20 seconds to the previous invocation. Since we've set up for
+    // weekly allowance transfers, this attempt should fail.
+    f.advance_time(20);
+
+    // We don't need an assertion here, since this invocation should fail and
+    // respond with `Status(ContractError(4))`.
+    f.client.withdraw();
+}
+
+/// In our next test function, `test_invalid_init()`, we test to make sure that
+/// invoking the AllowanceContract `init` function with invalid arguments will
+/// fail as expected. Specifically, we are passing `0` for the `step` value.
+#[test]
+#[should_panic(expected = "Status(ContractError(45))")] // We want this test to panic since we are giving an unusable argument.
+fn test_invalid_init() {
+    // This time, we give a `0` for the `step` field. This isn't possible
+    // because it would turn the allowance-dripping faucet into a rusted old
+    // faucet that has been welded shut. Also, dividing by zero is impossible.
+    // So, that's an important consideration, too.
+    let f = Fixture::new(Config {
+        step: 0, // 0 withdraw per second (why would you even do this?)
+        init: false,
+        ..Config::default()
+    });
+
+    // There's no need for an assertion here, since this invocation should
+    // fail and respond with `Status(ContractError(45))`.
+    f.init();
+}
+
+/// In our final test function, `test_invalid_init_withdrawal()`, we test to make sure that
+/// invoking the AllowanceContract `init` function with invalid arguments will
+/// fail as expected. Specifically, we are passing the arguments so that over the
+/// course of the year, the child can withdraw a portion of the total pot amount of
+/// 1 stroop every second, which is of course impossible.
+#[test]
+#[should_panic(expected = "Status(ContractError(46))")] // We want this test to panic since we are giving an unusable argument.
+fn test_invalid_init_withdrawal() {
+    // This time, we give a `1` for the `amount` field and a `1` for the `step`
+    // field. If you've followed along with the math so far, that comes out to
+    // 3.1709792e-15 **stroops** per withdraw. That's even more precision than
+    // Microsoft Excel can handle!
+    let f = Fixture::new(Config {
+        amount: 1, // 1 stroops == 0.0000001 units allowance for the year
+        step: 1,   // 1 withdraw per second
+        init: false,
+        ..Config::default()
+    });
+
+    // Again, there's no need for an assertion here, since this invocation
+    // should fail and respond with `Status(ContractError(46))`.
+    f.init();
+}
+
+/// In `test_init_step_overflow()`, we make sure a pathological `step` is
+/// rejected with a typed error at `init` instead of bricking `withdraw` later.
+/// A `step` of `u64::MAX` can't be subtracted from the current timestamp to
+/// give the `Latest` sentinel, so we should get `Error::Overflow` back.
+#[test]
+fn test_init_step_overflow() {
+    let f = Fixture::new(Config {
+        step: u64::MAX, // a `step` so large it can't possibly be scheduled
+        init: false,
+        ..Config::default()
+    });
+    assert_eq!(f.try_init(), Err(Error::Overflow));
+}
+
+/// In `test_init_amount_overflow()`, we pass an `amount` so large that the
+/// yearly sanity check (`amount * step`) would overflow an `i128`. Again, we
+/// want a typed `Error::Overflow` rather than a panic.
+#[test]
+fn test_init_amount_overflow() {
+    let f = Fixture::new(Config {
+        amount: i128::MAX, // more stroops than could ever exist
+        init: false,
+        ..Config::default()
+    });
+    assert_eq!(f.try_init(), Err(Error::Overflow));
+}
+
+/// In `test_init_step_longer_than_year()`, we check that a `step` longer than
+/// a year is refused. Otherwise there would be zero withdrawals per year, and
+/// `withdraw` would have to divide by zero to work out the amount.
+#[test]
+fn test_init_step_longer_than_year() {
+    let f = Fixture::new(Config {
+        step: 2 * 365 * 24 * 60 * 60, // 1 withdraw every two years
+        init: false,
+        ..Config::default()
+    });
+    assert_eq!(f.try_init(), Err(Error::InvalidArguments));
+}
+
+/// In `test_child_pause_auto_approved()`, the `Child` asks for a three week
+/// pause and the `Parent` never gets around to answering. Since the contract
+/// was set up to auto-approve, the pause goes into effect at `from`: nothing
+/// new comes due until `until`, and then the schedule picks up again, pushed
+/// back by the length of the pause.
+#[test]
+fn test_child_pause_auto_approved() {
     let env = Env::default();
+    set_timestamp(&env, START);
+
     let u1 = env.accounts().generate();
     let u2 = env.accounts().generate();
 
-    env.ledger().set(LedgerInfo {
-        timestamp: 1669726145,
-        protocol_version: 1,
-        sequence_number: 10,
-        network_passphrase: Default::default(),
-        base_reserve: 10,
-    });
-
     let contract_id = env.register_contract(None, AllowanceContract);
     let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
 
-    let id = env.register_contract_token(&BytesN::from_array(
-        &env,
-        &[
-            78, 52, 121, 202, 209, 66, 106, 25, 193, 181, 10, 91, 46, 213, 58, 244, 217, 115, 23,
-            232, 144, 71, 210, 113, 57, 46, 203, 166, 210, 20, 155, 105,
-        ],
-    ));
-
-    let token = token::Client::new(&env, &id);
-    token.init(
-        &Identifier::Account(u1.clone()),
-        &token::TokenMetadata {
-            name: "USD coin".into_val(&env),
-            symbol: "USDC".into_val(&env),
-            decimals: 7,
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            auto_approve_child_pause: true,
+            ..Default::default()
         },
     );
 
-    token.with_source_account(&u1).mint(
-        &Signature::Invoker,
-        &0,
-        &Identifier::Account(u1.clone()),
-        &1000000000,
-    );
+    // Withdrawals for weeks 0, 1 and 2 happen as usual, with the pause
+    // requested for just after week 2 comes due.
+    let from = START + 2 * WEEK + 10;
+    let until = from + 3 * WEEK;
+    client.with_source_account(&u2).request_pause(&from, &until);
 
-    token.with_source_account(&u1).approve(
-        &Signature::Invoker,
-        &0,
-        &Identifier::Contract(contract_id.clone()),
+    set_timestamp(&env, START + 1);
+    client.withdraw();
+    set_timestamp(&env, START + WEEK);
+    client.withdraw();
+    set_timestamp(&env, START + 2 * WEEK);
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 3);
+
+    // Week 3 would normally come due here, but we're in the pause now.
+    set_timestamp(&env, START + 3 * WEEK);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::Paused)));
+
+    // The pause is over, but the schedule was pushed back three weeks, so the
+    // next withdrawal isn't due until six weeks after the start.
+    set_timestamp(&env, START + 6 * WEEK - 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    set_timestamp(&env, START + 6 * WEEK);
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 4);
+}
+
+/// In `test_child_pause_lapses()`, the `Child` asks for a pause but this time
+/// the contract doesn't auto-approve, and the `Parent` never says yes. Once
+/// `from` rolls around, the request lapses and the allowance keeps flowing.
+#[test]
+fn test_child_pause_lapses() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
         &500000000,
+        &WEEK,
+        &Options::default(),
     );
 
+    let from = START + 10;
+    let until = from + 3 * WEEK;
+    client.with_source_account(&u2).request_pause(&from, &until);
+
+    set_timestamp(&env, START + 1);
+    client.withdraw();
+    set_timestamp(&env, START + WEEK);
+    client.withdraw();
+    set_timestamp(&env, START + 2 * WEEK);
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 3);
+
+    // It's too late for the `Parent` to approve it now, too.
     assert_eq!(
-        token.allowance(
-            &Identifier::Account(u1.clone()),
-            &Identifier::Contract(contract_id),
-        ),
-        500000000
+        client.with_source_account(&u1).try_approve_pause(),
+        Err(Ok(Error::NoPauseRequest))
     );
+}
 
-    client
-        .with_source_account(&u1)
-        .init(&u2, &id, &500000000, &(7 * 24 * 60 * 60));
+/// In `test_child_pause_rejected()`, the `Parent` rejects the pause before it
+/// starts, and only the `Child` may ask for one in the first place.
+#[test]
+fn test_child_pause_rejected() {
+    let env = Env::default();
+    set_timestamp(&env, START);
 
-    env.ledger().set(LedgerInfo {
-        timestamp: 1669726146,
-        protocol_version: 1,
-        sequence_number: 10,
-        network_passphrase: Default::default(),
-        base_reserve: 10,
-    });
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
 
-    client.withdraw();
-    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
 
-    env.ledger().set(LedgerInfo {
-        timestamp: 1669726146 + (7 * 24 * 60 * 60) + 1,
-        protocol_version: 1,
-        sequence_number: 10,
-        network_passphrase: Default::default(),
-        base_reserve: 10,
-    });
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            auto_approve_child_pause: true,
+            ..Default::default()
+        },
+    );
 
-    client.withdraw();
-    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 2);
+    let from = START + 10;
+    let until = from + 3 * WEEK;
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_request_pause(&from, &until),
+        Err(Ok(Error::Unauthorized))
+    );
 
-    // Ok, stop here! This time, for our third `withdraw` invocation, we are
-    // only adding 20 seconds to the previous invocation. Since we've set up for
-    // weekly allowance transfers, this attempt should fail.
-    env.ledger().set(LedgerInfo {
-        timestamp: 1669726146 + (7 * 24 * 60 * 60) + 1 + 20,
-        protocol_version: 1,
-        sequence_number: 10,
-        network_passphrase: Default::default(),
-        base_reserve: 10,
-    });
+    client.with_source_account(&u2).request_pause(&from, &until);
+    client.with_source_account(&u1).reject_pause();
 
-    // We don't need an assertion here, since this invocation should fail and
-    // respond with `Status(ContractError(4))`.
+    set_timestamp(&env, START + 1);
     client.withdraw();
+    set_timestamp(&env, START + WEEK);
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 2);
 }
 
-/// In our next test function, `test_invalid_init()`, we test to make sure that
-/// invoking the AllowanceContract `init` function with invalid arguments will
-/// fail as expected. Specifically, we are passing `0` for the `step` value.
+/// In `test_extra_waits_for_allowance()`, the `Parent` has only left enough
+/// allowance for one more payment, and both a regular withdrawal and an
+/// approved extra are waiting. The regular withdrawal must win, whichever
+/// one is attempted first. Here, the `Parent` tries to pay the extra first.
 #[test]
-#[should_panic(expected = "Status(ContractError(6))")] // We want this test to panic since we are giving an unusable argument.
-fn test_invalid_init() {
-    // Almost everything in this test is identical to the first one. We'll drop
-    // a comment to let you know when things are getting interesting again.
+fn test_extra_waits_for_allowance() {
     let env = Env::default();
-    env.ledger().set(LedgerInfo {
-        timestamp: 1669726145,
-        protocol_version: 1,
-        sequence_number: 10,
-        network_passphrase: Default::default(),
-        base_reserve: 10,
-    });
+    set_timestamp(&env, START);
 
     let u1 = env.accounts().generate();
     let u2 = env.accounts().generate();
 
     let contract_id = env.register_contract(None, AllowanceContract);
     let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
 
-    let id = env.register_contract_token(&BytesN::from_array(
-        &env,
-        &[
-            78, 52, 121, 202, 209, 66, 106, 25, 193, 181, 10, 91, 46, 213, 58, 244, 217, 115, 23,
-            232, 144, 71, 210, 113, 57, 46, 203, 166, 210, 20, 155, 105,
-        ],
-    ));
-
-    let token = token::Client::new(&env, &id);
-    token.init(
-        &Identifier::Account(u1.clone()),
-        &token::TokenMetadata {
-            name: "USD coin".into_val(&env),
-            symbol: "USDC".into_val(&env),
-            decimals: 7,
-        },
-    );
-
-    token.with_source_account(&u1).mint(
-        &Signature::Invoker,
-        &0,
-        &Identifier::Account(u1.clone()),
-        &1000000000,
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
     );
 
+    // The allowance now covers exactly one weekly payment.
     token.with_source_account(&u1).approve(
         &Signature::Invoker,
         &0,
         &Identifier::Contract(contract_id.clone()),
-        &500000000,
+        &9615384,
     );
+    client.with_source_account(&u2).invoice_extra(&9615384);
+    assert_eq!(client.required_allowance(&false), 9615384);
+    assert_eq!(client.required_allowance(&true), 9615384 * 2);
 
+    // The extra doesn't fit once the regular payment has been set aside...
     assert_eq!(
-        token.allowance(
-            &Identifier::Account(u1.clone()),
-            &Identifier::Contract(contract_id),
-        ),
-        500000000
+        client.with_source_account(&u1).try_approve_extra(),
+        Err(Ok(Error::InsufficientAllowance))
     );
 
-    // Ok, stop here! This time, when invoking `init`, we give a `0` for the
-    // `step` field. This isn't possible because it would turn the
-    // allowance-dripping faucet into a rusted old faucet that has been welded
-    // shut. Also, dividing by zero is impossible. So, that's an important
-    // consideration, too.
-    client.with_source_account(&u1).init(
-        &u2,        // our `Child` account
-        &id,        // our token contract id
-        &500000000, // 500000000 stroops == 50 units allowance for the year
-        &0,         // 0 withdraw per second (why would you even do this?)
-    );
-
-    // Again, there's no need for an assertion here, since this invocation
-    // should fail and respond with `Status(ContractError(6))`.
+    // ...so the regular withdrawal still goes through, and the extra is left
+    // waiting for the `Parent` to top up.
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+    assert_eq!(client.required_allowance(&false), 0);
+    assert_eq!(client.required_allowance(&true), 9615384);
 }
 
-/// In our final test function, `test_invalid_init_withdrawal()`, we test to make sure that
-/// invoking the AllowanceContract `init` function with invalid arguments will
-/// fail as expected. Specifically, we are passing the arguments so that over the
-/// course of the year, the child can withdraw a portion of the total pot amount of
-/// 1 stroop every second, which is of course impossible.
+/// In `test_withdraw_before_extra()`, we try the same thing the other way
+/// around: the regular withdrawal happens first, and the extra can't be paid.
 #[test]
-#[should_panic(expected = "Status(ContractError(6))")] // We want this test to panic since we are giving an unusable argument.
-fn test_invalid_init_withdrawal() {
-    // Almost everything in this test is identical to the first one. We'll drop
-    // a comment to let you know when things are getting interesting again.
+fn test_withdraw_before_extra() {
     let env = Env::default();
-    env.ledger().set(LedgerInfo {
-        timestamp: 1669726145,
-        protocol_version: 1,
-        sequence_number: 10,
-        network_passphrase: Default::default(),
-        base_reserve: 10,
-    });
+    set_timestamp(&env, START);
 
     let u1 = env.accounts().generate();
     let u2 = env.accounts().generate();
 
     let contract_id = env.register_contract(None, AllowanceContract);
     let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
 
-    let id = env.register_contract_token(&BytesN::from_array(
-        &env,
-        &[
-            78, 52, 121, 202, 209, 66, 106, 25, 193, 181, 10, 91, 46, 213, 58, 244, 217, 115, 23,
-            232, 144, 71, 210, 113, 57, 46, 203, 166, 210, 20, 155, 105,
-        ],
-    ));
-
-    let token = token::Client::new(&env, &id);
-    token.init(
-        &Identifier::Account(u1.clone()),
-        &token::TokenMetadata {
-            name: "USD coin".into_val(&env),
-            symbol: "USDC".into_val(&env),
-            decimals: 7,
-        },
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
     );
 
-    token.with_source_account(&u1).mint(
+    token.with_source_account(&u1).approve(
         &Signature::Invoker,
         &0,
-        &Identifier::Account(u1.clone()),
-        &1000000000,
+        &Identifier::Contract(contract_id.clone()),
+        &9615384,
+    );
+    client.with_source_account(&u2).invoice_extra(&9615384);
+
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+    assert_eq!(
+        client.with_source_account(&u1).try_approve_extra(),
+        Err(Ok(Error::InsufficientAllowance))
     );
 
+    // Once there's room in the allowance, the extra is paid in full.
     token.with_source_account(&u1).approve(
         &Signature::Invoker,
         &0,
         &Identifier::Contract(contract_id.clone()),
-        &500000000,
+        &9615384,
     );
+    client.with_source_account(&u1).approve_extra();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 2);
+    assert_eq!(client.required_allowance(&true), 0);
+}
 
-    assert_eq!(
-        token.allowance(
-            &Identifier::Account(u1.clone()),
-            &Identifier::Contract(contract_id),
-        ),
-        500000000
+/// `TreasuryContract` stands in for a DAO's treasury. It holds tokens of its
+/// own, gives an AllowanceContract an allowance on them and calls `init`
+/// itself, so the treasury is the `Parent`.
+pub struct TreasuryContract;
+
+#[contractimpl]
+impl TreasuryContract {
+    pub fn subscribe(
+        e: Env,
+        contract_id: BytesN<32>,
+        child: AccountId,
+        token_id: BytesN<32>,
+        amount: i128,
+        step: u64,
+    ) {
+        token::Client::new(&e, &token_id).approve(
+            &Signature::Invoker,
+            &0,
+            &Identifier::Contract(contract_id.clone()),
+            &amount,
+        );
+        AllowanceContractClient::new(&e, &contract_id).init_for_account(
+            &child,
+            &token_id,
+            &amount,
+            &step,
+            &Options::default(),
+        );
+    }
+}
+
+/// In `test_init_by_contract()`, `init` is invoked cross-contract by the
+/// treasury, which then pays the allowance out of its own balance.
+#[test]
+fn test_init_by_contract() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    let treasury_id = env.register_contract(None, TreasuryContract);
+    let treasury = Identifier::Contract(treasury_id.clone());
+    token
+        .with_source_account(&u1)
+        .mint(&Signature::Invoker, &0, &treasury, &1000000000);
+    TreasuryContractClient::new(&env, &treasury_id)
+        .with_source_account(&u1)
+        .subscribe(&contract_id, &u2, &id, &500000000, &WEEK);
+
+    client.with_source_account(&u2).withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+    assert_eq!(token.balance(&treasury), 1000000000 - 9615384);
+    assert_eq!(token.balance(&Identifier::Account(u1.clone())), 1000000000);
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.with_source_account(&u2).withdraw(), 2);
+    assert_eq!(token.balance(&treasury), 1000000000 - 9615384 * 3);
+}
+
+/// Sets up a contract whose storage was written by the original release of
+/// the AllowanceContract, rather than by today's `init`.
+fn setup_v0(
+    env: &Env,
+    parent: &AccountId,
+    child: &AccountId,
+    amount: i128,
+    latest: u64,
+) -> (BytesN<32>, AllowanceContractClient, token::Client) {
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(env, &contract_id);
+    let (id, token) = setup_token(env, parent, &contract_id);
+
+    legacy::write_v0_state(
+        env,
+        &contract_id,
+        &V0Params {
+            parent: parent.clone(),
+            child: child.clone(),
+            token_id: id,
+            amount,
+            step: WEEK,
+            latest,
+        },
+    );
+
+    (contract_id, client, token)
+}
+
+/// In `test_v0_layout_golden()`, we pin down the v0 storage layout: every key
+/// written by the legacy fixture must be readable through today's
+/// `StorageKey` variants, with the same values.
+#[test]
+fn test_v0_layout_golden() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let (contract_id, _client, _token) = setup_v0(&env, &u1, &u2, 500000000, START - WEEK);
+
+    env.as_contract(&contract_id, || {
+        let parent: AccountId = env.storage().get(StorageKey::Parent).unwrap().unwrap();
+        let child: AccountId = env.storage().get(StorageKey::Child).unwrap().unwrap();
+        let amount: i128 = env.storage().get(StorageKey::Amount).unwrap().unwrap();
+        let step: u64 = env.storage().get(StorageKey::Step).unwrap().unwrap();
+        let latest: u64 = env.storage().get(StorageKey::Latest).unwrap().unwrap();
+        assert_eq!(parent, u1);
+        assert_eq!(child, u2);
+        assert_eq!(amount, 500000000);
+        assert_eq!(step, WEEK);
+        assert_eq!(latest, START - WEEK);
+        assert!(env.storage().has(StorageKey::TokenId));
+
+        // Nothing that was added after v0 is present.
+        assert!(!env.storage().has(StorageKey::Options));
+        assert!(!env.storage().has(StorageKey::PauseReq));
+        assert!(!env.storage().has(StorageKey::Extra));
+    });
+}
+
+/// In `test_v0_fresh()`, a freshly initialized v0 contract pays out its first
+/// withdrawal under today's code.
+#[test]
+fn test_v0_fresh() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let (_, client, token) = setup_v0(&env, &u1, &u2, 500000000, START - WEEK);
+
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+    assert_eq!(
+        token.balance(&Identifier::Account(u1.clone())),
+        1000000000 - 9615384
+    );
+}
+
+/// In `test_v0_mid_catch_up()`, the v0 contract is three withdrawals behind.
+/// All three are caught up on in one go, and then nothing more is due.
+#[test]
+fn test_v0_mid_catch_up() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let (_, client, token) = setup_v0(&env, &u1, &u2, 500000000, START - 3 * WEEK);
+
+    assert_eq!(client.withdraw(), 3);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 3);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+}
+
+/// In `test_v0_changed_amount()`, the v0 contract's `Amount` has been changed
+/// since it was initialized, and withdrawals are priced at the new amount.
+#[test]
+fn test_v0_changed_amount() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let (_, client, token) = setup_v0(&env, &u1, &u2, 1000000000, START - WEEK);
+
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 19230769);
+}
+
+/// In `test_withdraw_missing_data()`, part of a v0 contract's data has gone
+/// missing (say, after a botched manual cleanup). Rather than trapping on an
+/// `unwrap()`, `withdraw` should tell us exactly that: `Error::MissingData`.
+/// (A contract with a `State` keeps it all in one entry, so it can't lose
+/// just part of it.)
+#[test]
+fn test_withdraw_missing_data() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let (contract_id, client, _token) = setup_v0(&env, &u1, &u2, 500000000, START - WEEK);
+
+    env.as_contract(&contract_id, || {
+        env.storage().remove(StorageKey::Child);
+    });
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::MissingData)));
+}
+
+/// In `test_withdraw_corrupt_data()`, the `State` has been overwritten with
+/// something that isn't a `State` at all, and we get `Error::CorruptData`.
+#[test]
+fn test_withdraw_corrupt_data() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    env.as_contract(&contract_id, || {
+        env.storage().set(StorageKey::State, symbol!("oops"));
+    });
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::CorruptData)));
+}
+
+/// In `test_init_self_allowance()`, the `Parent` names themselves as the
+/// `Child`. That's refused with `Error::InvalidArguments`.
+#[test]
+#[should_panic(expected = "Status(ContractError(6))")] // We want this test to panic since the `Parent` can't pay themselves.
+fn test_init_self_allowance() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u1.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+}
+
+/// In `test_init_zero_token()`, the token id is all zeroes, which is refused.
+/// With a proper token id and two distinct accounts, `init` goes through.
+#[test]
+fn test_init_zero_token() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _token) = setup_token(&env, &u1, &contract_id);
+
+    assert_eq!(
+        client.with_source_account(&u1).try_init(
+            &Identifier::Account(u2.clone()),
+            &BytesN::from_array(&env, &[0; 32]),
+            &500000000,
+            &WEEK,
+            &Options::default(),
+        ),
+        Err(Ok(Error::InvalidArguments))
+    );
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client.withdraw();
+}
+
+/// In `test_nothing_due()`, the schedule hasn't reached its first withdrawal
+/// yet (we move the `StartEpoch` a week into the future behind the contract's
+/// back). That's `Error::NothingDue`, which is different from the `Child`
+/// having already collected for the current period.
+#[test]
+fn test_nothing_due() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    env.as_contract(&contract_id, || {
+        let mut state: State = env.storage().get(StorageKey::State).unwrap().unwrap();
+        state.start_epoch = START + WEEK;
+        state.latest = START;
+        env.storage().set(StorageKey::State, state);
+    });
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::NothingDue)));
+
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+}
+
+/// In `test_lenient_withdraw()`, the contract was set up in lenient mode, so a
+/// `withdraw` with nothing to pay simply returns `0` instead of failing.
+#[test]
+fn test_lenient_withdraw() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            lenient: true,
+            ..Default::default()
+        },
+    );
+
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(client.withdraw(), 0);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(client.withdraw(), 0);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 2);
+}
+
+/// In `test_fix_start_epoch_later()`, the start date slips by two weeks before
+/// anything has been collected. Nothing is due until the new start date, and
+/// then the first withdrawal goes through right on time.
+#[test]
+fn test_fix_start_epoch_later() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client
+        .with_source_account(&u1)
+        .fix_start_epoch(&(START + 2 * WEEK));
+
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::NothingDue)));
+    set_timestamp(&env, START + 2 * WEEK - 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::NothingDue)));
+    set_timestamp(&env, START + 2 * WEEK);
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+}
+
+/// In `test_fix_start_epoch_earlier()`, the start date is moved two weeks into
+/// the past, so three weeks' worth of allowance can be collected right away.
+/// Once that's happened, the start date can't be moved anymore.
+#[test]
+fn test_fix_start_epoch_earlier() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    // Only the `Parent` gets to do this.
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_fix_start_epoch(&(START - 2 * WEEK)),
+        Err(Ok(Error::Unauthorized))
+    );
+    client
+        .with_source_account(&u1)
+        .fix_start_epoch(&(START - 2 * WEEK));
+
+    assert_eq!(client.withdraw(), 3);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 3);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_fix_start_epoch(&(START + WEEK)),
+        Err(Ok(Error::AlreadyStarted))
+    );
+}
+
+/// In `test_propose_accept_amount()`, the `Child` asks for a raise. Until the
+/// `Parent` accepts it, withdrawals are paid at the old amount (and the `Child`
+/// can't accept their own proposal). Once accepted, the new amount applies.
+#[test]
+fn test_propose_accept_amount() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    client.with_source_account(&u2).propose_amount(&1000000000);
+    assert_eq!(
+        client.with_source_account(&u2).try_accept_amount(),
+        Err(Ok(Error::Unauthorized))
+    );
+
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+
+    client.with_source_account(&u1).accept_amount();
+    set_timestamp(&env, START + WEEK);
+    client.withdraw();
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        9615384 + 19230769
+    );
+    assert_eq!(
+        client.with_source_account(&u1).try_accept_amount(),
+        Err(Ok(Error::NoAmountProposal))
+    );
+}
+
+/// In `test_propose_decrease()`, the `Parent` cuts the allowance. With the
+/// default options that takes effect straight away, but when the contract
+/// protects the `Child` from cuts, it has to wait for their acceptance, and
+/// if they never accept, the old amount keeps being paid.
+#[test]
+fn test_propose_decrease() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            decreases_need_acceptance: true,
+            ..Default::default()
+        },
+    );
+
+    client.with_source_account(&u1).propose_amount(&250000000);
+    client.withdraw();
+    set_timestamp(&env, START + WEEK);
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 2);
+
+    // A third party can't weigh in at all.
+    let u3 = env.accounts().generate();
+    assert_eq!(
+        client.with_source_account(&u3).try_accept_amount(),
+        Err(Ok(Error::Unauthorized))
+    );
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    token.with_source_account(&u1).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &500000000,
+    );
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client.with_source_account(&u1).propose_amount(&250000000);
+    client.withdraw();
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        9615384 * 2 + 4807692
+    );
+}
+
+/// In `test_amount_notice_period()`, the allowance is raised with two weeks'
+/// notice. Catching up later, the withdrawals that came due before the notice
+/// ran out are paid at the old amount, and the one after at the new amount.
+/// A second change before the first takes effect replaces it.
+#[test]
+fn test_amount_notice_period() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            amount_notice_period: 2 * WEEK,
+            ..Default::default()
+        },
+    );
+    client.withdraw();
+
+    // The raise takes effect at `START + 2 * WEEK + 1`, so only the
+    // withdrawal due at `START + 3 * WEEK` is paid at the new amount. We first
+    // propose something else, and then change our mind.
+    set_timestamp(&env, START + 1);
+    client.with_source_account(&u1).propose_amount(&750000000);
+    client.with_source_account(&u1).propose_amount(&1000000000);
+
+    set_timestamp(&env, START + 3 * WEEK);
+    assert_eq!(client.withdraw(), 3);
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        9615384 * 3 + 19230769
+    );
+
+    set_timestamp(&env, START + 4 * WEEK);
+    client.withdraw();
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        9615384 * 3 + 19230769 * 2
+    );
+}
+
+/// In `test_arrears_keep_their_rate()`, the `Child` has fallen two weeks behind
+/// when the allowance is doubled (with no notice at all). When they catch up,
+/// the two weeks that were already owed are paid at the old amount, and only
+/// the weeks after the change at the new amount.
+#[test]
+fn test_arrears_keep_their_rate() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client.withdraw();
+
+    set_timestamp(&env, START + 2 * WEEK + 1);
+    client.with_source_account(&u1).propose_amount(&1000000000);
+    assert_eq!(client.required_allowance(&false), 9615384 * 2);
+
+    set_timestamp(&env, START + 4 * WEEK);
+    assert_eq!(client.withdraw(), 4);
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        9615384 * 3 + 19230769 * 2
+    );
+
+    // Once the old weeks are paid off, the change is folded into the `Amount`
+    // and the rate history is cleaned up.
+    env.as_contract(&contract_id, || {
+        assert!(!env.storage().has(StorageKey::RateHistory));
+        let state: State = env.storage().get(StorageKey::State).unwrap().unwrap();
+        assert_eq!(state.amount, 1000000000);
+    });
+}
+
+/// In `test_rate_history_is_capped()`, the `Child` never withdraws while the
+/// `Parent` keeps changing the amount, so old rates can't be cleaned up. The
+/// history only grows so far before further changes are refused.
+#[test]
+fn test_rate_history_is_capped() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    for i in 1..=MAX_RATE_CHANGES as u64 {
+        set_timestamp(&env, START + i * WEEK);
+        client
+            .with_source_account(&u1)
+            .propose_amount(&(500000000 - i as i128));
+    }
+    set_timestamp(&env, START + 10 * WEEK);
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_propose_amount(&250000000),
+        Err(Ok(Error::TooManyRateChanges))
+    );
+
+    // Catching up clears the way again.
+    client.withdraw();
+    client.with_source_account(&u1).propose_amount(&250000000);
+}
+
+/// In `test_escalator()`, the allowance goes up 10% every two weeks. We check
+/// the withdrawals after the first escalation, and after the third.
+#[test]
+fn test_escalator() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            escalator_bps: 1000,
+            escalator_every: 2,
+            ..Default::default()
+        },
+    );
+
+    // Weeks 0 and 1 are at 50 units a year, and week 2 at 55.
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.required_allowance(&false), 9615384 * 2 + 10576923);
+    assert_eq!(client.withdraw(), 3);
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        9615384 * 2 + 10576923
+    );
+
+    // Week 3 is still at 55, weeks 4 and 5 at 60.5, and week 6 at 66.55.
+    set_timestamp(&env, START + 6 * WEEK);
+    assert_eq!(client.withdraw(), 4);
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        9615384 * 2 + 10576923 * 2 + 11634615 * 2 + 12798076
+    );
+}
+
+/// In `test_escalator_zero_bps()`, the escalator is set to 0 basis points,
+/// which changes nothing at all. An escalator that never says how often it
+/// kicks in is refused.
+#[test]
+fn test_escalator_zero_bps() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    assert_eq!(
+        client.with_source_account(&u1).try_init(
+            &Identifier::Account(u2.clone()),
+            &id,
+            &500000000,
+            &WEEK,
+            &Options {
+                escalator_bps: 1000,
+                ..Default::default()
+            },
+        ),
+        Err(Ok(Error::InvalidArguments))
+    );
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            escalator_every: 1,
+            ..Default::default()
+        },
+    );
+
+    set_timestamp(&env, START + 3 * WEEK);
+    assert_eq!(client.withdraw(), 4);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 4);
+}
+
+/// `MockOracle` hands back whatever price we last gave it, for any asset, so
+/// we can see how an indexed allowance reacts to the price moving (or going
+/// stale).
+pub struct MockOracle;
+
+#[contractimpl]
+impl MockOracle {
+    pub fn set_price(e: Env, price: i128, decimals: u32, timestamp: u64) {
+        e.storage().set(
+            symbol!("price"),
+            PriceData {
+                price,
+                decimals,
+                timestamp,
+            },
+        );
+    }
+
+    pub fn lastprice(e: Env, _asset: BytesN<32>) -> PriceData {
+        e.storage().get(symbol!("price")).unwrap().unwrap()
+    }
+}
+
+/// In `test_indexed_allowance()`, the allowance is 1 unit of a reference asset
+/// a week, paid in a token the oracle prices at 0.125 of a reference unit.
+/// We check the conversion, that it rounds down, and that a stale price is
+/// refused rather than used.
+#[test]
+fn test_indexed_allowance() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    let oracle_id = env.register_contract(None, MockOracle);
+    let oracle = MockOracleClient::new(&env, &oracle_id);
+    oracle.set_price(&1250000, &7, &START);
+
+    client.with_source_account(&u1).init_indexed(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &(52 * 10000000),
+        &WEEK,
+        &Options::default(),
+        &Oracle {
+            contract_id: oracle_id.clone(),
+            max_age: WEEK,
+        },
+    );
+
+    // 10,000,000 reference stroops at 0.125 each is 80,000,000 token stroops.
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 80000000);
+
+    // At a price of 3, the weekly amount doesn't divide evenly, and the
+    // `Parent` doesn't pay for the remainder.
+    set_timestamp(&env, START + WEEK);
+    oracle.set_price(&3, &0, &(START + WEEK));
+    client.withdraw();
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        80000000 + 3333333
+    );
+
+    // Two weeks later the price hasn't been updated in longer than we allow.
+    set_timestamp(&env, START + 3 * WEEK);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::StalePrice)));
+    assert_eq!(
+        client.try_required_allowance(&false),
+        Err(Ok(Error::StalePrice))
+    );
+
+    oracle.set_price(&3, &0, &(START + 3 * WEEK));
+    assert_eq!(client.withdraw(), 2);
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        80000000 + 3333333 + 6666666
+    );
+}
+
+/// In `test_usage_billing()`, the `Child` is a vendor billing 1000 stroops for
+/// every unit over the 10 included each week, with at most 50 units over in
+/// any one week. We check usage under what's included, an overage, usage right
+/// up against the cap, and usage reported after a week has been paid.
+#[test]
+fn test_usage_billing() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            unit_price: 1000,
+            included_units: 10,
+            max_overage: 50,
+            ..Default::default()
+        },
+    );
+    client.withdraw();
+
+    // Only the vendor reports usage.
+    assert_eq!(
+        client.with_source_account(&u1).try_report_usage(&5),
+        Err(Ok(Error::Unauthorized))
+    );
+
+    // Under what's included, only the regular allowance is paid.
+    client.with_source_account(&u2).report_usage(&5);
+    set_timestamp(&env, START + WEEK);
+    client.withdraw();
+    let mut paid = 9615384 * 2;
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), paid);
+
+    // 30 units is 20 over.
+    client.with_source_account(&u2).report_usage(&10);
+    client.with_source_account(&u2).report_usage(&20);
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.required_allowance(&false), 9615384 + 20 * 1000);
+    client.withdraw();
+    paid += 9615384 + 20 * 1000;
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), paid);
+
+    // 60 units is as far as it goes.
+    client.with_source_account(&u2).report_usage(&60);
+    assert_eq!(
+        client.with_source_account(&u2).try_report_usage(&1),
+        Err(Ok(Error::UsageCapExceeded))
+    );
+    set_timestamp(&env, START + 3 * WEEK);
+    client.withdraw();
+    paid += 9615384 + 50 * 1000;
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), paid);
+
+    // The week due at `START + 3 * WEEK` has been paid, so what's reported now
+    // goes towards the next one, and the cap starts over.
+    client.with_source_account(&u2).report_usage(&15);
+    set_timestamp(&env, START + 4 * WEEK);
+    client.withdraw();
+    paid += 9615384 + 5 * 1000;
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), paid);
+}
+
+/// In `test_trial_periods()`, the first three weeks are a free trial. Those
+/// withdrawals still come due (so the schedule stays in step), but nothing is
+/// paid for them, and a catch-up that runs past the end of the trial only pays
+/// for the weeks after it. Without a trial, the first week is paid as usual.
+#[test]
+fn test_trial_periods() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            trial_periods: 3,
+            ..Default::default()
+        },
+    );
+
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(client.required_allowance(&false), 0);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 0);
+
+    // Week 2 is the last free one, and weeks 3 and 4 are paid.
+    set_timestamp(&env, START + 4 * WEEK);
+    assert_eq!(client.withdraw(), 3);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 2);
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    token.with_source_account(&u1).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &500000000,
+    );
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 3);
+}
+
+/// In `test_discount()`, the first three paid weeks are 20% off. A catch-up
+/// over five weeks pays three at the discounted amount and two at full price.
+#[test]
+fn test_discount() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            discount_bps: 2000,
+            discount_periods: 3,
+            ..Default::default()
+        },
+    );
+
+    set_timestamp(&env, START + 4 * WEEK);
+    assert_eq!(client.withdraw(), 5);
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        7692307 * 3 + 9615384 * 2
+    );
+}
+
+/// In `test_full_discount()`, the discount is 100%, which works just like a
+/// free trial: nothing moves until the discount runs out. More than 100% off
+/// is refused.
+#[test]
+fn test_full_discount() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    assert_eq!(
+        client.with_source_account(&u1).try_init(
+            &Identifier::Account(u2.clone()),
+            &id,
+            &500000000,
+            &WEEK,
+            &Options {
+                discount_bps: 10001,
+                discount_periods: 2,
+                ..Default::default()
+            },
+        ),
+        Err(Ok(Error::InvalidArguments))
+    );
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            discount_bps: 10000,
+            discount_periods: 2,
+            ..Default::default()
+        },
+    );
+
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 0);
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.withdraw(), 2);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+}
+
+/// In `test_streaming()`, the allowance streams by the second. Collecting it
+/// in little bits at odd moments over a week adds up to exactly what a single
+/// weekly withdrawal pays, even though every bit is rounded down.
+#[test]
+fn test_streaming() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            streaming: true,
+            ..Default::default()
+        },
+    );
+
+    // Nothing has built up yet.
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::NothingDue)));
+
+    // Half a week in, half a week's worth is there.
+    set_timestamp(&env, START + WEEK / 2);
+    assert_eq!(client.required_allowance(&false), 4807692);
+
+    for offset in [1, 3, 1000, WEEK / 2 + 1, WEEK - 1, WEEK] {
+        set_timestamp(&env, START + offset);
+        assert_eq!(client.withdraw(), 1);
+    }
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+
+    // Catching up across a period boundary works the same way.
+    set_timestamp(&env, START + 2 * WEEK + WEEK / 2);
+    client.withdraw();
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        9615384 * 2 + 4807692
+    );
+}
+
+/// In `test_monthly_anchor()`, the allowance is paid on the 1st of every
+/// month. It's set up in the middle of January 2023, so January's withdrawal
+/// is due straight away. February is only 28 days long, and the `Child` later
+/// catches up on two months at once. Anchors past the 28th are refused.
+#[test]
+fn test_monthly_anchor() {
+    const JAN_1: u64 = 1672531200;
+    const JAN_15: u64 = 1673740800;
+    const FEB_1: u64 = 1675209600;
+    const MAR_1: u64 = 1677628800;
+    const MAY_1: u64 = 1682899200;
+
+    let env = Env::default();
+    set_timestamp(&env, JAN_15);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    assert_eq!(
+        client.with_source_account(&u1).try_init(
+            &Identifier::Account(u2.clone()),
+            &id,
+            &500000000,
+            &WEEK,
+            &Options {
+                monthly_anchor: 29,
+                ..Default::default()
+            },
+        ),
+        Err(Ok(Error::InvalidArguments))
+    );
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            monthly_anchor: 1,
+            ..Default::default()
+        },
+    );
+    env.as_contract(&contract_id, || {
+        let state: State = env.storage().get(StorageKey::State).unwrap().unwrap();
+        assert_eq!(state.start_epoch, JAN_1);
+    });
+
+    assert_eq!(client.withdraw(), 1);
+    set_timestamp(&env, FEB_1 - 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    set_timestamp(&env, FEB_1);
+    assert_eq!(client.withdraw(), 1);
+
+    set_timestamp(&env, MAR_1 - 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    set_timestamp(&env, MAR_1);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        41666666 * 3
+    );
+
+    // April and May, in one go.
+    set_timestamp(&env, MAY_1 + 10);
+    assert_eq!(client.withdraw(), 2);
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        41666666 * 5
+    );
+}
+
+/// Sets up a prorated allowance on the 1st of every month, joined at `now`,
+/// and returns what the first withdrawal pays.
+fn prorated_first_withdrawal(now: u64) -> i128 {
+    let env = Env::default();
+    set_timestamp(&env, now);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            monthly_anchor: 1,
+            prorate_first: true,
+            ..Default::default()
+        },
+    );
+    client.withdraw();
+    token.balance(&Identifier::Account(u2))
+}
+
+/// In `test_prorate_first()`, the allowance is paid on the 1st of every month
+/// and the 28 days of February 2023 are prorated. Joining right on the 1st
+/// pays the whole month, joining halfway pays half, and joining a second
+/// before March pays for just that one second (rounded down).
+#[test]
+fn test_prorate_first() {
+    const FEB_1: u64 = 1675209600;
+    const FEB_15: u64 = 1676419200;
+    const MAR_1: u64 = 1677628800;
+
+    assert_eq!(prorated_first_withdrawal(FEB_1), 41666666);
+    assert_eq!(prorated_first_withdrawal(FEB_15), 20833333);
+    assert_eq!(prorated_first_withdrawal(MAR_1 - 1), 17);
+}
+
+/// In `test_bill_in_arrears()`, we pin down exactly when the first two weeks
+/// can be collected, billing in advance (the default) and in arrears.
+#[test]
+fn test_bill_in_arrears() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    // In advance, week 0 is paid at the start, and week 1 a week later.
+    assert_eq!(client.withdraw(), 1);
+    set_timestamp(&env, START + WEEK - 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 2);
+
+    set_timestamp(&env, START);
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    token.with_source_account(&u1).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &500000000,
+    );
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            bill_in_arrears: true,
+            ..Default::default()
+        },
+    );
+
+    // In arrears, week 0 is paid once it's over, and week 1 a week after that.
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::NothingDue)));
+    set_timestamp(&env, START + WEEK - 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::NothingDue)));
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(client.withdraw(), 1);
+    set_timestamp(&env, START + 2 * WEEK - 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 4);
+}
+
+/// In `test_next_withdraw_time()`, we check the view against `withdraw`
+/// itself: a second before the time it gives, there's nothing to collect, and
+/// right at it, there is. That includes a pause pushing the schedule back.
+#[test]
+fn test_next_withdraw_time() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    assert_eq!(
+        client.try_next_withdraw_time(),
+        Err(Ok(Error::ContractNotInitialized))
+    );
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    assert_eq!(client.next_withdraw_time(), START);
+    assert_eq!(client.time_until_next(), 0);
+    client.withdraw();
+
+    let next = client.next_withdraw_time();
+    assert_eq!(next, START + WEEK);
+    assert_eq!(client.time_until_next(), WEEK);
+    set_timestamp(&env, next - 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    set_timestamp(&env, next);
+    client.withdraw();
+
+    // A three week pause starting before the next withdrawal comes due pushes
+    // it back three weeks.
+    let from = START + WEEK + 10;
+    client
+        .with_source_account(&u2)
+        .request_pause(&from, &(from + 3 * WEEK));
+    client.with_source_account(&u1).approve_pause();
+
+    let next = client.next_withdraw_time();
+    assert_eq!(next, START + 5 * WEEK);
+    set_timestamp(&env, next - 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    assert_eq!(client.next_withdraw_time(), next);
+    assert_eq!(client.time_until_next(), 1);
+    set_timestamp(&env, next);
+    client.withdraw();
+}
+
+/// In `test_can_withdraw_matches_withdraw()`, we step through a few weeks a
+/// little at a time (and right around every boundary), with a pause thrown in,
+/// and check that `can_withdraw` always agrees with what `withdraw` does.
+#[test]
+fn test_can_withdraw_matches_withdraw() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    assert!(!client.can_withdraw());
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    let from = START + 2 * WEEK + WEEK / 2;
+    client
+        .with_source_account(&u2)
+        .request_pause(&from, &(from + WEEK));
+    client.with_source_account(&u1).approve_pause();
+
+    let mut timestamps = [0; 48];
+    for (i, timestamp) in timestamps.iter_mut().enumerate() {
+        let i = i as u64;
+        *timestamp = match i % 3 {
+            0 => START + (i / 3) * WEEK / 3,
+            1 => START + (i / 3) * WEEK / 3 + WEEK - 1,
+            _ => START + (i / 3) * WEEK / 3 + WEEK,
+        };
+    }
+    timestamps.sort_unstable();
+
+    let mut paid = 0;
+    for timestamp in timestamps {
+        set_timestamp(&env, timestamp);
+        let can = client.can_withdraw();
+        let result = client.try_withdraw();
+        assert_eq!(can, result.is_ok(), "at {}", timestamp);
+        if can {
+            paid += 1;
+        }
+    }
+    assert!(paid > 0);
+}
+
+/// In `test_withdraw_up_to()`, the `Child` is four weeks behind and catches up
+/// up to a cut-off: first partway, then exactly on a boundary, and finally
+/// with a cut-off in the future, which doesn't let them collect early.
+#[test]
+fn test_withdraw_up_to() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client.withdraw();
+
+    set_timestamp(&env, START + 4 * WEEK);
+    assert_eq!(client.withdraw_up_to(&(START + 2 * WEEK + 10)), 2);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 3);
+
+    assert_eq!(
+        client.try_withdraw_up_to(&(START + 3 * WEEK - 1)),
+        Err(Ok(Error::NothingDue))
+    );
+    assert_eq!(client.withdraw_up_to(&(START + 3 * WEEK)), 1);
+
+    assert_eq!(client.withdraw_up_to(&(START + 10 * WEEK)), 1);
+    assert_eq!(
+        client.try_withdraw_up_to(&(START + 10 * WEEK)),
+        Err(Ok(Error::NothingDue))
+    );
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 5);
+}
+
+#[test]
+fn test_withdraw_periods() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    // Hourly, for 1000 a period.
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &8760000,
+        &3600,
+        &Options::default(),
+    );
+    client.withdraw();
+
+    set_timestamp(&env, START + 500 * 3600);
+    assert_eq!(
+        client.try_withdraw_periods(&0),
+        Err(Ok(Error::InvalidArguments))
+    );
+
+    assert_eq!(client.withdraw_periods(&200), 200);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 201 * 1000);
+    assert_eq!(client.required_allowance(), 300 * 1000);
+
+    assert_eq!(client.withdraw_periods(&200), 200);
+    assert_eq!(client.withdraw_periods(&200), 100);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 501 * 1000);
+
+    assert_eq!(
+        client.try_withdraw_periods(&200),
+        Err(Ok(Error::ChildAlreadyWithdrawn))
+    );
+}
+
+/// In `test_forecast()`, what the forecast says a window will cost is exactly
+/// what the `Child` ends up withdrawing for it, trial and escalator included.
+#[test]
+fn test_forecast() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            escalator_bps: 1000,
+            escalator_every: 2,
+            trial_periods: 1,
+            ..Default::default()
+        },
+    );
+
+    assert_eq!(
+        client.try_forecast(&(START + WEEK), &(START + WEEK)),
+        Err(Ok(Error::InvalidArguments))
+    );
+
+    // Week 0 is free, week 1 is at 50 units a year and week 2 at 55.
+    let first = client.forecast(&START, &(START + 3 * WEEK));
+    assert_eq!(first, 9615384 + 10576923);
+    assert_eq!(client.forecast(&(START + 1), &(START + WEEK + 1)), 9615384);
+
+    let later = client.forecast(&(START + 3 * WEEK), &(START + 7 * WEEK));
+    assert_eq!(later, 10576923 + 11634615 * 2 + 12798076);
+
+    set_timestamp(&env, START + 2 * WEEK);
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), first);
+
+    set_timestamp(&env, START + 6 * WEEK);
+    client.withdraw();
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        first + later
+    );
+
+    // Withdrawing doesn't change what the past cost.
+    assert_eq!(client.forecast(&START, &(START + 3 * WEEK)), first);
+}
+
+/// In `test_status()`, the allowance goes through every state it can get into
+/// today: set up but not started, active, behind, paused, and active again.
+#[test]
+fn test_status() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    assert_eq!(client.get_status(), Status::Uninitialized);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            auto_approve_child_pause: true,
+            ..Default::default()
+        },
+    );
+    assert_eq!(client.get_status(), Status::Active);
+    client
+        .with_source_account(&u1)
+        .fix_start_epoch(&(START + WEEK));
+    assert_eq!(client.get_status(), Status::NotStarted);
+
+    // One withdrawal due is just business as usual, but two is falling behind.
+    set_timestamp(&env, START + 2 * WEEK - 1);
+    assert_eq!(client.get_status(), Status::Active);
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.get_status(), Status::InArrears);
+    client.withdraw();
+    assert_eq!(client.get_status(), Status::Active);
+
+    let from = START + 2 * WEEK + 10;
+    let until = START + 4 * WEEK;
+    client.with_source_account(&u2).request_pause(&from, &until);
+    assert_eq!(client.get_status(), Status::Active);
+    set_timestamp(&env, from);
+    assert_eq!(client.get_status(), Status::Paused);
+
+    // A paused schedule can't fall behind, and once it's over, it picks up
+    // where it left off.
+    set_timestamp(&env, until - 1);
+    assert_eq!(client.get_status(), Status::Paused);
+    set_timestamp(&env, until);
+    assert_eq!(client.get_status(), Status::Active);
+}
+
+/// In `test_withdraw_count()`, every withdrawal is counted, however many are
+/// collected in one go.
+#[test]
+fn test_withdraw_count() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    assert_eq!(
+        client.try_withdraw_count(),
+        Err(Ok(Error::ContractNotInitialized))
+    );
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    assert_eq!(client.withdraw_count(), 0);
+
+    client.withdraw();
+    assert_eq!(client.withdraw_count(), 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    assert_eq!(client.withdraw_count(), 1);
+
+    set_timestamp(&env, START + 3 * WEEK);
+    assert_eq!(client.withdraw(), 3);
+    assert_eq!(client.withdraw_count(), 4);
+}
+
+/// In `test_period_index_of()`, timestamps are numbered into periods right up
+/// to (and just past) each boundary, both on a fixed `step` and on the
+/// calendar.
+#[test]
+fn test_period_index_of() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    assert_eq!(
+        client.try_period_index_of(&(START - 1)),
+        Err(Ok(Error::InvalidArguments))
+    );
+    assert_eq!(client.period_index_of(&START), 0);
+    assert_eq!(client.period_index_of(&(START + WEEK - 1)), 0);
+    assert_eq!(client.period_index_of(&(START + WEEK)), 1);
+    assert_eq!(client.period_index_of(&(START + WEEK + 1)), 1);
+    assert_eq!(client.period_index_of(&(START + 1000 * WEEK)), 1000);
+
+    // Anchored on the 15th, a period runs to the end of the 14th.
+    const JAN_15: u64 = 1673740800;
+    const FEB_15: u64 = 1676419200;
+    const JAN_15_2033: u64 = 1989360000;
+
+    set_timestamp(&env, JAN_15);
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            monthly_anchor: 15,
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        client.try_period_index_of(&(JAN_15 - 1)),
+        Err(Ok(Error::InvalidArguments))
+    );
+    assert_eq!(client.period_index_of(&JAN_15), 0);
+    assert_eq!(client.period_index_of(&(FEB_15 - 1)), 0);
+    assert_eq!(client.period_index_of(&FEB_15), 1);
+    assert_eq!(client.period_index_of(&(FEB_15 + 1)), 1);
+    assert_eq!(client.period_index_of(&(JAN_15_2033 - 1)), 119);
+    assert_eq!(client.period_index_of(&JAN_15_2033), 120);
+}
+
+/// `VaultContract` is as simple as a contract gets. It just holds whatever it's
+/// sent, like a DAO treasury would.
+pub struct VaultContract;
+
+#[contractimpl]
+impl VaultContract {
+    pub fn hello(_e: Env) {}
+}
+
+/// In `test_contract_child()`, the allowance is paid to a contract instead of
+/// a user account.
+#[test]
+fn test_contract_child() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    let vault_id = env.register_contract(None, VaultContract);
+    let vault = Identifier::Contract(vault_id);
+    client
+        .with_source_account(&u1)
+        .init(&vault, &id, &500000000, &WEEK, &Options::default());
+
+    client.with_source_account(&u1).withdraw();
+    assert_eq!(token.balance(&vault), 9615384);
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.with_source_account(&u1).withdraw(), 2);
+    assert_eq!(token.balance(&vault), 9615384 * 3);
+}
+
+/// In `test_init_for_account()`, the `Child` is given as a plain `AccountId`,
+/// which is the same as giving `init` an `Identifier::Account`.
+#[test]
+fn test_init_for_account() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init_for_account(
+        &u2,
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+
+    env.as_contract(&contract_id, || {
+        let state: State = env.storage().get(StorageKey::State).unwrap().unwrap();
+        assert_eq!(state.child, Identifier::Account(u2.clone()));
+    });
+}
+
+/// `SquatterContract` gets to a freshly deployed AllowanceContract before its
+/// `Parent` does, and tries to set it up to pay someone else out of their
+/// allowance.
+pub struct SquatterContract;
+
+#[contractimpl]
+impl SquatterContract {
+    pub fn squat(
+        e: Env,
+        contract_id: BytesN<32>,
+        salt: BytesN<32>,
+        parent: AccountId,
+        child: AccountId,
+        token_id: BytesN<32>,
+    ) -> Result<(), Error> {
+        let result = AllowanceContractClient::new(&e, &contract_id).try_init_on_behalf(
+            &salt,
+            &Identifier::Account(parent),
+            &Identifier::Account(child),
+            &token_id,
+            &500000000,
+            &WEEK,
+            &Options::default(),
+        );
+        match result {
+            Ok(_) => Ok(()),
+            Err(Ok(err)) => Err(err),
+            Err(Err(_)) => panic!("init_on_behalf failed without a contract error"),
+        }
+    }
+
+    pub fn squat_init(
+        e: Env,
+        contract_id: BytesN<32>,
+        salt: BytesN<32>,
+        child: AccountId,
+        token_id: BytesN<32>,
+    ) -> Result<(), Error> {
+        let result = AllowanceContractClient::new(&e, &contract_id).try_init(
+            &Identifier::Account(child),
+            &token_id,
+            &500000000,
+            &WEEK,
+            &Options {
+                deployer_salt: Some(salt),
+                ..Default::default()
+            },
+        );
+        match result {
+            Ok(_) => Ok(()),
+            Err(Ok(err)) => Err(err),
+            Err(Err(_)) => panic!("init failed without a contract error"),
+        }
+    }
+}
+
+/// In `test_verify_config()`, somebody beats the `Parent` to `init`. Their
+/// own `init` fails, which is easy to miss, but `verify_config` won't let it
+/// go unnoticed.
+#[test]
+fn test_verify_config() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    let expected = Config {
+        parent: Identifier::Account(u1.clone()),
+        child: Identifier::Account(u2.clone()),
+        token_id: id.clone(),
+        amount: 500000000,
+        step: WEEK,
+        options: Options::default(),
+    };
+    assert_eq!(
+        client.try_verify_config(&expected),
+        Err(Ok(Error::ContractNotInitialized))
+    );
+
+    // The squatter can't name `u1` as the `Parent`, but it can still beat
+    // them to `init`, as itself.
+    let squatter_id = env.register_contract(None, SquatterContract);
+    let salt = BytesN::from_array(&env, &[0; 32]);
+    assert_eq!(
+        SquatterContractClient::new(&env, &squatter_id).try_squat(
+            &contract_id,
+            &salt,
+            &u1,
+            &u3,
+            &id
+        ),
+        Err(Ok(Error::InvalidInvoker))
+    );
+    client.with_source_account(&u3).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    assert_eq!(
+        client.with_source_account(&u1).try_init(
+            &Identifier::Account(u2.clone()),
+            &id,
+            &500000000,
+            &WEEK,
+            &Options::default(),
+        ),
+        Err(Ok(Error::ContractAlreadyInitialized))
+    );
+    assert_eq!(
+        client.try_verify_config(&expected),
+        Err(Ok(Error::ConfigMismatch))
+    );
+
+    // Set up properly, it checks out.
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client.verify_config(&expected);
+
+    // And a user account can't name somebody else as the `Parent`.
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    assert_eq!(
+        client.with_source_account(&u3).try_init_on_behalf(
+            &salt,
+            &Identifier::Account(u1),
+            &Identifier::Account(u3),
+            &id,
+            &500000000,
+            &WEEK,
+            &Options::default(),
+        ),
+        Err(Ok(Error::InvalidInvoker))
+    );
+}
+
+/// `KeeperContract` is a tiny contract that does nothing but call `withdraw`
+/// on an AllowanceContract for us, the way a keeper bot's contract would.
+pub struct KeeperContract;
+
+#[contractimpl]
+impl KeeperContract {
+    pub fn sweep(e: Env, contract_id: BytesN<32>) -> u32 {
+        AllowanceContractClient::new(&e, &contract_id).withdraw()
+    }
+}
+
+/// In `test_withdraw_by_contract()`, `withdraw` is invoked cross-contract, but
+/// the contract wasn't set up to allow that, so we want a clean
+/// `Error::InvalidInvoker` back.
+#[test]
+#[should_panic(expected = "Status(ContractError(5))")] // We want this test to panic since contracts can't invoke `withdraw` here.
+fn test_withdraw_by_contract() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    let keeper_id = env.register_contract(None, KeeperContract);
+    KeeperContractClient::new(&env, &keeper_id)
+        .with_source_account(&u1)
+        .sweep(&contract_id);
+}
+
+/// In `test_withdraw_by_contract_allowed()`, the contract was set up to allow
+/// contract invokers, so the keeper's `withdraw` goes through, and the money
+/// still goes to the `Child`.
+#[test]
+fn test_withdraw_by_contract_allowed() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            allow_contract_invokers: true,
+            ..Default::default()
+        },
+    );
+
+    let keeper_id = env.register_contract(None, KeeperContract);
+    let keeper = KeeperContractClient::new(&env, &keeper_id);
+    assert_eq!(keeper.with_source_account(&u1).sweep(&contract_id), 1);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+    assert_eq!(token.balance(&Identifier::Contract(keeper_id)), 0);
+}
+
+/// In `test_invoker_allowlist()`, the `Parent` limits who may invoke
+/// `withdraw`. Somebody on the list can, a stranger can't, and the `Child`
+/// always can. Clearing the list lets anybody invoke it again.
+#[test]
+fn test_invoker_allowlist() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let u4 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    let invokers = vec![&env, u3.clone()];
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_set_invoker_allowlist(&invokers),
+        Err(Ok(Error::Unauthorized))
+    );
+    client
+        .with_source_account(&u1)
+        .set_invoker_allowlist(&invokers);
+
+    assert_eq!(
+        client.with_source_account(&u4).try_withdraw(),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(client.with_source_account(&u3).withdraw(), 1);
+
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(
+        client.with_source_account(&u1).try_withdraw(),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(client.with_source_account(&u2).withdraw(), 1);
+
+    set_timestamp(&env, START + 2 * WEEK);
+    client
+        .with_source_account(&u1)
+        .set_invoker_allowlist(&Vec::new(&env));
+    assert_eq!(client.with_source_account(&u4).withdraw(), 1);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 3);
+}
+
+/// In `test_subscriptions()`, one contract looks after two subscriptions,
+/// with different tokens, `Child`ren and schedules, and neither one gets in
+/// the other's way.
+#[test]
+fn test_subscriptions() {
+    const DAY: u64 = 24 * 60 * 60;
+
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (weekly_id, weekly_token) = setup_token(&env, &u1, &contract_id);
+    let (daily_id, daily_token) = setup_token_at(&env, &[1; 32], &u1, &contract_id);
+
+    let weekly = client.with_source_account(&u1).create(
+        &Identifier::Account(u2.clone()),
+        &weekly_id,
+        &START,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    let daily = client.with_source_account(&u1).create(
+        &Identifier::Account(u3.clone()),
+        &daily_id,
+        &(START + DAY),
+        &365000000,
+        &DAY,
+        &Options::default(),
+    );
+    assert_eq!((weekly, daily), (1, 2));
+
+    assert_eq!(client.withdraw_for(&weekly), 1);
+    assert_eq!(client.try_withdraw_for(&daily), Err(Ok(Error::NothingDue)));
+    assert!(!client.can_withdraw_for(&daily));
+
+    set_timestamp(&env, START + 3 * DAY);
+    assert_eq!(client.required_allowance_for(&daily, &false), 3000000);
+    assert_eq!(client.withdraw_for(&daily), 3);
+    assert_eq!(
+        client.try_withdraw_for(&weekly),
+        Err(Ok(Error::ChildAlreadyWithdrawn))
+    );
+
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(client.withdraw_for(&weekly), 1);
+    assert_eq!(client.get_status_for(&daily), Status::InArrears);
+    assert_eq!(client.withdraw_for(&daily), 4);
+
+    assert_eq!(
+        weekly_token.balance(&Identifier::Account(u2.clone())),
+        9615384 * 2
+    );
+    assert_eq!(
+        daily_token.balance(&Identifier::Account(u3.clone())),
+        1000000 * 7
+    );
+    assert_eq!(weekly_token.balance(&Identifier::Account(u3.clone())), 0);
+    assert_eq!(daily_token.balance(&Identifier::Account(u2.clone())), 0);
+
+    // Nothing was ever `init`ialized, and there's no third subscription.
+    assert_eq!(
+        client.try_withdraw(),
+        Err(Ok(Error::ContractNotInitialized))
+    );
+    assert_eq!(
+        client.try_withdraw_for(&3),
+        Err(Ok(Error::SubscriptionNotFound))
+    );
+    assert!(!client.can_withdraw_for(&3));
+}
+
+/// In `test_subscription_alongside_init()`, a contract that was `init`ialized
+/// the usual way can host more subscriptions, and the original allowance is
+/// subscription `0`.
+#[test]
+fn test_subscription_alongside_init() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    let sub = client.with_source_account(&u1).create(
+        &Identifier::Account(u3.clone()),
+        &id,
+        &START,
+        &250000000,
+        &WEEK,
+        &Options::default(),
+    );
+    assert_eq!(sub, 1);
+
+    // Each side can only change its own subscription's amount.
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_propose_amount_for(&sub, &500000000),
+        Err(Ok(Error::Unauthorized))
+    );
+    client
+        .with_source_account(&u1)
+        .propose_amount_for(&sub, &500000000);
+
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(
+        client.try_withdraw_for(&0),
+        Err(Ok(Error::ChildAlreadyWithdrawn))
+    );
+    assert_eq!(client.withdraw_for(&sub), 1);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+    assert_eq!(token.balance(&Identifier::Account(u3.clone())), 9615384);
+}
+
+/// In `test_subscription_entry_points()`, a `create`d subscription gets
+/// suspended, handed to an admin, reactivated and cancelled (by signature)
+/// through its own `_for` entry points, and the allowance set up by `init`
+/// never notices.
+#[test]
+fn test_subscription_entry_points() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let u4 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    let approve = |amount: i128| {
+        token.with_source_account(&u1).approve(
+            &Signature::Invoker,
+            &0,
+            &Identifier::Contract(contract_id.clone()),
+            &amount,
+        );
+    };
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    let sub = client.with_source_account(&u1).create(
+        &Identifier::Account(u3.clone()),
+        &id,
+        &START,
+        &500000000,
+        &WEEK,
+        &Options {
+            max_failures: 3,
+            ..Default::default()
+        },
+    );
+    approve(0);
+
+    for week in 0..3 {
+        set_timestamp(&env, START + week * WEEK);
+        assert_eq!(client.report_shortfall_for(&sub), 1);
+    }
+    assert_eq!(client.get_status_for(&sub), Status::Suspended);
+    assert_ne!(client.get_status(), Status::Suspended);
+
+    approve(500000000);
+    assert_eq!(
+        client.with_source_account(&u4).try_reactivate_for(&sub),
+        Err(Ok(Error::Unauthorized))
+    );
+    client
+        .with_source_account(&u1)
+        .set_admin_for(&sub, &Identifier::Account(u4.clone()));
+    assert_eq!(client.get_state().extras.admin, None);
+    client.with_source_account(&u4).reactivate_for(&sub);
+    assert_eq!(client.withdraw_for(&sub), 3);
+    assert_eq!(token.balance(&Identifier::Account(u3.clone())), 9615384 * 3);
+
+    client
+        .with_source_account(&u1)
+        .cancel_signed_for(&sub, &Signature::Invoker, &0);
+    assert_eq!(client.get_status_for(&sub), Status::Cancelled);
+    assert_ne!(client.get_status(), Status::Cancelled);
+}
+
+/// In `test_subscriptions_of()`, one account is the `Parent` of two
+/// subscriptions and the `Child` of another, and can look all of them up, a
+/// page at a time.
+#[test]
+fn test_subscriptions_of() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    let me = Identifier::Account(u1.clone());
+    let create = |parent: &AccountId, child: &AccountId| {
+        client.with_source_account(parent).create(
+            &Identifier::Account(child.clone()),
+            &id,
+            &START,
+            &500000000,
+            &WEEK,
+            &Options::default(),
+        )
+    };
+    let first = create(&u1, &u2);
+    let paid_to_me = create(&u2, &u1);
+    let second = create(&u1, &u3);
+
+    assert_eq!(
+        client.subscriptions_of(&me, &Role::Parent, &0, &10),
+        vec![&env, first, second]
+    );
+    assert_eq!(
+        client.subscriptions_of(&me, &Role::Child, &0, &10),
+        vec![&env, paid_to_me]
+    );
+    assert_eq!(
+        client.subscriptions_of(&Identifier::Account(u3.clone()), &Role::Parent, &0, &10),
+        Vec::new(&env)
+    );
+
+    assert_eq!(
+        client.subscriptions_of(&me, &Role::Parent, &0, &1),
+        vec![&env, first]
+    );
+    assert_eq!(
+        client.subscriptions_of(&me, &Role::Parent, &1, &1),
+        vec![&env, second]
+    );
+    assert_eq!(
+        client.subscriptions_of(&me, &Role::Parent, &2, &1),
+        Vec::new(&env)
+    );
+}
+
+/// In `test_due_subscriptions()`, a keeper pages through a contract with a
+/// paused subscription, two that are due and one that hasn't started yet.
+#[test]
+fn test_due_subscriptions() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    // Subscription 0 has been paid, and is paused from then on.
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            auto_approve_child_pause: true,
+            ..Default::default()
+        },
+    );
+    client.withdraw();
+    client
+        .with_source_account(&u2)
+        .request_pause(&(START + 10), &(START + 3 * WEEK));
+
+    let create = |start: u64| {
+        client.with_source_account(&u1).create(
+            &Identifier::Account(u2.clone()),
+            &id,
+            &start,
+            &500000000,
+            &WEEK,
+            &Options::default(),
+        )
+    };
+    assert_eq!(create(START), 1);
+    assert_eq!(create(START + 2 * WEEK), 2);
+    assert_eq!(create(START), 3);
+
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(client.due_subscriptions(&0, &1), (vec![&env, 1], 2));
+    assert_eq!(client.due_subscriptions(&2, &1), (vec![&env, 3], 0));
+    assert_eq!(client.due_subscriptions(&0, &10), (vec![&env, 1, 3], 0));
+
+    // Whatever it says is due really is.
+    assert_eq!(client.withdraw_for(&1), 2);
+    assert_eq!(client.due_subscriptions(&0, &10), (vec![&env, 3], 0));
+}
+
+/// In `test_withdraw_batch()`, a keeper collects for a handful of
+/// subscriptions at once. The ones that can't pay out don't stop the ones
+/// that can.
+#[test]
+fn test_withdraw_batch() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    let create = |child: &AccountId, start: u64, amount: i128| {
+        client.with_source_account(&u1).create(
+            &Identifier::Account(child.clone()),
+            &id,
+            &start,
+            &amount,
+            &WEEK,
+            &Options::default(),
+        )
+    };
+    let due = create(&u2, START, 500000000);
+    let not_due = create(&u2, START + WEEK, 500000000);
+    // A week of this is more than the `Parent` allowed us to spend.
+    let underfunded = create(&u3, START, 50000000000);
+
+    assert_eq!(
+        client.withdraw_batch(&vec![&env, due, not_due, underfunded, 9]),
+        vec![
+            &env,
+            Outcome::Paid(1),
+            Outcome::Failed(Error::NothingDue as u32),
+            Outcome::Failed(Error::InsufficientAllowance as u32),
+            Outcome::Failed(Error::SubscriptionNotFound as u32),
+        ]
+    );
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+    assert_eq!(token.balance(&Identifier::Account(u3.clone())), 0);
+
+    // Only the one that paid has moved on.
+    assert_eq!(
+        client.withdraw_batch(&vec![&env, due, underfunded]),
+        vec![
+            &env,
+            Outcome::Failed(Error::ChildAlreadyWithdrawn as u32),
+            Outcome::Failed(Error::InsufficientAllowance as u32),
+        ]
+    );
+
+    let mut too_many = Vec::new(&env);
+    for _ in 0..21 {
+        too_many.push_back(due);
+    }
+    assert_eq!(
+        client.try_withdraw_batch(&too_many),
+        Err(Ok(Error::InvalidArguments))
+    );
+}
+
+/// In `test_withdraw_batch_undoes_failures()`, one subscription's swap comes
+/// out short once the money is already in the pool, and another's `Parent`
+/// has approved more than they hold. Each one fails with nothing moved, and
+/// stays due, while the one that can pay does.
+#[test]
+fn test_withdraw_batch_undoes_failures() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let child = Identifier::Account(u2.clone());
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    let (out_id, out_token) = setup_token_at(&env, &[7; 32], &u1, &contract_id);
+
+    let pool_id = env.register_contract(None, MockPool);
+    let pool = MockPoolClient::new(&env, &pool_id);
+    out_token.with_source_account(&u1).xfer(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(pool_id.clone()),
+        &1000000000,
+    );
+    pool.set_rate(&9900);
+
+    let create = |parent: &AccountId, options: Options| {
+        client
+            .with_source_account(parent)
+            .create(&child, &id, &START, &500000000, &WEEK, &options)
+    };
+    let due = create(&u1, Options::default());
+    let swapped = create(
+        &u1,
+        Options {
+            swap: Some(SwapRoute {
+                pool: pool_id.clone(),
+                token_out: out_id,
+                min_price: 9950000,
+            }),
+            ..Default::default()
+        },
+    );
+    // `u3` has approved us, but has nothing to pay with.
+    token.with_source_account(&u3).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &500000000,
+    );
+    let broke = create(&u3, Options::default());
+
+    assert_eq!(
+        client.withdraw_batch(&vec![&env, swapped, broke, due]),
+        vec![
+            &env,
+            Outcome::Failed(Error::SlippageExceeded as u32),
+            Outcome::Failed(Error::InsufficientBalance as u32),
+            Outcome::Paid(1),
+        ]
+    );
+    assert_eq!(token.balance(&child), 9615384);
+    assert_eq!(out_token.balance(&child), 0);
+    assert_eq!(token.balance(&Identifier::Contract(pool_id.clone())), 0);
+    assert_eq!(
+        token.balance(&Identifier::Account(u1.clone())),
+        1000000000 - 9615384
+    );
+
+    pool.set_rate(&10000);
+    assert_eq!(
+        client.withdraw_batch(&vec![&env, swapped, due]),
+        vec![
+            &env,
+            Outcome::Paid(1),
+            Outcome::Failed(Error::ChildAlreadyWithdrawn as u32),
+        ]
+    );
+    assert_eq!(out_token.balance(&child), 9615384);
+
+    // Nobody else gets to pay out without the batch's checks.
+    assert_eq!(
+        client.try_batch_pay(&swapped),
+        Err(Ok(Error::InvalidInvoker))
+    );
+}
+
+/// In `test_upgrade()`, only the `Parent` gets to swap the contract's code.
+/// (Actually swapping it needs a second WASM build, which a unit test doesn't
+/// have, so we stop short of that.)
+#[test]
+fn test_upgrade() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    let wasm_hash = BytesN::from_array(&env, &[7; 32]);
+
+    assert_eq!(
+        client.try_upgrade(&wasm_hash),
+        Err(Ok(Error::ContractNotInitialized))
+    );
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    assert_eq!(
+        client.with_source_account(&u2).try_upgrade(&wasm_hash),
+        Err(Ok(Error::Unauthorized))
+    );
+
+    // The new code can tell which layout it's been handed.
+    env.as_contract(&contract_id, || {
+        let version: u32 = env
+            .storage()
+            .get(StorageKey::SchemaVersion)
+            .unwrap()
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+    });
+}
+
+/// In `test_migrate()`, a v0 contract's data is brought up to date, and it
+/// carries on paying out just the same. Data from a layout newer than the
+/// code isn't touched at all.
+#[test]
+fn test_migrate() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let (contract_id, client, token) = setup_v0(&env, &u1, &u2, 500000000, START - 3 * WEEK);
+
+    client.migrate();
+    env.as_contract(&contract_id, || {
+        let state: State = env.storage().get(StorageKey::State).unwrap().unwrap();
+        let version: u32 = env
+            .storage()
+            .get(StorageKey::SchemaVersion)
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.parent, Identifier::Account(u1.clone()));
+        assert_eq!(state.child, Identifier::Account(u2.clone()));
+        assert_eq!(state.latest, START - 3 * WEEK);
+        assert_eq!(version, SCHEMA_VERSION);
+
+        // Everything that's in the `State` now is gone from its old key.
+        for key in legacy::keys(&env) {
+            assert!(!env.storage().has(key));
+        }
+    });
+
+    // Migrating twice does nothing.
+    client.migrate();
+
+    assert_eq!(client.withdraw(), 3);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 3);
+
+    env.as_contract(&contract_id, || {
+        env.storage()
+            .set(StorageKey::SchemaVersion, SCHEMA_VERSION + 1);
+    });
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::MigrationRequired)));
+    assert_eq!(client.try_migrate(), Err(Ok(Error::MigrationRequired)));
+    assert!(!client.can_withdraw());
+}
+
+/// In `test_migrate_in_pages()`, a v1 contract has more subscriptions than one
+/// `migrate` gets through, so it takes a few, and they all carry on paying out
+/// in between.
+#[test]
+fn test_migrate_in_pages() {
+    let f = Fixture::new(Config {
+        init: false,
+        ..Config::default()
+    });
+    f.env.as_contract(&f.contract_id, || {
+        f.env.storage().set(StorageKey::SchemaVersion, 1u32);
+    });
+    f.init();
+    for _ in 0..MAX_BATCH + 4 {
+        f.client.with_source_account(&f.parent).create(
+            &f.child_id(),
+            &f.token_id,
+            &START,
+            &500000000,
+            &WEEK,
+            &Options::default(),
+        );
+    }
+    let migrated = |id: u64| {
+        f.env.as_contract(&f.contract_id, || {
+            let sub = Sub {
+                env: f.env.clone(),
+                id,
+            };
+            sub.storage().has(StorageKey::State)
+        })
+    };
+
+    assert!(!f.client.migrate());
+    assert!(migrated(MAX_BATCH as u64 - 1));
+    assert!(!migrated(MAX_BATCH as u64));
+    assert_eq!(f.client.withdraw_for(&(MAX_BATCH as u64 + 4)), 1);
+
+    assert!(f.client.migrate());
+    assert!(migrated(MAX_BATCH as u64 + 4));
+    f.env.as_contract(&f.contract_id, || {
+        let version: u32 = f
+            .env
+            .storage()
+            .get(StorageKey::SchemaVersion)
+            .unwrap()
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        assert!(!f.env.storage().has(StorageKey::MigratedTo));
+    });
+    assert!(f.client.migrate());
+    assert_eq!(f.client.withdraw_for(&1), 1);
+}
+
+/// In `test_admin()`, the `Parent` hands the running of the allowance to an
+/// operations key, which doubles it without the `Parent` lifting a finger.
+#[test]
+fn test_admin() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let u4 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    // Nobody else gets to pick the admin, and the `Child` can't be it.
+    let admin = Identifier::Account(u3.clone());
+    assert_eq!(
+        client.with_source_account(&u4).try_set_admin(&admin),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_set_admin(&Identifier::Account(u2.clone())),
+        Err(Ok(Error::InvalidArguments))
+    );
+    client.with_source_account(&u1).set_admin(&admin);
+
+    client.with_source_account(&u3).propose_amount(&1000000000);
+    assert_eq!(
+        client
+            .with_source_account(&u4)
+            .try_propose_amount(&250000000),
+        Err(Ok(Error::Unauthorized))
+    );
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 19230769);
+    assert_eq!(
+        token.balance(&Identifier::Account(u1.clone())),
+        1000000000 - 19230769
+    );
+
+    // The `Parent` can always take it back.
+    client.with_source_account(&u1).clear_admin();
+    assert_eq!(
+        client
+            .with_source_account(&u3)
+            .try_propose_amount(&250000000),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(
+        client.with_source_account(&u3).try_set_admin(&admin),
+        Err(Ok(Error::Unauthorized))
+    );
+}
+
+/// In `test_parent_handover()`, the allowance changes hands. The old `Parent`
+/// keeps paying until the new one accepts, and nobody else can accept for
+/// them.
+#[test]
+fn test_parent_handover() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let u4 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    token.with_source_account(&u1).mint(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Account(u3.clone()),
+        &1000000000,
+    );
+    token.with_source_account(&u3).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &500000000,
+    );
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    let new_parent = Identifier::Account(u3.clone());
+    assert_eq!(
+        client.with_source_account(&u3).try_accept_parent(),
+        Err(Ok(Error::NoParentProposal))
+    );
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_propose_parent(&new_parent),
+        Err(Ok(Error::Unauthorized))
+    );
+    client.with_source_account(&u1).propose_parent(&new_parent);
+    assert_eq!(
+        client.with_source_account(&u4).try_accept_parent(),
+        Err(Ok(Error::Unauthorized))
+    );
+
+    // Proposed, but not accepted yet.
+    client.withdraw();
+    assert_eq!(
+        token.balance(&Identifier::Account(u1.clone())),
+        1000000000 - 9615384
+    );
+
+    client.with_source_account(&u3).accept_parent();
+    set_timestamp(&env, START + WEEK);
+    client.withdraw();
+    assert_eq!(
+        token.balance(&Identifier::Account(u1.clone())),
+        1000000000 - 9615384
+    );
+    assert_eq!(token.balance(&new_parent), 1000000000 - 9615384);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 2);
+
+    assert_eq!(
+        client.subscriptions_of(&Identifier::Account(u1.clone()), &Role::Parent, &0, &10),
+        vec![&env]
+    );
+    assert_eq!(
+        client.subscriptions_of(&new_parent, &Role::Parent, &0, &10),
+        vec![&env, 0]
+    );
+}
+
+/// In `test_close_by_child()`, the `Child` walks away, once collecting what's
+/// due on the way out and once leaving it behind. Either way, that's the end
+/// of it.
+#[test]
+fn test_close_by_child() {
+    let env = Env::default();
+
+    for (token_id, final_withdraw) in [([1; 32], true), ([2; 32], false)] {
+        set_timestamp(&env, START);
+
+        let u1 = env.accounts().generate();
+        let u2 = env.accounts().generate();
+
+        let contract_id = env.register_contract(None, AllowanceContract);
+        let client = AllowanceContractClient::new(&env, &contract_id);
+        let (id, token) = setup_token_at(&env, &token_id, &u1, &contract_id);
+
+        client.with_source_account(&u1).init(
+            &Identifier::Account(u2.clone()),
+            &id,
+            &500000000,
+            &WEEK,
+            &Options::default(),
+        );
+        client.withdraw();
+
+        set_timestamp(&env, START + 3 * WEEK);
+        assert_eq!(
+            client
+                .with_source_account(&u1)
+                .try_close_by_child(&final_withdraw),
+            Err(Ok(Error::Unauthorized))
+        );
+        client
+            .with_source_account(&u2)
+            .close_by_child(&final_withdraw);
+
+        let paid = if final_withdraw { 4 } else { 1 };
+        assert_eq!(
+            token.balance(&Identifier::Account(u2.clone())),
+            9615384 * paid
+        );
+        assert_eq!(client.get_status(), Status::Cancelled);
+        assert_eq!(client.try_withdraw(), Err(Ok(Error::SubscriptionCancelled)));
+
+        set_timestamp(&env, START + 5 * WEEK);
+        assert_eq!(client.try_withdraw(), Err(Ok(Error::SubscriptionCancelled)));
+        assert!(!client.can_withdraw());
+        assert_eq!(
+            client
+                .with_source_account(&u2)
+                .try_close_by_child(&final_withdraw),
+            Err(Ok(Error::Cancelled))
+        );
+    }
+}
+
+/// In `test_cancel()`, the `Parent` cancels, with two weeks' notice. The
+/// `Child` is still paid for everything that comes due before it runs out,
+/// and nothing after.
+#[test]
+fn test_cancel() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            cancel_notice_period: 2 * WEEK,
+            ..Default::default()
+        },
+    );
+    client.withdraw();
+
+    set_timestamp(&env, START + WEEK / 2);
+    assert_eq!(
+        client.with_source_account(&u2).try_cancel(),
+        Err(Ok(Error::Unauthorized))
+    );
+    client.with_source_account(&u1).cancel();
+    assert_eq!(client.get_status(), Status::Terminating);
+
+    // During the notice.
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(client.withdraw(), 1);
+
+    // After it, the last one due within the notice is still paid.
+    set_timestamp(&env, START + 4 * WEEK);
+    assert_eq!(client.get_status(), Status::Cancelled);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::SubscriptionCancelled)));
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 3);
+    assert_eq!(
+        client.with_source_account(&u1).try_cancel(),
+        Err(Ok(Error::Cancelled))
+    );
+}
+
+/// In `test_cancel_without_notice()`, there's no notice to give, so a cancel
+/// takes effect straight away.
+#[test]
+fn test_cancel_without_notice() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client.withdraw();
+    client.with_source_account(&u1).cancel();
+    assert_eq!(client.get_status(), Status::Cancelled);
+
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::SubscriptionCancelled)));
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+}
+
+/// In `test_early_termination_fee()`, the `Parent` signed up for at least
+/// four weeks. Cancelling sooner costs them a fee, unless they can't pay it,
+/// in which case they can't cancel either. Once the four weeks are up,
+/// cancelling is free.
+#[test]
+fn test_early_termination_fee() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2.clone());
+
+    let options = Options {
+        min_periods: 4,
+        early_termination_fee: 20000000,
+        ..Default::default()
+    };
+    let setup = |token_id: &[u8; 32]| {
+        let contract_id = env.register_contract(None, AllowanceContract);
+        let client = AllowanceContractClient::new(&env, &contract_id);
+        let (id, token) = setup_token_at(&env, token_id, &u1, &contract_id);
+        client
+            .with_source_account(&u1)
+            .init(&child, &id, &500000000, &WEEK, &options);
+        client.withdraw();
+        (contract_id, client, token)
+    };
+
+    let (_, client, token) = setup(&[1; 32]);
+    client.with_source_account(&u1).cancel();
+    assert_eq!(token.balance(&child), 9615384 + 20000000);
+
+    // There's not enough allowance left for the fee.
+    set_timestamp(&env, START);
+    let (contract_id, client, token) = setup(&[2; 32]);
+    token.with_source_account(&u1).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id),
+        &10000000,
+    );
+    assert_eq!(
+        client.with_source_account(&u1).try_cancel(),
+        Err(Ok(Error::PenaltyPaymentFailed))
+    );
+    assert_eq!(client.get_status(), Status::Active);
+
+    set_timestamp(&env, START);
+    let (_, client, token) = setup(&[3; 32]);
+    set_timestamp(&env, START + 3 * WEEK);
+    client.withdraw();
+    client.with_source_account(&u1).cancel();
+    assert_eq!(token.balance(&child), 9615384 * 4);
+}
+
+/// In `test_early_termination_fee_paid_out()`, the fee is paid to whoever the
+/// `Child` has assigned their withdrawals to, and it gets a receipt and is
+/// counted along with everything else that's been paid.
+#[test]
+fn test_early_termination_fee_paid_out() {
+    let f = Fixture::new(Config {
+        options: Options {
+            min_periods: 4,
+            early_termination_fee: 20000000,
+            ..Default::default()
+        },
+        ..Config::default()
+    });
+    let financier = Identifier::Account(f.env.accounts().generate());
+    f.client.withdraw();
+    f.client
+        .with_source_account(&f.child)
+        .assign_payments(&financier, &3);
+
+    f.client.with_source_account(&f.parent).cancel();
+    f.assert_balance(&f.child_id(), 9615384);
+    f.assert_balance(&financier, 20000000);
+    assert_eq!(
+        f.client.history(&0, &1),
+        vec![
+            &f.env,
+            Receipt {
+                period: 1,
+                amount: 20000000,
+                delivered: 20000000,
+                paid_at: START,
+                label: symbol!("penalty"),
+            },
+        ]
+    );
+    assert_eq!(f.client.get_state().progress.delivered, 9615384);
+
+    // Without an assignment, it's counted as paid to the `Child`.
+    let f = Fixture::new(Config {
+        options: Options {
+            min_periods: 4,
+            early_termination_fee: 20000000,
+            ..Default::default()
+        },
+        ..Config::default()
+    });
+    f.client.with_source_account(&f.parent).cancel();
+    assert_eq!(f.client.get_state().progress.delivered, 20000000);
+    assert_eq!(f.client.history(&0, &1).len(), 1);
+}
+
+/// In `test_mutual_close()`, both sides agree to close, in either order, and
+/// whatever is still due is paid out on the way. Changing your mind in
+/// between means starting over, and nobody else gets a say.
+#[test]
+fn test_mutual_close() {
+    let env = Env::default();
+
+    for (token_id, parent_first) in [([1; 32], true), ([2; 32], false)] {
+        set_timestamp(&env, START);
+
+        let u1 = env.accounts().generate();
+        let u2 = env.accounts().generate();
+        let u3 = env.accounts().generate();
+
+        let contract_id = env.register_contract(None, AllowanceContract);
+        let client = AllowanceContractClient::new(&env, &contract_id);
+        let (id, token) = setup_token_at(&env, &token_id, &u1, &contract_id);
+
+        client.with_source_account(&u1).init(
+            &Identifier::Account(u2.clone()),
+            &id,
+            &500000000,
+            &WEEK,
+            &Options::default(),
+        );
+        client.withdraw();
+
+        let (first, second) = if parent_first { (&u1, &u2) } else { (&u2, &u1) };
+        assert_eq!(
+            client.with_source_account(&u3).try_approve_close(),
+            Err(Ok(Error::Unauthorized))
+        );
+        client.with_source_account(first).approve_close();
+        client.with_source_account(first).revoke_close_approval();
+
+        // That wasn't enough on its own.
+        set_timestamp(&env, START + WEEK);
+        client.with_source_account(second).approve_close();
+        assert_eq!(client.get_status(), Status::Active);
+
+        set_timestamp(&env, START + 2 * WEEK);
+        client.with_source_account(first).approve_close();
+        assert_eq!(client.get_status(), Status::Cancelled);
+        assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 3);
+
+        set_timestamp(&env, START + 3 * WEEK);
+        assert_eq!(client.try_withdraw(), Err(Ok(Error::SubscriptionCancelled)));
+    }
+}
+
+/// In `test_forgive_arrears()`, the `Child` is four withdrawals behind, and
+/// the `Parent` waives some of them: first two, then (asking for more than
+/// there are) the rest, and then there's nothing left to waive.
+#[test]
+fn test_forgive_arrears() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client.withdraw();
+
+    set_timestamp(&env, START + 4 * WEEK);
+    assert_eq!(
+        client.with_source_account(&u2).try_forgive_arrears(&2),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(client.with_source_account(&u1).forgive_arrears(&2), 2);
+    assert_eq!(client.withdraw(), 2);
+    assert_eq!(client.withdraw_count(), 3);
+
+    set_timestamp(&env, START + 7 * WEEK);
+    assert_eq!(client.with_source_account(&u1).forgive_arrears(&5), 3);
+    assert_eq!(client.with_source_account(&u1).forgive_arrears(&1), 0);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 3);
+    assert_eq!(client.withdraw_count(), 3);
+}
+
+/// In `test_forgive_shortfall()`, the `Parent` comes up short, and the first
+/// withdrawal is reported late. Once it's been forgiven, there's no late fee
+/// left to pay on it, and the withdrawals after it cost the usual.
+#[test]
+fn test_forgive_shortfall() {
+    let f = Fixture::new(Config {
+        options: Options {
+            late_fee_bps: 1000,
+            ..Default::default()
+        },
+        ..Config::default()
+    });
+    let approve = |amount: i128| {
+        f.token.with_source_account(&f.parent).approve(
+            &Signature::Invoker,
+            &0,
+            &Identifier::Contract(f.contract_id.clone()),
+            &amount,
+        );
+    };
+
+    approve(0);
+    assert_eq!(
+        f.client.try_withdraw(),
+        Err(Ok(Error::InsufficientAllowance))
+    );
+    assert_eq!(f.client.report_shortfall(), 1);
+
+    f.advance_time(2 * WEEK);
+    assert_eq!(
+        f.client.with_source_account(&f.parent).forgive_arrears(&1),
+        1
+    );
+    f.env.as_contract(&f.contract_id, || {
+        assert!(!f.env.storage().has(StorageKey::FailedAt(0)));
+    });
+
+    approve(500000000);
+    assert_eq!(f.client.required_allowance(&false), 9615384 * 2);
+    assert_eq!(f.client.withdraw(), 2);
+    f.assert_balance(&f.child_id(), 9615384 * 2);
+}
+
+/// In `test_prepay()`, the `Parent` pays for the next three weeks up front,
+/// so there's nothing for the `Child` to collect until the fourth. On a
+/// cancelled allowance, only what's left before the end can be prepaid.
+#[test]
+fn test_prepay() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            cancel_notice_period: 2 * WEEK,
+            ..Default::default()
+        },
+    );
+    client.withdraw();
+
+    assert_eq!(
+        client.with_source_account(&u2).try_prepay(&3),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(client.with_source_account(&u1).prepay(&3), 9615384 * 3);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 4);
+    assert_eq!(client.withdraw_count(), 4);
+
+    set_timestamp(&env, START + 3 * WEEK);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    set_timestamp(&env, START + 4 * WEEK);
+    assert_eq!(client.withdraw(), 1);
+
+    // The notice runs out at `START + 6 * WEEK`, so there are only two left.
+    client.with_source_account(&u1).cancel();
+    assert_eq!(client.with_source_account(&u1).prepay(&5), 9615384 * 2);
+    assert_eq!(
+        client.with_source_account(&u1).try_prepay(&1),
+        Err(Ok(Error::NothingDue))
+    );
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 7);
+}
+
+/// In `test_skip()`, both sides agree to skip next week's withdrawal. It
+/// comes and goes without costing anything, and the one after is paid as
+/// usual. A withdrawal that's already been paid can't be skipped, and
+/// neither side gets to skip one on their own.
+#[test]
+fn test_skip() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client.withdraw();
+
+    assert_eq!(
+        client.with_source_account(&u2).try_propose_skip(&0),
+        Err(Ok(Error::InvalidArguments))
+    );
+    assert_eq!(
+        client.with_source_account(&u3).try_propose_skip(&1),
+        Err(Ok(Error::Unauthorized))
+    );
+    client.with_source_account(&u2).propose_skip(&1);
+    assert_eq!(
+        client.with_source_account(&u2).try_accept_skip(&1),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(
+        client.with_source_account(&u1).try_accept_skip(&2),
+        Err(Ok(Error::NoSkipProposal))
+    );
+    client.with_source_account(&u1).accept_skip(&1);
+
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384);
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 2);
+}
+
+/// In `test_late_fee()`, the `Parent` can't pay the first withdrawal when
+/// it's due. By the time they can, two weeks later, it has picked up two
+/// weeks' worth of late fees. The two that came due in the meantime weren't
+/// reported late, so they cost the usual.
+#[test]
+fn test_late_fee() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            late_fee_bps: 1000,
+            ..Default::default()
+        },
+    );
+    assert_eq!(client.report_shortfall(), 0);
+
+    token.with_source_account(&u1).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &0,
+    );
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::InsufficientAllowance)));
+    assert_eq!(client.report_shortfall(), 1);
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.required_allowance(&false), 9615384 * 3 + 1923076);
+    token.with_source_account(&u1).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &500000000,
+    );
+    assert_eq!(client.withdraw(), 3);
+    assert_eq!(
+        token.balance(&Identifier::Account(u2.clone())),
+        9615384 * 3 + 1923076
+    );
+}
+
+/// In `test_suspend()`, the `Parent` comes up short three weeks running, and
+/// the allowance is suspended. Topping up isn't enough on its own: the
+/// `Parent` has to `reactivate` it, too.
+#[test]
+fn test_suspend() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    let approve = |amount: i128| {
+        token.with_source_account(&u1).approve(
+            &Signature::Invoker,
+            &0,
+            &Identifier::Contract(contract_id.clone()),
+            &amount,
+        );
+    };
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            max_failures: 3,
+            ..Default::default()
+        },
+    );
+    approve(0);
+
+    for week in 0..3 {
+        set_timestamp(&env, START + week * WEEK);
+        assert_ne!(client.get_status(), Status::Suspended);
+        client.report_shortfall();
+
+        // Once a week is all that counts.
+        client.report_shortfall();
+    }
+    assert_eq!(client.get_status(), Status::Suspended);
+
+    approve(500000000);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::Suspended)));
+    assert_eq!(
+        client.with_source_account(&u2).try_reactivate(),
+        Err(Ok(Error::Unauthorized))
+    );
+    client.with_source_account(&u1).reactivate();
+    assert_eq!(client.withdraw(), 3);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9615384 * 3);
+}
+
+/// In `test_protocol_fee()`, 2.5% of every withdrawal goes to the platform
+/// that set the allowance up, and the rest to the `Child`. A fee that rounds
+/// down to nothing isn't collected at all.
+#[test]
+fn test_protocol_fee() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let platform = Identifier::Account(u3.clone());
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+
+    // More than everything, or nobody to collect it, won't do.
+    for (collector, fee_bps) in [(Some(platform.clone()), 10001), (None, 250)] {
+        assert_eq!(
+            client.with_source_account(&u1).try_init(
+                &Identifier::Account(u2.clone()),
+                &id,
+                &500000000,
+                &WEEK,
+                &Options {
+                    fee_collector: collector,
+                    fee_bps,
+                    ..Default::default()
+                },
+            ),
+            Err(Ok(Error::InvalidArguments))
+        );
+    }
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            fee_collector: Some(platform.clone()),
+            fee_bps: 250,
+            ..Default::default()
+        },
+    );
+
+    // 2.5% of 9615384 is 240384.6, which the `Child` keeps the 0.6 of.
+    client.withdraw();
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.withdraw(), 2);
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 9375000 * 3);
+    assert_eq!(token.balance(&platform), 240384 * 3);
+    assert_eq!(
+        token.balance(&Identifier::Account(u1.clone())),
+        1000000000 - 9615384 * 3
+    );
+
+    // An allowance of 1 a week has no fee to speak of.
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    token.with_source_account(&u1).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &100,
+    );
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &53,
+        &WEEK,
+        &Options {
+            fee_collector: Some(platform.clone()),
+            fee_bps: 250,
+            ..Default::default()
+        },
+    );
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2)), 9375000 * 3 + 1);
+    assert_eq!(token.balance(&platform), 240384 * 3);
+}
+
+/// In `test_recipients()`, every withdrawal is split 70/30, and then three
+/// ways, with whatever doesn't divide evenly going to the first recipient.
+/// Shares that don't add up to everything are turned down.
+#[test]
+fn test_recipients() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let u4 = env.accounts().generate();
+    let (artist, label, agent) = (
+        Identifier::Account(u2.clone()),
+        Identifier::Account(u3.clone()),
+        Identifier::Account(u4.clone()),
+    );
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    let init = |client: &AllowanceContractClient, recipients| {
+        client.with_source_account(&u1).try_init(
+            &artist,
+            &id,
+            &500000000,
+            &WEEK,
+            &Options {
+                recipients: Some(recipients),
+                ..Default::default()
+            },
+        )
+    };
+
+    for recipients in [
+        vec![&env, (artist.clone(), 7000), (label.clone(), 2999)],
+        vec![&env, (artist.clone(), 7000), (label.clone(), 3001)],
+        vec![&env, (artist.clone(), 10000), (label.clone(), 0)],
+        vec![&env],
+    ] {
+        assert_eq!(init(&client, recipients), Err(Ok(Error::InvalidArguments)));
+    }
+
+    // 70% of 9615384 is 6730768.8, and 30% is 2884615.2, which leaves 1 over.
+    init(
+        &client,
+        vec![&env, (artist.clone(), 7000), (label.clone(), 3000)],
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(
+        client.recipients(),
+        vec![&env, (artist.clone(), 7000), (label.clone(), 3000)]
+    );
+    client.withdraw();
+    set_timestamp(&env, START + WEEK);
+    client.withdraw();
+    assert_eq!(token.balance(&artist), 6730769 * 2);
+    assert_eq!(token.balance(&label), 2884615 * 2);
+
+    // A third each, near enough.
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    token.with_source_account(&u1).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &500000000,
+    );
+    init(
+        &client,
+        vec![
+            &env,
+            (agent.clone(), 3333),
+            (artist.clone(), 3333),
+            (label.clone(), 3334),
+        ],
+    )
+    .unwrap()
+    .unwrap();
+    client.withdraw();
+    assert_eq!(token.balance(&agent), 3204808);
+    assert_eq!(token.balance(&artist), 6730769 * 2 + 3204807);
+    assert_eq!(token.balance(&label), 2884615 * 2 + 3205769);
+    assert_eq!(
+        token.balance(&Identifier::Account(u1)),
+        1000000000 - 9615384 * 3
+    );
+}
+
+/// In `test_propose_shares()`, the `Parent` moves some of the artist's and
+/// the label's shares to the agent. The agent is only gaining, so it's up to
+/// the other two, and until they've both accepted, withdrawals are split the
+/// old way. A second proposal is left to expire.
+#[test]
+fn test_propose_shares() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let u4 = env.accounts().generate();
+    let (artist, label, agent) = (
+        Identifier::Account(u2.clone()),
+        Identifier::Account(u3.clone()),
+        Identifier::Account(u4.clone()),
+    );
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &artist,
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            recipients: Some(vec![
+                &env,
+                (artist.clone(), 5000),
+                (label.clone(), 3000),
+                (agent.clone(), 2000),
+            ]),
+            shares_window: 2 * WEEK,
+            ..Default::default()
+        },
+    );
+    let old = client.recipients();
+    let new = vec![
+        &env,
+        (artist.clone(), 4000),
+        (label.clone(), 2000),
+        (agent.clone(), 4000),
+    ];
+
+    // Only the `Parent` proposes, and the shares still have to add up.
+    assert_eq!(
+        client.with_source_account(&u4).try_propose_shares(&new),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_propose_shares(&vec![&env, (agent.clone(), 9000)]),
+        Err(Ok(Error::InvalidArguments))
+    );
+    client.with_source_account(&u1).propose_shares(&new);
+
+    // Nobody asked the agent.
+    assert_eq!(
+        client.with_source_account(&u4).try_accept_shares(),
+        Err(Ok(Error::Unauthorized))
+    );
+    client.with_source_account(&u2).accept_shares();
+    assert_eq!(client.recipients(), old);
+    client.withdraw();
+    assert_eq!(token.balance(&agent), 1923076);
+
+    client.with_source_account(&u3).accept_shares();
+    assert_eq!(client.recipients(), new);
+    set_timestamp(&env, START + WEEK);
+    client.withdraw();
+    assert_eq!(token.balance(&agent), 1923076 + 3846153);
+    assert_eq!(token.balance(&artist), 4807693 + 3846155);
+    assert_eq!(token.balance(&label), 2884615 + 1923076);
+
+    // The agent is to leave, but doesn't sign off on it in time.
+    client.with_source_account(&u1).propose_shares(&vec![
+        &env,
+        (artist.clone(), 5000),
+        (label.clone(), 5000),
+    ]);
+    set_timestamp(&env, START + 3 * WEEK);
+    assert_eq!(
+        client.with_source_account(&u4).try_accept_shares(),
+        Err(Ok(Error::NoShareProposal))
+    );
+    assert_eq!(client.recipients(), new);
+}
+
+/// In `test_guarantor()`, the `Parent` only approves a week's worth, so the
+/// guarantor covers the second week. By the third, neither of them has.
+#[test]
+fn test_guarantor() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let guarantor = Identifier::Account(u3.clone());
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    token
+        .with_source_account(&u1)
+        .mint(&Signature::Invoker, &0, &guarantor, &1000000000);
+    for (who, amount) in [(&u1, 9615384), (&u3, 9615384)] {
+        token.with_source_account(who).approve(
+            &Signature::Invoker,
+            &0,
+            &Identifier::Contract(contract_id.clone()),
+            &amount,
+        );
+    }
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            guarantor: Some(guarantor.clone()),
+            ..Default::default()
+        },
+    );
+
+    client.withdraw();
+    assert_eq!(
+        token.balance(&Identifier::Account(u1.clone())),
+        1000000000 - 9615384
+    );
+    assert_eq!(token.balance(&guarantor), 1000000000);
+
+    set_timestamp(&env, START + WEEK);
+    assert!(client.can_withdraw());
+    client.withdraw();
+    assert_eq!(
+        token.balance(&Identifier::Account(u1.clone())),
+        1000000000 - 9615384
+    );
+    assert_eq!(token.balance(&guarantor), 1000000000 - 9615384);
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::InsufficientAllowance)));
+    assert_eq!(token.balance(&Identifier::Account(u2)), 9615384 * 2);
+}
+
+/// In `test_payers()`, two roommates split the rent 60/40. When one of them
+/// hasn't approved enough, nobody pays anything, and once they've moved out
+/// (`cancel_my_share`), the other one pays all of it.
+#[test]
+fn test_payers() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let (first, second) = (
+        Identifier::Account(u1.clone()),
+        Identifier::Account(u3.clone()),
+    );
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    token
+        .with_source_account(&u1)
+        .mint(&Signature::Invoker, &0, &second, &1000000000);
+    let approve = |amount: i128| {
+        token.with_source_account(&u3).approve(
+            &Signature::Invoker,
+            &0,
+            &Identifier::Contract(contract_id.clone()),
+            &amount,
+        );
+    };
+    approve(500000000);
+
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            payers: Some(vec![&env, (first.clone(), 6000), (second.clone(), 4000)]),
+            ..Default::default()
+        },
+    );
+
+    // 60% of 9615384 is 5769230.4, and 40% is 3846153.6, which leaves 1 over.
+    client.withdraw();
+    assert_eq!(token.balance(&first), 1000000000 - 5769231);
+    assert_eq!(token.balance(&second), 1000000000 - 3846153);
+
+    // Half a rent payment is no rent payment.
+    set_timestamp(&env, START + WEEK);
+    approve(0);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::InsufficientAllowance)));
+    assert_eq!(token.balance(&first), 1000000000 - 5769231);
+
+    // Only a payer can cancel their share.
+    assert_eq!(
+        client.with_source_account(&u2).try_cancel_my_share(),
+        Err(Ok(Error::Unauthorized))
+    );
+    client.with_source_account(&u3).cancel_my_share();
+    assert_eq!(client.payers(), vec![&env, (first.clone(), 10000)]);
+    client.withdraw();
+    assert_eq!(token.balance(&first), 1000000000 - 5769231 - 9615384);
+    assert_eq!(token.balance(&second), 1000000000 - 3846153);
+    assert_eq!(token.balance(&Identifier::Account(u2)), 9615384 * 2);
+}
+
+/// In `test_sponsor()`, a sponsor pays for two weeks, and then the `Parent`
+/// carries on. A second sponsor hasn't approved anything, so nothing goes
+/// through until the `Parent` revokes their sponsorship.
+#[test]
+fn test_sponsor() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let u4 = env.accounts().generate();
+    let (parent, child, sponsor) = (
+        Identifier::Account(u1.clone()),
+        Identifier::Account(u2.clone()),
+        Identifier::Account(u3.clone()),
+    );
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    token
+        .with_source_account(&u1)
+        .mint(&Signature::Invoker, &0, &sponsor, &1000000000);
+    token.with_source_account(&u3).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &500000000,
+    );
+
+    client
+        .with_source_account(&u1)
+        .init(&child, &id, &500000000, &WEEK, &Options::default());
+
+    client.with_source_account(&u3).sponsor(&2);
+    assert_eq!(
+        client.with_source_account(&u4).try_sponsor(&1),
+        Err(Ok(Error::AlreadySponsored))
+    );
+
+    // The second sponsored week and the first one back on the `Parent` come
+    // due together, but they're paid one at a time.
+    client.withdraw();
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&sponsor), 1000000000 - 9615384 * 2);
+    assert_eq!(token.balance(&parent), 1000000000);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&parent), 1000000000 - 9615384);
+    assert_eq!(token.balance(&child), 9615384 * 3);
+
+    // This one's all talk.
+    set_timestamp(&env, START + 3 * WEEK);
+    client.with_source_account(&u4).sponsor(&1);
+    assert!(!client.can_withdraw());
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::InsufficientAllowance)));
+    assert_eq!(
+        client.with_source_account(&u4).try_revoke_sponsorship(),
+        Err(Ok(Error::Unauthorized))
+    );
+    client.with_source_account(&u1).revoke_sponsorship();
+    client.withdraw();
+    assert_eq!(token.balance(&parent), 1000000000 - 9615384 * 2);
+}
+
+/// In `test_collateral()`, the `Parent` puts up collateral and then misses a
+/// week. A week later, the `Child` is paid for it out of the collateral,
+/// and the `Parent` gets the rest back once the allowance has ended.
+#[test]
+fn test_collateral() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let (parent, child) = (
+        Identifier::Account(u1.clone()),
+        Identifier::Account(u2.clone()),
+    );
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    let approve = |amount: i128| {
+        token.with_source_account(&u1).approve(
+            &Signature::Invoker,
+            &0,
+            &Identifier::Contract(contract_id.clone()),
+            &amount,
+        );
+    };
+
+    client
+        .with_source_account(&u1)
+        .init(&child, &id, &500000000, &WEEK, &Options::default());
+    client.with_source_account(&u1).post_collateral(&20000000);
+    assert_eq!(
+        token.balance(&Identifier::Contract(contract_id.clone())),
+        20000000
+    );
+    client.withdraw();
+
+    // The second week is reported short, but the `Child` has to give it a
+    // week. The first one was paid already.
+    set_timestamp(&env, START + WEEK);
+    approve(0);
+    assert_eq!(client.report_shortfall(), 1);
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_claim_from_collateral(&1),
+        Err(Ok(Error::NothingDue))
+    );
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_claim_from_collateral(&0),
+        Err(Ok(Error::ChildAlreadyWithdrawn))
+    );
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_claim_from_collateral(&1),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(
+        client.with_source_account(&u2).claim_from_collateral(&1),
+        9615384
+    );
+    assert_eq!(token.balance(&child), 9615384 * 2);
+
+    // Nobody reported the third week short (and it's only just come due).
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_claim_from_collateral(&2),
+        Err(Ok(Error::InvalidArguments))
+    );
+
+    // The collateral stays put until the allowance is over.
+    assert_eq!(
+        client.with_source_account(&u1).try_reclaim_collateral(),
+        Err(Ok(Error::InvalidArguments))
+    );
+    approve(500000000);
+    client.withdraw();
+    client.with_source_account(&u1).cancel();
+    assert_eq!(
+        client.with_source_account(&u1).reclaim_collateral(),
+        20000000 - 9615384
+    );
+    assert_eq!(token.balance(&parent), 1000000000 - 9615384 * 3);
+    assert_eq!(token.balance(&child), 9615384 * 3);
+}
+
+/// In `test_assign_payments()`, the `Child` sells their next three weeks to
+/// a financier, who is paid for those, and then it's back to the `Child`.
+#[test]
+fn test_assign_payments() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let (child, financier) = (
+        Identifier::Account(u2.clone()),
+        Identifier::Account(u3.clone()),
+    );
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    client
+        .with_source_account(&u1)
+        .init(&child, &id, &500000000, &WEEK, &Options::default());
+
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_assign_payments(&financier, &3),
+        Err(Ok(Error::Unauthorized))
+    );
+    client
+        .with_source_account(&u2)
+        .assign_payments(&financier, &3);
+
+    // Those three are spoken for.
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_assign_payments(&financier, &1),
+        Err(Ok(Error::AlreadyAssigned))
+    );
+    assert_eq!(
+        client.with_source_account(&u2).try_renounce_assignment(),
+        Err(Ok(Error::Unauthorized))
+    );
+
+    for week in 0..4 {
+        set_timestamp(&env, START + week * WEEK);
+        client.withdraw();
+    }
+    assert_eq!(token.balance(&financier), 9615384 * 3);
+    assert_eq!(token.balance(&child), 9615384);
+}
+
+/// In `test_metadata()`, the plan is named at `init`, renamed later, and a
+/// reference longer than 64 bytes is turned down.
+#[test]
+fn test_metadata() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    let basic = Metadata {
+        name: symbol!("basic"),
+        reference: Bytes::from_slice(&env, b"crm-0001"),
+    };
+    let too_long = Metadata {
+        name: symbol!("basic"),
+        reference: Bytes::from_array(&env, &[7; 65]),
+    };
+    assert_eq!(
+        client.with_source_account(&u1).try_init(
+            &Identifier::Account(u2.clone()),
+            &id,
+            &500000000,
+            &WEEK,
+            &Options {
+                metadata: Some(too_long.clone()),
+                ..Default::default()
+            },
+        ),
+        Err(Ok(Error::InvalidArguments))
+    );
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            metadata: Some(basic.clone()),
+            ..Default::default()
+        },
+    );
+    assert_eq!(client.get_metadata(), Some(basic));
+
+    let premium = Bytes::from_array(&env, &[7; 64]);
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_set_metadata(&symbol!("premium"), &premium),
+        Err(Ok(Error::Unauthorized))
+    );
+    client
+        .with_source_account(&u1)
+        .set_metadata(&symbol!("premium"), &premium);
+    assert_eq!(
+        client.get_metadata(),
+        Some(Metadata {
+            name: symbol!("premium"),
+            reference: premium,
+        })
+    );
+
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_set_metadata(&too_long.name, &too_long.reference),
+        Err(Ok(Error::InvalidArguments))
+    );
+}
+
+/// In `test_version()`, the contract reports the version it was built from,
+/// which is the crate's own, and what it can do.
+#[test]
+fn test_version() {
+    let env = Env::default();
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+
+    assert_eq!(
+        client.version(),
+        (
+            env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap(),
+            env!("CARGO_PKG_VERSION_MINOR").parse().unwrap(),
+            env!("CARGO_PKG_VERSION_PATCH").parse().unwrap(),
+        )
+    );
+
+    let features = client.features();
+    assert!(features.contains(&symbol!("streaming")));
+    assert_eq!(
+        features.contains(&symbol!("factory")),
+        cfg!(feature = "factory")
+    );
+}
+
+/// In `test_state_layout()`, a new contract keeps everything `withdraw` reads
+/// in one `State` entry, and none of it under the old keys, and `withdraw`
+/// carries on writing it there.
+#[test]
+fn test_state_layout() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client.withdraw();
+
+    env.as_contract(&contract_id, || {
+        let state: State = env.storage().get(StorageKey::State).unwrap().unwrap();
+        assert_eq!(state.latest, START);
+        assert_eq!(state.withdraw_count, 1);
+        for key in legacy::keys(&env) {
+            assert!(!env.storage().has(key));
+        }
+        assert!(!env.storage().has(StorageKey::Options));
+        assert!(!env.storage().has(StorageKey::StartEpoch));
+        assert!(!env.storage().has(StorageKey::WithdrawCount));
+    });
+}
+
+/// In `test_init_with_signature()`, the `Parent` is a bare ed25519 key. It
+/// signs `init` and `approve` for somebody else to submit, and the `Child`
+/// is paid from its balance just the same. The signed `init` can't be
+/// submitted a second time.
+#[test]
+fn test_init_with_signature() {
+    use soroban_auth::testutils::ed25519;
+
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate(); // token admin, and our relayer
+    let u2 = env.accounts().generate();
+    let (parent, signer) = ed25519::generate(&env);
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    token
+        .with_source_account(&u1)
+        .xfer(&Signature::Invoker, &0, &parent, &1000000000);
+
+    let spender = Identifier::Contract(contract_id.clone());
+    let approval = ed25519::sign(
+        &env,
+        &signer,
+        &id,
+        symbol!("approve"),
+        (&parent, &0_i128, &spender, &500000000_i128),
+    );
+    token.approve(&approval, &0, &spender, &500000000);
+
+    let child = Identifier::Account(u2);
+    let options = Options::default();
+    let sig = ed25519::sign(
+        &env,
+        &signer,
+        &contract_id,
+        symbol!("init"),
+        (
+            &parent,
+            &0_i128,
+            &child,
+            &id,
+            &500000000_i128,
+            &WEEK,
+            &options,
+        ),
+    );
+    assert_eq!(client.nonce(&parent), 0);
+    client
+        .with_source_account(&u1)
+        .init_with_signature(&sig, &0, &child, &id, &500000000, &WEEK, &options);
+    assert_eq!(client.nonce(&parent), 1);
+
+    client.withdraw();
+    assert_eq!(token.balance(&parent), 1000000000 - 9615384);
+    assert_eq!(token.balance(&child), 9615384);
+
+    assert_eq!(
+        client.try_init_with_signature(&sig, &0, &child, &id, &500000000, &WEEK, &options),
+        Err(Ok(Error::InvalidNonce))
+    );
+}
+
+/// In `test_signed_management()`, a `Parent` holding a bare ed25519 key signs
+/// an amount change and a `cancel`, and somebody else relays them. A
+/// signature can't be replayed, or used for another function, and the
+/// `Child` can't sign for the `Parent`.
+#[test]
+fn test_signed_management() {
+    use soroban_auth::testutils::ed25519;
+
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate(); // token admin, and our relayer
+    let (parent, parent_signer) = ed25519::generate(&env);
+    let (child, child_signer) = ed25519::generate(&env);
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    let options = Options::default();
+    let sig = ed25519::sign(
+        &env,
+        &parent_signer,
+        &contract_id,
+        symbol!("init"),
+        (
+            &parent,
+            &0_i128,
+            &child,
+            &id,
+            &500000000_i128,
+            &WEEK,
+            &options,
+        ),
+    );
+    client.init_with_signature(&sig, &0, &child, &id, &500000000, &WEEK, &options);
+
+    // A raise only needs the `Parent`, so it goes straight through.
+    let raise = ed25519::sign(
+        &env,
+        &parent_signer,
+        &contract_id,
+        symbol!("amount"),
+        (&parent, &1_i128, &600000000_i128),
+    );
+    client
+        .with_source_account(&u1)
+        .propose_amount_signed(&raise, &1, &600000000);
+    env.as_contract(&contract_id, || {
+        let e = Sub::main(env.clone());
+        assert_eq!(agreed_amount(&e).unwrap(), 600000000);
+    });
+    assert_eq!(client.nonce(&parent), 2);
+    assert_eq!(
+        client.try_propose_amount_signed(&raise, &1, &600000000),
+        Err(Ok(Error::InvalidNonce))
+    );
+
+    // The signature is for `amount`, with those arguments, and nothing else.
+    assert!(client
+        .try_propose_amount_signed(&raise, &2, &700000000)
+        .is_err());
+    assert!(client.try_cancel_signed(&raise, &2).is_err());
+
+    // The `Child` signs a perfectly good `cancel`, but it isn't theirs to
+    // make.
+    let by_child = ed25519::sign(
+        &env,
+        &child_signer,
+        &contract_id,
+        symbol!("cancel"),
+        (&child, &0_i128),
+    );
+    assert_eq!(
+        client.try_cancel_signed(&by_child, &0),
+        Err(Ok(Error::Unauthorized))
+    );
+
+    let cancel = ed25519::sign(
+        &env,
+        &parent_signer,
+        &contract_id,
+        symbol!("cancel"),
+        (&parent, &2_i128),
+    );
+    client.with_source_account(&u1).cancel_signed(&cancel, &2);
+    assert_eq!(client.nonce(&parent), 3);
+
+    let again = ed25519::sign(
+        &env,
+        &parent_signer,
+        &contract_id,
+        symbol!("cancel"),
+        (&parent, &3_i128),
+    );
+    assert_eq!(
+        client.try_cancel_signed(&again, &3),
+        Err(Ok(Error::Cancelled))
+    );
+}
+
+/// In `test_approve_action()`, the allowance has three officers, any two of
+/// whom have to agree on a change to the terms. One approval isn't enough,
+/// approving a different amount starts over, and approvals lapse.
+#[test]
+fn test_approve_action() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let s1 = env.accounts().generate();
+    let s2 = env.accounts().generate();
+    let s3 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            signers: Some(Signers {
+                accounts: vec![&env, s1.clone(), s2.clone(), s3.clone()],
+                threshold: 2,
+                approval_window: 24 * 60 * 60,
+            }),
+            ..Default::default()
+        },
+    );
+    let amount = || {
+        env.as_contract(&contract_id, || {
+            agreed_amount(&Sub::main(env.clone())).unwrap()
+        })
+    };
+
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_propose_amount(&600000000),
+        Err(Ok(Error::ApprovalRequired))
+    );
+    assert_eq!(
+        client.with_source_account(&u1).try_cancel(),
+        Err(Ok(Error::ApprovalRequired))
+    );
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_approve_action(&Action::Cancel),
+        Err(Ok(Error::Unauthorized))
+    );
+
+    // Approving twice doesn't make two approvals.
+    let raise = Action::Amount(600000000);
+    assert!(!client.with_source_account(&s1).approve_action(&raise));
+    assert!(!client.with_source_account(&s1).approve_action(&raise));
+    assert_eq!(amount(), 500000000);
+
+    // `s1` approved 600000000, not this.
+    let bigger = Action::Amount(700000000);
+    assert!(!client.with_source_account(&s2).approve_action(&bigger));
+    assert_eq!(amount(), 500000000);
+    assert!(client.with_source_account(&s3).approve_action(&bigger));
+    assert_eq!(amount(), 700000000);
+
+    // An approval from yesterday has lapsed.
+    assert!(!client
+        .with_source_account(&s1)
+        .approve_action(&Action::Cancel));
+    set_timestamp(&env, START + 24 * 60 * 60);
+    assert!(!client
+        .with_source_account(&s2)
+        .approve_action(&Action::Cancel));
+    assert!(client
+        .with_source_account(&s3)
+        .approve_action(&Action::Cancel));
+    assert_eq!(client.get_status(), Status::Cancelled);
+}
+
+/// In `test_propose_token()`, the `Parent` moves the allowance over to
+/// another token, with ten times as many decimals. The `Child` can only
+/// accept once nothing is owed in the old one, and the next withdrawal waits
+/// for an allowance on the new one.
+#[test]
+fn test_propose_token() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2.clone());
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (old_id, old_token) = setup_token(&env, &u1, &contract_id);
+    let (new_id, new_token) = setup_token_at(&env, &[7; 32], &u1, &contract_id);
+    new_token.with_source_account(&u1).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &0,
+    );
+
+    client
+        .with_source_account(&u1)
+        .init(&child, &old_id, &500000000, &WEEK, &Options::default());
+    client.withdraw();
+
+    assert_eq!(
+        client.with_source_account(&u2).try_accept_token(),
+        Err(Ok(Error::NoTokenProposal))
+    );
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_propose_token(&new_id, &5000000000),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_propose_token(&old_id, &5000000000),
+        Err(Ok(Error::InvalidArguments))
+    );
+    client
+        .with_source_account(&u1)
+        .propose_token(&new_id, &5000000000);
+
+    // Last week's withdrawal is still to be paid in the old token.
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(
+        client.with_source_account(&u2).try_accept_token(),
+        Err(Ok(Error::ArrearsOutstanding))
+    );
+    client.withdraw();
+    client.with_source_account(&u2).accept_token();
+    assert_eq!(old_token.balance(&child), 9615384 * 2);
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::InsufficientAllowance)));
+    new_token.with_source_account(&u1).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &1000000000,
+    );
+    client.withdraw();
+    assert_eq!(old_token.balance(&child), 9615384 * 2);
+    assert_eq!(new_token.balance(&child), 96153846);
+}
+
+/// In `test_receiver_frozen()`, the token's admin freezes the `Child`'s
+/// balance. The withdrawal fails with an error that says so, rather than a
+/// trap, and is still there to be paid once they're unfrozen.
+#[test]
+fn test_receiver_frozen() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2.clone());
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    client
+        .with_source_account(&u1)
+        .init(&child, &id, &500000000, &WEEK, &Options::default());
+
+    token
+        .with_source_account(&u1)
+        .freeze(&Signature::Invoker, &0, &child);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ReceiverCannotReceive)));
+    env.as_contract(&contract_id, || {
+        let state: State = env.storage().get(StorageKey::State).unwrap().unwrap();
+        assert_eq!(state.latest, START - WEEK);
+    });
+
+    token
+        .with_source_account(&u1)
+        .unfreeze(&Signature::Invoker, &0, &child);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&child), 9615384);
+}
+
+/// In `test_reconcile()`, the `Child`'s balance keeps up with what they've
+/// been paid, until the token's admin claws some of it back. The next
+/// `reconcile` reports the difference, and withdrawals carry on regardless.
+#[test]
+fn test_reconcile() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2.clone());
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &child,
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            reconcile_tolerance: 10,
+            ..Default::default()
+        },
+    );
+
+    assert_eq!(
+        client.reconcile(),
+        ReconcileReport {
+            since: START,
+            paid: 0,
+            expected: 0,
+            actual: 0,
+        }
+    );
+
+    client.withdraw();
+    set_timestamp(&env, START + WEEK);
+    client.withdraw();
+    assert_eq!(
+        client.reconcile(),
+        ReconcileReport {
+            since: START,
+            paid: 9615384 * 2,
+            expected: 9615384 * 2,
+            actual: 9615384 * 2,
+        }
+    );
+
+    set_timestamp(&env, START + 2 * WEEK);
+    token
+        .with_source_account(&u1)
+        .burn(&Signature::Invoker, &0, &child, &1000000);
+    client.withdraw();
+    assert_eq!(
+        client.reconcile(),
+        ReconcileReport {
+            since: START + WEEK,
+            paid: 9615384,
+            expected: 9615384 * 3,
+            actual: 9615384 * 3 - 1000000,
+        }
+    );
+
+    // The next one starts from where that left off.
+    set_timestamp(&env, START + 3 * WEEK);
+    client.withdraw();
+    assert_eq!(
+        client.reconcile(),
+        ReconcileReport {
+            since: START + 2 * WEEK,
+            paid: 9615384,
+            expected: 9615384 * 4 - 1000000,
+            actual: 9615384 * 4 - 1000000,
+        }
+    );
+}
+
+/// In `test_measure_received()`, the token takes its cut of every
+/// withdrawal, and the contract accounts for what the `Child` actually got.
+#[test]
+fn test_measure_received() {
+    let f = Fixture::new(Config {
+        options: Options {
+            measure_received: true,
+            ..Default::default()
+        },
+        mock_token: true,
+        ..Config::default()
+    });
+    f.client.reconcile();
+
+    f.mock().script(&vec![&f.env, Transfer::Skim(100)]);
+    f.client.withdraw();
+    f.assert_balance(&f.parent_id(), 1000000000 - 9615384);
+    f.assert_balance(&f.child_id(), 9615384 - 96153);
+    assert_eq!(
+        f.client.reconcile(),
+        ReconcileReport {
+            since: START,
+            paid: 9615384 - 96153,
+            expected: 9615384 - 96153,
+            actual: 9615384 - 96153,
+        }
+    );
+}
+
+/// In `test_measure_received_records()`, the token skims 1% off both the
+/// `Child`'s part and the fee, and the receipt, the lifetime cap and the fee
+/// all go by what arrived.
+#[test]
+fn test_measure_received_records() {
+    let mut f = Fixture::new(Config {
+        mock_token: true,
+        init: false,
+        ..Config::default()
+    });
+    let collector = Identifier::Account(f.env.accounts().generate());
+    f.config.options = Options {
+        measure_received: true,
+        fee_collector: Some(collector.clone()),
+        fee_bps: 100,
+        lifetime_cap: 100000000,
+        ..Default::default()
+    };
+    f.init();
+
+    f.mock()
+        .script(&vec![&f.env, Transfer::Skim(100), Transfer::Skim(100)]);
+    f.client.withdraw();
+    f.assert_balance(&f.parent_id(), 1000000000 - 9615384);
+    f.assert_balance(&f.child_id(), 9519231 - 95192);
+    f.assert_balance(&collector, 96153 - 961);
+
+    assert_eq!(
+        f.client.history(&0, &10),
+        vec![
+            &f.env,
+            Receipt {
+                period: 0,
+                amount: 9519231,
+                delivered: 9519231,
+                paid_at: START,
+                label: symbol!("withdraw"),
+            },
+        ]
+    );
+    assert_eq!(f.client.remaining_cap(), Some(100000000 - 9519231));
+}
+
+/// `MockPool` swaps anything for anything at whatever rate we last gave it
+/// (in basis points), paying out of its own balance. It remembers which token
+/// it was last told it had been paid in.
+pub struct MockPool;
+
+#[contractimpl]
+impl MockPool {
+    pub fn set_rate(e: Env, rate_bps: i128) {
+        e.storage().set(symbol!("rate"), rate_bps);
+    }
+
+    pub fn token_in(e: Env) -> BytesN<32> {
+        e.storage().get(symbol!("token_in")).unwrap().unwrap()
+    }
+
+    pub fn swap(
+        e: Env,
+        token_in: BytesN<32>,
+        amount_in: i128,
+        token_out: BytesN<32>,
+        _min_out: i128,
+        to: Identifier,
+    ) -> i128 {
+        e.storage().set(symbol!("token_in"), token_in);
+        let rate: i128 = e.storage().get(symbol!("rate")).unwrap().unwrap();
+        let out = amount_in * rate / 10000;
+        token::Client::new(&e, &token_out).xfer(&Signature::Invoker, &0, &to, &out);
+        out
+    }
+}
+
+/// In `test_swap_route()`, the `Parent` pays in one token and the `Child` is
+/// paid in another, worth half as much, through a pool. As long as the pool
+/// gives at least 1.99 for one, it goes through, and once it doesn't, the
+/// withdrawal fails and is still due. The receipts say what went in and what
+/// came out.
+#[test]
+fn test_swap_route() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2.clone());
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    let (out_id, out_token) = setup_token_at(&env, &[7; 32], &u1, &contract_id);
+
+    let pool_id = env.register_contract(None, MockPool);
+    let pool = MockPoolClient::new(&env, &pool_id);
+    out_token.with_source_account(&u1).xfer(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(pool_id.clone()),
+        &1000000000,
+    );
+
+    client.with_source_account(&u1).init(
+        &child,
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            swap: Some(SwapRoute {
+                pool: pool_id.clone(),
+                token_out: out_id,
+                min_price: 19900000,
+            }),
+            ..Default::default()
+        },
+    );
+
+    pool.set_rate(&19960);
+    client.withdraw();
+    assert_eq!(token.balance(&child), 0);
+    assert_eq!(out_token.balance(&child), 19192306);
+    assert_eq!(
+        token.balance(&Identifier::Contract(pool_id.clone())),
+        9615384
+    );
+    assert_eq!(pool.token_in(), id);
+
+    set_timestamp(&env, START + WEEK);
+    pool.set_rate(&19800);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::SlippageExceeded)));
+    assert_eq!(out_token.balance(&child), 19192306);
+
+    pool.set_rate(&20000);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(out_token.balance(&child), 19192306 + 19230768);
+
+    let receipt = |period: u64, delivered: i128| Receipt {
+        period,
+        amount: 9615384,
+        delivered,
+        paid_at: START + period * WEEK,
+        label: symbol!("withdraw"),
+    };
+    assert_eq!(
+        client.history(&0, &10),
+        vec![&env, receipt(1, 19230768), receipt(0, 19192306)]
+    );
+}
+
+/// In `test_accepted_tokens()`, the `Child` can be paid in either of two
+/// tokens, the second with ten times as many decimals. They pick one per
+/// withdrawal, or set a preference, and everything caught up on in one go is
+/// paid in the same token.
+#[test]
+fn test_accepted_tokens() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2.clone());
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    let (other_id, other) = setup_token_at(&env, &[7; 32], &u1, &contract_id);
+    let unlisted = BytesN::from_array(&env, &[8; 32]);
+
+    let mut accepted = Map::new(&env);
+    accepted.set(other_id.clone(), 5000000000);
+    client.with_source_account(&u1).init(
+        &child,
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            accepted_tokens: Some(accepted),
+            ..Default::default()
+        },
+    );
+
+    client.withdraw();
+    assert_eq!(token.balance(&child), 9615384);
+
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(
+        client.try_withdraw_in(&unlisted),
+        Err(Ok(Error::InvalidArguments))
+    );
+    assert_eq!(client.withdraw_in(&other_id), 1);
+    assert_eq!(other.balance(&child), 96153840);
+
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_set_preferred_token(&unlisted),
+        Err(Ok(Error::InvalidArguments))
+    );
+    client
+        .with_source_account(&u2)
+        .set_preferred_token(&other_id);
+    set_timestamp(&env, START + 2 * WEEK);
+    client.withdraw();
+    assert_eq!(other.balance(&child), 96153840 * 2);
+
+    // Two weeks behind, and both are paid in the token they're collected in.
+    client.with_source_account(&u2).set_preferred_token(&id);
+    set_timestamp(&env, START + 4 * WEEK);
+    assert_eq!(client.withdraw(), 2);
+    assert_eq!(token.balance(&child), 9615384 * 3);
+    assert_eq!(other.balance(&child), 96153840 * 2);
+}
+
+/// `RecordingHook` remembers every payment it's told about, and
+/// `BrokenHook` falls over whenever it's told about one.
+pub struct RecordingHook;
+
+#[contractimpl]
+impl RecordingHook {
+    pub fn on_payment(e: Env, period: u64, amount: i128) {
+        let mut payments: Vec<(u64, i128)> = e
+            .storage()
+            .get(symbol!("payments"))
+            .unwrap_or_else(|| Ok(Vec::new(&e)))
+            .unwrap();
+        payments.push_back((period, amount));
+        e.storage().set(symbol!("payments"), payments);
+    }
+
+    pub fn payments(e: Env) -> Vec<(u64, i128)> {
+        e.storage()
+            .get(symbol!("payments"))
+            .unwrap_or_else(|| Ok(Vec::new(&e)))
+            .unwrap()
+    }
+}
+
+pub struct BrokenHook;
+
+#[contractimpl]
+impl BrokenHook {
+    pub fn on_payment(_e: Env, _period: u64, _amount: i128) {
+        panic!("out of order");
+    }
+}
+
+/// In `test_payment_hook()`, the `Child` has a contract of theirs told about
+/// each withdrawal. A hook that panics doesn't stop the payment, and without
+/// a hook, nobody is told anything.
+#[test]
+fn test_payment_hook() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2.clone());
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    client
+        .with_source_account(&u1)
+        .init(&child, &id, &500000000, &WEEK, &Options::default());
+
+    let hook_id = env.register_contract(None, RecordingHook);
+    let hook = RecordingHookClient::new(&env, &hook_id);
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_set_payment_hook(&Some(hook_id.clone())),
+        Err(Ok(Error::Unauthorized))
+    );
+    client
+        .with_source_account(&u2)
+        .set_payment_hook(&Some(hook_id.clone()));
+
+    // Catching up on two weeks, the hook hears about both.
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(client.withdraw(), 2);
+    assert_eq!(hook.payments(), vec![&env, (0, 9615384), (1, 9615384)]);
+
+    let broken_id = env.register_contract(None, BrokenHook);
+    client
+        .with_source_account(&u2)
+        .set_payment_hook(&Some(broken_id));
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&child), 9615384 * 3);
+
+    client.with_source_account(&u2).set_payment_hook(&None);
+    set_timestamp(&env, START + 3 * WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&child), 9615384 * 4);
+    assert_eq!(hook.payments().len(), 2);
+}
+
+/// `MockPolicy` approves any withdrawal up to the limit we last gave it.
+pub struct MockPolicy;
+
+#[contractimpl]
+impl MockPolicy {
+    pub fn set_limit(e: Env, limit: i128) {
+        e.storage().set(symbol!("limit"), limit);
+    }
+
+    pub fn approve(e: Env, amount: i128, _period: u64) -> bool {
+        let limit: i128 = e.storage().get(symbol!("limit")).unwrap().unwrap();
+        amount <= limit
+    }
+}
+
+/// In `test_spend_policy()`, the `Parent`'s policy lets a week's withdrawal
+/// through but not two at once, and a policy that can't be reached stops
+/// everything. Without one, anything due is paid.
+#[test]
+fn test_spend_policy() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2.clone());
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    client
+        .with_source_account(&u1)
+        .init(&child, &id, &500000000, &WEEK, &Options::default());
+
+    let policy_id = env.register_contract(None, MockPolicy);
+    MockPolicyClient::new(&env, &policy_id).set_limit(&10000000);
+    assert_eq!(
+        client
+            .with_source_account(&u2)
+            .try_set_spend_policy(&Some(policy_id.clone())),
+        Err(Ok(Error::Unauthorized))
+    );
+    client
+        .with_source_account(&u1)
+        .set_spend_policy(&Some(policy_id));
+
+    client.withdraw();
+    assert_eq!(token.balance(&child), 9615384);
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::PolicyRejected)));
+    assert_eq!(client.withdraw_periods(&1), 1);
+    assert_eq!(token.balance(&child), 9615384 * 2);
+
+    client
+        .with_source_account(&u1)
+        .set_spend_policy(&Some(BytesN::from_array(&env, &[9; 32])));
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::PolicyRejected)));
+
+    client.with_source_account(&u1).set_spend_policy(&None);
+    set_timestamp(&env, START + 3 * WEEK);
+    assert_eq!(client.withdraw(), 2);
+    assert_eq!(token.balance(&child), 9615384 * 4);
+}
+
+/// `Gatekeeper` is some other contract that only serves paying subscribers.
+pub struct Gatekeeper;
+
+#[contractimpl]
+impl Gatekeeper {
+    pub fn serve(e: Env, subscriptions: BytesN<32>, user: AccountId) -> Symbol {
+        if AllowanceContractClient::new(&e, &subscriptions).is_active_for(&user) {
+            symbol!("served")
+        } else {
+            symbol!("refused")
+        }
+    }
+}
+
+/// In `test_is_active_for()`, another contract serves the `Child` of each
+/// subscription until they fall more than their grace period behind, or the
+/// subscription is cancelled, and never serves anybody else, even when the
+/// storage it's looking at makes no sense.
+#[test]
+fn test_is_active_for() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let u4 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let gate = GatekeeperClient::new(&env, &env.register_contract(None, Gatekeeper));
+
+    // Nothing's been set up yet.
+    assert_eq!(gate.serve(&contract_id, &u2), symbol!("refused"));
+
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            grace_periods: 1,
+            ..Options::default()
+        },
+    );
+    assert_eq!(
+        client.with_source_account(&u1).try_create(
+            &Identifier::Account(u3.clone()),
+            &id,
+            &START,
+            &52000,
+            &WEEK,
+            &Options {
+                grace_periods: 53,
+                ..Options::default()
+            },
+        ),
+        Err(Ok(Error::InvalidArguments))
+    );
+    client.with_source_account(&u1).create(
+        &Identifier::Account(u3.clone()),
+        &id,
+        &START,
+        &52000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    assert_eq!(gate.serve(&contract_id, &u2), symbol!("served"));
+    assert_eq!(gate.serve(&contract_id, &u3), symbol!("served"));
+    assert_eq!(gate.serve(&contract_id, &u4), symbol!("refused"));
+
+    // A week behind is all right for `u2`, but not for `u3`, who has no
+    // grace at all.
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(gate.serve(&contract_id, &u2), symbol!("served"));
+    assert_eq!(gate.serve(&contract_id, &u3), symbol!("refused"));
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(gate.serve(&contract_id, &u2), symbol!("refused"));
+    client.withdraw();
+    client.withdraw_for(&1);
+    assert_eq!(gate.serve(&contract_id, &u2), symbol!("served"));
+    assert_eq!(gate.serve(&contract_id, &u3), symbol!("served"));
+
+    client.with_source_account(&u1).cancel();
+    assert_eq!(gate.serve(&contract_id, &u2), symbol!("refused"));
+    assert_eq!(gate.serve(&contract_id, &u3), symbol!("served"));
+
+    // Whatever is in storage, the gate says no rather than trapping.
+    env.as_contract(&contract_id, || {
+        env.storage().set(
+            StorageKey::ByChild(Identifier::Account(u3.clone())),
+            symbol!("garbage"),
+        );
+    });
+    assert_eq!(gate.serve(&contract_id, &u3), symbol!("refused"));
+}
+
+/// `MockRegistry` keeps every notification it's sent, in order.
+pub struct MockRegistry;
+
+#[contractimpl]
+impl RegistryTrait for MockRegistry {
+    fn notify(e: Env, contract_id: BytesN<32>, status: Status) {
+        let mut notified: Vec<(BytesN<32>, Status)> = e
+            .storage()
+            .get(symbol!("notified"))
+            .unwrap_or_else(|| Ok(Vec::new(&e)))
+            .unwrap();
+        notified.push_back((contract_id, status));
+        e.storage().set(symbol!("notified"), notified);
+    }
+}
+
+fn notified(env: &Env, registry: &BytesN<32>) -> Vec<(BytesN<32>, Status)> {
+    env.as_contract(registry, || {
+        env.storage()
+            .get(symbol!("notified"))
+            .unwrap_or_else(|| Ok(Vec::new(env)))
+            .unwrap()
+    })
+}
+
+/// In `test_registry()`, the registry hears about the allowance being set
+/// up, paused, cancelled and, once the notice runs out, ending, each exactly
+/// once. A registry that can't be reached doesn't stop any of that.
+#[test]
+fn test_registry() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let registry = env.register_contract(None, MockRegistry);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            cancel_notice_period: 2 * WEEK,
+            registry: Some(registry.clone()),
+            ..Options::default()
+        },
+    );
+    assert_eq!(
+        notified(&env, &registry),
+        vec![&env, (contract_id.clone(), Status::Active)]
+    );
+
+    client
+        .with_source_account(&u2)
+        .request_pause(&(START + 10 * WEEK), &(START + 11 * WEEK));
+    client.with_source_account(&u1).approve_pause();
+
+    set_timestamp(&env, START + WEEK);
+    client.with_source_account(&u1).cancel();
+    assert_eq!(client.try_report_end(), Err(Ok(Error::NotEnded)));
+
+    // The last withdrawal notices the end, so `report_end` has nothing left
+    // to say.
+    set_timestamp(&env, START + 3 * WEEK);
+    client.withdraw();
+    client.report_end();
+    assert_eq!(
+        notified(&env, &registry),
+        vec![
+            &env,
+            (contract_id.clone(), Status::Active),
+            (contract_id.clone(), Status::Paused),
+            (contract_id.clone(), Status::Terminating),
+            (contract_id.clone(), Status::Cancelled),
+        ]
+    );
+
+    // Nobody's there, but the allowance still gets set up and closed.
+    let other_id = env.register_contract(None, AllowanceContract);
+    let other = AllowanceContractClient::new(&env, &other_id);
+    other.with_source_account(&u1).init(
+        &Identifier::Account(u3.clone()),
+        &id,
+        &52000,
+        &WEEK,
+        &Options {
+            registry: Some(BytesN::from_array(&env, &[9; 32])),
+            ..Options::default()
+        },
+    );
+    assert_eq!(other.get_status(), Status::Active);
+    other.with_source_account(&u3).close_by_child(&false);
+    assert_eq!(other.get_status(), Status::Cancelled);
+}
+
+/// In `test_get_state()`, a snapshot taken after two withdrawals and a couple
+/// of changes to the amount has everything an indexer would otherwise have to
+/// piece together from events.
+#[test]
+fn test_get_state() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let parent = Identifier::Account(u1.clone());
+    let child = Identifier::Account(u2.clone());
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    assert_eq!(
+        client.try_get_state(),
+        Err(Ok(Error::ContractNotInitialized))
+    );
+
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    client
+        .with_source_account(&u1)
+        .init(&child, &id, &500000000, &WEEK, &Options::default());
+    client.withdraw();
+    set_timestamp(&env, START + WEEK);
+    client.withdraw();
+
+    // The `Parent`'s raise goes straight through, and the `Child`'s waits
+    // for them.
+    client.with_source_account(&u1).propose_amount(&520000000);
+    client.with_source_account(&u2).propose_amount(&600000000);
+
+    assert_eq!(
+        client.get_state(),
+        FullState {
+            schema_version: SCHEMA_VERSION,
+            status: Status::Active,
+            config: Config {
+                parent,
+                child: child.clone(),
+                token_id: id,
+                amount: 500000000,
+                step: WEEK,
+                options: Options::default(),
+            },
+            progress: Progress {
+                latest: START + WEEK,
+                start_epoch: START,
+                withdraw_count: 2,
+                delivered: 9615384 * 2,
+                failure_count: 0,
+                ends_at: None,
+                agreed_amount: 520000000,
+                rate_history: vec![
+                    &env,
+                    RateChange {
+                        effective_from: START + WEEK,
+                        amount: 520000000,
+                    },
+                ],
+            },
+            pending: Pending {
+                amount: Some(AmountProposal {
+                    amount: 600000000,
+                    proposer: child,
+                }),
+                token: None,
+                parent: None,
+                pause: None,
+                shares: None,
+                action: None,
+                extra: None,
+            },
+            extras: Extras {
+                admin: None,
+                metadata: None,
+                sponsorship: None,
+                assignment: None,
+                collateral: None,
+                preferred_token: None,
+                payment_hook: None,
+                spend_policy: None,
+            },
+        }
+    );
+}
+
+/// In `test_history()`, a UI pages back through 25 weekly withdrawals, newest
+/// first, without knowing which periods they were for.
+#[test]
+fn test_history() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    assert_eq!(client.history(&0, &10).len(), 0);
+
+    for week in 0..25 {
+        set_timestamp(&env, START + week * WEEK);
+        client.withdraw();
+    }
+    let receipt = |period: u64| Receipt {
+        period,
+        amount: 9615384,
+        delivered: 9615384,
+        paid_at: START + period * WEEK,
+        label: symbol!("withdraw"),
+    };
+
+    let page = |newest: u64, len: u64| {
+        let mut page = Vec::new(&env);
+        for i in 0..len {
+            page.push_back(receipt(newest - i));
+        }
+        page
+    };
+
+    assert_eq!(client.history(&0, &10), page(24, 10));
+    assert_eq!(client.history(&10, &10), page(14, 10));
+    assert_eq!(client.history(&20, &10), page(4, 5));
+    assert_eq!(client.history(&30, &10), Vec::new(&env));
+    assert_eq!(client.history(&0, &50), page(24, 20));
+}
+
+/// In `test_prune_receipts()`, 30 weeks of receipts are pruned down to the
+/// last 5, ten at a time, and everything the allowance counts stays put.
+#[test]
+fn test_prune_receipts() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    for week in 0..30 {
+        set_timestamp(&env, START + week * WEEK);
+        client.withdraw();
+    }
+
+    assert_eq!(
+        client.with_source_account(&u3).try_prune_receipts(&5),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(client.with_source_account(&u1).prune_receipts(&5), 10);
+    assert_eq!(client.with_source_account(&u2).prune_receipts(&5), 10);
+    assert_eq!(client.with_source_account(&u2).prune_receipts(&5), 5);
+    assert_eq!(client.with_source_account(&u2).prune_receipts(&5), 0);
+
+    let history = client.history(&0, &20);
+    assert_eq!(history.len(), 5);
+    for (i, receipt) in history.iter().enumerate() {
+        assert_eq!(receipt.unwrap().period, 29 - i as u64);
+    }
+
+    let progress = client.get_state().progress;
+    assert_eq!(progress.withdraw_count, 30);
+    assert_eq!(progress.delivered, 9615384 * 30);
+
+    // New receipts carry on where the old ones left off.
+    set_timestamp(&env, START + 30 * WEEK);
+    client.withdraw();
+    assert_eq!(client.history(&0, &20).len(), 6);
+    assert_eq!(client.with_source_account(&u2).prune_receipts(&5), 1);
+}
+
+/// In `test_lifetime_cap()`, one allowance pays out exactly its cap and
+/// another straddles it, even after a raise, with a partial last withdrawal.
+/// Either way, that's the end of it.
+#[test]
+fn test_lifetime_cap() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let options = |lifetime_cap| Options {
+        lifetime_cap,
+        ..Options::default()
+    };
+
+    let exact_id = env.register_contract(None, AllowanceContract);
+    let exact = AllowanceContractClient::new(&env, &exact_id);
+    let (id, token) = setup_token(&env, &u1, &exact_id);
+    assert_eq!(
+        exact.with_source_account(&u1).try_init(
+            &Identifier::Account(u2.clone()),
+            &id,
+            &5200,
+            &WEEK,
+            &Options {
+                streaming: true,
+                ..options(200)
+            },
+        ),
+        Err(Ok(Error::InvalidArguments))
+    );
+    exact.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &5200,
+        &WEEK,
+        &options(200),
+    );
+    assert_eq!(exact.remaining_cap(), Some(200));
+
+    exact.withdraw();
+    set_timestamp(&env, START + WEEK);
+    exact.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2.clone())), 200);
+    assert_eq!(exact.remaining_cap(), Some(0));
+    assert_eq!(exact.get_status(), Status::Expired);
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(exact.try_withdraw(), Err(Ok(Error::SubscriptionExpired)));
+    assert!(!exact.can_withdraw());
+
+    set_timestamp(&env, START);
+    let straddle_id = env.register_contract(None, AllowanceContract);
+    let straddle = AllowanceContractClient::new(&env, &straddle_id);
+    let (id, token) = setup_token_at(&env, &[7; 32], &u1, &straddle_id);
+    straddle.with_source_account(&u1).init(
+        &Identifier::Account(u3.clone()),
+        &id,
+        &5200,
+        &WEEK,
+        &options(250),
+    );
+    straddle.withdraw();
+
+    // Doubling the amount doesn't get around the cap.
+    straddle.with_source_account(&u1).propose_amount(&10400);
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(straddle.required_allowance(&false), 150);
+    assert_eq!(straddle.withdraw(), 1);
+    assert_eq!(token.balance(&Identifier::Account(u3)), 250);
+    assert_eq!(straddle.remaining_cap(), Some(0));
+    assert_eq!(straddle.get_status(), Status::Expired);
+    assert_eq!(straddle.try_withdraw(), Err(Ok(Error::SubscriptionExpired)));
+}
+
+/// In `test_amount_change_limits()`, a 50% raise is too much when 20% is the
+/// most, a second change inside the cooldown is too soon, and one that keeps
+/// to both goes through.
+#[test]
+fn test_amount_change_limits() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            amount_change_cooldown: WEEK,
+            max_increase_bps: 2000,
+            ..Options::default()
+        },
+    );
+    let agreed = || client.get_state().progress.agreed_amount;
+
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_propose_amount(&750000000),
+        Err(Ok(Error::RaiseTooLarge))
+    );
+    client.with_source_account(&u1).propose_amount(&600000000);
+    assert_eq!(agreed(), 600000000);
+
+    set_timestamp(&env, START + WEEK - 1);
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_propose_amount(&550000000),
+        Err(Ok(Error::TooSoon))
+    );
+
+    // A week on, 20% of the new amount is fine, and so is any cut.
+    set_timestamp(&env, START + WEEK);
+    client.with_source_account(&u1).propose_amount(&720000000);
+    assert_eq!(agreed(), 720000000);
+    set_timestamp(&env, START + 2 * WEEK);
+    client.with_source_account(&u1).propose_amount(&100000000);
+    assert_eq!(agreed(), 100000000);
+}
+
+/// In `test_tolerance()`, the `Child` collects five minutes early every week
+/// for ten weeks, and gets ten withdrawals, no more.
+#[test]
+fn test_tolerance() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2);
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    let options = |tolerance| Options {
+        tolerance,
+        ..Options::default()
+    };
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_init(&child, &id, &500000000, &WEEK, &options(WEEK)),
+        Err(Ok(Error::InvalidArguments))
+    );
+    client
+        .with_source_account(&u1)
+        .init(&child, &id, &500000000, &WEEK, &options(300));
+    client.withdraw();
+
+    for week in 1..10 {
+        let due = START + week * WEEK;
+        assert_eq!(client.next_withdraw_time(), due - 300);
+
+        set_timestamp(&env, due - 301);
+        assert!(!client.can_withdraw());
+        set_timestamp(&env, due - 300);
+        assert!(client.can_withdraw());
+        assert_eq!(client.withdraw(), 1);
+        assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    }
+    assert_eq!(token.balance(&child), 9615384 * 10);
+    assert_eq!(client.get_state().progress.latest, START + 9 * WEEK);
+}
+
+/// In `test_lapse_missed()`, the `Child` of a gym membership skips three
+/// weeks, and when they're back, only the current week can be collected. The
+/// next one comes a week after that.
+#[test]
+fn test_lapse_missed() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2);
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &child,
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            lapse_missed: true,
+            ..Options::default()
+        },
+    );
+    client.withdraw();
+
+    set_timestamp(&env, START + 4 * WEEK + 100);
+    assert_eq!(client.get_status(), Status::Active);
+    assert_eq!(client.required_allowance(&false), 9615384);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&child), 9615384 * 2);
+
+    assert_eq!(client.get_state().progress.latest, START + 4 * WEEK);
+    assert_eq!(client.next_withdraw_time(), START + 5 * WEEK);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+}
+
+/// In `test_anchor_on_first_withdraw()`, the `Child` first collects ten days
+/// after the `StartEpoch`, and the next withdrawal comes a whole week after
+/// that, not after the `StartEpoch`.
+#[test]
+fn test_anchor_on_first_withdraw() {
+    const DAY: u64 = 24 * 60 * 60;
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2);
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &child,
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            anchor_on_first_withdraw: true,
+            ..Options::default()
+        },
+    );
+
+    let first = START + 10 * DAY;
+    set_timestamp(&env, first);
+    assert_eq!(client.next_withdraw_time(), first);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(client.get_state().progress.start_epoch, first);
+    assert_eq!(client.next_withdraw_time(), first + WEEK);
+
+    set_timestamp(&env, first + WEEK - 1);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    set_timestamp(&env, first + WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&child), 9615384 * 2);
+
+    let receipt = |period: u64| Receipt {
+        period,
+        amount: 9615384,
+        delivered: 9615384,
+        paid_at: first + period * WEEK,
+        label: symbol!("withdraw"),
+    };
+    assert_eq!(client.history(&0, &10), vec![&env, receipt(1), receipt(0)]);
+}
+
+/// Sets up an allowance of 500,000,000 stroops a year, paid every `step`, for
+/// the `fix_step` tests, returning the client, the token and the `Parent`.
+fn setup_fix_step(env: &Env, step: u64) -> (AllowanceContractClient, token::Client, AccountId) {
+    set_timestamp(env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(env, &contract_id);
+    let (id, token) = setup_token(env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2),
+        &id,
+        &500000000,
+        &step,
+        &Options::default(),
+    );
+    (client, token, u1)
+}
+
+/// In `test_fix_step_shrink()`, the `Parent` goes from every four weeks to
+/// every week with three withdrawals unpaid. Those are paid at the four
+/// weekly rate, and the first weekly one comes when the current four weeks
+/// are up, not straight away.
+#[test]
+fn test_fix_step_shrink() {
+    let env = Env::default();
+    let (client, token, u1) = setup_fix_step(&env, 4 * WEEK);
+    let child = client.get_state().config.child;
+
+    set_timestamp(&env, START + 9 * WEEK);
+    client.with_source_account(&u1).fix_step(&WEEK);
+    assert_eq!(token.balance(&child), 38461538 * 3);
+    assert_eq!(client.next_withdraw_time(), START + 12 * WEEK);
+
+    set_timestamp(&env, START + 11 * WEEK);
+    assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+    set_timestamp(&env, START + 12 * WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&child), 38461538 * 3 + 9615384);
+    for receipt in client.history(&0, &1).iter() {
+        assert_eq!(receipt.unwrap().period, 3);
+    }
+}
+
+/// In `test_fix_step_grow()`, the `Parent` goes from every week to every
+/// four weeks with three withdrawals unpaid. Those are paid weekly, and the
+/// first four weekly one comes at the end of the current week.
+#[test]
+fn test_fix_step_grow() {
+    let env = Env::default();
+    let (client, token, u1) = setup_fix_step(&env, WEEK);
+    let child = client.get_state().config.child;
+
+    set_timestamp(&env, START + 2 * WEEK + 60);
+    client.with_source_account(&u1).fix_step(&(4 * WEEK));
+    assert_eq!(token.balance(&child), 9615384 * 3);
+    assert_eq!(client.next_withdraw_time(), START + 3 * WEEK);
+
+    set_timestamp(&env, START + 3 * WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&child), 9615384 * 3 + 38461538);
+    assert_eq!(client.next_withdraw_time(), START + 7 * WEEK);
+}
+
+/// In `test_fix_step_on_boundary()`, the `Parent` changes the step at the
+/// very moment a withdrawal comes due. That one is the last at the old step.
+#[test]
+fn test_fix_step_on_boundary() {
+    let env = Env::default();
+    let (client, token, u1) = setup_fix_step(&env, WEEK);
+    let child = client.get_state().config.child;
+    client.withdraw();
+
+    set_timestamp(&env, START + WEEK);
+    let u3 = env.accounts().generate();
+    assert_eq!(
+        client.with_source_account(&u3).try_fix_step(&(2 * WEEK)),
+        Err(Ok(Error::Unauthorized))
+    );
+    client.with_source_account(&u1).fix_step(&(2 * WEEK));
+    assert_eq!(token.balance(&child), 9615384 * 2);
+    assert!(!client.can_withdraw());
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(client.withdraw(), 1);
+    assert_eq!(token.balance(&child), 9615384 * 2 + 19230769);
+    assert_eq!(client.get_state().config.step, 2 * WEEK);
+}
+
+/// In `test_fix_step_with_allowlist()`, only a keeper may invoke `withdraw`,
+/// but the `Parent` can still change the step, which pays what's due first.
+#[test]
+fn test_fix_step_with_allowlist() {
+    let env = Env::default();
+    let (client, token, u1) = setup_fix_step(&env, WEEK);
+    let child = client.get_state().config.child;
+    let keeper = env.accounts().generate();
+    client
+        .with_source_account(&u1)
+        .set_invoker_allowlist(&vec![&env, keeper]);
+
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(
+        client.with_source_account(&u1).try_withdraw(),
+        Err(Ok(Error::Unauthorized))
+    );
+    client.with_source_account(&u1).fix_step(&(2 * WEEK));
+    assert_eq!(token.balance(&child), 9615384 * 2);
+    assert_eq!(client.get_state().config.step, 2 * WEEK);
+}
+
+/// In `test_claimable_boundaries()`, a withdrawal can be collected at the
+/// very second it comes due (at the `StartEpoch`, and every `step` after
+/// the `Latest` one), and not a second before. `withdraw` and the views all
+/// agree. If this ever fails, the boundary has moved, and it mustn't.
+#[test]
+fn test_claimable_boundaries() {
+    const DAY: u64 = 24 * 60 * 60;
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    let sub = client.with_source_account(&u1).create(
+        &Identifier::Account(u2),
+        &id,
+        &(START + DAY),
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    for (due, error) in [
+        (START + DAY, Error::NothingDue),
+        (START + DAY + WEEK, Error::ChildAlreadyWithdrawn),
+    ] {
+        set_timestamp(&env, due - 1);
+        assert!(!client.can_withdraw_for(&sub));
+        assert_eq!(client.required_allowance_for(&sub, &false), 0);
+        assert_eq!(client.try_withdraw_for(&sub), Err(Ok(error)));
+
+        set_timestamp(&env, due);
+        assert!(client.can_withdraw_for(&sub));
+        assert_eq!(client.required_allowance_for(&sub, &false), 9615384);
+        assert_eq!(client.withdraw_for(&sub), 1);
+    }
+}
+
+/// In `test_reset()`, an allowance is cancelled and reset, and a different one
+/// is set up in its place on the same contract, starting from scratch.
+#[test]
+fn test_reset() {
+    const DAY: u64 = 24 * 60 * 60;
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let parent = Identifier::Account(u1.clone());
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u2.clone()),
+        &id,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+    client.withdraw();
+    assert_eq!(
+        client.with_source_account(&u1).try_reset(),
+        Err(Ok(Error::NotEnded))
+    );
+
+    client.with_source_account(&u1).cancel();
+    assert_eq!(client.get_status(), Status::Cancelled);
+    assert_eq!(
+        client.with_source_account(&u2).try_reset(),
+        Err(Ok(Error::Unauthorized))
+    );
+    assert_eq!(
+        client.with_source_account(&u1).try_reset(),
+        Err(Ok(Error::ReceiptsNotPruned))
+    );
+    client.with_source_account(&u1).prune_receipts(&0);
+    client.with_source_account(&u1).reset();
+    assert_eq!(client.get_status(), Status::Uninitialized);
+    assert_eq!(
+        client.subscriptions_of(&parent, &Role::Parent, &0, &10),
+        Vec::new(&env)
+    );
+
+    // The new one pays somebody else, daily, and its count starts over.
+    set_timestamp(&env, START + DAY);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(u3.clone()),
+        &id,
+        &365000000,
+        &DAY,
+        &Options::default(),
+    );
+    client.withdraw();
+    assert_eq!(token.balance(&Identifier::Account(u2)), 9615384);
+    assert_eq!(token.balance(&Identifier::Account(u3)), 1000000);
+    assert_eq!(client.get_status(), Status::Active);
+    assert_eq!(
+        client.subscriptions_of(&parent, &Role::Parent, &0, &10),
+        vec![&env, 0]
+    );
+
+    let progress = client.get_state().progress;
+    assert_eq!(progress.withdraw_count, 1);
+    assert_eq!(progress.delivered, 1000000);
+    assert_eq!(
+        client.history(&0, &10),
+        vec![
+            &env,
+            Receipt {
+                period: 0,
+                amount: 1000000,
+                delivered: 1000000,
+                paid_at: START + DAY,
+                label: symbol!("withdraw"),
+            }
+        ]
+    );
+    assert!(!client.can_withdraw());
+}
+
+/// Withdraws from `client`, checking that exactly what `preview_withdraw`
+/// said beforehand was paid, and to whom.
+fn withdraw_as_previewed(
+    env: &Env,
+    client: &AllowanceContractClient,
+    token: &token::Client,
+    fees: &Identifier,
+) {
+    let preview = client.preview_withdraw();
+    let mut before = Vec::new(env);
+    for payout in preview.payouts.iter() {
+        let (receiver, _) = payout.unwrap();
+        before.push_back(token.balance(&receiver));
+    }
+    let collected = token.balance(fees);
+
+    assert_eq!(client.withdraw(), preview.periods);
+    for (payout, balance) in preview.payouts.iter().zip(before.iter()) {
+        let (receiver, amount) = payout.unwrap();
+        assert_eq!(token.balance(&receiver), balance.unwrap() + amount);
+    }
+    assert_eq!(token.balance(fees), collected + preview.fee);
+    assert_eq!(client.get_state().progress.latest, preview.latest);
+}
+
+/// In `test_preview_withdraw()`, a few differently set up allowances are
+/// previewed before every withdrawal, and pay out exactly what was
+/// previewed, failing the same way when there's nothing to pay.
+#[test]
+fn test_preview_withdraw() {
+    let env = Env::default();
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+    let (child, partner) = (Identifier::Account(u2), Identifier::Account(u3));
+    let platform = Identifier::Account(env.accounts().generate());
+
+    let split = Options {
+        fee_collector: Some(platform.clone()),
+        fee_bps: 250,
+        recipients: Some(vec![&env, (child.clone(), 7000), (partner.clone(), 3000)]),
+        ..Default::default()
+    };
+    let trial = Options {
+        trial_periods: 2,
+        ..Default::default()
+    };
+    let lenient = Options {
+        lenient: true,
+        ..Default::default()
+    };
+    for (i, options) in [Options::default(), split, trial, lenient]
+        .into_iter()
+        .enumerate()
+    {
+        set_timestamp(&env, START);
+        let contract_id = env.register_contract(None, AllowanceContract);
+        let client = AllowanceContractClient::new(&env, &contract_id);
+        let (id, token) = setup_token_at(&env, &[i as u8 + 1; 32], &u1, &contract_id);
+        client
+            .with_source_account(&u1)
+            .init(&child, &id, &500000000, &WEEK, &options);
+
+        withdraw_as_previewed(&env, &client, &token, &platform);
+        if options.lenient {
+            assert_eq!(client.preview_withdraw().periods, 0);
+            assert_eq!(client.withdraw(), 0);
+        } else {
+            assert_eq!(
+                client.try_preview_withdraw(),
+                Err(Ok(Error::ChildAlreadyWithdrawn))
+            );
+            assert_eq!(client.try_withdraw(), Err(Ok(Error::ChildAlreadyWithdrawn)));
+        }
+
+        set_timestamp(&env, START + 3 * WEEK);
+        let preview = client.preview_withdraw();
+        assert_eq!((preview.periods, preview.first), (3, 1));
+        assert_eq!(preview.token_id, id);
+        withdraw_as_previewed(&env, &client, &token, &platform);
+    }
+
+    // With the fee and the split, the first withdrawal was 2.5% to the
+    // platform, and the rest 70/30.
+    set_timestamp(&env, START + 4 * WEEK);
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token_at(&env, &[9; 32], &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &child,
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            fee_collector: Some(platform),
+            fee_bps: 250,
+            recipients: Some(vec![&env, (child.clone(), 7000), (partner.clone(), 3000)]),
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        client.preview_withdraw(),
+        WithdrawPreview {
+            periods: 1,
+            first: 0,
+            token_id: id,
+            total: 9615384,
+            fee: 240384,
+            payouts: vec![&env, (child, 6562500), (partner, 2812500)],
+            latest: START + 4 * WEEK,
+        }
+    );
+}
+
+/// In `test_outstanding_obligation()`, a weekly allowance with an
+/// escalator is cancelled with eleven weeks' notice, so it makes twelve
+/// payments in all, and what's outstanding goes down by exactly what each one
+/// pays, to nothing. An open-ended allowance has no such figure.
+#[test]
+fn test_outstanding_obligation() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2);
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &child,
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            escalator_bps: 1000,
+            escalator_every: 4,
+            cancel_notice_period: 11 * WEEK,
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        client.try_outstanding_obligation(),
+        Err(Ok(Error::Unbounded))
     );
 
-    // Ok, stop here! This time, when invoking `init`, we give a `1` for the
-    // `amount` field and a `1` for the `step` field. If you've followed along
-    // with the math so far, that comes out to 3.1709792e-15 **stroops** per
-    // withdraw. That's even more precision than Microsoft Excel can handle!
+    client.withdraw();
+    client.with_source_account(&u1).cancel();
+    let mut outstanding = client.outstanding_obligation();
+    let mut paid = token.balance(&child);
+    assert!(outstanding > 9615384 * 11);
+
+    for week in 1..12 {
+        set_timestamp(&env, START + week * WEEK);
+        client.withdraw();
+        let payment = token.balance(&child) - paid;
+        paid += payment;
+        assert_eq!(client.outstanding_obligation(), outstanding - payment);
+        outstanding -= payment;
+    }
+    assert_eq!(outstanding, 0);
+    assert_eq!(client.get_state().progress.withdraw_count, 12);
+
+    // A cap is an end, too: whatever's left of it will be paid.
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token_at(&env, &[7; 32], &u1, &contract_id);
     client.with_source_account(&u1).init(
-        &u2, // our `Child` account
-        &id, // our token contract id
-        &1,  // 1 stroops == 0.0000001 units allowance for the year
-        &1,  // 1 withdraw per second
+        &child,
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            lifetime_cap: 50000000,
+            ..Default::default()
+        },
     );
+    client.withdraw();
+    assert_eq!(client.outstanding_obligation(), 50000000 - 9615384);
+}
 
-    // Again, there's no need for an assertion here, since this invocation
-    // should fail and respond with `Status(ContractError(6))`.
-}
\ No newline at end of file
+/// In `test_grace_periods()`, a week late is fine with a week's grace, and
+/// the allowance is only `InArrears` (and the `Child` no longer a paying
+/// subscriber) once it runs out. Without any grace, it's straight away.
+/// What's owed is the same either way, and shortfalls within the grace don't
+/// count towards a suspension.
+#[test]
+fn test_grace_periods() {
+    let env = Env::default();
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2.clone());
+
+    let setup = |grace_periods: u32| {
+        set_timestamp(&env, START);
+        let contract_id = env.register_contract(None, AllowanceContract);
+        let client = AllowanceContractClient::new(&env, &contract_id);
+        let (id, _) = setup_token_at(&env, &[grace_periods as u8 + 1; 32], &u1, &contract_id);
+        client.with_source_account(&u1).init(
+            &child,
+            &id,
+            &500000000,
+            &WEEK,
+            &Options {
+                grace_periods,
+                ..Default::default()
+            },
+        );
+        client.withdraw();
+        contract_id
+    };
+    let (strict_id, lenient_id) = (setup(0), setup(1));
+    let strict = AllowanceContractClient::new(&env, &strict_id);
+    let lenient = AllowanceContractClient::new(&env, &lenient_id);
+
+    set_timestamp(&env, START + WEEK);
+    assert_eq!(strict.get_status(), Status::Active);
+    assert_eq!(lenient.get_status(), Status::Active);
+
+    set_timestamp(&env, START + 2 * WEEK);
+    assert_eq!(strict.get_status(), Status::InArrears);
+    assert!(!strict.is_active_for(&u2));
+    assert_eq!(lenient.get_status(), Status::Active);
+    assert!(lenient.is_active_for(&u2));
+    assert_eq!(
+        strict.required_allowance(&false),
+        lenient.required_allowance(&false)
+    );
+
+    set_timestamp(&env, START + 3 * WEEK);
+    assert_eq!(lenient.get_status(), Status::InArrears);
+    assert!(!lenient.is_active_for(&u2));
+    assert_eq!(lenient.withdraw(), 3);
+    assert_eq!(lenient.get_status(), Status::Active);
+
+    // One shortfall is enough to suspend it, but not within the grace.
+    set_timestamp(&env, START);
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, token) = setup_token_at(&env, &[9; 32], &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &child,
+        &id,
+        &500000000,
+        &WEEK,
+        &Options {
+            grace_periods: 1,
+            max_failures: 1,
+            ..Default::default()
+        },
+    );
+    client.withdraw();
+    token.with_source_account(&u1).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id),
+        &0,
+    );
+
+    for week in 1..3 {
+        set_timestamp(&env, START + week * WEEK);
+        assert_eq!(client.report_shortfall(), week as u32);
+        assert_eq!(client.get_status(), Status::Active);
+    }
+    set_timestamp(&env, START + 3 * WEEK);
+    assert_eq!(client.report_shortfall(), 3);
+    assert_eq!(client.get_status(), Status::Suspended);
+}
+
+/// In `test_uninitialized()`, every entry point is invoked on a contract
+/// nobody has `init`ialized: whatever needs an allowance fails cleanly with
+/// `Error::ContractNotInitialized`, and the rest say there's nothing there.
+#[test]
+fn test_uninitialized() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let who = Identifier::Account(u1.clone());
+    let token_id = BytesN::from_array(&env, &[1; 32]);
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let not_initialized = Error::ContractNotInitialized;
+
+    assert!(!client.is_initialized());
+    assert_eq!(client.get_status(), Status::Uninitialized);
+    assert_eq!(client.get_status_for(&0), Status::Uninitialized);
+    assert!(!client.can_withdraw());
+    assert!(!client.can_withdraw_for(&0));
+    assert!(!client.is_active_for(&u1));
+    assert_eq!(client.nonce(&who), 0);
+    assert_eq!(
+        client.subscriptions_of(&who, &Role::Parent, &0, &10),
+        Vec::new(&env)
+    );
+    assert_eq!(client.due_subscriptions(&0, &10).0, Vec::new(&env));
+    assert_eq!(client.withdraw_batch(&Vec::new(&env)), Vec::new(&env));
+    assert_eq!(
+        client.try_withdraw_for(&1),
+        Err(Ok(Error::SubscriptionNotFound))
+    );
+
+    assert_eq!(
+        client.try_verify_config(&Config {
+            parent: who.clone(),
+            child: who.clone(),
+            token_id: token_id.clone(),
+            amount: 1000,
+            step: WEEK,
+            options: Options::default(),
+        }),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(client.try_withdraw(), Err(Ok(not_initialized)));
+    assert_eq!(
+        client.try_request_pause(&START, &(START + WEEK)),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(client.try_approve_pause(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_reject_pause(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_invoice_extra(&1000), Err(Ok(not_initialized)));
+    assert_eq!(client.try_approve_extra(), Err(Ok(not_initialized)));
+    assert_eq!(
+        client.try_required_allowance(&true),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(client.try_fix_start_epoch(&START), Err(Ok(not_initialized)));
+    assert_eq!(client.try_propose_amount(&1000), Err(Ok(not_initialized)));
+    assert_eq!(client.try_accept_amount(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_report_usage(&1), Err(Ok(not_initialized)));
+    assert_eq!(client.try_next_withdraw_time(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_time_until_next(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_withdraw_up_to(&START), Err(Ok(not_initialized)));
+    assert_eq!(client.try_withdraw_periods(&1), Err(Ok(not_initialized)));
+    assert_eq!(
+        client.try_forecast(&START, &(START + WEEK)),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(client.try_withdraw_count(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_period_index_of(&START), Err(Ok(not_initialized)));
+    assert_eq!(
+        client.try_set_invoker_allowlist(&Vec::new(&env)),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(client.try_withdraw_for(&0), Err(Ok(not_initialized)));
+    assert_eq!(
+        client.try_required_allowance_for(&0, &true),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(
+        client.try_propose_amount_for(&0, &1000),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(client.try_accept_amount_for(&0), Err(Ok(not_initialized)));
+    assert_eq!(client.try_upgrade(&token_id), Err(Ok(not_initialized)));
+    assert_eq!(client.try_migrate(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_set_admin(&who), Err(Ok(not_initialized)));
+    assert_eq!(client.try_clear_admin(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_propose_parent(&who), Err(Ok(not_initialized)));
+    assert_eq!(client.try_accept_parent(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_close_by_child(&true), Err(Ok(not_initialized)));
+    assert_eq!(client.try_cancel(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_approve_close(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_revoke_close_approval(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_forgive_arrears(&1), Err(Ok(not_initialized)));
+    assert_eq!(client.try_prepay(&1), Err(Ok(not_initialized)));
+    assert_eq!(client.try_propose_skip(&1), Err(Ok(not_initialized)));
+    assert_eq!(client.try_accept_skip(&1), Err(Ok(not_initialized)));
+    assert_eq!(client.try_report_shortfall(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_reactivate(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_recipients(), Err(Ok(not_initialized)));
+    assert_eq!(
+        client.try_propose_shares(&vec![&env, (who.clone(), 10000)]),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(client.try_accept_shares(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_cancel_my_share(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_payers(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_sponsor(&1), Err(Ok(not_initialized)));
+    assert_eq!(client.try_revoke_sponsorship(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_post_collateral(&1000), Err(Ok(not_initialized)));
+    assert_eq!(
+        client.try_claim_from_collateral(&0),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(client.try_reclaim_collateral(), Err(Ok(not_initialized)));
+    assert_eq!(
+        client.try_assign_payments(&who, &1),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(client.try_renounce_assignment(), Err(Ok(not_initialized)));
+    assert_eq!(
+        client.try_set_metadata(&symbol!("plan"), &Bytes::new(&env)),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(client.try_get_metadata(), Err(Ok(not_initialized)));
+    assert_eq!(
+        client.try_propose_amount_signed(&Signature::Invoker, &0, &1000),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(
+        client.try_cancel_signed(&Signature::Invoker, &0),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(
+        client.try_approve_action(&Action::Cancel),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(
+        client.try_propose_token(&token_id, &1000),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(client.try_accept_token(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_reconcile(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_withdraw_in(&token_id), Err(Ok(not_initialized)));
+    assert_eq!(
+        client.try_set_preferred_token(&token_id),
+        Err(Ok(not_initialized))
+    );
+    assert_eq!(client.try_set_payment_hook(&None), Err(Ok(not_initialized)));
+    assert_eq!(client.try_set_spend_policy(&None), Err(Ok(not_initialized)));
+    assert_eq!(client.try_report_end(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_get_state(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_history(&0, &10), Err(Ok(not_initialized)));
+    assert_eq!(client.try_prune_receipts(&0), Err(Ok(not_initialized)));
+    assert_eq!(client.try_remaining_cap(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_fix_step(&WEEK), Err(Ok(not_initialized)));
+    assert_eq!(client.try_reset(), Err(Ok(not_initialized)));
+    assert_eq!(client.try_preview_withdraw(), Err(Ok(not_initialized)));
+    assert_eq!(
+        client.try_outstanding_obligation(),
+        Err(Ok(not_initialized))
+    );
+
+    // Once it has been, it says so.
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    client.with_source_account(&u1).init(
+        &Identifier::Account(env.accounts().generate()),
+        &id,
+        &1000,
+        &WEEK,
+        &Options::default(),
+    );
+    assert!(client.is_initialized());
+}
+
+/// In `test_error_codes()`, every `Error` is pinned to its number, since
+/// that's all a client ever sees. A failure here means a number has changed
+/// under somebody.
+#[test]
+fn test_error_codes() {
+    for (error, code) in [
+        (Error::ContractAlreadyInitialized, 1),
+        (Error::ContractNotInitialized, 2),
+        (Error::InvalidAuth, 3),
+        (Error::ChildAlreadyWithdrawn, 4),
+        (Error::InvalidInvoker, 5),
+        (Error::InvalidArguments, 6),
+        (Error::Overflow, 7),
+        (Error::NoPauseRequest, 8),
+        (Error::Paused, 9),
+        (Error::InsufficientAllowance, 10),
+        (Error::NoExtraInvoice, 11),
+        (Error::MissingData, 12),
+        (Error::CorruptData, 13),
+        (Error::NothingDue, 14),
+        (Error::AlreadyStarted, 15),
+        (Error::NoAmountProposal, 16),
+        (Error::TooManyRateChanges, 17),
+        (Error::StalePrice, 18),
+        (Error::UsageCapExceeded, 19),
+        (Error::SubscriptionNotFound, 20),
+        (Error::ConfigMismatch, 21),
+        (Error::MigrationRequired, 22),
+        (Error::NoParentProposal, 23),
+        (Error::Cancelled, 24),
+        (Error::PenaltyPaymentFailed, 25),
+        (Error::NoSkipProposal, 26),
+        (Error::Suspended, 27),
+        (Error::NoShareProposal, 28),
+        (Error::AlreadySponsored, 29),
+        (Error::InsufficientCollateral, 30),
+        (Error::AlreadyAssigned, 31),
+        (Error::InvalidNonce, 32),
+        (Error::ApprovalRequired, 33),
+        (Error::NoTokenProposal, 34),
+        (Error::ArrearsOutstanding, 35),
+        (Error::ReceiverCannotReceive, 36),
+        (Error::SlippageExceeded, 37),
+        (Error::PolicyRejected, 38),
+        (Error::NotEnded, 39),
+        (Error::CapReached, 40),
+        (Error::TooSoon, 41),
+        (Error::RaiseTooLarge, 42),
+        (Error::CollateralHeld, 43),
+        (Error::Unbounded, 44),
+        (Error::ZeroStep, 45),
+        (Error::NonPositiveAmount, 46),
+        (Error::AmountUnchanged, 47),
+        (Error::TooManyEscalations, 48),
+        (Error::ReceiptsNotPruned, 49),
+        (Error::InsufficientBalance, 50),
+        (Error::Unauthorized, 51),
+        (Error::SubscriptionCancelled, 52),
+        (Error::SubscriptionExpired, 53),
+    ] {
+        assert_eq!(error as u32, code);
+    }
+}
+
+/// In `test_specific_errors()`, a zero `step`, an amount that comes to
+/// nothing, and proposing the amount that's already agreed each get an
+/// error of their own.
+#[test]
+fn test_specific_errors() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let child = Identifier::Account(u2);
+
+    let contract_id = env.register_contract(None, AllowanceContract);
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+    let init = |amount: i128, step: u64| {
+        client
+            .with_source_account(&u1)
+            .try_init(&child, &id, &amount, &step, &Options::default())
+    };
+    assert_eq!(init(500000000, 0), Err(Ok(Error::ZeroStep)));
+    assert_eq!(init(0, WEEK), Err(Ok(Error::NonPositiveAmount)));
+    assert_eq!(init(-500000000, WEEK), Err(Ok(Error::NonPositiveAmount)));
+    assert_eq!(init(51, WEEK), Err(Ok(Error::NonPositiveAmount)));
+    assert_eq!(init(500000000, WEEK), Ok(Ok(())));
+
+    assert_eq!(
+        client
+            .with_source_account(&u1)
+            .try_propose_amount(&500000000),
+        Err(Ok(Error::AmountUnchanged))
+    );
+    assert_eq!(
+        client.with_source_account(&u1).try_propose_amount(&-1),
+        Err(Ok(Error::NonPositiveAmount))
+    );
+    assert_eq!(
+        client.with_source_account(&u1).try_fix_step(&0),
+        Err(Ok(Error::ZeroStep))
+    );
+}
+
+/// In `test_transfer_fails()`, the token refuses to move anything. The
+/// `withdraw` fails along with it, and nothing has moved on: once the token is
+/// working again, the same withdrawal is paid.
+#[test]
+fn test_transfer_fails() {
+    let f = Fixture::new(Config {
+        mock_token: true,
+        ..Config::default()
+    });
+
+    f.mock().script(&vec![&f.env, Transfer::Fail(7)]);
+    assert!(f.client.try_withdraw().is_err());
+    f.assert_balance(&f.child_id(), 0);
+    f.assert_balance(&f.parent_id(), 1000000000);
+    let progress = f.client.get_state().progress;
+    assert_eq!(progress.latest, START - WEEK);
+    assert_eq!(progress.withdraw_count, 0);
+    assert_eq!(progress.delivered, 0);
+
+    f.mock().script(&Vec::new(&f.env));
+    assert_eq!(f.client.withdraw(), 1);
+    f.assert_balance(&f.child_id(), 9615384);
+}
+
+/// In `test_shortfall_on_balance()`, the `Parent` has left us plenty of
+/// allowance, but not the balance to back it. That's a shortfall, too, and it
+/// counts towards suspension like any other.
+#[test]
+fn test_shortfall_on_balance() {
+    let f = Fixture::new(Config {
+        options: Options {
+            max_failures: 1,
+            ..Default::default()
+        },
+        mock_token: true,
+        ..Config::default()
+    });
+
+    f.mock().set_balance(&f.parent_id(), &1000);
+    assert_eq!(f.client.report_shortfall(), 1);
+    assert_eq!(f.client.get_state().progress.failure_count, 1);
+    assert_eq!(f.client.get_status(), Status::Suspended);
+
+    // With the balance topped up, it's no shortfall at all.
+    f.mock().set_balance(&f.parent_id(), &1000000000);
+    assert_eq!(f.client.report_shortfall(), 0);
+    assert_eq!(f.client.get_state().progress.failure_count, 1);
+}
+
+/// In `test_guarantor_transfer_fails()`, the guarantor steps in once the
+/// `Parent`'s allowance runs out, but their transfer fails the first time.
+/// Nobody is charged for it, and the next try collects from them.
+#[test]
+fn test_guarantor_transfer_fails() {
+    let mut f = Fixture::new(Config {
+        init: false,
+        mock_token: true,
+        ..Config::default()
+    });
+    let guarantor = Identifier::Account(f.env.accounts().generate());
+    let contract = Identifier::Contract(f.contract_id.clone());
+    f.mock().set_balance(&guarantor, &1000000000);
+    f.mock().set_allowance(&guarantor, &contract, &1000000000);
+    f.mock().set_allowance(&f.parent_id(), &contract, &9615384);
+    f.config.options.guarantor = Some(guarantor.clone());
+    f.init();
+
+    f.client.withdraw();
+    f.assert_balance(&f.parent_id(), 1000000000 - 9615384);
+
+    f.advance_time(WEEK);
+    f.mock().script(&vec![&f.env, Transfer::Fail(7)]);
+    assert!(f.client.try_withdraw().is_err());
+    f.assert_balance(&guarantor, 1000000000);
+    f.assert_balance(&f.child_id(), 9615384);
+
+    f.mock().script(&Vec::new(&f.env));
+    assert_eq!(f.client.withdraw(), 1);
+    f.assert_balance(&f.parent_id(), 1000000000 - 9615384);
+    f.assert_balance(&guarantor, 1000000000 - 9615384);
+    f.assert_balance(&f.child_id(), 9615384 * 2);
+}
+
+/// In `test_reconcile_misreported()`, the token's books say the `Child` got a
+/// little less than they were sent, and `reconcile` notices.
+#[test]
+fn test_reconcile_misreported() {
+    let f = Fixture::new(Config {
+        mock_token: true,
+        ..Config::default()
+    });
+    f.client.reconcile();
+
+    f.mock()
+        .script(&vec![&f.env, Transfer::Misreport(-1000000)]);
+    f.client.withdraw();
+    assert_eq!(
+        f.client.reconcile(),
+        ReconcileReport {
+            since: START,
+            paid: 9615384,
+            expected: 9615384,
+            actual: 9615384 - 1000000,
+        }
+    );
+}
+
+/// In `test_init_on_behalf_needs_deployer()`, only the contract that deployed
+/// an AllowanceContract, with the salt it deployed it with, gets to set it up
+/// for somebody else. That's what keeps a squatter from naming a `Parent` who
+/// has already approved the new contract.
+#[test]
+fn test_init_on_behalf_needs_deployer() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+    let u3 = env.accounts().generate();
+
+    // The deployer is a `SquatterContract` too: what matters is whether the
+    // AllowanceContract's id comes from its id and the salt.
+    let deployer_id = env.register_contract(None, SquatterContract);
+    let deployer = SquatterContractClient::new(&env, &deployer_id);
+    let salt = BytesN::from_array(&env, &[7; 32]);
+    let contract_id = env.register_contract(
+        Some(&deployed_id(&env, &deployer_id, &salt)),
+        AllowanceContract,
+    );
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    let squatter_id = env.register_contract(None, SquatterContract);
+    let squatter = SquatterContractClient::new(&env, &squatter_id);
+    assert_eq!(
+        squatter.try_squat(&contract_id, &salt, &u1, &u3, &id),
+        Err(Ok(Error::InvalidInvoker))
+    );
+    assert_eq!(
+        deployer.try_squat(
+            &contract_id,
+            &BytesN::from_array(&env, &[8; 32]),
+            &u1,
+            &u2,
+            &id
+        ),
+        Err(Ok(Error::InvalidInvoker))
+    );
+    assert!(!client.is_initialized());
+
+    deployer.squat(&contract_id, &salt, &u1, &u2, &id);
+    let config = client.get_state().config;
+    assert_eq!(config.parent, Identifier::Account(u1));
+    assert_eq!(config.child, Identifier::Account(u2));
+}
+
+/// In `test_init_deployer_salt()`, a contract that deploys an
+/// AllowanceContract and pays for it itself gives `init` the salt it deployed
+/// it with, and nobody else gets to `init` it with that salt.
+#[test]
+fn test_init_deployer_salt() {
+    let env = Env::default();
+    set_timestamp(&env, START);
+
+    let u1 = env.accounts().generate();
+    let u2 = env.accounts().generate();
+
+    let deployer_id = env.register_contract(None, SquatterContract);
+    let deployer = SquatterContractClient::new(&env, &deployer_id);
+    let salt = BytesN::from_array(&env, &[7; 32]);
+    let contract_id = env.register_contract(
+        Some(&deployed_id(&env, &deployer_id, &salt)),
+        AllowanceContract,
+    );
+    let client = AllowanceContractClient::new(&env, &contract_id);
+    let (id, _) = setup_token(&env, &u1, &contract_id);
+
+    let squatter_id = env.register_contract(None, SquatterContract);
+    let squatter = SquatterContractClient::new(&env, &squatter_id);
+    assert_eq!(
+        squatter.try_squat_init(&contract_id, &salt, &u1, &id),
+        Err(Ok(Error::InvalidInvoker))
+    );
+    assert_eq!(
+        client.with_source_account(&u1).try_init(
+            &Identifier::Account(u1.clone()),
+            &id,
+            &500000000,
+            &WEEK,
+            &Options {
+                deployer_salt: Some(salt.clone()),
+                ..Default::default()
+            },
+        ),
+        Err(Ok(Error::InvalidInvoker))
+    );
+    assert!(!client.is_initialized());
+
+    deployer.squat_init(&contract_id, &salt, &u2, &id);
+    let config = client.get_state().config;
+    assert_eq!(config.parent, Identifier::Contract(deployer_id));
+    assert_eq!(config.child, Identifier::Account(u2));
+}
+
+/// In `test_prepay_past_cap()`, the `Parent` tries to prepay five weeks when
+/// only 150 of the 250 stroop cap is left. They pay for a week and a half,
+/// and that's the end of it.
+#[test]
+fn test_prepay_past_cap() {
+    let f = Fixture::new(Config {
+        amount: 5200,
+        options: Options {
+            lifetime_cap: 250,
+            ..Options::default()
+        },
+        ..Config::default()
+    });
+    f.client.withdraw();
+    assert_eq!(f.client.remaining_cap(), Some(150));
+
+    assert_eq!(f.client.with_source_account(&f.parent).prepay(&5), 150);
+    f.assert_balance(&f.child_id(), 250);
+    assert_eq!(f.client.remaining_cap(), Some(0));
+    assert_eq!(f.client.get_status(), Status::Expired);
+    assert_eq!(
+        f.client.with_source_account(&f.parent).try_prepay(&1),
+        Err(Ok(Error::SubscriptionExpired))
+    );
+
+    f.advance_time(10 * WEEK);
+    assert_eq!(f.client.try_withdraw(), Err(Ok(Error::SubscriptionExpired)));
+}
+
+/// In `test_create_reports_start()`, a subscription that starts in two weeks
+/// tells the registry it hasn't started yet, and isn't prorated for the
+/// time before it does.
+#[test]
+fn test_create_reports_start() {
+    let f = Fixture::new(Config {
+        init: false,
+        ..Config::default()
+    });
+    let registry = f.env.register_contract(None, MockRegistry);
+    let id = f.client.with_source_account(&f.parent).create(
+        &f.child_id(),
+        &f.token_id,
+        &(START + 2 * WEEK),
+        &500000000,
+        &WEEK,
+        &Options {
+            prorate_first: true,
+            registry: Some(registry.clone()),
+            ..Options::default()
+        },
+    );
+    assert_eq!(
+        notified(&f.env, &registry),
+        vec![&f.env, (f.contract_id.clone(), Status::NotStarted)]
+    );
+
+    f.set_time(START + 2 * WEEK);
+    assert_eq!(f.client.withdraw_for(&id), 1);
+    f.assert_balance(&f.child_id(), 9615384);
+}
+
+/// In `test_manage_subscription()`, a `create`d subscription is paused,
+/// cancelled, pruned and wiped by its id, and the allowance `init` set up
+/// alongside it carries on as if nothing happened.
+#[test]
+fn test_manage_subscription() {
+    let f = Fixture::new(Config::default());
+    let u3 = f.env.accounts().generate();
+    let id = f.client.with_source_account(&f.parent).create(
+        &Identifier::Account(u3.clone()),
+        &f.token_id,
+        &START,
+        &500000000,
+        &WEEK,
+        &Options::default(),
+    );
+
+    f.client
+        .with_source_account(&u3)
+        .request_pause_for(&id, &(START + WEEK), &(START + 2 * WEEK));
+    f.client
+        .with_source_account(&f.parent)
+        .approve_pause_for(&id);
+    assert!(f.client.get_state_for(&id).pending.pause.unwrap().approved);
+    assert_eq!(f.client.get_state().pending.pause, None);
+
+    assert_eq!(f.client.withdraw_for(&id), 1);
+    assert_eq!(f.client.history_for(&id, &0, &10).len(), 1);
+    assert_eq!(f.client.history(&0, &10).len(), 0);
+
+    f.client.with_source_account(&f.parent).cancel_for(&id);
+    assert_eq!(f.client.get_status_for(&id), Status::Cancelled);
+    assert_eq!(f.client.get_status(), Status::Active);
+
+    f.client
+        .with_source_account(&f.parent)
+        .prune_receipts_for(&id, &0);
+    f.client.with_source_account(&f.parent).reset_for(&id);
+    assert_eq!(
+        f.client.try_get_status_for(&id),
+        Err(Ok(Error::SubscriptionNotFound))
+    );
+    assert_eq!(f.client.withdraw(), 1);
+}
+
+/// In `test_cancel_unlists()`, a subscription cancelled straight away drops
+/// off both sides' lists, and one with a notice period stays on them until
+/// its last withdrawal.
+#[test]
+fn test_cancel_unlists() {
+    let f = Fixture::new(Config {
+        init: false,
+        ..Config::default()
+    });
+    let create = |notice: u64| {
+        f.client.with_source_account(&f.parent).create(
+            &f.child_id(),
+            &f.token_id,
+            &START,
+            &5200,
+            &WEEK,
+            &Options {
+                cancel_notice_period: notice,
+                ..Options::default()
+            },
+        )
+    };
+    let now = create(0);
+    let later = create(WEEK);
+    let listed = |role: Role| {
+        let who = match role {
+            Role::Parent => f.parent_id(),
+            Role::Child => f.child_id(),
+        };
+        f.client.subscriptions_of(&who, &role, &0, &10)
+    };
+    assert_eq!(listed(Role::Parent), vec![&f.env, now, later]);
+
+    f.client.with_source_account(&f.parent).cancel_for(&now);
+    assert_eq!(listed(Role::Parent), vec![&f.env, later]);
+    assert_eq!(listed(Role::Child), vec![&f.env, later]);
+
+    f.client.with_source_account(&f.parent).cancel_for(&later);
+    assert_eq!(listed(Role::Child), vec![&f.env, later]);
+    f.advance_time(WEEK);
+    assert_eq!(f.client.withdraw_for(&later), 2);
+    assert_eq!(listed(Role::Parent), Vec::new(&f.env));
+    assert_eq!(listed(Role::Child), Vec::new(&f.env));
+}
+
+/// In `test_accept_amount_needs_signers()`, the `Child` asks for a raise on an
+/// allowance with three officers. The `Parent` can't accept it on their own,
+/// but two of the officers approving it can.
+#[test]
+fn test_accept_amount_needs_signers() {
+    let mut f = Fixture::new(Config {
+        init: false,
+        ..Config::default()
+    });
+    let s1 = f.env.accounts().generate();
+    let s2 = f.env.accounts().generate();
+    let s3 = f.env.accounts().generate();
+    f.config.options.signers = Some(Signers {
+        accounts: vec![&f.env, s1.clone(), s2.clone(), s3],
+        threshold: 2,
+        approval_window: 24 * 60 * 60,
+    });
+    f.init();
+
+    f.client
+        .with_source_account(&f.child)
+        .propose_amount(&600000000);
+    assert_eq!(
+        f.client.with_source_account(&f.parent).try_accept_amount(),
+        Err(Ok(Error::ApprovalRequired))
+    );
+
+    let raise = Action::Amount(600000000);
+    assert!(!f.client.with_source_account(&s1).approve_action(&raise));
+    assert!(f.client.with_source_account(&s2).approve_action(&raise));
+    let state = f.client.get_state();
+    assert_eq!(state.progress.agreed_amount, 600000000);
+    assert_eq!(state.pending.amount, None);
+}
+
+/// In `test_amount_event()`, the `Parent` cuts the amount twice before the
+/// first cut takes effect. Both times, the old amount they're told about (and
+/// that the "amount" event publishes) is the one still in effect.
+#[test]
+fn test_amount_event() {
+    let f = Fixture::new(Config {
+        options: Options {
+            amount_notice_period: WEEK,
+            ..Options::default()
+        },
+        ..Config::default()
+    });
+    let last_event = || f.env.events().all().last().unwrap().unwrap();
+    let parent = f.client.with_source_account(&f.parent);
+
+    assert_eq!(parent.propose_amount(&400000000), 500000000);
+    assert_eq!(
+        last_event(),
+        (
+            f.contract_id.clone(),
+            (symbol!("amount"), 0u64).into_val(&f.env),
+            (500000000i128, 400000000i128, START + WEEK).into_val(&f.env),
+        )
+    );
+
+    f.advance_time(24 * 60 * 60);
+    assert_eq!(parent.propose_amount(&300000000), 500000000);
+    assert_eq!(
+        last_event(),
+        (
+            f.contract_id.clone(),
+            (symbol!("amount"), 0u64).into_val(&f.env),
+            (500000000i128, 300000000i128, START + WEEK + 24 * 60 * 60).into_val(&f.env),
+        )
+    );
+}
+
+/// In `test_escalator_bounded()`, an amount too small to go up by a whole
+/// stroop is left alone however many escalations are due, and anything else
+/// compounds at most 1000 times.
+#[test]
+fn test_escalator_bounded() {
+    let f = Fixture::new(Config {
+        options: Options {
+            escalator_bps: 1,
+            escalator_every: 1,
+            ..Options::default()
+        },
+        ..Config::default()
+    });
+    let escalate = |amount: i128, periods: u64| {
+        f.env.as_contract(&f.contract_id, || {
+            Pricing::load(&Sub::main(f.env.clone()))
+                .unwrap()
+                .escalate(amount, START + periods * WEEK)
+        })
+    };
+
+    assert_eq!(escalate(100, 1000000), Ok(100));
+    assert_eq!(escalate(100000, 1000), Ok(110000));
+    assert_eq!(escalate(100000, 1001), Err(Error::TooManyEscalations));
+}
+
+/// In `test_reset_period_keys()`, the usage counter and the skip proposal
+/// left over when an allowance is cancelled are wiped by `reset`, however far
+/// ahead the skip was proposed for, and `reset` waits for the receipts to be
+/// pruned.
+#[test]
+fn test_reset_period_keys() {
+    let f = Fixture::new(Config {
+        options: Options {
+            unit_price: 1,
+            max_overage: 1000,
+            ..Options::default()
+        },
+        ..Config::default()
+    });
+    let has = |key: StorageKey| {
+        f.env.as_contract(&f.contract_id, || {
+            Sub::main(f.env.clone()).storage().has(key)
+        })
+    };
+
+    f.client.with_source_account(&f.child).report_usage(&10);
+    assert_eq!(f.client.withdraw(), 1);
+    assert!(!has(StorageKey::Usage(0)));
+    f.client.with_source_account(&f.child).report_usage(&10);
+    f.client.with_source_account(&f.parent).propose_skip(&500);
+    assert!(has(StorageKey::Usage(1)));
+    assert!(has(StorageKey::SkipReq(500)));
+
+    f.client.with_source_account(&f.parent).cancel();
+    let parent = f.client.with_source_account(&f.parent);
+    assert_eq!(parent.try_reset(), Err(Ok(Error::ReceiptsNotPruned)));
+    assert_eq!(parent.prune_receipts(&0), 1);
+    parent.reset();
+    for key in [
+        StorageKey::Usage(1),
+        StorageKey::SkipReq(500),
+        StorageKey::UsedPeriods,
+        StorageKey::SkipReqs,
+        StorageKey::FailedPeriods,
+    ] {
+        assert!(!has(key));
+    }
+}
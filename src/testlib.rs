@@ -0,0 +1,366 @@
+#![cfg(test)]
+
+//! What the tests share. The first test in `test.rs` spells every step of the
+//! setup out in full, so you can follow along. Once you've seen it, a
+//! `Fixture` saves the next test from copy-pasting the same forty lines: it
+//! sets the ledger, generates a `Parent` and a `Child`, registers a token
+//! (funded and approved), registers the AllowanceContract and, unless told
+//! otherwise, initializes it.
+//!
+//! ```ignore
+//! let f = Fixture::new(Config::default());
+//! f.client.withdraw();
+//! f.advance_time(WEEK);
+//! f.client.withdraw();
+//! f.assert_balance(&f.child_id(), 9615384 * 2);
+//! ```
+
+use soroban_auth::{Identifier, Signature};
+use soroban_sdk::{
+    contractimpl, contracttype, symbol,
+    testutils::{Accounts, Ledger, LedgerInfo},
+    AccountId, Address, BytesN, Env, IntoVal, Status, Vec,
+};
+
+use crate::{token, AllowanceContract, AllowanceContractClient, Error, Options};
+
+/// The timestamp all of our tests start from, and the `step` they mostly use.
+pub(crate) const START: u64 = 1669726145;
+pub(crate) const WEEK: u64 = 7 * 24 * 60 * 60;
+
+/// The id `setup_token` registers its token at. Any will do, so long as two
+/// tokens in the same test don't share one.
+const TOKEN_ID: [u8; 32] = [
+    78, 52, 121, 202, 209, 66, 106, 25, 193, 181, 10, 91, 46, 213, 58, 244, 217, 115, 23, 232, 144,
+    71, 210, 113, 57, 46, 203, 166, 210, 20, 155, 105,
+];
+
+pub(crate) fn set_timestamp(env: &Env, timestamp: u64) {
+    env.ledger().set(LedgerInfo {
+        timestamp,
+        protocol_version: 1,
+        sequence_number: 10,
+        network_passphrase: Default::default(),
+        base_reserve: 10,
+    });
+}
+
+/// Registers and initializes a token, mints 1,000,000,000 stroops to the
+/// `parent`, and approves a 500,000,000 stroop allowance for `contract_id`.
+pub(crate) fn setup_token(
+    env: &Env,
+    parent: &AccountId,
+    contract_id: &BytesN<32>,
+) -> (BytesN<32>, token::Client) {
+    setup_token_at(env, &TOKEN_ID, parent, contract_id)
+}
+
+/// The same as `setup_token`, for when a test needs more than one token.
+pub(crate) fn setup_token_at(
+    env: &Env,
+    token_id: &[u8; 32],
+    parent: &AccountId,
+    contract_id: &BytesN<32>,
+) -> (BytesN<32>, token::Client) {
+    let id = env.register_contract_token(&BytesN::from_array(env, token_id));
+
+    let token = token::Client::new(env, &id);
+    token.init(
+        &Identifier::Account(parent.clone()),
+        &token::TokenMetadata {
+            name: "USD coin".into_val(env),
+            symbol: "USDC".into_val(env),
+            decimals: 7,
+        },
+    );
+    token.with_source_account(parent).mint(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Account(parent.clone()),
+        &1000000000,
+    );
+    token.with_source_account(parent).approve(
+        &Signature::Invoker,
+        &0,
+        &Identifier::Contract(contract_id.clone()),
+        &500000000,
+    );
+
+    (id, token)
+}
+
+/// What `MockToken` does with a transfer.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Transfer {
+    // Just what a real token would.
+    Succeed,
+    // Fails with this contract error, and moves nothing.
+    Fail(u32),
+    // Takes the full amount from the sender, but keeps this many basis points
+    // of it for itself.
+    Skim(i128),
+    // Goes through, but from then on `balance` says the receiver has this
+    // much more than they do (or less, if it's negative).
+    Misreport(i128),
+}
+
+/// A stand-in for the token that can be told what to do with each transfer
+/// (see `script`), for trying out everything a real token only does when
+/// something has gone badly wrong. It only does as much as the
+/// AllowanceContract needs, and nobody needs to sign for anything.
+pub struct MockToken;
+
+#[contractimpl]
+impl MockToken {
+    pub fn set_balance(e: Env, id: Identifier, amount: i128) {
+        e.storage().set(id, amount);
+    }
+
+    pub fn set_allowance(e: Env, from: Identifier, spender: Identifier, amount: i128) {
+        e.storage().set((from, spender), amount);
+    }
+
+    /// What the next transfers do, one each, in order. Once they've all been
+    /// used up, transfers succeed. A transfer that fails takes the rest of
+    /// the invocation down with it, script and all, so a `Fail` stays at the
+    /// front until the next `script`.
+    pub fn script(e: Env, transfers: Vec<Transfer>) {
+        e.storage().set(symbol!("script"), transfers);
+    }
+
+    pub fn balance(e: Env, id: Identifier) -> i128 {
+        let offset: i128 = e
+            .storage()
+            .get((symbol!("offset"), id.clone()))
+            .unwrap_or(Ok(0))
+            .unwrap();
+        Self::held(&e, &id) + offset
+    }
+
+    pub fn allowance(e: Env, from: Identifier, spender: Identifier) -> i128 {
+        e.storage().get((from, spender)).unwrap_or(Ok(0)).unwrap()
+    }
+
+    pub fn is_frozen(_e: Env, _id: Identifier) -> bool {
+        false
+    }
+
+    pub fn xfer(
+        e: Env,
+        _from: Signature,
+        _nonce: i128,
+        to: Identifier,
+        amount: i128,
+    ) -> Result<(), Status> {
+        let from = Self::invoker(&e);
+        Self::transfer(&e, from, to, amount)
+    }
+
+    pub fn xfer_from(
+        e: Env,
+        _spender: Signature,
+        _nonce: i128,
+        from: Identifier,
+        to: Identifier,
+        amount: i128,
+    ) -> Result<(), Status> {
+        let spender = Self::invoker(&e);
+        let allowance = Self::allowance(e.clone(), from.clone(), spender.clone());
+        if allowance < amount {
+            panic!("allowance is not sufficient");
+        }
+        e.storage().set((from.clone(), spender), allowance - amount);
+        Self::transfer(&e, from, to, amount)
+    }
+}
+
+impl MockToken {
+    fn invoker(e: &Env) -> Identifier {
+        match e.invoker() {
+            Address::Account(id) => Identifier::Account(id),
+            Address::Contract(id) => Identifier::Contract(id),
+        }
+    }
+
+    /// What `id` really holds, whatever `balance` says.
+    fn held(e: &Env, id: &Identifier) -> i128 {
+        e.storage().get(id.clone()).unwrap_or(Ok(0)).unwrap()
+    }
+
+    fn transfer(e: &Env, from: Identifier, to: Identifier, amount: i128) -> Result<(), Status> {
+        let mut script: Vec<Transfer> = e
+            .storage()
+            .get(symbol!("script"))
+            .unwrap_or_else(|| Ok(Vec::new(e)))
+            .unwrap();
+        let next = script.pop_front().map_or(Transfer::Succeed, |t| t.unwrap());
+        e.storage().set(symbol!("script"), script);
+
+        let received = match next {
+            Transfer::Succeed => amount,
+            Transfer::Fail(code) => return Err(Status::from_contract_error(code)),
+            Transfer::Skim(bps) => amount - amount * bps / 10000,
+            Transfer::Misreport(offset) => {
+                let key = (symbol!("offset"), to.clone());
+                let old: i128 = e.storage().get(key.clone()).unwrap_or(Ok(0)).unwrap();
+                e.storage().set(key, old + offset);
+                amount
+            }
+        };
+        let held = Self::held(e, &from);
+        if held < amount {
+            panic!("balance is not sufficient");
+        }
+        e.storage().set(from, held - amount);
+        let held = Self::held(e, &to);
+        e.storage().set(to, held + received);
+        Ok(())
+    }
+}
+
+/// How a `Fixture` is set up. The default is the weekly, 500,000,000 stroop a
+/// year allowance most of the tests use, starting at `START`, with no
+/// optional behaviours.
+#[derive(Clone)]
+pub(crate) struct Config {
+    pub start: u64,
+    pub amount: i128,
+    pub step: u64,
+    pub options: Options,
+    // Whether `Fixture::new` calls `init`. Tests of `init` itself turn it off
+    // and call `try_init` (or `init`) when they're ready.
+    pub init: bool,
+    // Whether the token is a `MockToken` rather than a real one. It's funded
+    // and approved just the same.
+    pub mock_token: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            start: START,
+            amount: 500000000,
+            step: WEEK,
+            options: Options::default(),
+            init: true,
+            mock_token: false,
+        }
+    }
+}
+
+pub(crate) struct Fixture {
+    pub env: Env,
+    pub parent: AccountId,
+    pub child: AccountId,
+    pub contract_id: BytesN<32>,
+    pub token_id: BytesN<32>,
+    pub token: token::Client,
+    // The same token as `token`, with a `MockToken`.
+    pub mock: Option<MockTokenClient>,
+    pub client: AllowanceContractClient,
+    pub config: Config,
+}
+
+impl Fixture {
+    pub fn new(config: Config) -> Self {
+        let env = Env::default();
+        set_timestamp(&env, config.start);
+
+        let parent = env.accounts().generate();
+        let child = env.accounts().generate();
+
+        let contract_id = env.register_contract(None, AllowanceContract);
+        let client = AllowanceContractClient::new(&env, &contract_id);
+        let (token_id, token, mock) = if config.mock_token {
+            let id = env.register_contract(None, MockToken);
+            let mock = MockTokenClient::new(&env, &id);
+            let parent = Identifier::Account(parent.clone());
+            mock.set_balance(&parent, &1000000000);
+            mock.set_allowance(
+                &parent,
+                &Identifier::Contract(contract_id.clone()),
+                &500000000,
+            );
+            (id.clone(), token::Client::new(&env, &id), Some(mock))
+        } else {
+            let (id, token) = setup_token(&env, &parent, &contract_id);
+            (id, token, None)
+        };
+
+        let fixture = Fixture {
+            env,
+            parent,
+            child,
+            contract_id,
+            token_id,
+            token,
+            mock,
+            client,
+            config,
+        };
+        if fixture.config.init {
+            fixture.init();
+        }
+        fixture
+    }
+
+    /// Calls `init` as the `Parent`, with the `Config` the fixture was built
+    /// from.
+    pub fn init(&self) {
+        self.client.with_source_account(&self.parent).init(
+            &self.child_id(),
+            &self.token_id,
+            &self.config.amount,
+            &self.config.step,
+            &self.config.options,
+        );
+    }
+
+    /// The same as `init`, but hands back the error instead of panicking.
+    pub fn try_init(&self) -> Result<(), Error> {
+        match self.client.with_source_account(&self.parent).try_init(
+            &self.child_id(),
+            &self.token_id,
+            &self.config.amount,
+            &self.config.step,
+            &self.config.options,
+        ) {
+            Ok(Ok(())) => Ok(()),
+            Err(Ok(err)) => Err(err),
+            _ => panic!("init failed without a contract error"),
+        }
+    }
+
+    /// The `MockToken`, for a fixture that has one.
+    pub fn mock(&self) -> &MockTokenClient {
+        self.mock
+            .as_ref()
+            .expect("the fixture was built without mock_token")
+    }
+
+    pub fn parent_id(&self) -> Identifier {
+        Identifier::Account(self.parent.clone())
+    }
+
+    pub fn child_id(&self) -> Identifier {
+        Identifier::Account(self.child.clone())
+    }
+
+    pub fn now(&self) -> u64 {
+        self.env.ledger().timestamp()
+    }
+
+    pub fn set_time(&self, timestamp: u64) {
+        set_timestamp(&self.env, timestamp);
+    }
+
+    pub fn advance_time(&self, seconds: u64) {
+        set_timestamp(&self.env, self.now() + seconds);
+    }
+
+    #[track_caller]
+    pub fn assert_balance(&self, id: &Identifier, amount: i128) {
+        assert_eq!(self.token.balance(id), amount);
+    }
+}